@@ -25,6 +25,89 @@ use tokio::sync::OnceCell;
 1.ID
 2.cid
 3.user_id
+4.token_id
+5.created_at (登记时间，预览图中的 {date})
+6.metadata (随图片提交的拍摄、提交信息，JSON)
+*/
+// table3: tx_journal
+/**
+1.ID
+2.sender
+3.nonce
+4.method
+5.tx_hash
+6.kind (original / speed_up / cancel)
+7.replaces
+8.status (pending / mined / replaced / cancelled)
+9.max_fee_per_gas
+10.max_priority_fee_per_gas
+11.created_at
+12.requested_by (发起交易的用户名)
+*/
+// table4: market_orders
+/**
+1.ID
+2.kind (listing / offer)
+3.token_id
+4.maker
+5.price
+6.nonce
+7.expiry
+8.signature
+9.status (open / cancelled / settling / filled)
+10.tx_hash
+11.created_at
+12.payment_tx_hash (买家付款交易)
+(maker, nonce) 上有唯一索引 uk_market_orders_maker_nonce
+*/
+// table5: upload_jobs
+/**
+1.ID
+2.job_id
+3.username
+4.state (received / watermarking / storing / pinning / minting / done / failed)
+5.file_path
+6.metadata
+7.mint
+8.cid
+9.token_id
+10.tx_hash (铸造交易广播后立即写入，重启后据此核对而不是重新铸造)
+11.error
+12.created_at
+13.updated_at
+14.watermark_base64 (加水印后写入，重启后不再重新加水印)
+15.engine (加水印所用引擎及参数，json)
+*/
+// table6: idempotency_keys，(scope, idempotency_key) 上建唯一索引 uk_idempotency_scope_key，启动时不存在则自动创建
+/**
+1.ID
+2.scope (user:用户名，未登录时为 ip:客户端地址)
+3.idempotency_key
+4.fingerprint
+5.state (in_progress / completed)
+6.response_status
+7.content_type
+8.response_body
+9.created_at
+*/
+// table7: watermarks，每张图片一行，image_id 对应 images.id
+/**
+1.ID
+2.image_id
+3.token_id
+4.engine (python / native / payload / noop)
+5.parameters (引擎及参数，json)
+6.watermark_base64
+7.watermark_hash (水印的 sha256，与链上 tokenInfo 中的 watermark 相同)
+8.created_at
+9.payload_image_id (payload 引擎嵌入的图片 ID，解码出载荷后按它找到记录，其他引擎为空)
+*/
+// table8: metadata_uris，本服务为 token 生成并上传到 IPFS 的元数据
+/**
+1.ID
+2.token_id
+3.cid
+4.created_at
 */
 static RB: OnceCell<RBatis> = OnceCell::const_new();
 
@@ -41,14 +124,169 @@ pub struct Users {
 }
 crud!(Users {});
 impl_select!(Users{select_by_username(username:&str) -> Option => "`where username = #{username} limit 1`"});
+impl_select!(Users{select_by_id(id:i32) -> Option => "`where id = #{id} limit 1`"});
+impl_select!(Users{select_by_address(address:&str) -> Option => "`where lower(address) = lower(#{address}) limit 1`"});
 
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct Images {
     pub id: Option<i32>,
     pub cid: Option<String>,
     pub user_id: Option<i32>,
+    pub token_id: Option<String>, // 铸造后对应的 token id，未铸造时为空
+    pub created_at: Option<DateTime>,
+    pub metadata: Option<String>, // 随图片提交的 ImageUploadMetadata，JSON
 }
 crud!(Images {});
+impl_select!(Images{select_by_id(id:i32) -> Option => "`where id = #{id} limit 1`"});
+impl_select!(Images{select_by_cid(cid:&str) -> Option => "`where cid = #{cid} limit 1`"});
+
+// 表tx_journal，每发送一笔交易（包括加速、取消的替换交易）记录一行，同一 sender + nonce 的行构成一条替换链
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct TxJournal {
+    pub id: Option<i32>,
+    pub sender: Option<String>,
+    pub nonce: Option<i64>,
+    pub method: Option<String>,
+    pub tx_hash: Option<String>,
+    pub kind: Option<String>,
+    pub replaces: Option<String>,
+    pub status: Option<String>,
+    pub max_fee_per_gas: Option<String>,
+    pub max_priority_fee_per_gas: Option<String>,
+    pub created_at: Option<DateTime>,
+    pub requested_by: Option<String>,
+}
+crud!(TxJournal {});
+impl_select!(TxJournal{select_by_tx_hash(tx_hash:&str) -> Option => "`where tx_hash = #{tx_hash} limit 1`"});
+impl_select!(TxJournal{select_by_nonce(sender:&str, nonce:i64) => "`where sender = #{sender} and nonce = #{nonce} order by id`"});
+
+// 表market_orders，保存链下签名的挂单（卖家签名）与报价（买家签名），成交时才上链
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct MarketOrders {
+    pub id: Option<i32>,
+    pub kind: Option<String>,
+    pub token_id: Option<String>,
+    pub maker: Option<String>,
+    pub price: Option<String>,
+    pub nonce: Option<String>,
+    pub expiry: Option<i64>,
+    pub signature: Option<String>,
+    pub status: Option<String>,
+    pub tx_hash: Option<String>,
+    pub created_at: Option<DateTime>,
+    pub payment_tx_hash: Option<String>,
+}
+crud!(MarketOrders {});
+impl_select!(MarketOrders{select_by_id(id:i32) -> Option => "`where id = #{id} limit 1`"});
+impl_select!(MarketOrders{select_by_nonce(maker:&str, nonce:&str) -> Option => "`where maker = #{maker} and nonce = #{nonce} limit 1`"});
+impl_select!(MarketOrders{select_open(now:i64) => "`where status = 'open' and expiry > #{now} order by id desc`"});
+
+/// 以条件更新在状态之间切换订单，只有状态仍为 from 时才会更新，返回是否更新成功，
+/// 并发接受、取消同一订单时只有一个请求能成功
+pub async fn transition_order(rb: &RBatis, id: i32, from: &str, to: &str) -> Result<bool, Error> {
+    let result = rb
+        .exec(
+            "update market_orders set status = ? where id = ? and status = ?",
+            vec![rbs::to_value!(to), rbs::to_value!(id), rbs::to_value!(from)],
+        )
+        .await?;
+    Ok(result.rows_affected == 1)
+}
+
+/// 表上不存在名为 index 的索引时在 columns 上创建唯一索引，已有重复数据时创建失败并返回错误
+pub async fn ensure_unique_index(
+    rb: &RBatis,
+    table: &str,
+    index: &str,
+    columns: &[&str],
+) -> Result<(), Error> {
+    let existing: u64 = rb
+        .query_decode(
+            "select count(*) from information_schema.statistics \
+             where table_schema = database() and table_name = ? and index_name = ?",
+            vec![rbs::to_value!(table), rbs::to_value!(index)],
+        )
+        .await?;
+    if existing == 0 {
+        let sql = format!(
+            "create unique index {} on {} ({})",
+            index,
+            table,
+            columns.join(", ")
+        );
+        rb.exec(&sql, vec![]).await?;
+    }
+    Ok(())
+}
+
+// 表upload_jobs，异步上传任务，state 记录任务当前所处的阶段
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct UploadJobs {
+    pub id: Option<i32>,
+    pub job_id: Option<String>,
+    pub username: Option<String>,
+    pub state: Option<String>,
+    pub file_path: Option<String>,
+    pub metadata: Option<String>,
+    pub mint: Option<bool>, // 是否在存储后铸造 token（register_image）
+    pub cid: Option<String>,
+    pub token_id: Option<String>,
+    pub tx_hash: Option<String>,
+    pub error: Option<String>,
+    pub created_at: Option<DateTime>,
+    pub updated_at: Option<DateTime>,
+    pub watermark_base64: Option<String>,
+    pub engine: Option<String>,
+}
+crud!(UploadJobs {});
+impl_select!(UploadJobs{select_by_job_id(job_id:&str) -> Option => "`where job_id = #{job_id} limit 1`"});
+impl_select!(UploadJobs{select_unfinished() => "`where state not in ('done', 'failed') order by id`"});
+
+// 表idempotency_keys，保存带 Idempotency-Key 的写请求的首次响应，scope 区分发起请求的用户或客户端
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct IdempotencyKeys {
+    pub id: Option<i32>,
+    pub scope: Option<String>,
+    pub idempotency_key: Option<String>,
+    pub fingerprint: Option<String>, // 请求方法、路径与请求体的 sha256
+    pub state: Option<String>,
+    pub response_status: Option<i32>,
+    pub content_type: Option<String>,
+    pub response_body: Option<String>,
+    pub created_at: Option<DateTime>,
+}
+crud!(IdempotencyKeys {});
+impl_select!(IdempotencyKeys{select_by_key(scope:&str, idempotency_key:&str) -> Option => "`where scope = #{scope} and idempotency_key = #{idempotency_key} limit 1`"});
+
+// 表watermarks，每张图片嵌入的水印，验证归属时按 parameters 中的引擎提取后与 watermark_base64 比较
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Watermarks {
+    pub id: Option<i32>,
+    pub image_id: Option<i32>,
+    pub token_id: Option<String>,
+    pub engine: Option<String>,
+    pub parameters: Option<String>,
+    pub watermark_base64: Option<String>,
+    pub watermark_hash: Option<String>,
+    pub created_at: Option<DateTime>,
+    pub payload_image_id: Option<i64>,
+}
+crud!(Watermarks {});
+impl_select!(Watermarks{select_by_image_id(image_id:i32) -> Option => "`where image_id = #{image_id} limit 1`"});
+impl_select!(Watermarks{select_by_hash(watermark_hash:&str) => "`where watermark_hash = #{watermark_hash} order by id`"});
+impl_select!(Watermarks{select_by_payload_image_id(payload_image_id:i64) => "`where payload_image_id = #{payload_image_id} order by id`"});
+impl_select!(Watermarks{select_after(id:i32, limit:u64) => "`where id > #{id} order by id limit ${limit}`"});
+
+// 表metadata_uris，只有记录在这张表中的 ipfs://<cid> tokenURI 才被当作元数据文档读取
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct MetadataUris {
+    pub id: Option<i32>,
+    pub token_id: Option<String>,
+    pub cid: Option<String>,
+    pub created_at: Option<DateTime>,
+}
+crud!(MetadataUris {});
+impl_select!(MetadataUris{select_by_token(token_id:&str, cid:&str) -> Option => "`where token_id = #{token_id} and cid = #{cid} limit 1`"});
 
 /// 确保数据库连接池已初始化（懒加载）
 async fn ensure_db_initialized() -> Result<&'static RBatis, Error> {
@@ -67,9 +305,12 @@ async fn ensure_db_initialized() -> Result<&'static RBatis, Error> {
 
 /// 获取数据库连接池（隐式初始化）
 pub async fn get_db() -> &'static RBatis {
-    ensure_db_initialized()
-        .await
-        .expect("Failed to initialize database")
+    try_get_db().await.expect("Failed to initialize database")
+}
+
+/// 数据库不可用时返回错误而不是 panic，供交易日志等不能影响主流程的地方使用
+pub async fn try_get_db() -> Result<&'static RBatis, Error> {
+    ensure_db_initialized().await
 }
 
 #[tokio::test]
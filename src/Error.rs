@@ -3,9 +3,12 @@
 /// 2.定义自己的错误类型
 /// 3.判断哪些错误需要在当前函数中处理，哪些错误需要向上传递
 use rbatis::Error;
-use rocket::http::Status;
+use rocket::fairing::{Fairing, Info, Kind};
+use rocket::http::{ContentType, Header, Status};
 use rocket::response::Responder;
 use rocket::serde::json::Json;
+use rocket::{Request, Response};
+use serde::{Deserialize, Serialize};
 use std::fmt;
 
 pub enum TxError {
@@ -15,23 +18,115 @@ pub enum TxError {
 pub trait ApiError: std::fmt::Debug + Send + Sync {
     fn status(&self) -> rocket::http::Status;
     fn message(&self) -> String;
+    /// 稳定的机器可读错误码，客户端应依据它而不是 message 做判断
+    fn error_code(&self) -> &'static str;
+    /// 字段级的校验错误
+    fn details(&self) -> Option<Vec<FieldError>> {
+        None
+    }
 }
 
-impl<'r> Responder<'r, 'static> for Box<dyn ApiError> {
-    fn respond_to(self, _: &'r rocket::Request<'_>) -> rocket::response::Result<'static> {
-        rocket::response::Response::build()
-            .status(self.status())
-            .sized_body(self.message().len(), std::io::Cursor::new(self.message()))
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct FieldError {
+    pub field: String,
+    pub message: String,
+}
+
+// 所有错误（包括 catcher 返回的错误）统一的 JSON 响应格式
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ErrorResponse {
+    pub error_code: String, // 错误码，例如 "UserAlreadyExists"
+    pub message: String,    // 错误的详细描述，例如 "User already exists"
+    pub status: u16,
+    pub request_id: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub details: Option<Vec<FieldError>>,
+}
+
+impl ErrorResponse {
+    pub fn new(error_code: &str, message: String, status: Status, request: &Request<'_>) -> Self {
+        ErrorResponse {
+            error_code: error_code.to_string(),
+            message,
+            status: status.code,
+            request_id: RequestId::of(request).to_string(),
+            details: None,
+        }
+    }
+
+    fn from_api_error(error: &dyn ApiError, request: &Request<'_>) -> Self {
+        ErrorResponse {
+            details: error.details(),
+            ..ErrorResponse::new(error.error_code(), error.message(), error.status(), request)
+        }
+    }
+
+    fn respond(self, status: Status) -> rocket::response::Result<'static> {
+        let body = serde_json::to_string(&self).map_err(|_| Status::InternalServerError)?;
+        Response::build()
+            .status(status)
+            .header(ContentType::JSON)
+            .sized_body(body.len(), std::io::Cursor::new(body))
             .ok()
     }
 }
 
+/// 请求 ID：优先使用客户端传入的 X-Request-Id，否则随机生成，在同一请求内保持不变
+pub struct RequestId(String);
+
+impl RequestId {
+    pub const HEADER: &'static str = "X-Request-Id";
+
+    pub fn of<'r>(request: &'r Request<'_>) -> &'r str {
+        &request
+            .local_cache(|| {
+                let id = match request.headers().get_one(Self::HEADER) {
+                    Some(id) if !id.is_empty() && id.len() <= 128 => id.to_string(),
+                    _ => format!("{:032x}", rand::random::<u128>()),
+                };
+                RequestId(id)
+            })
+            .0
+    }
+}
+
+// 在每个响应中返回请求 ID，便于客户端与服务端日志对应
+pub struct RequestIdFairing;
+
+#[rocket::async_trait]
+impl Fairing for RequestIdFairing {
+    fn info(&self) -> Info {
+        Info {
+            name: "Attach request id to responses",
+            kind: Kind::Response,
+        }
+    }
+
+    async fn on_response<'r>(&self, request: &'r Request<'_>, response: &mut Response<'r>) {
+        response.set_header(Header::new(RequestId::HEADER, RequestId::of(request).to_string()));
+    }
+}
+
+// 允许在返回 Box<dyn ApiError> 的路由中直接对具体错误类型使用 `?`
+impl<E: ApiError + 'static> From<E> for Box<dyn ApiError> {
+    fn from(error: E) -> Self {
+        Box::new(error)
+    }
+}
+
+impl<'r> Responder<'r, 'static> for Box<dyn ApiError> {
+    fn respond_to(self, request: &'r rocket::Request<'_>) -> rocket::response::Result<'static> {
+        ErrorResponse::from_api_error(self.as_ref(), request).respond(self.status())
+    }
+}
+
 #[derive(Debug)]
 pub enum UserError {
     UserAlreadyExists,
     UserNotFound,
     InvalidPassword,
     DatabaseError(rbatis::Error),
+    PermissionDenied(String), // 调用者不是 token 的所有者或被授权者
 }
 
 impl fmt::Display for UserError {
@@ -49,6 +144,9 @@ impl fmt::Display for UserError {
             UserError::DatabaseError(e) => {
                 write!(f, "Database error: {}", e)
             }
+            UserError::PermissionDenied(e) => {
+                write!(f, "Permission denied: {}", e)
+            }
         }
     }
 }
@@ -60,6 +158,7 @@ impl ApiError for UserError {
             UserError::UserNotFound => Status::InternalServerError,
             UserError::InvalidPassword => Status::BadRequest,
             UserError::DatabaseError(_) => Status::InternalServerError,
+            UserError::PermissionDenied(_) => Status::Forbidden,
         }
     }
 
@@ -69,19 +168,24 @@ impl ApiError for UserError {
             UserError::UserNotFound => "User not found".to_string(),
             UserError::InvalidPassword => "Invalid password".to_string(),
             UserError::DatabaseError(e) => format!("Database error: {}", e),
+            UserError::PermissionDenied(e) => format!("Permission denied: {}", e),
+        }
+    }
+
+    fn error_code(&self) -> &'static str {
+        match self {
+            UserError::UserAlreadyExists => "UserAlreadyExists",
+            UserError::UserNotFound => "UserNotFound",
+            UserError::InvalidPassword => "InvalidPassword",
+            UserError::DatabaseError(_) => "DatabaseError",
+            UserError::PermissionDenied(_) => "PermissionDenied",
         }
     }
 }
 
 impl<'r> Responder<'r, 'static> for UserError {
-    fn respond_to(self, _: &'r rocket::Request<'_>) -> rocket::response::Result<'static> {
-        let status = self.status();
-        let body = self.message();
-
-        rocket::response::Response::build()
-            .status(status)
-            .sized_body(body.len(), std::io::Cursor::new(body))
-            .ok()
+    fn respond_to(self, request: &'r rocket::Request<'_>) -> rocket::response::Result<'static> {
+        ErrorResponse::from_api_error(&self, request).respond(self.status())
     }
 }
 
@@ -89,6 +193,8 @@ impl<'r> Responder<'r, 'static> for UserError {
 pub enum RequestError {
     EmptyUsername,
     TooShortPassword,
+    InvalidParameter(String), // 参数格式错误，包含参数名及原因
+    ValidationFailed(Vec<FieldError>), // 请求体未通过校验，包含每个字段的错误
 }
 
 impl fmt::Display for RequestError {
@@ -100,6 +206,16 @@ impl fmt::Display for RequestError {
             RequestError::TooShortPassword => {
                 write!(f, "password too short, at least 3 characters")
             }
+            RequestError::InvalidParameter(err) => {
+                write!(f, "Invalid parameter: {}", err)
+            }
+            RequestError::ValidationFailed(fields) => {
+                let fields: Vec<String> = fields
+                    .iter()
+                    .map(|e| format!("{}: {}", e.field, e.message))
+                    .collect();
+                write!(f, "Validation failed: {}", fields.join(", "))
+            }
         }
     }
 }
@@ -109,12 +225,38 @@ impl ApiError for RequestError {
         match self {
             RequestError::EmptyUsername => rocket::http::Status::BadRequest,
             RequestError::TooShortPassword => rocket::http::Status::BadRequest,
+            RequestError::InvalidParameter(_) => rocket::http::Status::BadRequest,
+            RequestError::ValidationFailed(_) => rocket::http::Status::UnprocessableEntity,
         }
     }
     fn message(&self) -> String {
         match self {
             RequestError::EmptyUsername => "Empty username".to_string(),
             RequestError::TooShortPassword => "Password too short".to_string(),
+            RequestError::InvalidParameter(_) => self.to_string(),
+            RequestError::ValidationFailed(_) => self.to_string(),
+        }
+    }
+
+    fn error_code(&self) -> &'static str {
+        match self {
+            RequestError::EmptyUsername => "EmptyUsername",
+            RequestError::TooShortPassword => "TooShortPassword",
+            RequestError::InvalidParameter(_) => "InvalidParameter",
+            RequestError::ValidationFailed(_) => "ValidationFailed",
+        }
+    }
+
+    fn details(&self) -> Option<Vec<FieldError>> {
+        let field_error = |field: &str| FieldError {
+            field: field.to_string(),
+            message: self.message(),
+        };
+        match self {
+            RequestError::EmptyUsername => Some(vec![field_error("username")]),
+            RequestError::TooShortPassword => Some(vec![field_error("password")]),
+            RequestError::InvalidParameter(_) => None,
+            RequestError::ValidationFailed(fields) => Some(fields.clone()),
         }
     }
 }
@@ -129,6 +271,11 @@ pub enum ImageError {
     IOError(String),               // 通用 I/O 错误，包含详细描述
     DatabaseError(rbatis::Error),
     IpfsError(String),
+    JobNotFound(String),           // 上传任务不存在或不属于当前用户
+    MintError(String),             // 图片存储后铸造 token 失败
+    ImageRejected(String),         // 图片不符合上传策略（大小、格式、尺寸）
+    CorruptImage(String),          // 图片文件损坏或被截断
+    ImageNotFound(String),         // 图片没有在本系统登记
 }
 
 impl fmt::Display for ImageError {
@@ -146,10 +293,17 @@ impl fmt::Display for ImageError {
             ImageError::IOError(err) => write!(f, "I/O error: {}", err),
             ImageError::DatabaseError(err) => write!(f, "Database error: {}", err),
             ImageError::IpfsError(err) => write!(f, "IPFS error: {}", err),
+            ImageError::JobNotFound(job_id) => write!(f, "Upload job {} not found", job_id),
+            ImageError::MintError(err) => write!(f, "Failed to mint image token: {}", err),
+            ImageError::ImageRejected(err) => write!(f, "Image rejected: {}", err),
+            ImageError::CorruptImage(err) => write!(f, "Corrupt or truncated image: {}", err),
+            ImageError::ImageNotFound(cid) => write!(f, "Image {} not found", cid),
         }
     }
 }
 
+impl std::error::Error for ImageError {}
+
 impl ApiError for ImageError {
     fn status(&self) -> rocket::http::Status {
         match self {
@@ -161,12 +315,35 @@ impl ApiError for ImageError {
             ImageError::IOError(_) => rocket::http::Status::InternalServerError,
             ImageError::DatabaseError(_) => rocket::http::Status::InternalServerError,
             ImageError::IpfsError(_) => rocket::http::Status::InternalServerError,
+            ImageError::JobNotFound(_) => rocket::http::Status::NotFound,
+            ImageError::MintError(_) => rocket::http::Status::InternalServerError,
+            ImageError::ImageRejected(_) => rocket::http::Status::UnprocessableEntity,
+            ImageError::CorruptImage(_) => rocket::http::Status::UnprocessableEntity,
+            ImageError::ImageNotFound(_) => rocket::http::Status::NotFound,
         }
     }
 
     fn message(&self) -> String {
         self.to_string() // 使用 fmt::Display 的实现作为错误消息
     }
+
+    fn error_code(&self) -> &'static str {
+        match self {
+            ImageError::DecodeBytesError => "DecodeBytesError",
+            ImageError::EncodeBytesError => "EncodeBytesError",
+            ImageError::FailedStartAddWatermark => "FailedStartAddWatermark",
+            ImageError::WatermarkProcessError(_) => "WatermarkProcessError",
+            ImageError::JsonParseError => "JsonParseError",
+            ImageError::IOError(_) => "IOError",
+            ImageError::DatabaseError(_) => "DatabaseError",
+            ImageError::IpfsError(_) => "IpfsError",
+            ImageError::JobNotFound(_) => "JobNotFound",
+            ImageError::MintError(_) => "MintError",
+            ImageError::ImageRejected(_) => "ImageRejected",
+            ImageError::CorruptImage(_) => "CorruptImage",
+            ImageError::ImageNotFound(_) => "ImageNotFound",
+        }
+    }
 }
 
 #[derive(Debug)]
@@ -175,6 +352,9 @@ pub enum BlockchainError {
     WatchTransactionError(String), // 监听交易哈希失败
     ContractCallError(String),     // 合约调用失败
     ContractInitializeError(String),
+    TransactionNotFound(String),   // 交易不存在或不在交易日志中
+    JournalError(String),          // 读写交易日志失败
+    NotTransactionOwner(String),   // 交易不是由当前用户发起的
 }
 
 impl fmt::Display for BlockchainError {
@@ -192,6 +372,15 @@ impl fmt::Display for BlockchainError {
             BlockchainError::ContractInitializeError(err) => {
                 write!(f, "Contract initialize error: {}", err)
             }
+            BlockchainError::TransactionNotFound(err) => {
+                write!(f, "Transaction not found: {}", err)
+            }
+            BlockchainError::JournalError(err) => {
+                write!(f, "Transaction journal error: {}", err)
+            }
+            BlockchainError::NotTransactionOwner(err) => {
+                write!(f, "Transaction was not requested by current user: {}", err)
+            }
         }
     }
 }
@@ -203,10 +392,123 @@ impl ApiError for BlockchainError {
             BlockchainError::WatchTransactionError(_) => Status::InternalServerError,
             BlockchainError::ContractCallError(_) => Status::InternalServerError,
             BlockchainError::ContractInitializeError(_) => Status::BadRequest,
+            BlockchainError::TransactionNotFound(_) => Status::NotFound,
+            BlockchainError::JournalError(_) => Status::InternalServerError,
+            BlockchainError::NotTransactionOwner(_) => Status::Forbidden,
         }
     }
 
     fn message(&self) -> String {
         self.to_string() // 使用 fmt::Display 的实现作为错误消息
     }
+
+    fn error_code(&self) -> &'static str {
+        match self {
+            BlockchainError::SendTransactionError(_) => "SendTransactionError",
+            BlockchainError::WatchTransactionError(_) => "WatchTransactionError",
+            BlockchainError::ContractCallError(_) => "ContractCallError",
+            BlockchainError::ContractInitializeError(_) => "ContractInitializeError",
+            BlockchainError::TransactionNotFound(_) => "TransactionNotFound",
+            BlockchainError::JournalError(_) => "JournalError",
+            BlockchainError::NotTransactionOwner(_) => "NotTransactionOwner",
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum MarketError {
+    OrderNotFound(i32),          // 挂单或报价不存在
+    InvalidSignature(String),    // EIP-712 签名无效或签名者不是 maker
+    OrderExpired,                // 挂单或报价已过期
+    OrderClosed(String),         // 挂单或报价已被取消或成交
+    NonceUsed(String),           // 同一 maker 的 nonce 已被使用
+    DatabaseError(rbatis::Error),
+}
+
+impl fmt::Display for MarketError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            MarketError::OrderNotFound(id) => write!(f, "Order {} not found", id),
+            MarketError::InvalidSignature(err) => write!(f, "Invalid signature: {}", err),
+            MarketError::OrderExpired => write!(f, "Order expired"),
+            MarketError::OrderClosed(status) => write!(f, "Order is already {}", status),
+            MarketError::NonceUsed(nonce) => write!(f, "Nonce {} is already used", nonce),
+            MarketError::DatabaseError(err) => write!(f, "Database error: {}", err),
+        }
+    }
+}
+
+impl ApiError for MarketError {
+    fn status(&self) -> Status {
+        match self {
+            MarketError::OrderNotFound(_) => Status::NotFound,
+            MarketError::InvalidSignature(_) => Status::BadRequest,
+            MarketError::OrderExpired => Status::BadRequest,
+            MarketError::OrderClosed(_) => Status::Conflict,
+            MarketError::NonceUsed(_) => Status::Conflict,
+            MarketError::DatabaseError(_) => Status::InternalServerError,
+        }
+    }
+
+    fn message(&self) -> String {
+        self.to_string() // 使用 fmt::Display 的实现作为错误消息
+    }
+
+    fn error_code(&self) -> &'static str {
+        match self {
+            MarketError::OrderNotFound(_) => "OrderNotFound",
+            MarketError::InvalidSignature(_) => "InvalidSignature",
+            MarketError::OrderExpired => "OrderExpired",
+            MarketError::OrderClosed(_) => "OrderClosed",
+            MarketError::NonceUsed(_) => "NonceUsed",
+            MarketError::DatabaseError(_) => "DatabaseError",
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum IdempotencyError {
+    InvalidKey(String), // Idempotency-Key 为空、过长或包含非法字符
+    KeyReused,          // 同一个 key 被用于不同的请求
+    KeyInProgress,      // 使用同一个 key 的请求仍在处理中
+    DatabaseError(rbatis::Error),
+}
+
+impl fmt::Display for IdempotencyError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            IdempotencyError::InvalidKey(err) => write!(f, "Invalid idempotency key: {}", err),
+            IdempotencyError::KeyReused => {
+                write!(f, "Idempotency key was already used for a different request")
+            }
+            IdempotencyError::KeyInProgress => {
+                write!(f, "A request with this idempotency key is still in progress")
+            }
+            IdempotencyError::DatabaseError(err) => write!(f, "Database error: {}", err),
+        }
+    }
+}
+
+impl ApiError for IdempotencyError {
+    fn status(&self) -> Status {
+        match self {
+            IdempotencyError::InvalidKey(_) => Status::BadRequest,
+            IdempotencyError::KeyReused => Status::UnprocessableEntity,
+            IdempotencyError::KeyInProgress => Status::Conflict,
+            IdempotencyError::DatabaseError(_) => Status::InternalServerError,
+        }
+    }
+
+    fn message(&self) -> String {
+        self.to_string() // 使用 fmt::Display 的实现作为错误消息
+    }
+
+    fn error_code(&self) -> &'static str {
+        match self {
+            IdempotencyError::InvalidKey(_) => "InvalidIdempotencyKey",
+            IdempotencyError::KeyReused => "IdempotencyKeyReused",
+            IdempotencyError::KeyInProgress => "IdempotencyKeyInProgress",
+            IdempotencyError::DatabaseError(_) => "DatabaseError",
+        }
+    }
 }
@@ -8,6 +8,10 @@ use std::fs::File;
 use std::io::Read;
 use std::io::Write;
 
+pub const IPFS_API_URL: &str = "http://192.168.0.7:5001";
+// 写入 tokenURI 的公共网关地址前缀
+pub const IPFS_GATEWAY_URL: &str = "https://ipfs.io/ipfs/";
+
 /// 将图片放入ipfs中，并返回其cid
 async fn upload_and_pin_file(
     file_path: &str,
@@ -62,18 +66,30 @@ pub async fn upload_and_pin_base64(
         .decode(base64_data)
         .map_err(|e| format!("Failed to decode base64: {}", e))?;
 
+    let cid = add_bytes(file_contents, "watermarked_image.jpg", ipfs_api_url).await?;
+    pin_cid(&cid, ipfs_api_url).await?;
+
+    Ok(cid)
+}
+
+/// 将字节内容添加到 IPFS（不固定），返回其cid
+pub async fn add_bytes(
+    file_contents: Vec<u8>,
+    file_name: &str,
+    ipfs_api_url: &str,
+) -> Result<String, Box<dyn Error>> {
     // 创建 HTTP 客户端
     let client = reqwest::Client::new();
 
     // 创建 multipart 表单
     let form = reqwest::multipart::Form::new().part(
         "file",
-        reqwest::multipart::Part::bytes(file_contents).file_name("watermarked_image.jpg"),
+        reqwest::multipart::Part::bytes(file_contents).file_name(file_name.to_string()),
     );
 
     // 上传文件到 IPFS
     let res = client
-        .post(&format!("{}/api/v0/add", ipfs_api_url))
+        .post(format!("{}/api/v0/add", ipfs_api_url))
         .multipart(form)
         .send()
         .await?;
@@ -87,19 +103,152 @@ pub async fn upload_and_pin_base64(
         .to_string();
 
     println!("File uploaded, CID: {}", cid);
+    Ok(cid)
+}
 
-    // 固定文件
-    let res = client
-        .post(&format!("{}/api/v0/pin/add?arg={}", ipfs_api_url, cid))
+/// 固定 IPFS 中的文件，防止被垃圾回收
+pub async fn pin_cid(cid: &str, ipfs_api_url: &str) -> Result<(), Box<dyn Error>> {
+    let res = reqwest::Client::new()
+        .post(format!("{}/api/v0/pin/add?arg={}", ipfs_api_url, cid))
         .send()
         .await?;
+    if !res.status().is_success() {
+        return Err(format!("Failed to pin {}: HTTP {}", cid, res.status()).into());
+    }
 
     let res_text = res.text().await?;
     println!("File has been pinned: {}", res_text);
+    Ok(())
+}
+
+/// 取消固定，后续流程失败时用于回滚已固定的文件
+pub async fn unpin_cid(cid: &str, ipfs_api_url: &str) -> Result<(), Box<dyn Error>> {
+    let res = reqwest::Client::new()
+        .post(format!("{}/api/v0/pin/rm?arg={}", ipfs_api_url, cid))
+        .send()
+        .await?;
+    if !res.status().is_success() {
+        return Err(format!("Failed to unpin {}: HTTP {}", cid, res.status()).into());
+    }
+    println!("File has been unpinned: {}", cid);
+    Ok(())
+}
+
+/// 将 JSON（如 token 元数据）放入ipfs中，并返回其cid
+pub async fn upload_and_pin_json(
+    json: &serde_json::Value,
+    ipfs_api_url: &str,
+) -> Result<String, Box<dyn Error>> {
+    let client = reqwest::Client::new();
+
+    let form = reqwest::multipart::Form::new().part(
+        "file",
+        reqwest::multipart::Part::bytes(serde_json::to_vec(json)?).file_name("metadata.json"),
+    );
+
+    let res = client
+        .post(format!("{}/api/v0/add", ipfs_api_url))
+        .multipart(form)
+        .send()
+        .await?;
+
+    let res_text = res.text().await?;
+    let res_json: serde_json::Value = serde_json::from_str(&res_text)?;
+    let cid = res_json["Hash"]
+        .as_str()
+        .ok_or("Failed to extract CID from response")?
+        .to_string();
+
+    client
+        .post(format!("{}/api/v0/pin/add?arg={}", ipfs_api_url, cid))
+        .send()
+        .await?;
+    println!("Metadata uploaded and pinned, CID: {}", cid);
 
     Ok(cid)
 }
 
+/// 根据 CID 从 IPFS 读取 JSON 文件
+pub async fn download_json_by_cid(
+    cid: &str,
+    ipfs_api_url: &str,
+) -> Result<serde_json::Value, ImageError> {
+    let url = format!(
+        "{}/api/v0/cat?arg={}",
+        ipfs_api_url.trim_end_matches('/'),
+        cid
+    );
+    let res = Client::new()
+        .post(&url)
+        .send()
+        .await
+        .map_err(|e| ImageError::IpfsError(e.to_string()))?;
+    if !res.status().is_success() {
+        return Err(ImageError::IpfsError(format!(
+            "Failed to download file: HTTP {}",
+            res.status()
+        )));
+    }
+    res.json()
+        .await
+        .map_err(|e| ImageError::IpfsError(e.to_string()))
+}
+
+/// 查询 IPFS 中文件的大小（字节）
+pub async fn ipfs_file_size(cid: &str, ipfs_api_url: &str) -> Result<u64, ImageError> {
+    let url = format!(
+        "{}/api/v0/files/stat?arg=/ipfs/{}",
+        ipfs_api_url.trim_end_matches('/'),
+        cid
+    );
+    let res = Client::new()
+        .post(&url)
+        .send()
+        .await
+        .map_err(|e| ImageError::IpfsError(e.to_string()))?;
+    if !res.status().is_success() {
+        return Err(ImageError::IpfsError(format!(
+            "Failed to stat file: HTTP {}",
+            res.status()
+        )));
+    }
+    let stat: serde_json::Value = res
+        .json()
+        .await
+        .map_err(|e| ImageError::IpfsError(e.to_string()))?;
+    stat["Size"]
+        .as_u64()
+        .ok_or_else(|| ImageError::IpfsError("Failed to extract size from response".to_string()))
+}
+
+/// 从 IPFS 读取文件的一段内容，返回尚未读取响应体的 HTTP 响应，调用方按块流式读取
+pub async fn cat_file_range(
+    cid: &str,
+    ipfs_api_url: &str,
+    offset: u64,
+    length: u64,
+) -> Result<reqwest::Response, ImageError> {
+    let url = format!(
+        "{}/api/v0/cat?arg={}&offset={}&length={}",
+        ipfs_api_url.trim_end_matches('/'),
+        cid,
+        offset,
+        length
+    );
+    let res = Client::new()
+        .post(&url)
+        .send()
+        .await
+        .map_err(|e| ImageError::IpfsError(e.to_string()))?;
+    if !res.status().is_success() {
+        return Err(ImageError::IpfsError(format!(
+            "Failed to download file: HTTP {}",
+            res.status()
+        )));
+    }
+    Ok(res)
+}
+
 pub fn get_cid() -> String {
     let token_urls =
         "https://ipfs.io/ipfs/QmRackxfCSTUg1GBSFGy6xMhFzNcfnR5vJDY8HSmaySNXF".to_string();
@@ -1,26 +1,55 @@
-use crate::Error::{ApiError, ImageError};
-use crate::IPFSImageStorage::storeImage::download_file_by_cid_as_base64;
+use crate::DataBase::{get_db, Images, Users, Watermarks};
+use crate::Error::{ApiError, ErrorResponse, ImageError, RequestError};
+use crate::IPFSImageStorage::storeImage::{
+    cat_file_range, download_file_by_cid_as_base64, ipfs_file_size, IPFS_API_URL,
+};
 use crate::IdentityAuthentication::Jwt::validate_token;
-use crate::Router::routers::AuthenticatedUser;
-use crate::WatermarkService::watermarkservice::{execute_watermark_base64, storage_image};
+use crate::Router::idempotency::{Idempotency, Idempotent};
+use crate::Router::routers::{validation_error, AuthenticatedUser};
+use crate::UploadJob::batchUpload::{
+    extract_archive, register_batch, BatchFileResult, BatchInput, MAX_BATCH_FILES,
+};
+use crate::UploadJob::uploadJob::{
+    is_finished, job_dir, load_job, new_job_id, submit_job, subscribe_progress,
+    ImageUploadMetadata, JobProgress,
+};
+use crate::WatermarkService::engine::engine_for_user;
+use crate::WatermarkService::uploadPolicy::upload_policy;
+use crate::WatermarkService::verification::{
+    find_owner, WatermarkMatch, DEFAULT_MAX_BIT_ERROR_RATE, MAX_BIT_ERROR_RATE_LIMIT,
+};
+use crate::WatermarkService::visibleWatermark::{
+    cache_preview, cached_preview, overlay_assets, preview_config, render_preview, OverlayContext,
+};
+use crate::WatermarkService::watermarkservice::{
+    execute_watermark_base64, execute_watermark_bytes, storage_image,
+};
 use base64::{decode, Engine};
+use image::codecs::jpeg::JpegEncoder;
 use image::{DynamicImage, Rgba, RgbaImage};
-use imageproc::drawing::draw_text_mut;
-use rocket::data::Data;
-use rocket::http::Status;
+use rocket::data::{Data, Limits, ToByteUnit};
+use rocket::form::{Form, FromForm};
+use rocket::fs::TempFile;
+use rocket::http::{ContentType, Header, Status};
 use rocket::request::{FromRequest, Outcome, Request};
+use rocket::response::stream::{ByteStream, Event, EventStream};
+use rocket::response::{self, status, Responder, Response};
+use rocket::tokio::sync::broadcast::error::RecvError;
 use rocket::serde::{json::Json, Deserialize, Serialize};
-use rocket::tokio::io::AsyncWriteExt;
-use rusttype::{Font, Scale};
+use rocket::tokio::io::{AsyncReadExt, AsyncWriteExt};
+use sha2::{Digest, Sha256};
 use std::io;
 use std::io::{Cursor, Error};
+use std::sync::Arc;
+use validator::Validate;
 
 fn decode_base64_image(base64_image: &str) -> Result<Vec<u8>, base64::DecodeError> {
     base64::decode(base64_image)
 }
 
-#[derive(Serialize, Deserialize)]
+#[derive(Serialize, Deserialize, Validate)]
 pub struct AddWatermarkRequest {
+    #[validate(length(min = 1, message = "base64_image cannot be empty"))]
     base64_image: String,
 }
 
@@ -34,37 +63,485 @@ pub struct AddWatermarkResponse {
 pub async fn upload_image(
     image_data: Json<AddWatermarkRequest>, // 前端提交的图片数据
     auth_user: AuthenticatedUser,          // 从 JWT 提取的用户信息
-) -> Result<Json<AddWatermarkResponse>, Box<dyn ApiError>> {
-    // 从请求中提取 base64 编码的图片数据
-    let base64_image = image_data.base64_image.clone();
-
-    // 调用 execute_watermark 处理图片
-    let (watermarked_base64, watermark_base64) = match execute_watermark_base64(base64_image) {
-        Ok((_watermarked_base64, _watermark_base64)) => (_watermarked_base64, _watermark_base64),
-        Err(e) => {
-            eprintln!("Watermark processing failed: {}", e);
-            return Err(Box::new(e));
+    idempotency: Idempotency<'_>,
+) -> Result<Idempotent<Json<AddWatermarkResponse>>, Box<dyn ApiError>> {
+    idempotency
+        .payload(&*image_data)
+        .run(async move {
+            image_data.validate().map_err(|e| validation_error(&e))?;
+            // 从请求中提取 base64 编码的图片数据
+            let base64_image = image_data.base64_image.clone();
+
+            // 按用户所属组织选择水印引擎，调用 execute_watermark 处理图片
+            let engine = engine_for_user(&auth_user.username).await?;
+            // 加水印是阻塞调用，放到阻塞线程池中执行
+            let job_engine = engine.clone();
+            let watermarked = rocket::tokio::task::spawn_blocking(move || {
+                execute_watermark_base64(base64_image, &job_engine)
+            })
+            .await
+            .map_err(|e| ImageError::WatermarkProcessError(e.to_string()))?;
+            let (watermarked, watermark_base64) = match watermarked {
+                Ok((_watermarked, _watermark_base64)) => (_watermarked, _watermark_base64),
+                Err(e) => {
+                    eprintln!("Watermark processing failed: {}", e);
+                    return Err(e.into());
+                }
+            };
+
+            // 调用 storage_image 存储到 IPFS 和数据库
+            match storage_image(
+                watermarked,
+                watermark_base64,
+                auth_user.username, // 使用用户信息存储数据
+                &engine,
+                None,
+            )
+            .await
+            {
+                Ok(cid) => Ok(Json(AddWatermarkResponse {
+                    cid,
+                    message: "succeed save image".to_string(),
+                })),
+                Err(error) => Err(error.into()),
+            }
+        })
+        .await
+}
+
+// 单个上传文件的大小上限（20 MiB），超出时 Rocket 在读取请求体阶段即返回 413
+pub const MAX_IMAGE_FILE_SIZE: u64 = 20 * 1024 * 1024;
+// 批量登记时整个表单（多个文件或一个 ZIP 压缩包）的大小上限（512 MiB）
+pub const MAX_BATCH_UPLOAD_SIZE: u64 = 512 * 1024 * 1024;
+
+/// 文件上传相关的请求体大小限制，单个图片文件不超过 20 MiB，ZIP 压缩包与整个表单不超过 512 MiB
+pub fn upload_limits() -> Limits {
+    Limits::default()
+        .limit("file", MAX_IMAGE_FILE_SIZE.bytes())
+        .limit("file/zip", MAX_BATCH_UPLOAD_SIZE.bytes())
+        .limit("data-form", MAX_BATCH_UPLOAD_SIZE.bytes())
+}
+
+#[derive(FromForm)]
+pub struct UploadImageForm<'r> {
+    file: TempFile<'r>,
+    capture_time: Option<u64>,
+    capture_device: Option<String>,
+    capture_company: Option<String>,
+    submission_time: Option<u64>,
+    submission_receiver: Option<String>,
+}
+
+impl UploadImageForm<'_> {
+    // 随文件一起提交的可选图片信息
+    fn metadata(&self) -> ImageUploadMetadata {
+        ImageUploadMetadata {
+            capture_time: self.capture_time,
+            capture_device: self.capture_device.clone(),
+            capture_company: self.capture_company.clone(),
+            submission_time: self.submission_time,
+            submission_receiver: self.submission_receiver.clone(),
         }
-    };
+    }
+}
 
-    // 调用 storage_image 存储到 IPFS 和数据库
-    match storage_image(
-        watermarked_base64,
-        watermark_base64,
-        auth_user.username, // 使用用户信息存储数据
-    )
-    .await
-    {
-        Ok(cid) => Ok(Json(AddWatermarkResponse {
-            cid,
-            message: "succeed save image".to_string(),
-        })),
-        Err(error) => Err(Box::new(error)),
+#[derive(Serialize, Deserialize)]
+pub struct UploadImageFileResponse {
+    pub cid: String,
+    pub file_name: Option<String>,
+    pub size: u64,
+    pub metadata: ImageUploadMetadata,
+    pub message: String,
+}
+
+/// multipart/form-data 上传图片：文件由 Rocket 流式写入临时文件，不需要 base64 编码
+#[post("/upload_image_file", format = "multipart/form-data", data = "<form>")]
+pub async fn upload_image_file(
+    form: Form<UploadImageForm<'_>>,
+    auth_user: AuthenticatedUser,
+    idempotency: Idempotency<'_>,
+) -> Result<Idempotent<Json<UploadImageFileResponse>>, Box<dyn ApiError>> {
+    let form = form.into_inner();
+    form.metadata()
+        .validate()
+        .map_err(|e| validation_error(&e))?;
+    let payload = upload_payload(&idempotency, &[&form.file], form.metadata()).await?;
+    idempotency
+        .payload(&payload)
+        .run(async move {
+            let size = form.file.len();
+            if size == 0 {
+                return Err(RequestError::InvalidParameter("file is empty".to_string()).into());
+            }
+            check_upload(&form.file).await?;
+            let file_name = form.file.name().map(|name| name.to_string());
+
+            let mut img_bytes = Vec::with_capacity(size as usize);
+            form.file
+                .open()
+                .await
+                .map_err(|e| ImageError::IOError(e.to_string()))?
+                .read_to_end(&mut img_bytes)
+                .await
+                .map_err(|e| ImageError::IOError(e.to_string()))?;
+
+            let engine = engine_for_user(&auth_user.username).await?;
+            let job_engine = engine.clone();
+            let (watermarked, watermark_base64) = rocket::tokio::task::spawn_blocking(move || {
+                execute_watermark_bytes(&img_bytes, &job_engine)
+            })
+            .await
+            .map_err(|e| ImageError::WatermarkProcessError(e.to_string()))??;
+            let metadata = form.metadata();
+            let cid = storage_image(
+                watermarked,
+                watermark_base64,
+                auth_user.username,
+                &engine,
+                Some(&metadata),
+            )
+            .await?;
+
+            Ok(Json(UploadImageFileResponse {
+                cid,
+                file_name,
+                size,
+                metadata,
+                message: "succeed save image".to_string(),
+            }))
+        })
+        .await
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct UploadJobResponse {
+    pub job: JobProgress,
+    pub message: String,
+}
+
+/// 创建异步上传任务：保存文件后立即返回任务 ID，加水印、存储等由后台 worker 完成
+#[post("/upload_jobs", format = "multipart/form-data", data = "<form>")]
+pub async fn create_upload_job(
+    form: Form<UploadImageForm<'_>>,
+    auth_user: AuthenticatedUser,
+    idempotency: Idempotency<'_>,
+) -> Result<Idempotent<status::Accepted<Json<UploadJobResponse>>>, Box<dyn ApiError>> {
+    let form = form.into_inner();
+    form.metadata()
+        .validate()
+        .map_err(|e| validation_error(&e))?;
+    let payload = upload_payload(&idempotency, &[&form.file], form.metadata()).await?;
+    idempotency
+        .payload(&payload)
+        .run(start_upload_job(form, auth_user, false))
+        .await
+}
+
+/// 一次完成图片登记：加水印、存储并固定到 ipfs、以水印摘要和拍摄信息铸造 token，并把 token id 记录到 images 表
+/// 与 upload_jobs 相同，通过任务 ID 查询进度，结束后任务中包含 cid、token_id 和交易哈希
+#[post("/register_image", format = "multipart/form-data", data = "<form>")]
+pub async fn register_image(
+    form: Form<UploadImageForm<'_>>,
+    auth_user: AuthenticatedUser,
+    idempotency: Idempotency<'_>,
+) -> Result<Idempotent<status::Accepted<Json<UploadJobResponse>>>, Box<dyn ApiError>> {
+    let form = form.into_inner();
+    form.metadata()
+        .validate()
+        .map_err(|e| validation_error(&e))?;
+    let payload = upload_payload(&idempotency, &[&form.file], form.metadata()).await?;
+    idempotency
+        .payload(&payload)
+        .run(start_upload_job(form, auth_user, true))
+        .await
+}
+
+// 带 Idempotency-Key 时以上传文件内容的 sha256 和图片信息作为请求指纹，multipart 的分隔符每次请求都不同，不能直接使用请求体
+async fn upload_payload(
+    idempotency: &Idempotency<'_>,
+    files: &[&TempFile<'_>],
+    metadata: ImageUploadMetadata,
+) -> Result<serde_json::Value, ImageError> {
+    if !idempotency.has_key() {
+        return Ok(serde_json::Value::Null);
+    }
+    let mut digests = Vec::with_capacity(files.len());
+    for file in files {
+        let mut reader = file
+            .open()
+            .await
+            .map_err(|e| ImageError::IOError(e.to_string()))?;
+        let mut hasher = Sha256::new();
+        let mut buf = vec![0u8; 64 * 1024];
+        loop {
+            let n = reader
+                .read(&mut buf)
+                .await
+                .map_err(|e| ImageError::IOError(e.to_string()))?;
+            if n == 0 {
+                break;
+            }
+            hasher.update(&buf[..n]);
+        }
+        digests.push(format!("{:x}", hasher.finalize()));
+    }
+    Ok(serde_json::json!({ "files": digests, "metadata": metadata }))
+}
+
+// 按上传策略检查文件大小和文件头中的格式
+async fn check_upload(file: &TempFile<'_>) -> Result<(), ImageError> {
+    let policy = upload_policy();
+    if file.len() > policy.max_bytes {
+        return Err(ImageError::ImageRejected(format!(
+            "image is {} bytes, the limit is {} bytes",
+            file.len(),
+            policy.max_bytes
+        )));
+    }
+    let mut head = Vec::new();
+    file.open()
+        .await
+        .map_err(|e| ImageError::IOError(e.to_string()))?
+        .take(SNIFF_LEN)
+        .read_to_end(&mut head)
+        .await
+        .map_err(|e| ImageError::IOError(e.to_string()))?;
+    policy.check_bytes(&head).map(|_| ())
+}
+
+async fn start_upload_job(
+    mut form: UploadImageForm<'_>,
+    auth_user: AuthenticatedUser,
+    mint: bool,
+) -> Result<status::Accepted<Json<UploadJobResponse>>, Box<dyn ApiError>> {
+    if form.file.len() == 0 {
+        return Err(Box::new(RequestError::InvalidParameter(
+            "file is empty".to_string(),
+        )));
+    }
+    // 在创建任务前拒绝过大或格式不支持的文件，尺寸等检查由 worker 解码前完成
+    check_upload(&form.file).await?;
+    let job_id = new_job_id();
+    let dir = job_dir();
+    rocket::tokio::fs::create_dir_all(&dir)
+        .await
+        .map_err(|e| ImageError::IOError(e.to_string()))?;
+    let path = dir.join(&job_id);
+    form.file
+        .move_copy_to(&path)
+        .await
+        .map_err(|e| ImageError::IOError(e.to_string()))?;
+
+    match submit_job(&auth_user.username, &job_id, path.clone(), &form.metadata(), mint).await {
+        Ok(job) => Ok(status::Accepted(Json(UploadJobResponse {
+            job: JobProgress::from(&job),
+            message: "upload job created".to_string(),
+        }))),
+        Err(error) => {
+            let _ = rocket::tokio::fs::remove_file(&path).await;
+            Err(Box::new(error))
+        }
+    }
+}
+
+#[derive(FromForm)]
+pub struct RegisterImagesForm<'r> {
+    files: Vec<TempFile<'r>>,
+    archive: Option<TempFile<'r>>,
+    capture_time: Option<u64>,
+    capture_device: Option<String>,
+    capture_company: Option<String>,
+    submission_time: Option<u64>,
+    submission_receiver: Option<String>,
+}
+
+impl RegisterImagesForm<'_> {
+    // 批量登记时所有图片共用同一份图片信息
+    fn metadata(&self) -> ImageUploadMetadata {
+        ImageUploadMetadata {
+            capture_time: self.capture_time,
+            capture_device: self.capture_device.clone(),
+            capture_company: self.capture_company.clone(),
+            submission_time: self.submission_time,
+            submission_receiver: self.submission_receiver.clone(),
+        }
     }
 }
 
 #[derive(Serialize, Deserialize)]
+pub struct BatchRegisterResponse {
+    pub results: Vec<BatchFileResult>,
+    pub succeeded: usize,
+    pub failed: usize,
+    pub message: String,
+}
+
+/// 批量登记图片：以多个 files 字段或一个 ZIP 压缩包（archive 字段）提交，
+/// 并发完成加水印和 ipfs 存储后合并铸造，返回每个文件的处理结果
+#[post("/register_images", format = "multipart/form-data", data = "<form>")]
+pub async fn register_images(
+    form: Form<RegisterImagesForm<'_>>,
+    auth_user: AuthenticatedUser,
+    idempotency: Idempotency<'_>,
+) -> Result<Idempotent<Json<BatchRegisterResponse>>, Box<dyn ApiError>> {
+    let mut form = form.into_inner();
+    let metadata = form.metadata();
+    metadata
+        .validate()
+        .map_err(|e| validation_error(&e))?;
+    let files: Vec<&TempFile<'_>> = form.files.iter().chain(form.archive.iter()).collect();
+    let payload = upload_payload(&idempotency, &files, metadata.clone()).await?;
+    idempotency
+        .payload(&payload)
+        .run(async move {
+            if form.files.is_empty() && form.archive.is_none() {
+                return Err(RequestError::InvalidParameter(
+                    "files or archive is required".to_string(),
+                )
+                .into());
+            }
+            if form.files.len() > MAX_BATCH_FILES {
+                return Err(RequestError::InvalidParameter(format!(
+                    "at most {} files can be registered at once",
+                    MAX_BATCH_FILES
+                ))
+                .into());
+            }
+
+            let dir = job_dir().join(new_job_id());
+            rocket::tokio::fs::create_dir_all(&dir)
+                .await
+                .map_err(|e| ImageError::IOError(e.to_string()))?;
+            let inputs = collect_batch_inputs(&mut form, &dir).await;
+            let results = match inputs {
+                Ok(inputs) if inputs.is_empty() => Err(RequestError::InvalidParameter(
+                    "no image found in the upload".to_string(),
+                )
+                .into()),
+                Ok(inputs) if inputs.len() > MAX_BATCH_FILES => {
+                    Err(RequestError::InvalidParameter(format!(
+                        "at most {} files can be registered at once",
+                        MAX_BATCH_FILES
+                    ))
+                    .into())
+                }
+                Ok(inputs) => Ok(register_batch(&auth_user.username, inputs, &metadata).await),
+                Err(e) => Err(e),
+            };
+            let _ = rocket::tokio::fs::remove_dir_all(&dir).await;
+            let results = results?;
+
+            let failed = results
+                .iter()
+                .filter(|result| result.error.is_some())
+                .count();
+            Ok(Json(BatchRegisterResponse {
+                succeeded: results.len() - failed,
+                failed,
+                message: format!(
+                    "registered {} of {} images",
+                    results.len() - failed,
+                    results.len()
+                ),
+                results,
+            }))
+        })
+        .await
+}
+
+// 把上传的文件复制到批次目录，并解压 ZIP 压缩包
+async fn collect_batch_inputs(
+    form: &mut RegisterImagesForm<'_>,
+    dir: &std::path::Path,
+) -> Result<Vec<BatchInput>, Box<dyn ApiError>> {
+    let mut inputs = Vec::new();
+    for (index, file) in form.files.iter_mut().enumerate() {
+        let file_name = file
+            .raw_name()
+            .map(|name| name.dangerous_unsafe_unsanitized_raw().to_string())
+            .unwrap_or_else(|| format!("file-{}", index));
+        let path = dir.join(format!("file-{}", index));
+        let path = if file.len() == 0 {
+            Err("file is empty".to_string())
+        } else {
+            file.move_copy_to(&path)
+                .await
+                .map(|_| path)
+                .map_err(|e| e.to_string())
+        };
+        inputs.push(BatchInput { file_name, path });
+    }
+
+    if let Some(archive) = form.archive.as_mut() {
+        let archive_path = dir.join("archive.zip");
+        archive
+            .move_copy_to(&archive_path)
+            .await
+            .map_err(|e| ImageError::IOError(e.to_string()))?;
+        let limit = MAX_BATCH_FILES.saturating_sub(inputs.len());
+        let max_bytes = upload_policy().max_archive_bytes;
+        let dir = dir.to_path_buf();
+        let extracted = rocket::tokio::task::spawn_blocking(move || {
+            extract_archive(&archive_path, &dir, limit, max_bytes)
+        })
+        .await
+        .map_err(|e| ImageError::IOError(e.to_string()))??;
+        inputs.extend(extracted);
+    }
+    Ok(inputs)
+}
+
+/// 轮询上传任务的当前状态
+#[get("/upload_jobs/<job_id>")]
+pub async fn get_upload_job(
+    job_id: &str,
+    auth_user: AuthenticatedUser,
+) -> Result<Json<JobProgress>, Box<dyn ApiError>> {
+    let job = load_job(job_id, &auth_user.username).await?;
+    Ok(Json(JobProgress::from(&job)))
+}
+
+/// 以 server-sent events 推送上传任务的进度，任务结束后关闭连接
+#[get("/upload_jobs/<job_id>/events")]
+pub async fn upload_job_events(
+    job_id: &str,
+    auth_user: AuthenticatedUser,
+) -> Result<EventStream![], Box<dyn ApiError>> {
+    // 先订阅再读取当前状态，避免错过两者之间的状态变化
+    let mut receiver = subscribe_progress();
+    let current = JobProgress::from(&load_job(job_id, &auth_user.username).await?);
+    let job_id = job_id.to_string();
+    let username = auth_user.username;
+
+    Ok(EventStream! {
+        let finished = is_finished(&current.state);
+        yield Event::json(&current).event("progress");
+        if finished {
+            return;
+        }
+        loop {
+            let progress = match receiver.recv().await {
+                Ok(progress) if progress.job_id == job_id => progress,
+                Ok(_) => continue,
+                // 消费太慢丢失了部分进度时，直接读取数据库中的最新状态
+                Err(RecvError::Lagged(_)) => match load_job(&job_id, &username).await {
+                    Ok(job) => JobProgress::from(&job),
+                    Err(_) => break,
+                },
+                Err(RecvError::Closed) => break,
+            };
+            let finished = is_finished(&progress.state);
+            yield Event::json(&progress).event("progress");
+            if finished {
+                break;
+            }
+        }
+    })
+}
+
+#[derive(Serialize, Deserialize, Validate)]
 pub struct GetImageRequest {
+    #[validate(length(min = 1, max = 128, message = "image_cid must be 1 to 128 characters long"))]
     image_cid: String,
 }
 
@@ -81,6 +558,9 @@ pub async fn get_image(
     image_data: Json<GetImageRequest>,
     auth_user: AuthenticatedUser,
 ) -> Result<Json<GetImageResponse>, Box<dyn ApiError>> {
+    image_data
+        .validate()
+        .map_err(|e| validation_error(&e))?;
     match download_file_by_cid_as_base64(&image_data.image_cid).await {
         Ok(image) => Ok(Json(GetImageResponse {
             image_base64: Some(image),
@@ -89,3 +569,404 @@ pub async fn get_image(
         Err(error) => Err(Box::new(error)),
     }
 }
+
+// 用于识别图片格式的文件头长度
+const SNIFF_LEN: u64 = 32;
+
+/// 下载图片时关心的条件请求头
+pub struct ConditionalHeaders {
+    range: Option<String>,
+    if_none_match: Option<String>,
+}
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for ConditionalHeaders {
+    type Error = ();
+
+    async fn from_request(request: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+        Outcome::Success(ConditionalHeaders {
+            range: request.headers().get_one("Range").map(|h| h.to_string()),
+            if_none_match: request.headers().get_one("If-None-Match").map(|h| h.to_string()),
+        })
+    }
+}
+
+/// 图片下载响应：CID 即内容哈希，内容永远不会变化，因此以 CID 作为 ETag 并允许永久缓存
+#[allow(clippy::large_enum_variant)]
+pub enum ImageDownload {
+    NotModified {
+        etag: String,
+    },
+    Content {
+        body: Option<reqwest::Response>,
+        content_type: ContentType,
+        etag: String,
+        total: u64,
+        range: Option<(u64, u64)>,
+    },
+    RangeNotSatisfiable {
+        total: u64,
+    },
+}
+
+const CACHE_CONTROL: &str = "public, max-age=31536000, immutable";
+
+impl<'r> Responder<'r, 'r> for ImageDownload {
+    fn respond_to(self, request: &'r Request<'_>) -> response::Result<'r> {
+        match self {
+            ImageDownload::NotModified { etag } => Response::build()
+                .status(Status::NotModified)
+                .header(Header::new("ETag", etag))
+                .header(Header::new("Cache-Control", CACHE_CONTROL))
+                .ok(),
+            ImageDownload::RangeNotSatisfiable { total } => {
+                let error = ErrorResponse::new(
+                    "RangeNotSatisfiable",
+                    format!("Requested range is outside the {} byte image", total),
+                    Status::RangeNotSatisfiable,
+                    request,
+                );
+                Response::build_from(Json(error).respond_to(request)?)
+                    .status(Status::RangeNotSatisfiable)
+                    .header(Header::new("Content-Range", format!("bytes */{}", total)))
+                    .ok()
+            }
+            ImageDownload::Content {
+                body,
+                content_type,
+                etag,
+                total,
+                range,
+            } => {
+                // 按块转发 IPFS 的响应体，不在内存中缓存整个文件。
+                // 响应头中声明了 Content-Length，读取 IPFS 中途出错时提前结束响应体会让服务器中断连接，
+                // 客户端不会把截断的内容当作完整的图片缓存
+                let length = match range {
+                    Some((start, end)) => end - start + 1,
+                    None => total,
+                };
+                let cid = etag.trim_matches('"').to_string();
+                let stream = ByteStream! {
+                    if let Some(mut body) = body {
+                        loop {
+                            match body.chunk().await {
+                                Ok(Some(chunk)) => yield chunk.to_vec(),
+                                Ok(None) => break,
+                                Err(e) => {
+                                    println!("Failed to stream image {} from IPFS: {}", cid, e);
+                                    break;
+                                }
+                            }
+                        }
+                    }
+                };
+                let mut builder = Response::build_from(stream.respond_to(request)?);
+                builder
+                    .header(Header::new("Content-Length", length.to_string()))
+                    .header(content_type)
+                    .header(Header::new("ETag", etag))
+                    .header(Header::new("Cache-Control", CACHE_CONTROL))
+                    .header(Header::new("Accept-Ranges", "bytes"));
+                if let Some((start, end)) = range {
+                    builder.status(Status::PartialContent).header(Header::new(
+                        "Content-Range",
+                        format!("bytes {}-{}/{}", start, end, total),
+                    ));
+                }
+                builder.ok()
+            }
+        }
+    }
+}
+
+/// 以原始字节流下载图片，支持 Range 与 If-None-Match，任何人都可以下载，
+/// 只提供在本系统登记过的图片，其他 CID 返回 404
+#[get("/image/<cid>")]
+pub async fn download_image(
+    cid: &str,
+    headers: ConditionalHeaders,
+) -> Result<ImageDownload, Box<dyn ApiError>> {
+    if cid.is_empty() || !cid.chars().all(|c| c.is_ascii_alphanumeric()) {
+        return Err(Box::new(RequestError::InvalidParameter(format!(
+            "cid {}",
+            cid
+        ))));
+    }
+    let registered = Images::select_by_cid(get_db().await, cid)
+        .await
+        .map_err(ImageError::DatabaseError)?;
+    if registered.is_none() {
+        return Err(Box::new(ImageError::ImageNotFound(cid.to_string())));
+    }
+    let etag = format!("\"{}\"", cid);
+    if let Some(if_none_match) = &headers.if_none_match {
+        if etag_matches(if_none_match, &etag) {
+            return Ok(ImageDownload::NotModified { etag });
+        }
+    }
+
+    let total = ipfs_file_size(cid, IPFS_API_URL).await?;
+    let head = cat_file_range(cid, IPFS_API_URL, 0, SNIFF_LEN)
+        .await?
+        .bytes()
+        .await
+        .map_err(|e| ImageError::IpfsError(e.to_string()))?;
+    let content_type = image::guess_format(&head)
+        .ok()
+        .and_then(|format| ContentType::parse_flexible(format.to_mime_type()))
+        .unwrap_or(ContentType::Binary);
+
+    let range = match headers.range.as_deref().map(|h| parse_range(h, total)) {
+        Some(Err(())) => return Ok(ImageDownload::RangeNotSatisfiable { total }),
+        Some(Ok(range)) => range,
+        None => None,
+    };
+    let body = match range {
+        Some((start, end)) => Some(cat_file_range(cid, IPFS_API_URL, start, end - start + 1).await?),
+        None if total > 0 => Some(cat_file_range(cid, IPFS_API_URL, 0, total).await?),
+        None => None,
+    };
+
+    Ok(ImageDownload::Content {
+        body,
+        content_type,
+        etag,
+        total,
+        range,
+    })
+}
+
+/// 带可见水印的预览图
+#[derive(Responder)]
+#[response(content_type = "image/jpeg")]
+pub struct PreviewImage {
+    body: Vec<u8>,
+    cache_control: Header<'static>,
+}
+
+/// 公开预览图：从 ipfs 取出登记过的原图，缩小后叠加所有者、token id 等可见水印，原图不受影响
+#[get("/preview/<cid>")]
+pub async fn get_preview(cid: &str) -> Result<PreviewImage, Box<dyn ApiError>> {
+    if cid.is_empty() || !cid.chars().all(|c| c.is_ascii_alphanumeric()) {
+        return Err(Box::new(RequestError::InvalidParameter(format!(
+            "cid {}",
+            cid
+        ))));
+    }
+    let rb = get_db().await;
+    let image = Images::select_by_cid(rb, cid)
+        .await
+        .map_err(ImageError::DatabaseError)?
+        .ok_or_else(|| ImageError::ImageNotFound(cid.to_string()))?;
+    let owner = match image.user_id {
+        Some(user_id) => Users::select_by_id(rb, user_id)
+            .await
+            .map_err(ImageError::DatabaseError)?
+            .and_then(|user| user.username),
+        None => None,
+    };
+
+    // {date} 为图片登记的时间，旧的图片记录没有登记时间时取水印记录的时间
+    let created_at = match image.created_at {
+        Some(created_at) => Some(created_at),
+        None => match image.id {
+            Some(image_id) => Watermarks::select_by_image_id(rb, image_id)
+                .await
+                .map_err(ImageError::DatabaseError)?
+                .and_then(|watermark| watermark.created_at),
+            None => None,
+        },
+    };
+    let context = OverlayContext {
+        owner: owner.unwrap_or_default(),
+        token_id: image.token_id,
+        date: created_at
+            .and_then(|created_at| chrono::DateTime::from_timestamp(created_at.unix_timestamp(), 0))
+            .map(|created_at| created_at.format("%Y-%m-%d").to_string())
+            .unwrap_or_else(|| "-".to_string()),
+    };
+    let key = context.cache_key(cid);
+    let body = match cached_preview(&key) {
+        Some(body) => body,
+        None => {
+            let body = Arc::new(render_preview_jpeg(cid, context).await?);
+            cache_preview(key, body.clone(), preview_config().cache_entries);
+            body
+        }
+    };
+
+    // 预览中的 token id 等信息可能变化，只短时间缓存
+    Ok(PreviewImage {
+        body: body.to_vec(),
+        cache_control: Header::new("Cache-Control", "public, max-age=300"),
+    })
+}
+
+// 从 ipfs 取出原图，缩小并叠加可见水印后编码为 JPEG
+async fn render_preview_jpeg(cid: &str, context: OverlayContext) -> Result<Vec<u8>, ImageError> {
+    let total = ipfs_file_size(cid, IPFS_API_URL).await?;
+    if total > upload_policy().max_bytes {
+        return Err(ImageError::ImageRejected(format!(
+            "image is {} bytes, the limit is {} bytes",
+            total,
+            upload_policy().max_bytes
+        )));
+    }
+    let master = cat_file_range(cid, IPFS_API_URL, 0, total)
+        .await?
+        .bytes()
+        .await
+        .map_err(|e| ImageError::IpfsError(e.to_string()))?;
+
+    rocket::tokio::task::spawn_blocking(move || {
+        let config = preview_config();
+        let master = upload_policy().decode(&master)?;
+        let preview = render_preview(&master, config, overlay_assets(), &context)?;
+        let mut jpeg = Vec::new();
+        JpegEncoder::new_with_quality(&mut jpeg, config.quality)
+            .encode_image(&preview.to_rgb8())
+            .map_err(|_| ImageError::EncodeBytesError)?;
+        Ok::<_, ImageError>(jpeg)
+    })
+    .await
+    .map_err(|e| ImageError::WatermarkProcessError(e.to_string()))?
+}
+
+#[derive(FromForm)]
+pub struct VerifyWatermarkForm<'r> {
+    file: TempFile<'r>,
+    // 允许的最大误码率，默认 DEFAULT_MAX_BIT_ERROR_RATE，不超过 MAX_BIT_ERROR_RATE_LIMIT
+    threshold: Option<f32>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct VerifyWatermarkResponse {
+    pub matched: bool,
+    #[serde(flatten)]
+    pub owner: Option<WatermarkMatch>,
+    // 参与比较的水印记录数
+    pub compared_records: usize,
+    // 引擎不支持提取（如 python 引擎）或无法解析而没有参与比较的记录数
+    pub skipped_records: usize,
+    pub message: String,
+}
+
+/// 验证图片归属：从上传的可疑图片中提取水印，与已登记图片的水印比较，
+/// 返回匹配的用户、图片 cid、token id 以及误码率和置信度
+#[post("/verify_watermark", format = "multipart/form-data", data = "<form>")]
+pub async fn verify_watermark(
+    form: Form<VerifyWatermarkForm<'_>>,
+    _auth_user: AuthenticatedUser,
+) -> Result<Json<VerifyWatermarkResponse>, Box<dyn ApiError>> {
+    let threshold = form.threshold.unwrap_or(DEFAULT_MAX_BIT_ERROR_RATE);
+    if !(0.0..=MAX_BIT_ERROR_RATE_LIMIT).contains(&threshold) {
+        return Err(Box::new(RequestError::InvalidParameter(format!(
+            "threshold must be between 0 and {}",
+            MAX_BIT_ERROR_RATE_LIMIT
+        ))));
+    }
+    if form.file.len() == 0 {
+        return Err(Box::new(RequestError::InvalidParameter(
+            "file is empty".to_string(),
+        )));
+    }
+    check_upload(&form.file).await?;
+
+    let mut img_bytes = Vec::with_capacity(form.file.len() as usize);
+    form.file
+        .open()
+        .await
+        .map_err(|e| ImageError::IOError(e.to_string()))?
+        .read_to_end(&mut img_bytes)
+        .await
+        .map_err(|e| ImageError::IOError(e.to_string()))?;
+    let img = rocket::tokio::task::spawn_blocking(move || upload_policy().decode(&img_bytes))
+        .await
+        .map_err(|e| ImageError::WatermarkProcessError(e.to_string()))??;
+
+    let search = find_owner(img, threshold).await?;
+    let mut message = match &search.owner {
+        Some(_) => "watermark matched a registered image".to_string(),
+        None => "no registered watermark matched".to_string(),
+    };
+    if search.skipped > 0 {
+        message.push_str(&format!(
+            ", {} registered watermarks could not be checked",
+            search.skipped
+        ));
+    }
+    Ok(Json(VerifyWatermarkResponse {
+        matched: search.owner.is_some(),
+        owner: search.owner,
+        compared_records: search.compared,
+        skipped_records: search.skipped,
+        message,
+    }))
+}
+
+// If-None-Match 可以是 * 或逗号分隔的多个（可能带 W/ 前缀的）ETag
+fn etag_matches(if_none_match: &str, etag: &str) -> bool {
+    if_none_match
+        .split(',')
+        .map(|tag| tag.trim())
+        .any(|tag| tag == "*" || tag.trim_start_matches("W/") == etag)
+}
+
+// 解析单段 Range 头，返回闭区间 [start, end]；多段或格式不支持时返回 Ok(None) 按完整内容响应，
+// 范围完全超出文件时返回 Err(()) 对应 416
+fn parse_range(header: &str, total: u64) -> Result<Option<(u64, u64)>, ()> {
+    let Some(spec) = header.trim().strip_prefix("bytes=") else {
+        return Ok(None);
+    };
+    if spec.contains(',') {
+        return Ok(None);
+    }
+    let Some((start, end)) = spec.trim().split_once('-') else {
+        return Ok(None);
+    };
+    let last = total.saturating_sub(1);
+    let (start, end) = match (start.parse::<u64>().ok(), end.parse::<u64>().ok()) {
+        (Some(start), Some(end)) if start <= end => (start, end.min(last)),
+        (Some(start), None) if end.is_empty() => (start, last),
+        (None, Some(suffix)) if start.is_empty() => {
+            if suffix == 0 {
+                return Err(());
+            }
+            (total.saturating_sub(suffix), last)
+        }
+        _ => return Ok(None),
+    };
+    if start >= total {
+        return Err(());
+    }
+    Ok(Some((start, end)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_range() {
+        assert_eq!(parse_range("bytes=0-99", 1000), Ok(Some((0, 99))));
+        assert_eq!(parse_range("bytes=900-", 1000), Ok(Some((900, 999))));
+        assert_eq!(parse_range("bytes=-100", 1000), Ok(Some((900, 999))));
+        // 结束位置超出文件时截断到最后一个字节
+        assert_eq!(parse_range("bytes=500-5000", 1000), Ok(Some((500, 999))));
+        assert_eq!(parse_range("bytes=-5000", 1000), Ok(Some((0, 999))));
+        assert_eq!(parse_range("bytes=1000-", 1000), Err(()));
+        assert_eq!(parse_range("bytes=-0", 1000), Err(()));
+        // 不支持的格式按完整内容响应
+        assert_eq!(parse_range("bytes=0-1,5-9", 1000), Ok(None));
+        assert_eq!(parse_range("items=0-1", 1000), Ok(None));
+        assert_eq!(parse_range("bytes=9-1", 1000), Ok(None));
+    }
+
+    #[test]
+    fn test_etag_matches() {
+        assert!(etag_matches("\"Qm1\"", "\"Qm1\""));
+        assert!(etag_matches("\"Qm0\", W/\"Qm1\"", "\"Qm1\""));
+        assert!(etag_matches("*", "\"Qm1\""));
+        assert!(!etag_matches("\"Qm0\"", "\"Qm1\""));
+    }
+}
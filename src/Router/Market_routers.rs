@@ -0,0 +1,360 @@
+use crate::DataBase::{ensure_unique_index, get_db, transition_order, try_get_db, MarketOrders};
+use crate::Error::{ApiError, MarketError, RequestError, UserError};
+use crate::Router::idempotency::{Idempotency, Idempotent};
+use crate::Router::onchain_router::{connect_address_handler, connect_handler};
+use crate::Router::routers::{parse_address, parse_token_id, validation_error, AuthenticatedUser};
+use crate::Router::validation;
+use crate::Transaction::Market::{
+    now_secs, verify_order, KIND_LISTING, KIND_OFFER, STATUS_CANCELLED, STATUS_FILLED,
+    STATUS_OPEN, STATUS_SETTLING,
+};
+use alloy::primitives::{Address, U256};
+use rbatis::rbdc::datetime::DateTime;
+use rocket::fairing::AdHoc;
+use rocket::serde::{json::Json, Deserialize, Serialize};
+use validator::Validate;
+
+/// 链下交易市场接口
+/// 挂单与报价由用户在客户端按 /market/domain 返回的 EIP-712 域签名后提交，服务端只做校验与保存，
+/// 被接受时重新校验签名、有效期以及 token 当前所有者，然后由买家付款、卖家转出 token 成交
+
+#[derive(Serialize, Deserialize)]
+pub struct MarketDomainResponse {
+    name: String,
+    version: String,
+    chain_id: u64,
+    verifying_contract: Address,
+}
+
+#[get("/market/domain")]
+pub async fn market_domain() -> Result<Json<MarketDomainResponse>, Box<dyn ApiError>> {
+    let handler = connect_handler().await?;
+    let domain = handler.market_domain().await?;
+    Ok(Json(MarketDomainResponse {
+        name: domain.name.unwrap_or_default().to_string(),
+        version: domain.version.unwrap_or_default().to_string(),
+        chain_id: domain.chain_id.unwrap_or_default().to(),
+        verifying_contract: domain.verifying_contract.unwrap_or_default(),
+    }))
+}
+
+#[derive(Serialize, Deserialize, Validate)]
+pub struct CreateOrderRequest {
+    token_id: U256,
+    price: U256,
+    nonce: U256,
+    expiry: u64, // unix 时间（秒）
+    #[validate(custom(function = "validation::signature"))]
+    signature: String,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct OrderResponse {
+    order: MarketOrders,
+    message: String,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct AcceptOrderResponse {
+    order: MarketOrders,
+    tx_hash: String,
+    message: String,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct OrderListResponse {
+    orders: Vec<MarketOrders>,
+    message: String,
+}
+
+/// 卖家挂单，调用者必须是 token 当前的所有者
+#[post("/market/listings", format = "json", data = "<request>")]
+pub async fn create_listing(
+    request: Json<CreateOrderRequest>,
+    auth_user: AuthenticatedUser,
+    idempotency: Idempotency<'_>,
+) -> Result<Idempotent<Json<OrderResponse>>, Box<dyn ApiError>> {
+    idempotency
+        .payload(&*request)
+        .run(async move {
+            request.validate().map_err(|e| validation_error(&e))?;
+            let seller = auth_user.address().await?;
+            let handler = connect_handler().await?;
+            if handler.owner_of(request.token_id).await? != seller {
+                return Err(UserError::PermissionDenied(format!(
+                    "token {} is not owned by {}",
+                    request.token_id, seller
+                ))
+                .into());
+            }
+            create_order(KIND_LISTING, seller, request.into_inner()).await
+        })
+        .await
+}
+
+/// 买家报价，调用者不能是 token 当前的所有者
+#[post("/market/offers", format = "json", data = "<request>")]
+pub async fn create_offer(
+    request: Json<CreateOrderRequest>,
+    auth_user: AuthenticatedUser,
+    idempotency: Idempotency<'_>,
+) -> Result<Idempotent<Json<OrderResponse>>, Box<dyn ApiError>> {
+    idempotency
+        .payload(&*request)
+        .run(async move {
+            request.validate().map_err(|e| validation_error(&e))?;
+            let buyer = auth_user.address().await?;
+            let handler = connect_handler().await?;
+            if handler.owner_of(request.token_id).await? == buyer {
+                return Err(RequestError::InvalidParameter(
+                    "cannot make an offer on your own token".to_string(),
+                )
+                .into());
+            }
+            create_order(KIND_OFFER, buyer, request.into_inner()).await
+        })
+        .await
+}
+
+const NONCE_INDEX: &str = "uk_market_orders_maker_nonce";
+
+/// 启动时在 market_orders 的 (maker, nonce) 上创建唯一索引，无法创建时拒绝启动
+pub fn market_order_index() -> AdHoc {
+    AdHoc::try_on_ignite("Market Order Index", |rocket| async {
+        let ensured = match try_get_db().await {
+            Ok(rb) => {
+                ensure_unique_index(rb, "market_orders", NONCE_INDEX, &["maker", "nonce"]).await
+            }
+            Err(e) => Err(e),
+        };
+        match ensured {
+            Ok(()) => Ok(rocket),
+            Err(e) => {
+                println!("Failed to create index {}: {}", NONCE_INDEX, e);
+                Err(rocket)
+            }
+        }
+    })
+}
+
+// 校验签名后保存订单，同一 maker 的 nonce 不能重复使用
+async fn create_order(
+    kind: &str,
+    maker: Address,
+    request: CreateOrderRequest,
+) -> Result<Json<OrderResponse>, Box<dyn ApiError>> {
+    let expiry = i64::try_from(request.expiry)
+        .map_err(|_| RequestError::InvalidParameter("expiry is out of range".to_string()))?;
+    let row = MarketOrders {
+        id: None,
+        kind: Some(kind.to_string()),
+        token_id: Some(request.token_id.to_string()),
+        maker: Some(maker.to_string()),
+        price: Some(request.price.to_string()),
+        nonce: Some(request.nonce.to_string()),
+        expiry: Some(expiry),
+        signature: Some(request.signature),
+        status: Some(STATUS_OPEN.to_string()),
+        tx_hash: None,
+        created_at: Some(DateTime::now()),
+        payment_tx_hash: None,
+    };
+    let domain = connect_handler().await?.market_domain().await?;
+    verify_order(&row, &domain)?;
+
+    let rb = get_db().await;
+    let maker = maker.to_string();
+    let nonce = request.nonce.to_string();
+    // 并发提交相同 nonce 时由唯一索引保证只有一个成功，失败后查到已有订单说明 nonce 已被使用
+    if let Err(e) = MarketOrders::insert(rb, &row).await {
+        let existing = MarketOrders::select_by_nonce(rb, &maker, &nonce)
+            .await
+            .map_err(MarketError::DatabaseError)?;
+        return Err(match existing {
+            Some(_) => Box::new(MarketError::NonceUsed(nonce)),
+            None => Box::new(MarketError::DatabaseError(e)),
+        });
+    }
+    let order = MarketOrders::select_by_nonce(rb, &maker, &nonce)
+        .await
+        .map_err(MarketError::DatabaseError)?
+        .unwrap_or(row);
+
+    Ok(Json(OrderResponse {
+        order,
+        message: format!("succeed create {}", kind),
+    }))
+}
+
+/// 浏览未过期、未成交的挂单与报价，可按类型、token、maker 过滤
+#[get("/market/orders?<kind>&<token_id>&<maker>")]
+pub async fn list_orders(
+    kind: Option<&str>,
+    token_id: Option<&str>,
+    maker: Option<&str>,
+) -> Result<Json<OrderListResponse>, Box<dyn ApiError>> {
+    let token_id = token_id
+        .map(|id| parse_token_id("token_id", id))
+        .transpose()?
+        .map(|id| id.to_string());
+    let maker = maker
+        .map(|addr| parse_address("maker", addr))
+        .transpose()?
+        .map(|addr| addr.to_string());
+    let orders = MarketOrders::select_open(get_db().await, now_secs() as i64)
+        .await
+        .map_err(MarketError::DatabaseError)?
+        .into_iter()
+        .filter(|order| kind.is_none() || order.kind.as_deref() == kind)
+        .filter(|order| token_id.is_none() || order.token_id == token_id)
+        .filter(|order| maker.is_none() || order.maker == maker)
+        .collect();
+    Ok(Json(OrderListResponse {
+        orders,
+        message: "succeed query orders".to_string(),
+    }))
+}
+
+/// 取消挂单或报价，只有 maker 本人可以取消
+#[post("/market/orders/<id>/cancel")]
+pub async fn cancel_order(
+    id: i32,
+    auth_user: AuthenticatedUser,
+    idempotency: Idempotency<'_>,
+) -> Result<Idempotent<Json<OrderResponse>>, Box<dyn ApiError>> {
+    idempotency
+        .run(async move {
+            let caller = auth_user.address().await?;
+            let mut order = load_open_order(id).await?;
+            if order.maker.as_deref() != Some(caller.to_string().as_str()) {
+                return Err(UserError::PermissionDenied(format!(
+                    "order {} is not made by {}",
+                    id, caller
+                ))
+                .into());
+            }
+            // 与接受订单互斥：订单已被占用成交时取消失败
+            if !transition_order(get_db().await, id, STATUS_OPEN, STATUS_CANCELLED)
+                .await
+                .map_err(MarketError::DatabaseError)?
+            {
+                return Err(MarketError::OrderClosed(STATUS_SETTLING.to_string()).into());
+            }
+            order.status = Some(STATUS_CANCELLED.to_string());
+            Ok(Json(OrderResponse {
+                order,
+                message: "succeed cancel order".to_string(),
+            }))
+        })
+        .await
+}
+
+/// 接受挂单（调用者为买家）或报价（调用者为卖家），校验通过后上链成交：
+/// 先以条件更新把订单从 open 占用为 settling，并发的接受、取消请求只有一个能成功；
+/// 然后由买家账户向卖家转账 price 付款，付款打包后再由卖家账户以 safeTransferFromWithValue 转出 token，
+/// 付款广播之前失败会把订单恢复为 open，广播之后失败则保持 settling，按 payment_tx_hash 人工核对
+#[post("/market/orders/<id>/accept")]
+pub async fn accept_order(
+    id: i32,
+    auth_user: AuthenticatedUser,
+    idempotency: Idempotency<'_>,
+) -> Result<Idempotent<Json<AcceptOrderResponse>>, Box<dyn ApiError>> {
+    idempotency
+        .run(async move {
+            let caller = auth_user.address().await?;
+            let mut order = load_open_order(id).await?;
+            let handler = connect_handler().await?;
+            verify_order(&order, &handler.market_domain().await?)?;
+
+            let maker = parse_address("maker", order.maker.as_deref().unwrap_or_default())?;
+            let token_id =
+                parse_token_id("token_id", order.token_id.as_deref().unwrap_or_default())?;
+            let price = U256::from_str_radix(order.price.as_deref().unwrap_or_default(), 10)
+                .map_err(|_| {
+                    RequestError::InvalidParameter("price is not a valid number".to_string())
+                })?;
+            let (seller, buyer) = match order.kind.as_deref() {
+                Some(KIND_LISTING) => (maker, caller),
+                _ => (caller, maker),
+            };
+            if seller == buyer {
+                return Err(RequestError::InvalidParameter(
+                    "cannot accept your own order".to_string(),
+                )
+                .into());
+            }
+            let owner = handler.owner_of(token_id).await?;
+            if owner != seller {
+                return Err(UserError::PermissionDenied(format!(
+                    "token {} is not owned by {}",
+                    token_id, seller
+                ))
+                .into());
+            }
+
+            let rb = get_db().await;
+            if !transition_order(rb, id, STATUS_OPEN, STATUS_SETTLING)
+                .await
+                .map_err(MarketError::DatabaseError)?
+            {
+                return Err(MarketError::OrderClosed(STATUS_SETTLING.to_string()).into());
+            }
+            order.status = Some(STATUS_SETTLING.to_string());
+
+            let payment = async {
+                let buyer_handler = connect_address_handler(buyer, &auth_user.username).await?;
+                let seller_handler = connect_address_handler(seller, &auth_user.username).await?;
+                let payment_hash = buyer_handler.send_value(seller, price).await?;
+                Ok::<_, Box<dyn ApiError>>((buyer_handler, seller_handler, payment_hash))
+            };
+            let (buyer_handler, seller_handler, payment_hash) = match payment.await {
+                Ok(payment) => payment,
+                Err(e) => {
+                    // 付款还未广播，释放订单
+                    if let Err(reopen_error) =
+                        transition_order(rb, id, STATUS_SETTLING, STATUS_OPEN).await
+                    {
+                        println!("Failed to reopen order {}: {}", id, reopen_error);
+                    }
+                    return Err(e);
+                }
+            };
+            order.payment_tx_hash = Some(payment_hash.to_string());
+            MarketOrders::update_by_column(rb, &order, "id")
+                .await
+                .map_err(MarketError::DatabaseError)?;
+
+            buyer_handler
+                .wait_for_mined("transferValue", payment_hash)
+                .await?;
+            // safeTransferFromWithValue 的 value 只作为成交价记入合约的销售记录，款项已由上面的付款交易支付
+            let tx_hash = seller_handler
+                .safe_transfer_from_with_value(seller, buyer, token_id, price)
+                .await?;
+            order.status = Some(STATUS_FILLED.to_string());
+            order.tx_hash = Some(tx_hash.to_string());
+            MarketOrders::update_by_column(rb, &order, "id")
+                .await
+                .map_err(MarketError::DatabaseError)?;
+
+            Ok(Json(AcceptOrderResponse {
+                order,
+                tx_hash: tx_hash.to_string(),
+                message: "succeed settle order".to_string(),
+            }))
+        })
+        .await
+}
+
+async fn load_open_order(id: i32) -> Result<MarketOrders, MarketError> {
+    let order = MarketOrders::select_by_id(get_db().await, id)
+        .await
+        .map_err(MarketError::DatabaseError)?
+        .ok_or(MarketError::OrderNotFound(id))?;
+    match order.status.as_deref() {
+        Some(STATUS_OPEN) => Ok(order),
+        other => Err(MarketError::OrderClosed(
+            other.unwrap_or_default().to_string(),
+        )),
+    }
+}
@@ -0,0 +1,754 @@
+use crate::DataBase::{get_db, MetadataUris};
+use crate::Error::{ApiError, BlockchainError, RequestError, UserError};
+use crate::IPFSImageStorage::storeImage::{download_json_by_cid, IPFS_API_URL};
+use crate::Router::idempotency::{Idempotency, Idempotent};
+use crate::Router::onchain_router::{connect_handler, connect_user_handler};
+use crate::Router::routers::{parse_address, parse_token_id, validation_error, AuthenticatedUser};
+use crate::Router::validation;
+use crate::Transaction::sendTx::Handler;
+use crate::Transaction::sendTx::IERC721A::SaleInfo;
+use crate::Transaction::ContractMethod::{ContractMethod, ContractMethodResult};
+use crate::Transaction::Metadata::{build_metadata, metadata_cid, TokenMetadata};
+use crate::Transaction::Simulation::SimulationResult;
+use alloy::primitives::{Address, Bytes, U256};
+use rocket::serde::{json::Json, Deserialize, Serialize};
+use rocket::Either;
+use validator::Validate;
+
+/// ImageToken 中所有会修改链上状态的函数对应的接口
+/// 交易由调用者自己的托管账户签名，链上的 msg.sender 即调用者，
+/// 发送交易前先检查调用者（当前登录用户的地址）是否为 token 的所有者或被授权者，
+/// 所有接口都支持 simulate=true，只预执行而不发送交易
+/// 以及只读函数对应的 GET 查询接口，参数全部通过路径或查询字符串传入
+
+#[derive(Serialize, Deserialize)]
+pub struct TxResponse {
+    method: String,
+    tx_hash: Option<String>,
+    simulation: Option<SimulationResult>,
+    message: String,
+}
+
+// 预执行或发送交易，并统一包装为 TxResponse
+async fn send_write(
+    handler: &Handler,
+    caller: Address,
+    func_call: ContractMethod,
+    simulate: Option<bool>,
+) -> Result<Json<TxResponse>, Box<dyn ApiError>> {
+    let method = func_call.method_name().to_string();
+    if simulate.unwrap_or(false) {
+        let simulation = handler.simulate(&func_call, caller).await?;
+        let message = match &simulation.revert_reason {
+            None => format!("{} would succeed", method),
+            Some(reason) => format!("{} would revert: {}", method, reason),
+        };
+        return Ok(Json(TxResponse {
+            method,
+            tx_hash: None,
+            simulation: Some(simulation),
+            message,
+        }));
+    }
+
+    match func_call.execute(handler).await? {
+        ContractMethodResult::TxHash(hash) => Ok(Json(TxResponse {
+            message: format!("succeed send {} transaction", method),
+            method,
+            tx_hash: Some(hash.to_string()),
+            simulation: None,
+        })),
+        other => Err(Box::new(BlockchainError::ContractCallError(format!(
+            "unexpected result of {}: {:?}",
+            method, other
+        )))),
+    }
+}
+
+// 调用者必须是 token 的所有者、token 的被授权者或所有者的全局操作员
+async fn ensure_owner_or_approved(
+    handler: &Handler,
+    caller: Address,
+    token_id: U256,
+) -> Result<Address, Box<dyn ApiError>> {
+    let owner = handler.owner_of(token_id).await?;
+    if owner == caller
+        || handler.get_approved(token_id).await? == caller
+        || handler.is_approved_for_all(owner, caller).await?
+    {
+        return Ok(owner);
+    }
+    Err(Box::new(UserError::PermissionDenied(format!(
+        "{} is not owner nor approved for token {}",
+        caller, token_id
+    ))))
+}
+
+// 转账类接口额外检查 from 是否为 token 当前的所有者
+async fn ensure_transferable(
+    handler: &Handler,
+    caller: Address,
+    from: Address,
+    token_id: U256,
+) -> Result<(), Box<dyn ApiError>> {
+    let owner = ensure_owner_or_approved(handler, caller, token_id).await?;
+    if owner != from {
+        return Err(Box::new(UserError::PermissionDenied(format!(
+            "token {} is not owned by {}",
+            token_id, from
+        ))));
+    }
+    Ok(())
+}
+
+#[derive(Serialize, Deserialize, Validate)]
+pub struct TransferRequest {
+    #[validate(custom(function = "validation::address"))]
+    from: String,
+    #[validate(custom(function = "validation::address"))]
+    to: String,
+    token_id: U256,
+}
+
+#[post("/token/transfer_from?<simulate>", format = "json", data = "<request>")]
+pub async fn transfer_from(
+    request: Json<TransferRequest>,
+    simulate: Option<bool>,
+    auth_user: AuthenticatedUser,
+    idempotency: Idempotency<'_>,
+) -> Result<Idempotent<Json<TxResponse>>, Box<dyn ApiError>> {
+    idempotency
+        .payload(&*request)
+        .run(async move {
+            request.validate().map_err(|e| validation_error(&e))?;
+            let from = parse_address("from", &request.from)?;
+            let to = parse_address("to", &request.to)?;
+            let handler = connect_user_handler(&auth_user).await?;
+            let caller = handler.user_address();
+            ensure_transferable(&handler, caller, from, request.token_id).await?;
+
+            let func_call = ContractMethod::transferFrom {
+                from,
+                to,
+                tokenId: request.token_id,
+            };
+            send_write(&handler, caller, func_call, simulate).await
+        })
+        .await
+}
+
+#[derive(Serialize, Deserialize, Validate)]
+pub struct SafeTransferRequest {
+    #[validate(custom(function = "validation::address"))]
+    from: String,
+    #[validate(custom(function = "validation::address"))]
+    to: String,
+    token_id: U256,
+    data: Option<Bytes>,
+}
+
+#[post("/token/safe_transfer_from?<simulate>", format = "json", data = "<request>")]
+pub async fn safe_transfer_from(
+    request: Json<SafeTransferRequest>,
+    simulate: Option<bool>,
+    auth_user: AuthenticatedUser,
+    idempotency: Idempotency<'_>,
+) -> Result<Idempotent<Json<TxResponse>>, Box<dyn ApiError>> {
+    idempotency
+        .payload(&*request)
+        .run(async move {
+            request.validate().map_err(|e| validation_error(&e))?;
+            let from = parse_address("from", &request.from)?;
+            let to = parse_address("to", &request.to)?;
+            let handler = connect_user_handler(&auth_user).await?;
+            let caller = handler.user_address();
+            ensure_transferable(&handler, caller, from, request.token_id).await?;
+
+            let request = request.into_inner();
+            let func_call = ContractMethod::safeTransferFrom {
+                from,
+                to,
+                tokenId: request.token_id,
+                amount: request.data,
+            };
+            send_write(&handler, caller, func_call, simulate).await
+        })
+        .await
+}
+
+#[derive(Serialize, Deserialize, Validate)]
+pub struct TransferWithValueRequest {
+    #[validate(custom(function = "validation::address"))]
+    from: String,
+    #[validate(custom(function = "validation::address"))]
+    to: String,
+    token_id: U256,
+    value: U256,
+}
+
+#[post(
+    "/token/safe_transfer_from_with_value?<simulate>",
+    format = "json",
+    data = "<request>"
+)]
+pub async fn safe_transfer_from_with_value(
+    request: Json<TransferWithValueRequest>,
+    simulate: Option<bool>,
+    auth_user: AuthenticatedUser,
+    idempotency: Idempotency<'_>,
+) -> Result<Idempotent<Json<TxResponse>>, Box<dyn ApiError>> {
+    idempotency
+        .payload(&*request)
+        .run(async move {
+            request.validate().map_err(|e| validation_error(&e))?;
+            let from = parse_address("from", &request.from)?;
+            let to = parse_address("to", &request.to)?;
+            let handler = connect_user_handler(&auth_user).await?;
+            let caller = handler.user_address();
+            ensure_transferable(&handler, caller, from, request.token_id).await?;
+
+            let func_call = ContractMethod::safeTransferFromWithValue {
+                from,
+                to,
+                tokenId: request.token_id,
+                value: request.value,
+            };
+            send_write(&handler, caller, func_call, simulate).await
+        })
+        .await
+}
+
+#[derive(Serialize, Deserialize, Validate)]
+pub struct ApproveRequest {
+    #[validate(custom(function = "validation::address"))]
+    to: String,
+    token_id: U256,
+}
+
+#[post("/token/approve?<simulate>", format = "json", data = "<request>")]
+pub async fn approve(
+    request: Json<ApproveRequest>,
+    simulate: Option<bool>,
+    auth_user: AuthenticatedUser,
+    idempotency: Idempotency<'_>,
+) -> Result<Idempotent<Json<TxResponse>>, Box<dyn ApiError>> {
+    idempotency
+        .payload(&*request)
+        .run(async move {
+            request.validate().map_err(|e| validation_error(&e))?;
+            let to = parse_address("to", &request.to)?;
+            let handler = connect_user_handler(&auth_user).await?;
+            let caller = handler.user_address();
+
+            // 与 ERC721 一致：只有所有者或所有者的全局操作员可以授权
+            let owner = handler.owner_of(request.token_id).await?;
+            if owner != caller && !handler.is_approved_for_all(owner, caller).await? {
+                return Err(UserError::PermissionDenied(format!(
+                    "{} is not owner nor operator of token {}",
+                    caller, request.token_id
+                ))
+                .into());
+            }
+
+            let func_call = ContractMethod::approve {
+                to,
+                tokenId: request.token_id,
+            };
+            send_write(&handler, caller, func_call, simulate).await
+        })
+        .await
+}
+
+#[derive(Serialize, Deserialize, Validate)]
+pub struct SetApprovalForAllRequest {
+    #[validate(custom(function = "validation::address"))]
+    operator: String,
+    approved: bool,
+}
+
+/// 交易由调用者自己的账户签名，授权只作用于调用者持有的 token
+#[post("/token/set_approval_for_all?<simulate>", format = "json", data = "<request>")]
+pub async fn set_approval_for_all(
+    request: Json<SetApprovalForAllRequest>,
+    simulate: Option<bool>,
+    auth_user: AuthenticatedUser,
+    idempotency: Idempotency<'_>,
+) -> Result<Idempotent<Json<TxResponse>>, Box<dyn ApiError>> {
+    idempotency
+        .payload(&*request)
+        .run(async move {
+            request.validate().map_err(|e| validation_error(&e))?;
+            let operator = parse_address("operator", &request.operator)?;
+            let handler = connect_user_handler(&auth_user).await?;
+            let caller = handler.user_address();
+
+            let func_call = ContractMethod::setApprovalForAll {
+                operator,
+                approved: request.approved,
+            };
+            send_write(&handler, caller, func_call, simulate).await
+        })
+        .await
+}
+
+#[derive(Serialize, Deserialize, Validate)]
+pub struct BatchTransferRequest {
+    #[validate(custom(function = "validation::address"))]
+    from: String,
+    #[validate(custom(function = "validation::address"))]
+    to: String,
+    #[validate(length(min = 1, message = "tokenIds cannot be empty"))]
+    token_ids: Vec<U256>,
+}
+
+#[post("/token/batch_transfer_from?<simulate>", format = "json", data = "<request>")]
+pub async fn batch_transfer_from(
+    request: Json<BatchTransferRequest>,
+    simulate: Option<bool>,
+    auth_user: AuthenticatedUser,
+    idempotency: Idempotency<'_>,
+) -> Result<Idempotent<Json<TxResponse>>, Box<dyn ApiError>> {
+    idempotency
+        .payload(&*request)
+        .run(async move {
+            request.validate().map_err(|e| validation_error(&e))?;
+            let from = parse_address("from", &request.from)?;
+            let to = parse_address("to", &request.to)?;
+            let handler = connect_user_handler(&auth_user).await?;
+            let caller = handler.user_address();
+            for token_id in &request.token_ids {
+                ensure_transferable(&handler, caller, from, *token_id).await?;
+            }
+
+            let func_call = ContractMethod::batchTransferFrom {
+                from,
+                to,
+                tokenIds: request.into_inner().token_ids,
+            };
+            send_write(&handler, caller, func_call, simulate).await
+        })
+        .await
+}
+
+#[derive(Serialize, Deserialize, Validate)]
+pub struct SafeBatchTransferRequest {
+    #[validate(custom(function = "validation::address"))]
+    from: String,
+    #[validate(custom(function = "validation::address"))]
+    to: String,
+    #[validate(length(min = 1, message = "tokenIds cannot be empty"))]
+    token_ids: Vec<U256>,
+    data: Option<Bytes>,
+}
+
+/// by 固定为调用者地址
+#[post(
+    "/token/safe_batch_transfer_from?<simulate>",
+    format = "json",
+    data = "<request>"
+)]
+pub async fn safe_batch_transfer_from(
+    request: Json<SafeBatchTransferRequest>,
+    simulate: Option<bool>,
+    auth_user: AuthenticatedUser,
+    idempotency: Idempotency<'_>,
+) -> Result<Idempotent<Json<TxResponse>>, Box<dyn ApiError>> {
+    idempotency
+        .payload(&*request)
+        .run(async move {
+            request.validate().map_err(|e| validation_error(&e))?;
+            let from = parse_address("from", &request.from)?;
+            let to = parse_address("to", &request.to)?;
+            let handler = connect_user_handler(&auth_user).await?;
+            let caller = handler.user_address();
+            for token_id in &request.token_ids {
+                ensure_transferable(&handler, caller, from, *token_id).await?;
+            }
+
+            let request = request.into_inner();
+            let func_call = ContractMethod::safeBatchTransferFrom {
+                by: caller,
+                from,
+                to,
+                tokenIds: request.token_ids,
+                data: request.data.unwrap_or_default(),
+            };
+            send_write(&handler, caller, func_call, simulate).await
+        })
+        .await
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct BurnRequest {
+    token_id: U256,
+}
+
+#[post("/token/burn?<simulate>", format = "json", data = "<request>")]
+pub async fn burn(
+    request: Json<BurnRequest>,
+    simulate: Option<bool>,
+    auth_user: AuthenticatedUser,
+    idempotency: Idempotency<'_>,
+) -> Result<Idempotent<Json<TxResponse>>, Box<dyn ApiError>> {
+    idempotency
+        .payload(&*request)
+        .run(async move {
+            let handler = connect_user_handler(&auth_user).await?;
+            let caller = handler.user_address();
+            ensure_owner_or_approved(&handler, caller, request.token_id).await?;
+
+            let func_call = ContractMethod::burn {
+                tokenId: request.token_id,
+            };
+            send_write(&handler, caller, func_call, simulate).await
+        })
+        .await
+}
+
+#[derive(Serialize, Deserialize, Validate)]
+pub struct BatchBurnRequest {
+    #[validate(length(min = 1, message = "tokenIds cannot be empty"))]
+    token_ids: Vec<U256>,
+}
+
+#[post("/token/batch_burn?<simulate>", format = "json", data = "<request>")]
+pub async fn batch_burn(
+    request: Json<BatchBurnRequest>,
+    simulate: Option<bool>,
+    auth_user: AuthenticatedUser,
+    idempotency: Idempotency<'_>,
+) -> Result<Idempotent<Json<TxResponse>>, Box<dyn ApiError>> {
+    idempotency
+        .payload(&*request)
+        .run(async move {
+            request.validate().map_err(|e| validation_error(&e))?;
+            let handler = connect_user_handler(&auth_user).await?;
+            let caller = handler.user_address();
+            for token_id in &request.token_ids {
+                ensure_owner_or_approved(&handler, caller, *token_id).await?;
+            }
+
+            let func_call = ContractMethod::batchBurn {
+                tokenIds: request.into_inner().token_ids,
+            };
+            send_write(&handler, caller, func_call, simulate).await
+        })
+        .await
+}
+
+#[derive(Serialize, Deserialize, Validate)]
+pub struct ModifyImageInfoRequest {
+    token_id: U256,
+    #[validate(custom(function = "validation::token_uri"))]
+    token_uri: String,
+    #[validate(custom(function = "validation::address"))]
+    owner: String,
+    #[validate(custom(function = "validation::watermark"))]
+    watermark: String,
+    #[validate(custom(function = "validation::timestamp"))]
+    capture_time: U256,
+    #[validate(custom(function = "validation::text"))]
+    capture_device: String,
+    #[validate(custom(function = "validation::text"))]
+    capture_company: String,
+    #[validate(custom(function = "validation::timestamp"))]
+    submission_time: U256,
+    #[validate(custom(function = "validation::text"))]
+    submission_receiver: String,
+}
+
+#[post("/token/modify_image_info?<simulate>", format = "json", data = "<request>")]
+pub async fn modify_image_info(
+    request: Json<ModifyImageInfoRequest>,
+    simulate: Option<bool>,
+    auth_user: AuthenticatedUser,
+    idempotency: Idempotency<'_>,
+) -> Result<Idempotent<Json<TxResponse>>, Box<dyn ApiError>> {
+    idempotency
+        .payload(&*request)
+        .run(async move {
+            request.validate().map_err(|e| validation_error(&e))?;
+            let owner = parse_address("owner", &request.owner)?;
+            let handler = connect_user_handler(&auth_user).await?;
+            let caller = handler.user_address();
+            ensure_owner_or_approved(&handler, caller, request.token_id).await?;
+
+            let request = request.into_inner();
+            let func_call = ContractMethod::modifyImageInfo {
+                tokenId: request.token_id,
+                _tokenURIs: request.token_uri,
+                owner,
+                watermark: request.watermark,
+                captureTime: request.capture_time,
+                captureDevice: request.capture_device,
+                captureCompany: request.capture_company,
+                submissionTime: request.submission_time,
+                submissionReceiver: request.submission_receiver,
+            };
+            send_write(&handler, caller, func_call, simulate).await
+        })
+        .await
+}
+
+#[derive(Serialize, Deserialize, Validate)]
+pub struct ModifyCaptureInfoRequest {
+    token_id: U256,
+    #[validate(custom(function = "validation::timestamp"))]
+    capture_time: U256,
+    #[validate(custom(function = "validation::text"))]
+    capture_device: String,
+    #[validate(custom(function = "validation::text"))]
+    capture_company: String,
+}
+
+#[post("/token/modify_capture_info?<simulate>", format = "json", data = "<request>")]
+pub async fn modify_capture_info(
+    request: Json<ModifyCaptureInfoRequest>,
+    simulate: Option<bool>,
+    auth_user: AuthenticatedUser,
+    idempotency: Idempotency<'_>,
+) -> Result<Idempotent<Json<TxResponse>>, Box<dyn ApiError>> {
+    idempotency
+        .payload(&*request)
+        .run(async move {
+            request.validate().map_err(|e| validation_error(&e))?;
+            let handler = connect_user_handler(&auth_user).await?;
+            let caller = handler.user_address();
+            ensure_owner_or_approved(&handler, caller, request.token_id).await?;
+
+            let request = request.into_inner();
+            let func_call = ContractMethod::modifyCaptureInfo {
+                tokenId: request.token_id,
+                captureTime: request.capture_time,
+                captureDevice: request.capture_device,
+                captureCompany: request.capture_company,
+            };
+            send_write(&handler, caller, func_call, simulate).await
+        })
+        .await
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct QueryResponse<T> {
+    result: T,
+    message: String,
+}
+
+fn query_response<T>(result: T, method: &str) -> Json<QueryResponse<T>> {
+    Json(QueryResponse {
+        result,
+        message: format!("succeed query {}", method),
+    })
+}
+
+#[get("/token/name")]
+pub async fn token_name() -> Result<Json<QueryResponse<String>>, Box<dyn ApiError>> {
+    let handler = connect_handler().await?;
+    Ok(query_response(handler.name().await?, "name"))
+}
+
+#[get("/token/symbol")]
+pub async fn token_symbol() -> Result<Json<QueryResponse<String>>, Box<dyn ApiError>> {
+    let handler = connect_handler().await?;
+    Ok(query_response(handler.symbol().await?, "symbol"))
+}
+
+#[get("/token/total_supply")]
+pub async fn total_supply() -> Result<Json<QueryResponse<U256>>, Box<dyn ApiError>> {
+    let handler = connect_handler().await?;
+    Ok(query_response(handler.total_supply().await?, "totalSupply"))
+}
+
+#[get("/token/<token_id>/owner")]
+pub async fn owner_of(token_id: &str) -> Result<Json<QueryResponse<Address>>, Box<dyn ApiError>> {
+    let token_id = parse_token_id("token_id", token_id)?;
+    let handler = connect_handler().await?;
+    Ok(query_response(handler.owner_of(token_id).await?, "ownerOf"))
+}
+
+#[get("/token/<token_id>/approved")]
+pub async fn get_approved(
+    token_id: &str,
+) -> Result<Json<QueryResponse<Address>>, Box<dyn ApiError>> {
+    let token_id = parse_token_id("token_id", token_id)?;
+    let handler = connect_handler().await?;
+    Ok(query_response(handler.get_approved(token_id).await?, "getApproved"))
+}
+
+#[get("/token/<token_id>/uri")]
+pub async fn token_uri(token_id: &str) -> Result<Json<QueryResponse<String>>, Box<dyn ApiError>> {
+    let token_id = parse_token_id("token_id", token_id)?;
+    let handler = connect_handler().await?;
+    Ok(query_response(handler.token_uri(token_id).await?, "tokenURI"))
+}
+
+#[get("/balance/<owner>")]
+pub async fn balance_of(owner: &str) -> Result<Json<QueryResponse<U256>>, Box<dyn ApiError>> {
+    let owner = parse_address("owner", owner)?;
+    let handler = connect_handler().await?;
+    Ok(query_response(handler.balance_of(owner).await?, "balanceOf"))
+}
+
+#[get("/approval_for_all?<owner>&<operator>")]
+pub async fn is_approved_for_all(
+    owner: &str,
+    operator: &str,
+) -> Result<Json<QueryResponse<bool>>, Box<dyn ApiError>> {
+    let owner = parse_address("owner", owner)?;
+    let operator = parse_address("operator", operator)?;
+    let handler = connect_handler().await?;
+    Ok(query_response(
+        handler.is_approved_for_all(owner, operator).await?,
+        "isApprovedForAll",
+    ))
+}
+
+/// ERC-721 元数据，由链上 ImageInfo 实时生成（销售次数等随链上状态变化）
+#[get("/metadata/<token_id>")]
+pub async fn token_metadata(token_id: &str) -> Result<Json<TokenMetadata>, Box<dyn ApiError>> {
+    let token_id = parse_token_id("token_id", token_id)?;
+    let handler = connect_handler().await?;
+    let info = handler.image_info(token_id).await?;
+    // tokenURI 指向本服务上传到 IPFS 的元数据时，图片地址取自那份元数据，
+    // 其他 ipfs:// 地址（例如直接指向图片）原样作为图片地址
+    let produced = match metadata_cid(&info._tokenURIs) {
+        Some(cid) => MetadataUris::select_by_token(get_db().await, &token_id.to_string(), cid)
+            .await
+            .map_err(UserError::DatabaseError)?
+            .map(|_| cid),
+        None => None,
+    };
+    let image = match produced {
+        Some(cid) => download_json_by_cid(cid, IPFS_API_URL).await?["image"]
+            .as_str()
+            .unwrap_or_default()
+            .to_string(),
+        None => info._tokenURIs.clone(),
+    };
+    Ok(Json(build_metadata(token_id, &info, &image)))
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct SaleHistoryResponse {
+    token_id: U256,
+    page: u64,
+    page_size: u64,
+    has_more: bool,
+    sales: Vec<SaleInfo>,
+    message: String,
+}
+
+const DEFAULT_SALE_PAGE_SIZE: u64 = 20;
+const MAX_SALE_PAGE_SIZE: u64 = 100;
+
+/// 分页查询 token 的全部销售记录，page 从 0 开始，按下标逐条读取合约中的记录直到越界
+#[get("/token/<token_id>/sales?<page>&<page_size>")]
+pub async fn sale_history(
+    token_id: &str,
+    page: Option<u64>,
+    page_size: Option<u64>,
+) -> Result<Json<SaleHistoryResponse>, Box<dyn ApiError>> {
+    let token_id = parse_token_id("token_id", token_id)?;
+    let page = page.unwrap_or(0);
+    let page_size = page_size
+        .unwrap_or(DEFAULT_SALE_PAGE_SIZE)
+        .clamp(1, MAX_SALE_PAGE_SIZE);
+
+    let handler = connect_handler().await?;
+
+    // 多读一条用于判断是否还有下一页
+    let mut sales = handler
+        .sale_history_range(token_id, page.saturating_mul(page_size), page_size + 1)
+        .await?;
+    let has_more = sales.len() as u64 > page_size;
+    sales.truncate(page_size as usize);
+    Ok(Json(SaleHistoryResponse {
+        token_id,
+        page,
+        page_size,
+        has_more,
+        sales,
+        message: "succeed query sale history".to_string(),
+    }))
+}
+
+#[derive(Serialize, Deserialize, Validate)]
+pub struct SellRequest {
+    #[validate(custom(function = "validation::address"))]
+    buyer: String,
+    token_id: U256,
+    price: U256,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct SellResponse {
+    tx_hash: String,
+    sale: SaleInfo,
+    message: String,
+}
+
+/// 出售 token：交易由调用者（卖家）自己的账户签名，调用者必须是 token 的所有者，
+/// 通过 safeTransferFromWithValue 转给买家，合约会同时记录买家、价格和时间，
+/// 交易打包后返回合约中新增的这条 SaleInfo
+#[post("/token/sell?<simulate>", format = "json", data = "<request>")]
+pub async fn sell(
+    request: Json<SellRequest>,
+    simulate: Option<bool>,
+    auth_user: AuthenticatedUser,
+    idempotency: Idempotency<'_>,
+) -> Result<Idempotent<Either<Json<TxResponse>, Json<SellResponse>>>, Box<dyn ApiError>> {
+    idempotency
+        .payload(&*request)
+        .run(async move {
+            request.validate().map_err(|e| validation_error(&e))?;
+            let buyer = parse_address("buyer", &request.buyer)?;
+            let handler = connect_user_handler(&auth_user).await?;
+            let seller = handler.user_address();
+            if buyer == seller {
+                return Err(RequestError::InvalidParameter(
+                    "buyer cannot be the seller".to_string(),
+                )
+                .into());
+            }
+            let owner = handler.owner_of(request.token_id).await?;
+            if owner != seller {
+                return Err(UserError::PermissionDenied(format!(
+                    "token {} is not owned by {}",
+                    request.token_id, seller
+                ))
+                .into());
+            }
+
+            let func_call = ContractMethod::safeTransferFromWithValue {
+                from: seller,
+                to: buyer,
+                tokenId: request.token_id,
+                value: request.price,
+            };
+            if simulate.unwrap_or(false) {
+                return Ok(Either::Left(
+                    send_write(&handler, seller, func_call, simulate).await?,
+                ));
+            }
+
+            let tx_hash = handler
+                .safe_transfer_from_with_value(seller, buyer, request.token_id, request.price)
+                .await?;
+            // safe_transfer_from_with_value 等到回执后才返回，此时最新的一条即为本次销售
+            match handler.latest_sale(request.token_id).await? {
+                Some(sale) if sale.buyer == buyer && sale.saleValue == request.price => {
+                    Ok(Either::Right(Json(SellResponse {
+                        tx_hash: tx_hash.to_string(),
+                        sale,
+                        message: "succeed sell token".to_string(),
+                    })))
+                }
+                _ => Err(BlockchainError::ContractCallError(format!(
+                    "sale of token {} in transaction {} was not recorded",
+                    request.token_id, tx_hash
+                ))
+                .into()),
+            }
+        })
+        .await
+}
@@ -1,6 +1,7 @@
 use crate::DataBase::get_db;
 use crate::DataBase::Users;
 use crate::Error::{ApiError, RequestError, UserError};
+use crate::Router::routers::validation_error;
 use crate::UserInfo::Generate_address::generate_random_account;
 use crate::UserInfo::Login::{login_user, register_user};
 use rbatis::executor::Executor;
@@ -20,10 +21,11 @@ use validator::{Validate, ValidationErrors};
 
 #[derive(Debug, Serialize, Deserialize, Validate)]
 pub struct RegisterRequest {
-    #[validate(length(min = 1, message = "Username cannot be empty"))]
+    #[validate(length(min = 1, max = 64, message = "Username must be 1 to 64 characters long"))]
     username: String,
-    #[validate(length(min = 3, message = "Password must be at least 3 characters long"))]
+    #[validate(length(min = 3, max = 128, message = "Password must be 3 to 128 characters long"))]
     password: String,
+    #[validate(length(max = 128, message = "Company name must be at most 128 characters long"))]
     company_name: String,
 }
 
@@ -33,9 +35,11 @@ struct RegisterResponse {
     privatekey: String,
 }
 
-#[derive(Serialize, Deserialize)]
+#[derive(Serialize, Deserialize, Validate)]
 struct LoginRequest {
+    #[validate(length(min = 1, message = "Username cannot be empty"))]
     username: String,
+    #[validate(length(min = 1, message = "Password cannot be empty"))]
     password: String,
 }
 
@@ -46,12 +50,6 @@ struct LoginResponse {
     token: Option<String>,
 }
 
-#[derive(Serialize, Deserialize)]
-struct ErrorResponse {
-    error_code: String, // 错误码，例如 "UserAlreadyExists"
-    message: String,    // 错误的详细描述，例如 "The username is already taken"
-}
-
 // 已测试接口
 #[post("/register", format = "json", data = "<register_request>")]
 pub async fn register(
@@ -59,10 +57,10 @@ pub async fn register(
 ) -> Result<Json<RegisterResponse>, Box<dyn ApiError>> {
     // Validate the incoming request data
     if let Err(validation_errors) = register_request.validate() {
-        // Map `validation_errors` to `RequestError`
-        if let Some(error) = map_validation_errors(&validation_errors) {
-            return Err(Box::new(error));
-        }
+        // Map `validation_errors` to `RequestError`，其余字段返回字段级别的错误
+        let error = map_validation_errors(&validation_errors)
+            .unwrap_or_else(|| validation_error(&validation_errors));
+        return Err(Box::new(error));
     }
 
     let result = register_user(
@@ -94,6 +92,9 @@ pub async fn login_options(
 pub async fn login(
     login_request: Json<LoginRequest>,
 ) -> Result<Json<LoginResponse>, Box<dyn ApiError>> {
+    login_request
+        .validate()
+        .map_err(|e| validation_error(&e))?;
     let result = login_user(&login_request.username, &login_request.password).await;
     match result {
         Ok((address, token)) => Ok(Json(LoginResponse {
@@ -111,12 +112,14 @@ pub async fn login(
 fn map_validation_errors(errors: &ValidationErrors) -> Option<RequestError> {
     for (field, field_errors) in errors.field_errors() {
         for error in field_errors {
+            // 过短时沿用原有的错误码，过长等其他情况交给 validation_error
+            let value = error.params.get("value").and_then(|v| v.as_str());
             match field {
-                "username" => return Some(RequestError::EmptyUsername),
-                "password" => {
-                    if error.code == "length" {
-                        return Some(RequestError::TooShortPassword);
-                    }
+                "username" if error.code == "length" && value.is_some_and(str::is_empty) => {
+                    return Some(RequestError::EmptyUsername)
+                }
+                "password" if error.code == "length" && value.is_some_and(|v| v.chars().count() < 3) => {
+                    return Some(RequestError::TooShortPassword)
                 }
                 _ => {}
             }
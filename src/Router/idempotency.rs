@@ -0,0 +1,388 @@
+use crate::DataBase::{ensure_unique_index, get_db, try_get_db, IdempotencyKeys};
+use crate::Error::{ApiError, IdempotencyError};
+use crate::Router::routers::AuthenticatedUser;
+use crate::Transaction::sendTx::track_broadcast;
+use rbatis::rbdc::datetime::DateTime;
+use rocket::fairing::{self, Fairing, Info, Kind};
+use rocket::http::{ContentType, Header, Status};
+use rocket::request::{FromRequest, Outcome};
+use rocket::response::{self, Responder};
+use rocket::{Build, Request, Response, Rocket};
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+use std::convert::Infallible;
+use std::future::Future;
+use std::io::Cursor;
+use std::sync::Mutex;
+
+// 写接口的幂等处理
+// 客户端超时重试时带上相同的 Idempotency-Key，服务端只执行一次：首次成功的响应保存到 idempotency_keys 表，
+// 之后相同 key、相同请求的重试直接重放保存的响应（带 Idempotent-Replayed 头），
+// 相同 key 用于不同请求时返回 422，首次请求仍在处理中时返回 409。
+// 失败的响应不保存，客户端可以用同一个 key 重试；但 handler 已经广播过交易时（如等待回执超时），
+// 失败的响应也会保存，重试只重放该响应，避免重复发送交易。
+// key 按用户隔离，未登录的请求按客户端地址隔离，请求指纹为请求方法、路径（含查询参数）与请求体的 sha256。
+// 并发的相同请求由 (scope, idempotency_key) 上的唯一索引保证只有一个执行，索引在启动时创建。
+// 注册和登录接口不使用幂等 key：注册本身按用户名去重，且响应中包含私钥，不应再保存一份。
+
+pub const IDEMPOTENCY_KEY_HEADER: &str = "Idempotency-Key";
+pub const IDEMPOTENT_REPLAYED_HEADER: &str = "Idempotent-Replayed";
+
+// 保存的响应在 24 小时后过期，过期后同一个 key 可以重新使用
+const KEY_TTL_SECS: i64 = 24 * 60 * 60;
+const MAX_KEY_LEN: usize = 255;
+
+const STATE_IN_PROGRESS: &str = "in_progress";
+const STATE_COMPLETED: &str = "completed";
+
+const UNIQUE_INDEX: &str = "uk_idempotency_scope_key";
+
+// 当前请求占用的 idempotency_keys 行，由 IdempotencyFairing 在响应时写入结果或释放
+#[derive(Default)]
+struct IdempotencyClaim(Mutex<Option<ClaimedKey>>);
+
+struct ClaimedKey {
+    row: IdempotencyKeys,
+    // handler 执行过程中是否广播过交易
+    broadcast: bool,
+}
+
+/// 写接口的请求守卫，没有 Idempotency-Key 头时直接执行
+pub struct Idempotency<'r> {
+    key: Option<&'r str>,
+    // 无法识别调用方时为 None，此时不接受 Idempotency-Key
+    scope: Option<String>,
+    request_line: String,
+    payload: Vec<u8>,
+    claim: &'r IdempotencyClaim,
+}
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for Idempotency<'r> {
+    type Error = Infallible;
+
+    async fn from_request(request: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+        // 加上前缀区分用户名和客户端地址，未登录的请求之间不共用 key
+        let scope = match request.guard::<AuthenticatedUser>().await {
+            Outcome::Success(user) => Some(format!("user:{}", user.username)),
+            _ => request.client_ip().map(|ip| format!("ip:{}", ip)),
+        };
+        Outcome::Success(Idempotency {
+            key: request.headers().get_one(IDEMPOTENCY_KEY_HEADER),
+            scope,
+            request_line: format!("{} {}", request.method(), request.uri()),
+            payload: Vec::new(),
+            claim: request.local_cache(IdempotencyClaim::default),
+        })
+    }
+}
+
+impl Idempotency<'_> {
+    /// 请求是否带有 Idempotency-Key，没有时不需要计算请求指纹
+    pub fn has_key(&self) -> bool {
+        self.key.is_some()
+    }
+
+    /// 请求体参与请求指纹的计算，没有请求体的接口只按请求方法和路径计算
+    pub fn payload<P: Serialize + ?Sized>(mut self, payload: &P) -> Self {
+        self.payload = serde_json::to_vec(payload).unwrap_or_default();
+        self
+    }
+
+    /// 执行 handler；相同 key 的重复请求不再执行，直接返回首次保存的响应
+    pub async fn run<R, F>(self, handler: F) -> Result<Idempotent<R>, Box<dyn ApiError>>
+    where
+        F: Future<Output = Result<R, Box<dyn ApiError>>>,
+    {
+        let Some(key) = self.key else {
+            return handler.await.map(Idempotent::Fresh);
+        };
+        validate_key(key)?;
+        let scope = self.scope.as_deref().ok_or_else(|| {
+            IdempotencyError::InvalidKey("the caller could not be identified".to_string())
+        })?;
+
+        let fingerprint = fingerprint(&self.request_line, &self.payload);
+        match claim(scope, key, &fingerprint).await? {
+            Claim::Claimed(row) => {
+                if let Ok(mut slot) = self.claim.0.lock() {
+                    *slot = Some(ClaimedKey {
+                        row,
+                        broadcast: false,
+                    });
+                }
+                let (result, broadcast) = track_broadcast(handler).await;
+                if let Ok(mut slot) = self.claim.0.lock() {
+                    if let Some(claimed) = slot.as_mut() {
+                        claimed.broadcast = broadcast;
+                    }
+                }
+                result.map(Idempotent::Fresh)
+            }
+            Claim::Replay(stored) => Ok(Idempotent::Replay(stored)),
+        }
+    }
+}
+
+/// 首次执行的响应或重放保存的响应
+pub enum Idempotent<R> {
+    Fresh(R),
+    Replay(StoredResponse),
+}
+
+pub struct StoredResponse {
+    status: u16,
+    content_type: Option<String>,
+    body: String,
+}
+
+impl<'r, 'o: 'r, R: Responder<'r, 'o>> Responder<'r, 'o> for Idempotent<R> {
+    fn respond_to(self, request: &'r Request<'_>) -> response::Result<'o> {
+        match self {
+            Idempotent::Fresh(inner) => inner.respond_to(request),
+            Idempotent::Replay(stored) => {
+                let mut response = Response::build();
+                response
+                    .status(Status::from_code(stored.status).unwrap_or(Status::Ok))
+                    .header(Header::new(IDEMPOTENT_REPLAYED_HEADER, "true"));
+                if let Some(content_type) = stored
+                    .content_type
+                    .as_deref()
+                    .and_then(ContentType::parse_flexible)
+                {
+                    response.header(content_type);
+                }
+                response
+                    .sized_body(stored.body.len(), Cursor::new(stored.body))
+                    .ok()
+            }
+        }
+    }
+}
+
+// 启动时创建唯一索引；保存首次成功或已经广播过交易的响应，其余失败释放 key 以便重试
+pub struct IdempotencyFairing;
+
+#[rocket::async_trait]
+impl Fairing for IdempotencyFairing {
+    fn info(&self) -> Info {
+        Info {
+            name: "Store responses of idempotent requests",
+            kind: Kind::Ignite | Kind::Response,
+        }
+    }
+
+    async fn on_ignite(&self, rocket: Rocket<Build>) -> fairing::Result {
+        // 没有唯一索引时并发的相同请求会同时执行，无法创建索引时拒绝启动
+        let ensured = match try_get_db().await {
+            Ok(rb) => {
+                ensure_unique_index(
+                    rb,
+                    "idempotency_keys",
+                    UNIQUE_INDEX,
+                    &["scope", "idempotency_key"],
+                )
+                .await
+            }
+            Err(e) => Err(e),
+        };
+        match ensured {
+            Ok(()) => Ok(rocket),
+            Err(e) => {
+                println!("Failed to create index {}: {}", UNIQUE_INDEX, e);
+                Err(rocket)
+            }
+        }
+    }
+
+    async fn on_response<'r>(&self, request: &'r Request<'_>, response: &mut Response<'r>) {
+        let claimed = request
+            .local_cache(IdempotencyClaim::default)
+            .0
+            .lock()
+            .ok()
+            .and_then(|mut slot| slot.take());
+        let Some(ClaimedKey { mut row, broadcast }) = claimed else {
+            return;
+        };
+        let rb = get_db().await;
+
+        let body = if response.status().class().is_success() || broadcast {
+            response.body_mut().to_string().await.ok()
+        } else {
+            None
+        };
+        let Some(body) = body else {
+            if let Err(e) = IdempotencyKeys::delete_by_column(rb, "id", row.id).await {
+                println!("Failed to release idempotency key: {}", e);
+            }
+            return;
+        };
+        response.set_sized_body(body.len(), Cursor::new(body.clone()));
+
+        row.state = Some(STATE_COMPLETED.to_string());
+        row.response_status = Some(response.status().code as i32);
+        row.content_type = response
+            .content_type()
+            .map(|content_type| content_type.to_string());
+        row.response_body = Some(body);
+        if let Err(e) = IdempotencyKeys::update_by_column(rb, &row, "id").await {
+            println!("Failed to store idempotent response: {}", e);
+        }
+    }
+}
+
+enum Claim {
+    Claimed(IdempotencyKeys),
+    Replay(StoredResponse),
+}
+
+#[derive(Debug, PartialEq)]
+enum Decision {
+    Expired,
+    Replay,
+    InProgress,
+    Mismatch,
+}
+
+// 占用 key；已经被占用时根据保存的记录决定重放还是报告冲突
+async fn claim(scope: &str, key: &str, fingerprint: &str) -> Result<Claim, IdempotencyError> {
+    let rb = get_db().await;
+    let now = DateTime::now();
+    if let Some(existing) = IdempotencyKeys::select_by_key(rb, scope, key)
+        .await
+        .map_err(IdempotencyError::DatabaseError)?
+    {
+        match decide(&existing, fingerprint, now.unix_timestamp()) {
+            Decision::Expired => {
+                IdempotencyKeys::delete_by_column(rb, "id", existing.id)
+                    .await
+                    .map_err(IdempotencyError::DatabaseError)?;
+            }
+            decision => return resolve(decision, existing),
+        }
+    }
+
+    let row = IdempotencyKeys {
+        id: None,
+        scope: Some(scope.to_string()),
+        idempotency_key: Some(key.to_string()),
+        fingerprint: Some(fingerprint.to_string()),
+        state: Some(STATE_IN_PROGRESS.to_string()),
+        response_status: None,
+        content_type: None,
+        response_body: None,
+        created_at: Some(now.clone()),
+    };
+    // 并发的相同请求同时插入时，唯一索引保证只有一个成功
+    let inserted = IdempotencyKeys::insert(rb, &row).await;
+    let existing = IdempotencyKeys::select_by_key(rb, scope, key)
+        .await
+        .map_err(IdempotencyError::DatabaseError)?;
+    match (inserted, existing) {
+        (Ok(_), Some(claimed)) => Ok(Claim::Claimed(claimed)),
+        (Err(_), Some(existing)) => resolve(
+            decide(&existing, fingerprint, now.unix_timestamp()),
+            existing,
+        ),
+        (Err(e), None) => Err(IdempotencyError::DatabaseError(e)),
+        (Ok(_), None) => Err(IdempotencyError::DatabaseError(rbatis::Error::E(
+            "idempotency key disappeared after insert".to_string(),
+        ))),
+    }
+}
+
+fn resolve(decision: Decision, existing: IdempotencyKeys) -> Result<Claim, IdempotencyError> {
+    match decision {
+        Decision::Replay => Ok(Claim::Replay(StoredResponse {
+            status: existing.response_status.unwrap_or(200) as u16,
+            content_type: existing.content_type,
+            body: existing.response_body.unwrap_or_default(),
+        })),
+        Decision::Mismatch => Err(IdempotencyError::KeyReused),
+        Decision::InProgress | Decision::Expired => Err(IdempotencyError::KeyInProgress),
+    }
+}
+
+fn decide(existing: &IdempotencyKeys, fingerprint: &str, now: i64) -> Decision {
+    let created_at = existing
+        .created_at
+        .as_ref()
+        .map(|created_at| created_at.unix_timestamp())
+        .unwrap_or_default();
+    if now - created_at > KEY_TTL_SECS {
+        Decision::Expired
+    } else if existing.fingerprint.as_deref() != Some(fingerprint) {
+        Decision::Mismatch
+    } else if existing.state.as_deref() == Some(STATE_COMPLETED) {
+        Decision::Replay
+    } else {
+        Decision::InProgress
+    }
+}
+
+fn validate_key(key: &str) -> Result<(), IdempotencyError> {
+    if key.is_empty() || key.len() > MAX_KEY_LEN {
+        return Err(IdempotencyError::InvalidKey(format!(
+            "length must be between 1 and {}",
+            MAX_KEY_LEN
+        )));
+    }
+    if !key.chars().all(|c| c.is_ascii_graphic()) {
+        return Err(IdempotencyError::InvalidKey(
+            "only visible ASCII characters are allowed".to_string(),
+        ));
+    }
+    Ok(())
+}
+
+fn fingerprint(request_line: &str, payload: &[u8]) -> String {
+    let mut hasher = Sha256::new();
+    hasher.update(request_line.as_bytes());
+    hasher.update(b"\n");
+    hasher.update(payload);
+    format!("{:x}", hasher.finalize())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_decide_idempotent_request() {
+        let now = DateTime::now().unix_timestamp();
+        let request_line = "POST /token/burn";
+        let first = fingerprint(request_line, br#"{"token_id":1}"#);
+        let mut existing = IdempotencyKeys {
+            id: Some(1),
+            scope: Some("user:alice".to_string()),
+            idempotency_key: Some("key-1".to_string()),
+            fingerprint: Some(first.clone()),
+            state: Some(STATE_IN_PROGRESS.to_string()),
+            response_status: None,
+            content_type: None,
+            response_body: None,
+            created_at: Some(DateTime::from_timestamp(now - 10)),
+        };
+
+        assert_eq!(decide(&existing, &first, now), Decision::InProgress);
+        existing.state = Some(STATE_COMPLETED.to_string());
+        assert_eq!(decide(&existing, &first, now), Decision::Replay);
+
+        // 相同 key 的不同请求体或不同路径
+        let other_body = fingerprint(request_line, br#"{"token_id":2}"#);
+        assert_eq!(decide(&existing, &other_body, now), Decision::Mismatch);
+        let other_path = fingerprint("POST /token/batch_burn", br#"{"token_id":1}"#);
+        assert_eq!(decide(&existing, &other_path, now), Decision::Mismatch);
+
+        assert_eq!(
+            decide(&existing, &first, now + KEY_TTL_SECS),
+            Decision::Expired
+        );
+
+        assert!(validate_key("8e4c2a1f-retry").is_ok());
+        assert!(validate_key("").is_err());
+        assert!(validate_key("has space").is_err());
+        assert!(validate_key(&"k".repeat(MAX_KEY_LEN + 1)).is_err());
+    }
+}
@@ -1,7 +1,11 @@
 pub mod Image_routers;
+pub mod Market_routers;
+pub mod Token_routers;
 pub mod User_routers;
+pub mod idempotency;
 pub mod onchain_router;
 pub mod routers;
+pub mod validation;
 
 use rocket_cors::{AllowedHeaders, AllowedOrigins, Cors, CorsOptions};
 
@@ -1,106 +1,319 @@
-use crate::Error::{ApiError, BlockchainError, ImageError};
+use crate::DataBase::{get_db, MetadataUris, Users};
+use crate::Error::{ApiError, BlockchainError, ImageError, RequestError, UserError};
 use crate::IdentityAuthentication::Jwt::validate_token;
-use crate::Router::routers::AuthenticatedUser;
+use crate::IPFSImageStorage::storeImage::{upload_and_pin_json, IPFS_API_URL};
+use crate::Router::idempotency::{Idempotency, Idempotent};
+use crate::Router::routers::{validation_error, AuthenticatedUser};
+use crate::Router::validation;
 use crate::Transaction::sendTx::Handler;
+use crate::Transaction::sendTx::IERC721A::ImageInfo;
 use crate::Transaction::ContractMethod::{ContractMethod, ContractMethodResult};
-use alloy::primitives::U256;
+use crate::Transaction::Journal::JournalStatus;
+use crate::Transaction::Metadata::{build_metadata, IPFS_SCHEME};
+use crate::Transaction::Simulation::SimulationResult;
+use crate::WatermarkService::watermarkservice::backfill_token_ids;
+use alloy::primitives::aliases::TxHash;
+use alloy::primitives::{Address, U256};
 use rbatis::Error;
+use rbatis::rbdc::datetime::DateTime;
 use rocket::http::Status;
 use rocket::request::{FromRequest, Outcome};
 use rocket::serde::{json::Json, Deserialize, Serialize};
-use rocket::Request;
+use rocket::{Either, Request};
+use std::str::FromStr;
+use validator::{Validate, ValidationError};
 
-/// 1.将图片信息上传到链上 2.从链上获取图片信息
+/// 1.将图片信息上传到链上 2.从链上获取图片信息 3.加速、取消卡在内存池中的交易
 
-#[derive(Serialize, Deserialize, Clone)]
+const HTTP_URL: &str = "http://localhost:8545";
+const CONTRACT_ADDRESS: &str = "0xa6a0110367e24c541FC29124E8E89E3556263177";
+
+// 构造链上链下连接，交易由服务端账户签名（铸造等不属于某个用户 token 的操作）
+pub(crate) async fn connect_handler() -> Result<Handler, BlockchainError> {
+    let pk = "0x3ba5c6a17da00c75e9377e03ae98aa3dcdca7c4e537c84399125dfefa89be521";
+    let user_address = "0x6d0d470a22c15a14817c51116932312a00ff00c8";
+    Handler::initialize_contract(HTTP_URL, pk, user_address, CONTRACT_ADDRESS).await
+}
+
+// 构造以当前登录用户的托管账户签名的连接，链上的 msg.sender 就是调用者本人，
+// 所有权、授权等检查由合约按调用者执行，账户需要持有足够的 ETH 支付 gas
+pub(crate) async fn connect_user_handler(
+    auth_user: &AuthenticatedUser,
+) -> Result<Handler, Box<dyn ApiError>> {
+    let user = Users::select_by_username(get_db().await, &auth_user.username)
+        .await
+        .map_err(UserError::DatabaseError)?;
+    let handler = account_handler(user).await?;
+    Ok(handler.on_behalf_of(&auth_user.username))
+}
+
+// 按地址找到注册用户，构造以其托管账户签名的连接（如市场成交时由卖家签名转出 token）
+pub(crate) async fn connect_address_handler(
+    address: Address,
+    requested_by: &str,
+) -> Result<Handler, Box<dyn ApiError>> {
+    let user = Users::select_by_address(get_db().await, &address.to_string())
+        .await
+        .map_err(UserError::DatabaseError)?;
+    let handler = account_handler(user).await?;
+    Ok(handler.on_behalf_of(requested_by))
+}
+
+async fn account_handler(user: Option<Users>) -> Result<Handler, Box<dyn ApiError>> {
+    let Some(Users {
+        address: Some(address),
+        privatekey: Some(privatekey),
+        ..
+    }) = user
+    else {
+        return Err(UserError::UserNotFound.into());
+    };
+    Ok(Handler::initialize_contract(HTTP_URL, &privatekey, &address, CONTRACT_ADDRESS).await?)
+}
+
+// 加速、取消交易时必须用原交易的签名账户发送替换交易：
+// 用户自己签名的交易用用户的账户，服务端签名的交易（如铸造）用服务端账户
+async fn replacement_handler(
+    tx_hash: TxHash,
+    auth_user: &AuthenticatedUser,
+) -> Result<Handler, Box<dyn ApiError>> {
+    let server = connect_handler().await?.on_behalf_of(&auth_user.username);
+    if server.transaction_sender(tx_hash).await? == server.user_address() {
+        Ok(server)
+    } else {
+        connect_user_handler(auth_user).await
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Validate)]
+#[validate(schema(function = "validate_mint_lengths", skip_on_field_errors = false))]
 pub struct UploadImageInfoRequest {
+    #[validate(
+        length(min = 1, message = "tokenURIs cannot be empty"),
+        custom(function = "validation::token_uris")
+    )]
     token_uris: Vec<String>,
+    #[validate(custom(function = "validation::address"))]
     to: String,
     quantity: U256,
+    #[validate(custom(function = "validation::watermarks"))]
     watermarks: Vec<String>,
+    #[validate(custom(function = "validation::timestamps"))]
     capture_times: Vec<U256>,
+    #[validate(custom(function = "validation::texts"))]
     capture_devices: Vec<String>,
+    #[validate(custom(function = "validation::texts"))]
     capture_companies: Vec<String>,
+    #[validate(custom(function = "validation::timestamps"))]
     submission_times: Vec<U256>,
+    #[validate(custom(function = "validation::texts"))]
     submission_receivers: Vec<String>,
 }
 
+// quantity 必须等于每个数组的长度，否则交易只会在链上 revert
+fn validate_mint_lengths(request: &UploadImageInfoRequest) -> Result<(), ValidationError> {
+    let lengths = [
+        ("token_uris", request.token_uris.len()),
+        ("watermarks", request.watermarks.len()),
+        ("capture_times", request.capture_times.len()),
+        ("capture_devices", request.capture_devices.len()),
+        ("capture_companies", request.capture_companies.len()),
+        ("submission_times", request.submission_times.len()),
+        ("submission_receivers", request.submission_receivers.len()),
+    ];
+    for (field, len) in lengths {
+        if U256::from(len) != request.quantity {
+            return Err(validation::field_error(
+                field,
+                "length_mismatch",
+                format!("{} has {} items but quantity is {}", field, len, request.quantity),
+            ));
+        }
+    }
+    Ok(())
+}
+
 #[derive(Serialize, Deserialize)]
 pub struct UploadImageInfoResponse {
     result: ContractMethodResult,
     token_id: Vec<U256>,
 }
 
-#[post("/upload_imageInfo", data = "<image_info>")]
+/// simulate=true 时只在 pending 状态上预执行 safeMint，不发送交易
+/// metadata=true 时在铸造之后为每个 token 生成元数据 JSON 上传到 IPFS，并把 tokenURI 更新为 ipfs://<cid>
+#[post("/upload_imageInfo?<simulate>&<metadata>", data = "<image_info>")]
 pub async fn upload_imageInfo(
     image_info: Json<UploadImageInfoRequest>,
+    simulate: Option<bool>,
+    metadata: Option<bool>,
     auth_user: AuthenticatedUser,
-) -> Result<Json<UploadImageInfoResponse>, Box<dyn ApiError>> {
-    let func_call = ContractMethod::safeMint {
-        to: image_info.to.parse().unwrap(),
-        quantity: image_info.clone().quantity,
-        _tokenURIs: image_info.token_uris.clone(),
-        watermarks: Some(image_info.watermarks.clone()),
-        captureTimes: Some(image_info.capture_times.clone()),
-        captureDevices: Some(image_info.capture_devices.clone()),
-        captureCompanies: Some(image_info.capture_companies.clone()),
-        submissionTimes: Some(image_info.submission_times.clone()),
-        submissionReceivers: Some(image_info.submission_receivers.clone()),
-    };
+    idempotency: Idempotency<'_>,
+) -> Result<
+    Idempotent<Either<Json<UploadImageInfoResponse>, Json<SimulationResult>>>,
+    Box<dyn ApiError>,
+> {
+    idempotency
+        .payload(&*image_info)
+        .run(async move {
+            image_info
+                .validate()
+                .map_err(|e| validation_error(&e))?;
+            let handler = match connect_handler().await {
+                Ok(handler) => handler.on_behalf_of(&auth_user.username),
+                Err(_initialize_error) => {
+                    return Err(_initialize_error.into());
+                }
+            };
 
-    // 构造链上链下连接
-    let http_url = "http://localhost:8545";
-    let pk = "0x3ba5c6a17da00c75e9377e03ae98aa3dcdca7c4e537c84399125dfefa89be521";
-    let user_address = "0x6d0d470a22c15a14817c51116932312a00ff00c8";
-    let contract_address = "0xa6a0110367e24c541FC29124E8E89E3556263177";
-    let handler: Handler =
-        match Handler::initialize_contract(http_url, pk, user_address, contract_address).await {
-            Ok(handler) => handler,
-            Err(_initialize_error) => {
-                return Err(Box::new(_initialize_error));
+            let to = match image_info.to.parse() {
+                Ok(to) => to,
+                Err(_) => {
+                    return Err(BlockchainError::ContractCallError(
+                        "parse to address failed".to_string(),
+                    )
+                    .into());
+                }
+            };
+            let image_info = image_info.into_inner();
+            if simulate.unwrap_or(false) {
+                let func_call = ContractMethod::safeMint {
+                    to,
+                    quantity: image_info.quantity,
+                    _tokenURIs: image_info.token_uris,
+                    watermarks: Some(image_info.watermarks),
+                    captureTimes: Some(image_info.capture_times),
+                    captureDevices: Some(image_info.capture_devices),
+                    captureCompanies: Some(image_info.capture_companies),
+                    submissionTimes: Some(image_info.submission_times),
+                    submissionReceivers: Some(image_info.submission_receivers),
+                };
+                // 真正的 safeMint 由服务端账户发送，按同一个发送方预执行
+                return match handler.simulate(&func_call, handler.user_address()).await {
+                    Ok(simulation) => Ok(Either::Right(Json(simulation))),
+                    Err(error) => Err(error.into()),
+                };
             }
-        };
 
-    let find_call = ContractMethod::totalSupply;
-    let start_id_option = match handler.match_func(find_call.clone()).await {
-        Ok(id) => extract_u256(id),
-        Err(e) => {
-            return Err(Box::new(e));
-        }
-    };
-    let start_id = match start_id_option {
-        Some(id) => id,
-        None => {
-            return Err(Box::new(BlockchainError::ContractCallError(
-                "convert error".to_string(),
-            )));
-        }
-    };
-    // 上传信息到链上
-    match handler.match_func(func_call).await {
-        Ok(result) => {
-            let end_id_option = match handler.match_func(find_call.clone()).await {
-                Ok(id) => extract_u256(id),
-                Err(e) => {
-                    return Err(Box::new(e));
-                }
+            // 元数据写在铸造之后，由 token 所有者的账户通过 modifyImageInfo 更新 tokenURI，
+            // 所有者必须是服务端账户或注册用户的托管账户，在铸造之前就检查
+            let metadata_handler = if !metadata.unwrap_or(false) {
+                None
+            } else if to == handler.user_address() {
+                Some(handler.clone())
+            } else {
+                Some(connect_address_handler(to, &auth_user.username).await?)
             };
-            let end_id = match end_id_option {
-                Some(id) => id,
-                None => {
-                    return Err(Box::new(BlockchainError::ContractCallError(
-                        "convert error".to_string(),
-                    )));
-                }
+
+            // 上传信息到链上
+            let tx_hash = match handler
+                .safe_mint_with_info(
+                    to,
+                    image_info.quantity,
+                    image_info.token_uris.clone(),
+                    image_info.watermarks.clone(),
+                    image_info.capture_times.clone(),
+                    image_info.capture_devices.clone(),
+                    image_info.capture_companies.clone(),
+                    image_info.submission_times.clone(),
+                    image_info.submission_receivers.clone(),
+                )
+                .await
+            {
+                Ok(tx_hash) => tx_hash,
+                Err(error) => return Err(error.into()),
             };
-            let token_ids: Vec<U256> = U256Range::new(start_id, end_id).collect();
-            Ok(Json(UploadImageInfoResponse {
-                result,
+            // token id 取自交易回执中的 Transfer 事件，不受销毁以及并发铸造的影响
+            let token_ids = handler.minted_token_ids(tx_hash).await?;
+            // 铸造的是调用方先前登记（未铸造）的图片时，按水印摘要把 token id 补写到图片和水印记录
+            let user = Users::select_by_username(get_db().await, &auth_user.username)
+                .await
+                .map_err(ImageError::DatabaseError)?;
+            let minted: Vec<(String, String)> = image_info
+                .watermarks
+                .iter()
+                .cloned()
+                .zip(token_ids.iter().map(|token_id| token_id.to_string()))
+                .collect();
+            backfill_token_ids(user.and_then(|user| user.id), &minted)
+                .await
+                .map_err(|e| {
+                    ImageError::MintError(format!(
+                        "tokens {:?} minted in {} but failed to record them: {}",
+                        token_ids, tx_hash, e
+                    ))
+                })?;
+            if let Some(metadata_handler) = metadata_handler {
+                upload_token_metadata(&metadata_handler, &token_ids, to, &image_info)
+                    .await
+                    .map_err(|e| {
+                        BlockchainError::ContractCallError(format!(
+                            "tokens {:?} minted in {} but failed to write metadata: {}",
+                            token_ids, tx_hash, e.message()
+                        ))
+                    })?;
+            }
+            Ok(Either::Left(Json(UploadImageInfoResponse {
+                result: ContractMethodResult::TxHash(tx_hash),
                 token_id: token_ids,
-            }))
-        }
-        Err(error) => Err(Box::new(error)),
+            })))
+        })
+        .await
+}
+
+// 按铸造得到的 token id 生成每个 token 的元数据并上传到 IPFS，记录到 metadata_uris 表，
+// 再以 ipfs://<cid> 更新 tokenURI
+async fn upload_token_metadata(
+    handler: &Handler,
+    token_ids: &[U256],
+    to: Address,
+    request: &UploadImageInfoRequest,
+) -> Result<(), Box<dyn ApiError>> {
+    for (i, (token_id, image)) in token_ids.iter().zip(&request.token_uris).enumerate() {
+        let info = ImageInfo {
+            _tokenURIs: image.clone(),
+            owner: to,
+            watermark: String::new(),
+            captureTime: request.capture_times.get(i).copied().unwrap_or_default(),
+            captureDevice: request.capture_devices.get(i).cloned().unwrap_or_default(),
+            captureCompany: request.capture_companies.get(i).cloned().unwrap_or_default(),
+            submissionTime: request.submission_times.get(i).copied().unwrap_or_default(),
+            submissionReceiver: request
+                .submission_receivers
+                .get(i)
+                .cloned()
+                .unwrap_or_default(),
+            saleHistory: vec![],
+        };
+        let metadata = build_metadata(*token_id, &info, image);
+        let json = serde_json::to_value(&metadata)
+            .map_err(|e| ImageError::IpfsError(e.to_string()))?;
+        let cid = upload_and_pin_json(&json, IPFS_API_URL)
+            .await
+            .map_err(|e| ImageError::IpfsError(e.to_string()))?;
+        let record = MetadataUris {
+            id: None,
+            token_id: Some(token_id.to_string()),
+            cid: Some(cid.clone()),
+            created_at: Some(DateTime::now()),
+        };
+        MetadataUris::insert(get_db().await, &record)
+            .await
+            .map_err(ImageError::DatabaseError)?;
+        handler
+            .modify_image_info(
+                *token_id,
+                format!("{}{}", IPFS_SCHEME, cid),
+                to,
+                request.watermarks.get(i).cloned().unwrap_or_default(),
+                info.captureTime,
+                info.captureDevice,
+                info.captureCompany,
+                info.submissionTime,
+                info.submissionReceiver,
+            )
+            .await?;
     }
+    Ok(())
 }
 
 #[derive(Serialize, Deserialize, Clone)]
@@ -120,64 +333,115 @@ pub async fn get_imageInfo(
 ) -> Result<Json<GetImageInfoResponse>, Box<dyn ApiError>> {
     let image_id = image_info.image_id;
 
-    let func_call = ContractMethod::_imageInfo {
-        tokenId: U256::try_from(image_id).unwrap(),
+    let handler = match connect_handler().await {
+        Ok(handler) => handler,
+        Err(_initialize_error) => {
+            return Err(Box::new(_initialize_error));
+        }
     };
 
-    // 构造链上链下连接
-    let http_url = "http://localhost:8545";
-    let pk = "0x3ba5c6a17da00c75e9377e03ae98aa3dcdca7c4e537c84399125dfefa89be521";
-    let user_address = "0x6d0d470a22c15a14817c51116932312a00ff00c8";
-    let contract_address = "0xa6a0110367e24c541FC29124E8E89E3556263177";
-    let handler: Handler =
-        match Handler::initialize_contract(http_url, pk, user_address, contract_address).await {
-            Ok(handler) => handler,
-            Err(_initialize_error) => {
-                return Err(Box::new(_initialize_error));
-            }
-        };
-
-    match handler.match_func(func_call).await {
+    match handler.image_info(U256::from(image_id)).await {
         Ok(imageInfo) => Ok(Json(GetImageInfoResponse {
-            result: imageInfo,
+            result: ContractMethodResult::ImageInfo(imageInfo),
             message: "succeed get image on blockchain".to_string(),
         })),
         Err(error) => Err(Box::new(error)),
     }
 }
 
-fn extract_u256(result: ContractMethodResult) -> Option<U256> {
-    match result {
-        ContractMethodResult::U256(value) => Some(value), // 提取 U256 值
-        _ => None,                                        // 其他情况返回 None
-    }
+#[derive(Serialize, Deserialize, Clone, Validate)]
+pub struct ReplaceTransactionRequest {
+    #[validate(range(min = 1, max = 1000, message = "fee bump must be between 1 and 1000 percent"))]
+    fee_bump_percent: Option<u64>,
 }
 
-// 自定义范围迭代器
-struct U256Range {
-    current: U256,
-    end: U256,
+#[derive(Serialize, Deserialize)]
+pub struct ReplaceTransactionResponse {
+    original_hash: String,
+    replacement_hash: String,
+    message: String,
 }
 
-impl U256Range {
-    fn new(start: U256, end: U256) -> Self {
-        Self {
-            current: start,
-            end,
-        }
-    }
+// 未指定时手续费提高 20%
+const DEFAULT_FEE_BUMP_PERCENT: u64 = 20;
+
+fn parse_tx_hash(tx_hash: &str) -> Result<TxHash, RequestError> {
+    TxHash::from_str(tx_hash)
+        .map_err(|_| RequestError::InvalidParameter(format!("tx_hash {}", tx_hash)))
+}
+
+/// 以更高的手续费重新发送一笔还未被打包的交易，只能操作当前用户自己发起的交易
+#[post("/tx/<tx_hash>/speed_up", data = "<request>")]
+pub async fn speed_up_tx(
+    tx_hash: &str,
+    request: Option<Json<ReplaceTransactionRequest>>,
+    auth_user: AuthenticatedUser,
+    idempotency: Idempotency<'_>,
+) -> Result<Idempotent<Json<ReplaceTransactionResponse>>, Box<dyn ApiError>> {
+    idempotency
+        .payload(&request.as_deref())
+        .run(async move {
+            if let Some(request) = &request {
+                request.validate().map_err(|e| validation_error(&e))?;
+            }
+            let original = parse_tx_hash(tx_hash)?;
+            let fee_bump_percent = request
+                .and_then(|request| request.fee_bump_percent)
+                .unwrap_or(DEFAULT_FEE_BUMP_PERCENT);
+            let handler = replacement_handler(original, &auth_user).await?;
+            match handler.speed_up(original, fee_bump_percent).await {
+                Ok(replacement) => Ok(Json(ReplaceTransactionResponse {
+                    original_hash: original.to_string(),
+                    replacement_hash: replacement.to_string(),
+                    message: "succeed speed up transaction".to_string(),
+                })),
+                Err(error) => Err(error.into()),
+            }
+        })
+        .await
 }
 
-impl Iterator for U256Range {
-    type Item = U256;
+/// 以同一 nonce 的 0 值自转账取消一笔还未被打包的交易，只能操作当前用户自己发起的交易
+#[post("/tx/<tx_hash>/cancel", data = "<request>")]
+pub async fn cancel_tx(
+    tx_hash: &str,
+    request: Option<Json<ReplaceTransactionRequest>>,
+    auth_user: AuthenticatedUser,
+    idempotency: Idempotency<'_>,
+) -> Result<Idempotent<Json<ReplaceTransactionResponse>>, Box<dyn ApiError>> {
+    idempotency
+        .payload(&request.as_deref())
+        .run(async move {
+            if let Some(request) = &request {
+                request.validate().map_err(|e| validation_error(&e))?;
+            }
+            let original = parse_tx_hash(tx_hash)?;
+            let fee_bump_percent = request
+                .and_then(|request| request.fee_bump_percent)
+                .unwrap_or(DEFAULT_FEE_BUMP_PERCENT);
+            let handler = replacement_handler(original, &auth_user).await?;
+            match handler.cancel(original, fee_bump_percent).await {
+                Ok(replacement) => Ok(Json(ReplaceTransactionResponse {
+                    original_hash: original.to_string(),
+                    replacement_hash: replacement.to_string(),
+                    message: "succeed cancel transaction".to_string(),
+                })),
+                Err(error) => Err(error.into()),
+            }
+        })
+        .await
+}
 
-    fn next(&mut self) -> Option<Self::Item> {
-        if self.current < self.end {
-            let next = self.current;
-            self.current += U256::from(1); // 递增
-            Some(next)
-        } else {
-            None
-        }
+/// 查询交易的替换链，以及最终被打包的交易哈希
+#[get("/tx/<tx_hash>")]
+pub async fn get_tx_status(
+    tx_hash: &str,
+    _auth_user: AuthenticatedUser,
+) -> Result<Json<JournalStatus>, Box<dyn ApiError>> {
+    let tx_hash = parse_tx_hash(tx_hash)?;
+    let handler = connect_handler().await?;
+    match handler.journal_status(tx_hash).await {
+        Ok(status) => Ok(Json(status)),
+        Err(error) => Err(Box::new(error)),
     }
 }
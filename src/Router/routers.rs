@@ -1,8 +1,14 @@
+use crate::DataBase::{get_db, Users};
+use crate::Error::{ErrorResponse, FieldError, RequestError, UserError};
 use crate::IdentityAuthentication::Jwt::validate_token;
 use crate::Router::User_routers::*;
 use rocket::http::Status;
 use rocket::request::{FromRequest, Outcome};
-use rocket::{fairing::AdHoc, Build, Request, Rocket, Route};
+use rocket::serde::json::Json;
+use rocket::{fairing::AdHoc, Build, Catcher, Request, Rocket, Route};
+use alloy::primitives::{Address, U256};
+use std::str::FromStr;
+use validator::ValidationErrors;
 
 // 受保护资源路由
 #[get("/protected")]
@@ -39,3 +45,189 @@ impl<'r> FromRequest<'r> for AuthenticatedUser {
         }
     }
 }
+
+impl AuthenticatedUser {
+    /// 查询当前登录用户的以太坊地址
+    pub async fn address(&self) -> Result<Address, UserError> {
+        let rb = get_db().await;
+        match Users::select_by_username(rb, &self.username).await {
+            Ok(Some(Users {
+                address: Some(address),
+                ..
+            })) => address.parse().map_err(|_| {
+                UserError::DatabaseError(rbatis::Error::E(format!(
+                    "invalid address of user {}",
+                    self.username
+                )))
+            }),
+            Ok(_) => Err(UserError::UserNotFound),
+            Err(e) => Err(UserError::DatabaseError(e)),
+        }
+    }
+}
+
+// 解析请求中的地址参数
+pub(crate) fn parse_address(field: &str, value: &str) -> Result<Address, RequestError> {
+    value
+        .parse()
+        .map_err(|_| RequestError::InvalidParameter(format!("{} is not a valid address", field)))
+}
+
+// 解析请求中的 token id，支持十进制以及 0x 开头的十六进制，范围为完整的 U256
+pub(crate) fn parse_token_id(field: &str, value: &str) -> Result<U256, RequestError> {
+    U256::from_str(value.trim()).map_err(|_| {
+        RequestError::InvalidParameter(format!("{} is not a valid uint256", field))
+    })
+}
+
+// 将 validator 的校验错误转换为 RequestError
+pub(crate) fn validation_error(errors: &ValidationErrors) -> RequestError {
+    let mut fields: Vec<FieldError> = errors
+        .field_errors()
+        .into_iter()
+        .flat_map(|(field, field_errors)| {
+            field_errors.iter().map(move |e| FieldError {
+                // schema 校验的错误统一记在 __all__ 下，由 field 参数指明具体字段
+                field: match e.params.get("field").and_then(|param| param.as_str()) {
+                    Some(param) if field == "__all__" => param.to_string(),
+                    _ => field.to_string(),
+                },
+                message: e
+                    .message
+                    .as_ref()
+                    .map(|message| message.to_string())
+                    .unwrap_or_else(|| e.code.to_string()),
+            })
+        })
+        .collect();
+    fields.sort_by(|a, b| a.field.cmp(&b.field));
+    RequestError::ValidationFailed(fields)
+}
+
+// 与 ApiError 相同格式的错误响应，覆盖路由守卫失败、路由不存在、请求体过大或无法解析以及未处理的服务端错误
+#[catch(401)]
+pub fn unauthorized(request: &Request<'_>) -> Json<ErrorResponse> {
+    Json(ErrorResponse::new(
+        "Unauthorized",
+        "Missing or invalid authorization token".to_string(),
+        Status::Unauthorized,
+        request,
+    ))
+}
+
+#[catch(404)]
+pub fn not_found(request: &Request<'_>) -> Json<ErrorResponse> {
+    Json(ErrorResponse::new(
+        "NotFound",
+        format!("No route for {} {}", request.method(), request.uri()),
+        Status::NotFound,
+        request,
+    ))
+}
+
+#[catch(413)]
+pub fn payload_too_large(request: &Request<'_>) -> Json<ErrorResponse> {
+    Json(ErrorResponse::new(
+        "PayloadTooLarge",
+        "Request body exceeds the size limit".to_string(),
+        Status::PayloadTooLarge,
+        request,
+    ))
+}
+
+#[catch(422)]
+pub fn unprocessable_entity(request: &Request<'_>) -> Json<ErrorResponse> {
+    Json(ErrorResponse::new(
+        "UnprocessableEntity",
+        "Request body could not be parsed".to_string(),
+        Status::UnprocessableEntity,
+        request,
+    ))
+}
+
+#[catch(500)]
+pub fn internal_error(request: &Request<'_>) -> Json<ErrorResponse> {
+    Json(ErrorResponse::new(
+        "InternalServerError",
+        "Internal server error".to_string(),
+        Status::InternalServerError,
+        request,
+    ))
+}
+
+pub fn error_catchers() -> Vec<Catcher> {
+    catchers![
+        unauthorized,
+        not_found,
+        payload_too_large,
+        unprocessable_entity,
+        internal_error
+    ]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_token_id() {
+        assert_eq!(parse_token_id("token_id", "42").unwrap(), U256::from(42));
+        assert_eq!(parse_token_id("token_id", "0x2a").unwrap(), U256::from(42));
+        assert_eq!(
+            parse_token_id(
+                "token_id",
+                "115792089237316195423570985008687907853269984665640564039457584007913129639935"
+            )
+            .unwrap(),
+            U256::MAX
+        );
+        assert!(parse_token_id("token_id", "-1").is_err());
+        assert!(parse_token_id("token_id", "abc").is_err());
+        assert!(parse_token_id(
+            "token_id",
+            "115792089237316195423570985008687907853269984665640564039457584007913129639936"
+        )
+        .is_err());
+    }
+
+    #[get("/fail")]
+    fn fail() -> Result<&'static str, Box<dyn crate::Error::ApiError>> {
+        Err(Box::new(RequestError::ValidationFailed(vec![FieldError {
+            field: "token_ids".to_string(),
+            message: "token_ids cannot be empty".to_string(),
+        }])))
+    }
+
+    #[rocket::async_test]
+    async fn test_error_response_shape() {
+        use rocket::local::asynchronous::Client;
+
+        let rocket = rocket::build()
+            .attach(crate::Error::RequestIdFairing)
+            .register("/", error_catchers())
+            .mount("/", routes![fail]);
+        let client = Client::tracked(rocket).await.unwrap();
+
+        let response = client
+            .get("/fail")
+            .header(rocket::http::Header::new("X-Request-Id", "req-1"))
+            .dispatch()
+            .await;
+        assert_eq!(response.status(), Status::UnprocessableEntity);
+        assert_eq!(response.headers().get_one("X-Request-Id"), Some("req-1"));
+        let body: ErrorResponse = response.into_json().await.unwrap();
+        assert_eq!(body.error_code, "ValidationFailed");
+        assert_eq!(body.status, 422);
+        assert_eq!(body.request_id, "req-1");
+        assert_eq!(body.details.unwrap()[0].field, "token_ids");
+
+        // catcher 返回相同格式，请求 ID 与响应头一致
+        let response = client.get("/missing").dispatch().await;
+        assert_eq!(response.status(), Status::NotFound);
+        let request_id = response.headers().get_one("X-Request-Id").unwrap().to_string();
+        let body: ErrorResponse = response.into_json().await.unwrap();
+        assert_eq!(body.error_code, "NotFound");
+        assert_eq!(body.request_id, request_id);
+        assert!(body.details.is_none());
+    }
+}
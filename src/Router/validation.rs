@@ -0,0 +1,184 @@
+use alloy::primitives::{Address, U256};
+use std::borrow::Cow;
+use std::time::{SystemTime, UNIX_EPOCH};
+use validator::ValidationError;
+
+// 请求 DTO 通过 #[validate(custom(function = ...))] 使用的校验函数，
+// 错误由 validation_error 转换为带字段信息的 RequestError::ValidationFailed
+
+pub const MAX_URI_LEN: usize = 2048;
+pub const MAX_WATERMARK_LEN: usize = 256;
+pub const MAX_TEXT_LEN: usize = 128;
+
+// 时间戳（秒）不早于 2000-01-01，也不晚于当前时间一天以上（允许客户端时钟误差）
+const MIN_TIMESTAMP: u64 = 946_684_800;
+const MAX_CLOCK_SKEW_SECS: u64 = 24 * 60 * 60;
+
+fn invalid(code: &'static str, message: String) -> ValidationError {
+    ValidationError::new(code).with_message(Cow::from(message))
+}
+
+/// 地址必须是 0x 开头的 20 字节十六进制；大小写混合时按 EIP-55 校验和检查
+pub fn address(value: &str) -> Result<(), ValidationError> {
+    let hex = value.strip_prefix("0x").unwrap_or_default();
+    let mixed_case =
+        hex.chars().any(|c| c.is_ascii_lowercase()) && hex.chars().any(|c| c.is_ascii_uppercase());
+    let parsed = if mixed_case {
+        Address::parse_checksummed(value, None).is_ok()
+    } else {
+        hex.len() == 40 && value.parse::<Address>().is_ok()
+    };
+    if !parsed {
+        return Err(invalid(
+            "address",
+            format!("{} is not a valid checksummed address", value),
+        ));
+    }
+    Ok(())
+}
+
+/// 65 字节的 ECDSA 签名，0x 开头的十六进制
+pub fn signature(value: &str) -> Result<(), ValidationError> {
+    let hex = value.strip_prefix("0x").unwrap_or(value);
+    if hex.len() != 130 || !hex.chars().all(|c| c.is_ascii_hexdigit()) {
+        return Err(invalid(
+            "signature",
+            "signature must be 65 bytes of hex".to_string(),
+        ));
+    }
+    Ok(())
+}
+
+pub fn token_uri(value: &str) -> Result<(), ValidationError> {
+    if value.trim().is_empty() {
+        return Err(invalid(
+            "token_uri",
+            "token URI cannot be empty".to_string(),
+        ));
+    }
+    if value.len() > MAX_URI_LEN {
+        return Err(invalid(
+            "token_uri",
+            format!("token URI exceeds {} bytes", MAX_URI_LEN),
+        ));
+    }
+    Ok(())
+}
+
+pub fn token_uris(values: &[String]) -> Result<(), ValidationError> {
+    values.iter().try_for_each(|value| token_uri(value))
+}
+
+pub fn watermark(value: &str) -> Result<(), ValidationError> {
+    if value.len() > MAX_WATERMARK_LEN {
+        return Err(invalid(
+            "watermark",
+            format!("watermark exceeds {} bytes", MAX_WATERMARK_LEN),
+        ));
+    }
+    Ok(())
+}
+
+pub fn watermarks(values: &[String]) -> Result<(), ValidationError> {
+    values.iter().try_for_each(|value| watermark(value))
+}
+
+/// 拍摄设备、公司、接收方等短文本
+pub fn text(value: &str) -> Result<(), ValidationError> {
+    if value.len() > MAX_TEXT_LEN {
+        return Err(invalid(
+            "text",
+            format!("text exceeds {} bytes", MAX_TEXT_LEN),
+        ));
+    }
+    Ok(())
+}
+
+pub fn texts(values: &[String]) -> Result<(), ValidationError> {
+    values.iter().try_for_each(|value| text(value))
+}
+
+pub fn unix_time(value: u64) -> Result<(), ValidationError> {
+    let latest = now_secs() + MAX_CLOCK_SKEW_SECS;
+    if !(MIN_TIMESTAMP..=latest).contains(&value) {
+        return Err(invalid(
+            "timestamp",
+            format!(
+                "{} is not a unix timestamp between 2000-01-01 and now",
+                value
+            ),
+        ));
+    }
+    Ok(())
+}
+
+pub fn timestamp(value: &U256) -> Result<(), ValidationError> {
+    match u64::try_from(*value) {
+        Ok(value) => unix_time(value),
+        Err(_) => Err(invalid(
+            "timestamp",
+            format!("{} is not a unix timestamp", value),
+        )),
+    }
+}
+
+pub fn timestamps(values: &[U256]) -> Result<(), ValidationError> {
+    values.iter().try_for_each(timestamp)
+}
+
+// 字段级别以外的（schema）校验错误，field 参数指明出错的字段
+pub fn field_error(field: &'static str, code: &'static str, message: String) -> ValidationError {
+    let mut error = invalid(code, message);
+    error.add_param(Cow::from("field"), &field);
+    error
+}
+
+fn now_secs() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_custom_validators() {
+        let checksummed = "0xa6a0110367e24c541FC29124E8E89E3556263177"
+            .parse::<Address>()
+            .unwrap()
+            .to_checksum(None);
+        assert!(address(&checksummed).is_ok());
+        assert!(address(&checksummed.to_lowercase()).is_ok());
+        // 校验和错误、长度错误、缺少 0x
+        let wrong_case: String = checksummed
+            .chars()
+            .enumerate()
+            .map(|(i, c)| {
+                if i > 1 && c.is_ascii_alphabetic() {
+                    c.to_ascii_uppercase()
+                } else {
+                    c
+                }
+            })
+            .collect::<String>()
+            .replacen('A', "a", 1);
+        assert!(address(&wrong_case).is_err());
+        assert!(address("0x6d0d470a22c15a14817c5111693231").is_err());
+        assert!(address("6d0d470a22c15a14817c51116932312a00ff00c8").is_err());
+
+        assert!(signature(&format!("0x{}", "ab".repeat(65))).is_ok());
+        assert!(signature("0x1234").is_err());
+
+        assert!(token_uris(&["ipfs://Qm".to_string()]).is_ok());
+        assert!(token_uris(&["ipfs://Qm".to_string(), " ".to_string()]).is_err());
+        assert!(texts(&["x".repeat(MAX_TEXT_LEN + 1)]).is_err());
+
+        assert!(timestamp(&U256::from(1_700_000_000u64)).is_ok());
+        assert!(timestamp(&U256::ZERO).is_err());
+        assert!(timestamp(&U256::from(now_secs() + 2 * MAX_CLOCK_SKEW_SECS)).is_err());
+        assert!(timestamp(&U256::MAX).is_err());
+    }
+}
@@ -1,12 +1,16 @@
+use crate::Error::BlockchainError;
+use crate::Transaction::sendTx::Handler;
 use crate::Transaction::sendTx::IERC721A::{ImageInfo, SaleInfo};
 use alloy::primitives::aliases::TxHash;
 use alloy::primitives::{Address, Bytes, FixedBytes, U256};
 use serde::{Deserialize, Serialize};
 
 ///当前文件内容旨在静态指定合约中所有可通过外部调用的函数以及这些函数可能的返回值类型
+///ContractMethod 可序列化，用作队列、日志中的调用命令，通过 execute 交给 Handler 的类型化方法执行
 
 // include all functions can be called
-#[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+#[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+#[serde(tag = "method")]
 pub enum ContractMethod {
     /// view
     name,
@@ -134,3 +138,235 @@ pub enum ContractMethodResult {
     TxHash(TxHash),
     Error,
 }
+
+impl ContractMethod {
+    /// 合约中的函数名，用于日志与错误信息
+    pub fn method_name(&self) -> &'static str {
+        match self {
+            ContractMethod::name => "name",
+            ContractMethod::symbol => "symbol",
+            ContractMethod::totalSupply => "totalSupply",
+            ContractMethod::balanceOf { .. } => "balanceOf",
+            ContractMethod::ownerOf { .. } => "ownerOf",
+            ContractMethod::isApprovedForAll { .. } => "isApprovedForAll",
+            ContractMethod::supportsInterface { .. } => "supportsInterface",
+            ContractMethod::tokenURI { .. } => "tokenURI",
+            ContractMethod::_imageInfo { .. } => "_imageInfo",
+            ContractMethod::_imageSaleHistory { .. } => "_imageSaleHistory",
+            ContractMethod::safeTransferFrom { .. } => "safeTransferFrom",
+            ContractMethod::safeTransferFromWithValue { .. } => "safeTransferFromWithValue",
+            ContractMethod::transferFrom { .. } => "transferFrom",
+            ContractMethod::approve { .. } => "approve",
+            ContractMethod::getApproved { .. } => "getApproved",
+            ContractMethod::setApprovalForAll { .. } => "setApprovalForAll",
+            ContractMethod::modifyImageInfo { .. } => "modifyImageInfo",
+            ContractMethod::modifyCaptureInfo { .. } => "modifyCaptureInfo",
+            ContractMethod::safeMint { .. } => "safeMint",
+            ContractMethod::safeBatchTransferFrom { .. } => "safeBatchTransferFrom",
+            ContractMethod::batchTransferFrom { .. } => "batchTransferFrom",
+            ContractMethod::burn { .. } => "burn",
+            ContractMethod::batchBurn { .. } => "batchBurn",
+        }
+    }
+
+    /// 是否为只读（view）函数，只读函数不需要发送交易
+    pub fn is_view(&self) -> bool {
+        matches!(
+            self,
+            ContractMethod::name
+                | ContractMethod::symbol
+                | ContractMethod::totalSupply
+                | ContractMethod::balanceOf { .. }
+                | ContractMethod::ownerOf { .. }
+                | ContractMethod::isApprovedForAll { .. }
+                | ContractMethod::supportsInterface { .. }
+                | ContractMethod::tokenURI { .. }
+                | ContractMethod::_imageInfo { .. }
+                | ContractMethod::_imageSaleHistory { .. }
+                | ContractMethod::getApproved { .. }
+        )
+    }
+
+    /// 通过 Handler 的类型化方法执行当前命令，并将结果包装为 ContractMethodResult
+    pub async fn execute(self, handler: &Handler) -> Result<ContractMethodResult, BlockchainError> {
+        let result = match self {
+            ContractMethod::name => ContractMethodResult::String(handler.name().await?),
+            ContractMethod::symbol => ContractMethodResult::String(handler.symbol().await?),
+            ContractMethod::totalSupply => ContractMethodResult::U256(handler.total_supply().await?),
+            ContractMethod::balanceOf { owner } => {
+                ContractMethodResult::U256(handler.balance_of(owner).await?)
+            }
+            ContractMethod::ownerOf { tokenId } => {
+                ContractMethodResult::Address(handler.owner_of(tokenId).await?)
+            }
+            ContractMethod::isApprovedForAll { owner, operator } => {
+                ContractMethodResult::Bool(handler.is_approved_for_all(owner, operator).await?)
+            }
+            ContractMethod::supportsInterface { interfaceId } => {
+                ContractMethodResult::Bool(handler.supports_interface(interfaceId).await?)
+            }
+            ContractMethod::tokenURI { tokenId } => {
+                ContractMethodResult::String(handler.token_uri(tokenId).await?)
+            }
+            ContractMethod::_imageInfo { tokenId } => {
+                ContractMethodResult::ImageInfo(handler.image_info(tokenId).await?)
+            }
+            ContractMethod::_imageSaleHistory { tokenId, index } => {
+                ContractMethodResult::SaleInfo(handler.image_sale_history(tokenId, index).await?)
+            }
+            ContractMethod::safeTransferFrom {
+                from,
+                to,
+                tokenId,
+                amount,
+            } => match amount {
+                None => handler.safe_transfer_from(from, to, tokenId).await?,
+                Some(data) => {
+                    handler
+                        .safe_transfer_from_with_data(from, to, tokenId, data)
+                        .await?
+                }
+            }
+            .into(),
+            ContractMethod::safeTransferFromWithValue {
+                from,
+                to,
+                tokenId,
+                value,
+            } => handler
+                .safe_transfer_from_with_value(from, to, tokenId, value)
+                .await?
+                .into(),
+            ContractMethod::transferFrom { from, to, tokenId } => {
+                handler.transfer_from(from, to, tokenId).await?.into()
+            }
+            ContractMethod::approve { to, tokenId } => handler.approve(to, tokenId).await?.into(),
+            ContractMethod::getApproved { tokenId } => {
+                ContractMethodResult::Address(handler.get_approved(tokenId).await?)
+            }
+            ContractMethod::setApprovalForAll { operator, approved } => handler
+                .set_approval_for_all(operator, approved)
+                .await?
+                .into(),
+            ContractMethod::modifyImageInfo {
+                tokenId,
+                _tokenURIs,
+                owner,
+                watermark,
+                captureTime,
+                captureDevice,
+                captureCompany,
+                submissionTime,
+                submissionReceiver,
+            } => handler
+                .modify_image_info(
+                    tokenId,
+                    _tokenURIs,
+                    owner,
+                    watermark,
+                    captureTime,
+                    captureDevice,
+                    captureCompany,
+                    submissionTime,
+                    submissionReceiver,
+                )
+                .await?
+                .into(),
+            ContractMethod::modifyCaptureInfo {
+                tokenId,
+                captureTime,
+                captureDevice,
+                captureCompany,
+            } => handler
+                .modify_capture_info(tokenId, captureTime, captureDevice, captureCompany)
+                .await?
+                .into(),
+            ContractMethod::safeMint {
+                to,
+                quantity,
+                _tokenURIs,
+                watermarks,
+                captureTimes,
+                captureDevices,
+                captureCompanies,
+                submissionTimes,
+                submissionReceivers,
+            } => {
+                if watermarks.is_none()
+                    && captureTimes.is_none()
+                    && captureDevices.is_none()
+                    && captureCompanies.is_none()
+                    && submissionTimes.is_none()
+                    && submissionReceivers.is_none()
+                {
+                    handler.safe_mint(to, quantity, _tokenURIs).await?.into()
+                } else {
+                    handler
+                        .safe_mint_with_info(
+                            to,
+                            quantity,
+                            _tokenURIs,
+                            watermarks.unwrap_or_default(),
+                            captureTimes.unwrap_or_default(),
+                            captureDevices.unwrap_or_default(),
+                            captureCompanies.unwrap_or_default(),
+                            submissionTimes.unwrap_or_default(),
+                            submissionReceivers.unwrap_or_default(),
+                        )
+                        .await?
+                        .into()
+                }
+            }
+            ContractMethod::safeBatchTransferFrom {
+                by,
+                from,
+                to,
+                tokenIds,
+                data,
+            } => handler
+                .safe_batch_transfer_from(by, from, to, tokenIds, data)
+                .await?
+                .into(),
+            ContractMethod::batchTransferFrom { from, to, tokenIds } => handler
+                .batch_transfer_from(from, to, tokenIds)
+                .await?
+                .into(),
+            ContractMethod::burn { tokenId } => handler.burn(tokenId).await?.into(),
+            ContractMethod::batchBurn { tokenIds } => handler.batch_burn(tokenIds).await?.into(),
+        };
+        Ok(result)
+    }
+}
+
+impl From<TxHash> for ContractMethodResult {
+    fn from(hash: TxHash) -> Self {
+        ContractMethodResult::TxHash(hash)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_contract_method_serde_roundtrip() {
+        let func_call = ContractMethod::safeMint {
+            to: Address::repeat_byte(0x11),
+            quantity: U256::from(1),
+            _tokenURIs: vec!["QmcbD1QEKKWkQQumdrhVUBHgdkPyU5GYzwMk5PkoRzQiP7".to_string()],
+            watermarks: Some(vec!["Gzhu".to_string()]),
+            captureTimes: Some(vec![U256::from(10)]),
+            captureDevices: None,
+            captureCompanies: None,
+            submissionTimes: None,
+            submissionReceivers: None,
+        };
+        let json = serde_json::to_string(&func_call).unwrap();
+        assert!(json.contains("\"method\":\"safeMint\""));
+        let decoded: ContractMethod = serde_json::from_str(&json).unwrap();
+        assert_eq!(decoded, func_call);
+
+        let decoded: ContractMethod = serde_json::from_str(r#"{"method":"totalSupply"}"#).unwrap();
+        assert_eq!(decoded, ContractMethod::totalSupply);
+    }
+}
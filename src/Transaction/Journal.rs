@@ -0,0 +1,366 @@
+use crate::DataBase::{try_get_db, TxJournal};
+use crate::Error::BlockchainError;
+use crate::Transaction::sendTx::Handler;
+use alloy::consensus::Transaction as TransactionTrait;
+use alloy::network::TransactionBuilder;
+use alloy::primitives::aliases::TxHash;
+use alloy::primitives::{Address, U256};
+use alloy::providers::Provider;
+use alloy::rpc::types::TransactionRequest;
+use rbatis::rbdc::datetime::DateTime;
+use serde::{Deserialize, Serialize};
+use std::str::FromStr;
+use std::time::{Duration, Instant};
+
+// 交易日志：记录每一笔发送出去的交易，以及对它的加速（相同 calldata、更高手续费）和取消（同 nonce 的 0 值自转账），
+// 等待交易上链时会检查整条替换链，客户端可以据此知道最终被打包的是哪一个哈希
+
+pub const KIND_ORIGINAL: &str = "original";
+pub const KIND_SPEED_UP: &str = "speed_up";
+pub const KIND_CANCEL: &str = "cancel";
+
+pub const STATUS_PENDING: &str = "pending";
+pub const STATUS_MINED: &str = "mined";
+pub const STATUS_REPLACED: &str = "replaced";
+pub const STATUS_CANCELLED: &str = "cancelled";
+
+// 节点一般要求替换交易的手续费至少提高 10%
+const MIN_FEE_BUMP_PERCENT: u64 = 10;
+const POLL_INTERVAL: Duration = Duration::from_secs(1);
+const WAIT_TIMEOUT: Duration = Duration::from_secs(600);
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct JournalStatus {
+    pub sender: String,
+    pub nonce: i64,
+    pub mined_hash: Option<String>,
+    pub entries: Vec<TxJournal>,
+}
+
+impl Handler {
+    /// 使用相同的 calldata、更高的手续费重新发送一笔还在内存池中的交易，返回新交易哈希
+    pub async fn speed_up(
+        &self,
+        tx_hash: TxHash,
+        fee_bump_percent: u64,
+    ) -> Result<TxHash, BlockchainError> {
+        self.replace(tx_hash, fee_bump_percent, KIND_SPEED_UP).await
+    }
+
+    /// 以相同 nonce 发送一笔 0 值的自转账来取消还在内存池中的交易，返回取消交易的哈希
+    pub async fn cancel(
+        &self,
+        tx_hash: TxHash,
+        fee_bump_percent: u64,
+    ) -> Result<TxHash, BlockchainError> {
+        self.replace(tx_hash, fee_bump_percent, KIND_CANCEL).await
+    }
+
+    /// 查询交易的发送者，加速、取消交易时需要用同一个账户签名替换交易
+    pub async fn transaction_sender(&self, tx_hash: TxHash) -> Result<Address, BlockchainError> {
+        self.contract()?
+            .provider()
+            .get_transaction_by_hash(tx_hash)
+            .await
+            .map_err(|e| BlockchainError::ContractCallError(e.to_string()))?
+            .map(|tx| tx.from)
+            .ok_or_else(|| BlockchainError::TransactionNotFound(tx_hash.to_string()))
+    }
+
+    /// 查询交易所在的替换链，并刷新其中已被打包的交易状态
+    pub async fn journal_status(&self, tx_hash: TxHash) -> Result<JournalStatus, BlockchainError> {
+        let mut chain = load_chain(tx_hash).await?;
+        if chain.is_empty() {
+            return Err(BlockchainError::TransactionNotFound(tx_hash.to_string()));
+        }
+        let mined_hash = match self.find_mined(&chain).await? {
+            Some(mined) => {
+                mark_mined(&mut chain, mined).await;
+                Some(mined.to_string())
+            }
+            None => None,
+        };
+        Ok(JournalStatus {
+            sender: chain[0].sender.clone().unwrap_or_default(),
+            nonce: chain[0].nonce.unwrap_or_default(),
+            mined_hash,
+            entries: chain,
+        })
+    }
+
+    /// 将刚发送的交易写入交易日志，数据库不可用或写入失败时只打印日志，不影响交易本身
+    pub(crate) async fn record_broadcast(
+        &self,
+        method: &str,
+        kind: &str,
+        replaces: Option<TxHash>,
+        tx_hash: TxHash,
+    ) {
+        let provider = match self.contract() {
+            Ok(contract) => contract.provider(),
+            Err(_) => return,
+        };
+        let tx = match provider.get_transaction_by_hash(tx_hash).await {
+            Ok(Some(tx)) => tx,
+            _ => {
+                println!("Failed to load transaction {} for journal", tx_hash);
+                return;
+            }
+        };
+        let row = TxJournal {
+            id: None,
+            sender: Some(tx.from.to_string()),
+            nonce: Some(tx.nonce() as i64),
+            method: Some(method.to_string()),
+            tx_hash: Some(tx_hash.to_string()),
+            kind: Some(kind.to_string()),
+            replaces: replaces.map(|hash| hash.to_string()),
+            status: Some(STATUS_PENDING.to_string()),
+            max_fee_per_gas: Some(
+                tx.gas_price()
+                    .unwrap_or_else(|| tx.max_fee_per_gas())
+                    .to_string(),
+            ),
+            max_priority_fee_per_gas: tx.max_priority_fee_per_gas().map(|fee| fee.to_string()),
+            created_at: Some(DateTime::now()),
+            requested_by: self.requested_by().map(str::to_string),
+        };
+        let result = match try_get_db().await {
+            Ok(rb) => TxJournal::insert(rb, &row).await.map(|_| ()),
+            Err(e) => Err(e),
+        };
+        if let Err(e) = result {
+            println!("Failed to write transaction journal: {}", e);
+        }
+    }
+
+    /// 等待交易（或替换它的加速、取消交易）被打包，返回最终被打包的交易哈希
+    pub(crate) async fn wait_for_mined(
+        &self,
+        method: &str,
+        tx_hash: TxHash,
+    ) -> Result<TxHash, BlockchainError> {
+        let deadline = Instant::now() + WAIT_TIMEOUT;
+        loop {
+            // 日志不可用时只跟踪原始交易
+            let mut chain = load_chain(tx_hash).await.unwrap_or_default();
+            let mined = if chain.is_empty() {
+                self.find_mined_hashes(&[tx_hash]).await?
+            } else {
+                self.find_mined(&chain).await?
+            };
+            if let Some(mined) = mined {
+                mark_mined(&mut chain, mined).await;
+                let cancelled = chain.iter().any(|row| {
+                    row.tx_hash.as_deref() == Some(mined.to_string().as_str())
+                        && row.kind.as_deref() == Some(KIND_CANCEL)
+                });
+                if cancelled {
+                    return Err(BlockchainError::SendTransactionError(format!(
+                        "{} transaction {} was cancelled by {}",
+                        method, tx_hash, mined
+                    )));
+                }
+                return Ok(mined);
+            }
+            if Instant::now() >= deadline {
+                return Err(BlockchainError::WatchTransactionError(format!(
+                    "Timed out waiting for {} transaction {}",
+                    method, tx_hash
+                )));
+            }
+            tokio::time::sleep(POLL_INTERVAL).await;
+        }
+    }
+
+    async fn replace(
+        &self,
+        tx_hash: TxHash,
+        fee_bump_percent: u64,
+        kind: &str,
+    ) -> Result<TxHash, BlockchainError> {
+        // 只能加速、取消自己发起的交易，日志中没有记录的交易无法确认归属，一律拒绝
+        let original = load_entry(tx_hash)
+            .await?
+            .ok_or_else(|| BlockchainError::TransactionNotFound(tx_hash.to_string()))?;
+        if original.requested_by.is_none()
+            || original.requested_by.as_deref() != self.requested_by()
+        {
+            return Err(BlockchainError::NotTransactionOwner(tx_hash.to_string()));
+        }
+
+        let provider = self.contract()?.provider();
+        let tx = provider
+            .get_transaction_by_hash(tx_hash)
+            .await
+            .map_err(|e| BlockchainError::ContractCallError(e.to_string()))?
+            .ok_or_else(|| BlockchainError::TransactionNotFound(tx_hash.to_string()))?;
+        if tx.block_number.is_some() {
+            return Err(BlockchainError::SendTransactionError(format!(
+                "transaction {} is already mined",
+                tx_hash
+            )));
+        }
+        if tx.from != self.user_address() {
+            return Err(BlockchainError::SendTransactionError(format!(
+                "transaction {} was not sent by {}",
+                tx_hash,
+                self.user_address()
+            )));
+        }
+
+        let mut request = TransactionRequest::default()
+            .with_from(tx.from)
+            .with_nonce(tx.nonce());
+        request = if kind == KIND_CANCEL {
+            request
+                .with_to(tx.from)
+                .with_value(U256::ZERO)
+                .with_gas_limit(21_000)
+        } else {
+            let request = request
+                .with_value(tx.value())
+                .with_input(tx.input().clone())
+                .with_gas_limit(tx.gas_limit());
+            match tx.to() {
+                Some(to) => request.with_to(to),
+                None => request,
+            }
+        };
+
+        if tx.is_dynamic_fee() {
+            let priority_fee = bump_fee(
+                tx.max_priority_fee_per_gas().unwrap_or_default(),
+                fee_bump_percent,
+            );
+            let mut max_fee = bump_fee(tx.max_fee_per_gas(), fee_bump_percent);
+            if let Ok(estimate) = provider.estimate_eip1559_fees(None).await {
+                max_fee = max_fee.max(estimate.max_fee_per_gas);
+            }
+            request = request
+                .with_max_fee_per_gas(max_fee.max(priority_fee))
+                .with_max_priority_fee_per_gas(priority_fee);
+        } else {
+            let mut gas_price = bump_fee(tx.gas_price().unwrap_or_default(), fee_bump_percent);
+            if let Ok(current) = provider.get_gas_price().await {
+                gas_price = gas_price.max(current);
+            }
+            request = request.with_gas_price(gas_price);
+        }
+
+        let new_hash = self.sign_and_send(kind, request).await?;
+
+        let method = original.method.unwrap_or_default();
+        self.record_broadcast(&method, kind, Some(tx_hash), new_hash)
+            .await;
+        Ok(new_hash)
+    }
+
+    async fn find_mined(&self, chain: &[TxJournal]) -> Result<Option<TxHash>, BlockchainError> {
+        let hashes: Vec<TxHash> = chain
+            .iter()
+            .filter_map(|row| row.tx_hash.as_deref())
+            .filter_map(|hash| TxHash::from_str(hash).ok())
+            .collect();
+        self.find_mined_hashes(&hashes).await
+    }
+
+    async fn find_mined_hashes(&self, hashes: &[TxHash]) -> Result<Option<TxHash>, BlockchainError> {
+        let provider = self.contract()?.provider();
+        for hash in hashes {
+            let receipt = provider.get_transaction_receipt(*hash).await.map_err(|e| {
+                BlockchainError::WatchTransactionError(format!(
+                    "Failed to get receipt of {}: {}",
+                    hash, e
+                ))
+            })?;
+            if receipt.is_some() {
+                return Ok(Some(*hash));
+            }
+        }
+        Ok(None)
+    }
+}
+
+// 按交易哈希查询交易日志中的一行
+async fn load_entry(tx_hash: TxHash) -> Result<Option<TxJournal>, BlockchainError> {
+    let rb = try_get_db()
+        .await
+        .map_err(|e| BlockchainError::JournalError(e.to_string()))?;
+    TxJournal::select_by_tx_hash(rb, &tx_hash.to_string())
+        .await
+        .map_err(|e| BlockchainError::JournalError(e.to_string()))
+}
+
+// 按交易哈希找到同一 sender + nonce 的整条替换链
+async fn load_chain(tx_hash: TxHash) -> Result<Vec<TxJournal>, BlockchainError> {
+    let rb = try_get_db()
+        .await
+        .map_err(|e| BlockchainError::JournalError(e.to_string()))?;
+    match load_entry(tx_hash).await? {
+        Some(TxJournal {
+            sender: Some(sender),
+            nonce: Some(nonce),
+            ..
+        }) => TxJournal::select_by_nonce(rb, &sender, nonce)
+            .await
+            .map_err(|e| BlockchainError::JournalError(e.to_string())),
+        _ => Ok(vec![]),
+    }
+}
+
+// 更新替换链中每笔交易的状态，链为空时（日志不可用）不访问数据库
+async fn mark_mined(chain: &mut [TxJournal], mined: TxHash) {
+    if chain.is_empty() {
+        return;
+    }
+    let rb = match try_get_db().await {
+        Ok(rb) => rb,
+        Err(e) => {
+            println!("Failed to update transaction journal: {}", e);
+            return;
+        }
+    };
+    let mined = mined.to_string();
+    let mined_is_cancel = chain.iter().any(|row| {
+        row.tx_hash.as_deref() == Some(mined.as_str()) && row.kind.as_deref() == Some(KIND_CANCEL)
+    });
+    for row in chain.iter_mut() {
+        let status = if row.tx_hash.as_deref() == Some(mined.as_str()) {
+            STATUS_MINED
+        } else if mined_is_cancel {
+            STATUS_CANCELLED
+        } else {
+            STATUS_REPLACED
+        };
+        if row.status.as_deref() == Some(status) {
+            continue;
+        }
+        row.status = Some(status.to_string());
+        if let Err(e) = TxJournal::update_by_column(rb, row, "id").await {
+            println!("Failed to update transaction journal: {}", e);
+        }
+    }
+}
+
+/// 按百分比提高手续费，至少提高 MIN_FEE_BUMP_PERCENT，且至少加 1 wei
+fn bump_fee(fee: u128, percent: u64) -> u128 {
+    let percent = percent.max(MIN_FEE_BUMP_PERCENT) as u128;
+    let bumped = fee.saturating_add(fee.saturating_mul(percent) / 100);
+    bumped.max(fee.saturating_add(1))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_bump_fee() {
+        assert_eq!(bump_fee(1_000, 20), 1_200);
+        // 低于节点要求的幅度时按 10% 提高
+        assert_eq!(bump_fee(1_000, 1), 1_100);
+        // 手续费为 0 时至少加 1 wei
+        assert_eq!(bump_fee(0, 50), 1);
+        assert_eq!(bump_fee(u128::MAX, 20), u128::MAX);
+    }
+}
@@ -0,0 +1,175 @@
+use crate::DataBase::MarketOrders;
+use crate::Error::{BlockchainError, MarketError};
+use crate::Transaction::sendTx::Handler;
+use alloy::primitives::{Address, PrimitiveSignature, U256};
+use alloy::providers::Provider;
+use alloy::sol;
+use alloy::sol_types::{eip712_domain, Eip712Domain, SolStruct};
+use serde::{Deserialize, Serialize};
+use std::str::FromStr;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+// 链下交易市场：卖家的挂单（Listing）和买家的报价（Offer）都是 EIP-712 签名消息，只保存在数据库中，
+// 被接受时先由买家账户向卖家付款，再由卖家账户以 safeTransferFromWithValue 转出 token，
+// 成交前会重新校验签名、有效期以及 token 当前的所有者
+
+pub const KIND_LISTING: &str = "listing";
+pub const KIND_OFFER: &str = "offer";
+
+pub const STATUS_OPEN: &str = "open";
+pub const STATUS_CANCELLED: &str = "cancelled";
+pub const STATUS_FILLED: &str = "filled";
+// 已被某个接受请求占用、正在上链成交，其他请求不能再接受或取消
+pub const STATUS_SETTLING: &str = "settling";
+
+sol! {
+    /// 卖家签名的挂单：愿意以 price 出售 tokenId
+    #[derive(Debug, Serialize, Deserialize)]
+    struct Listing {
+        address seller;
+        uint256 tokenId;
+        uint256 price;
+        uint256 nonce;
+        uint256 expiry;
+    }
+
+    /// 买家签名的报价：愿意以 price 购买 tokenId
+    #[derive(Debug, Serialize, Deserialize)]
+    struct Offer {
+        address buyer;
+        uint256 tokenId;
+        uint256 price;
+        uint256 nonce;
+        uint256 expiry;
+    }
+}
+
+/// 市场签名使用的 EIP-712 域，verifying_contract 为 ImageToken 合约地址
+pub fn market_domain(chain_id: u64, verifying_contract: Address) -> Eip712Domain {
+    eip712_domain! {
+        name: "ImageTokenMarket",
+        version: "1",
+        chain_id: chain_id,
+        verifying_contract: verifying_contract,
+    }
+}
+
+/// 从签名中恢复签名者，并检查其是否为 maker
+pub fn verify_signature<T: SolStruct>(
+    order: &T,
+    domain: &Eip712Domain,
+    signature: &str,
+    maker: Address,
+) -> Result<(), MarketError> {
+    let signature = PrimitiveSignature::from_str(signature)
+        .map_err(|e| MarketError::InvalidSignature(e.to_string()))?;
+    let signer = signature
+        .recover_address_from_prehash(&order.eip712_signing_hash(domain))
+        .map_err(|e| MarketError::InvalidSignature(e.to_string()))?;
+    if signer != maker {
+        return Err(MarketError::InvalidSignature(format!(
+            "signed by {} instead of {}",
+            signer, maker
+        )));
+    }
+    Ok(())
+}
+
+/// 按数据库中保存的字段还原签名消息并校验签名与有效期
+pub fn verify_order(row: &MarketOrders, domain: &Eip712Domain) -> Result<(), MarketError> {
+    let maker = parse_field::<Address>("maker", row.maker.as_deref())?;
+    let token_id = parse_field::<U256>("token_id", row.token_id.as_deref())?;
+    let price = parse_field::<U256>("price", row.price.as_deref())?;
+    let nonce = parse_field::<U256>("nonce", row.nonce.as_deref())?;
+    let expiry = U256::from(row.expiry.unwrap_or_default().max(0) as u64);
+    if expiry <= U256::from(now_secs()) {
+        return Err(MarketError::OrderExpired);
+    }
+    let signature = row.signature.as_deref().unwrap_or_default();
+
+    match row.kind.as_deref() {
+        Some(KIND_LISTING) => {
+            let listing = Listing {
+                seller: maker,
+                tokenId: token_id,
+                price,
+                nonce,
+                expiry,
+            };
+            verify_signature(&listing, domain, signature, maker)
+        }
+        Some(KIND_OFFER) => {
+            let offer = Offer {
+                buyer: maker,
+                tokenId: token_id,
+                price,
+                nonce,
+                expiry,
+            };
+            verify_signature(&offer, domain, signature, maker)
+        }
+        other => Err(MarketError::InvalidSignature(format!(
+            "unknown order kind {:?}",
+            other
+        ))),
+    }
+}
+
+/// 当前 unix 时间（秒）
+pub fn now_secs() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default()
+}
+
+// 数据库中的字段都以字符串保存，解析失败视为订单数据损坏
+fn parse_field<T: FromStr>(field: &str, value: Option<&str>) -> Result<T, MarketError> {
+    value.and_then(|v| v.parse().ok()).ok_or_else(|| {
+        MarketError::DatabaseError(rbatis::Error::E(format!("invalid order field {}", field)))
+    })
+}
+
+impl Handler {
+    /// 当前链与合约对应的市场签名域
+    pub async fn market_domain(&self) -> Result<Eip712Domain, BlockchainError> {
+        let contract = self.contract()?;
+        let chain_id = contract
+            .provider()
+            .get_chain_id()
+            .await
+            .map_err(|e| BlockchainError::ContractCallError(format!("Failed to get chain id: {}", e)))?;
+        Ok(market_domain(chain_id, *contract.address()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloy::signers::local::PrivateKeySigner;
+    use alloy::signers::SignerSync;
+
+    #[test]
+    fn test_verify_listing_signature() {
+        let signer = PrivateKeySigner::random();
+        let domain = market_domain(1337, Address::repeat_byte(0x11));
+        let listing = Listing {
+            seller: signer.address(),
+            tokenId: U256::from(1),
+            price: U256::from(100),
+            nonce: U256::ZERO,
+            expiry: U256::from(now_secs() + 3600),
+        };
+        let signature = signer
+            .sign_hash_sync(&listing.eip712_signing_hash(&domain))
+            .unwrap();
+        let signature = format!("0x{}", alloy::hex::encode(signature.as_bytes()));
+
+        assert!(verify_signature(&listing, &domain, &signature, signer.address()).is_ok());
+        // 签名者与 maker 不一致
+        assert!(verify_signature(&listing, &domain, &signature, Address::ZERO).is_err());
+        // 签名绑定到具体的链和合约
+        let other_domain = market_domain(1, Address::repeat_byte(0x11));
+        assert!(verify_signature(&listing, &other_domain, &signature, signer.address()).is_err());
+    }
+}
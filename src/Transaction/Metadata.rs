@@ -0,0 +1,115 @@
+use crate::Transaction::sendTx::IERC721A::ImageInfo;
+use alloy::primitives::U256;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+
+// ERC-721 / OpenSea 风格的 token 元数据，由链上的 ImageInfo 生成
+// 钱包和区块浏览器通过 tokenURI 读取这份 JSON，拍摄、提交信息以及销售次数放在 attributes 中
+
+pub const IPFS_SCHEME: &str = "ipfs://";
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct TokenMetadata {
+    pub name: String,
+    pub description: String,
+    pub image: String,
+    pub attributes: Vec<MetadataAttribute>,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct MetadataAttribute {
+    pub trait_type: String,
+    pub value: Value,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub display_type: Option<String>,
+}
+
+impl MetadataAttribute {
+    fn text(trait_type: &str, value: &str) -> Self {
+        MetadataAttribute {
+            trait_type: trait_type.to_string(),
+            value: json!(value),
+            display_type: None,
+        }
+    }
+
+    // 时间戳以 date 类型展示
+    fn date(trait_type: &str, value: U256) -> Self {
+        MetadataAttribute {
+            trait_type: trait_type.to_string(),
+            value: json!(value.saturating_to::<u64>()),
+            display_type: Some("date".to_string()),
+        }
+    }
+
+    fn number(trait_type: &str, value: u64) -> Self {
+        MetadataAttribute {
+            trait_type: trait_type.to_string(),
+            value: json!(value),
+            display_type: Some("number".to_string()),
+        }
+    }
+}
+
+/// 根据 ImageInfo 构造元数据，image 为图片的访问地址（铸造时 tokenURI 可能已经指向元数据本身）
+pub fn build_metadata(token_id: U256, info: &ImageInfo, image: &str) -> TokenMetadata {
+    TokenMetadata {
+        name: format!("Image #{}", token_id),
+        description: format!(
+            "Image captured by {} with {}, submitted to {}",
+            info.captureCompany, info.captureDevice, info.submissionReceiver
+        ),
+        image: image.to_string(),
+        attributes: vec![
+            MetadataAttribute::date("Capture Time", info.captureTime),
+            MetadataAttribute::text("Capture Device", &info.captureDevice),
+            MetadataAttribute::text("Capture Company", &info.captureCompany),
+            MetadataAttribute::date("Submission Time", info.submissionTime),
+            MetadataAttribute::text("Submission Receiver", &info.submissionReceiver),
+            MetadataAttribute::number("Sale Count", info.saleHistory.len() as u64),
+        ],
+    }
+}
+
+/// tokenURI 形如 ipfs://<cid> 时返回 cid，它可能指向元数据也可能直接指向图片，
+/// 调用方需要再确认该 cid 记录在 metadata_uris 表中（由本服务生成）才能当作元数据读取
+pub fn metadata_cid(token_uri: &str) -> Option<&str> {
+    token_uri
+        .strip_prefix(IPFS_SCHEME)
+        .filter(|cid| !cid.is_empty())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloy::primitives::Address;
+
+    #[test]
+    fn test_build_metadata() {
+        let info = ImageInfo {
+            _tokenURIs: "https://ipfs.io/ipfs/QmImage".to_string(),
+            owner: Address::ZERO,
+            watermark: "Gzhu".to_string(),
+            captureTime: U256::from(10),
+            captureDevice: "huawei_mate60".to_string(),
+            captureCompany: "huawei".to_string(),
+            submissionTime: U256::from(20),
+            submissionReceiver: "xiaomi".to_string(),
+            saleHistory: vec![],
+        };
+        let metadata = build_metadata(U256::from(3), &info, &info._tokenURIs);
+        let value = serde_json::to_value(&metadata).unwrap();
+
+        assert_eq!(value["name"], "Image #3");
+        assert_eq!(value["image"], "https://ipfs.io/ipfs/QmImage");
+        assert_eq!(value["attributes"][0]["display_type"], "date");
+        assert_eq!(value["attributes"][0]["value"], 10);
+        assert!(value["attributes"][1].get("display_type").is_none());
+        assert_eq!(value["attributes"][5]["value"], 0);
+        // 水印信息不出现在公开的元数据中
+        assert!(!value.to_string().contains("Gzhu"));
+
+        assert_eq!(metadata_cid("ipfs://QmMeta"), Some("QmMeta"));
+        assert_eq!(metadata_cid("https://ipfs.io/ipfs/QmImage"), None);
+    }
+}
@@ -0,0 +1,348 @@
+use crate::Error::BlockchainError;
+use crate::Transaction::sendTx::Handler;
+use crate::Transaction::sendTx::ImageToken::{ImageTokenErrors, ImageTokenEvents};
+use crate::Transaction::ContractMethod::ContractMethod;
+use alloy::eips::BlockId;
+use alloy::network::TransactionBuilder;
+use alloy::primitives::{Address, Bytes, B256};
+use alloy::providers::Provider;
+use alloy::rpc::types::TransactionRequest;
+use alloy::sol_types::{ContractError, SolEventInterface};
+use alloy::transports::TransportError;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+
+/// 交易预执行（dry-run）：以发起请求的用户地址为 from 在 pending 状态上执行 eth_call，
+/// 在真正发送交易、消耗 gas 之前得到 revert 原因、预估 gas 以及预测会触发的事件
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct SimulationResult {
+    pub method: String,
+    pub from: Address,
+    pub success: bool,
+    pub revert_reason: Option<String>,
+    pub estimated_gas: Option<u64>,
+    pub events: Vec<PredictedEvent>,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct PredictedEvent {
+    pub name: String,
+    pub address: Address,
+    pub args: Value,
+}
+
+impl Handler {
+    /// 预执行一个会修改链上状态的合约调用，不发送交易
+    /// from 为发起请求的用户地址，这样调用者自己会遇到的所有权、授权等 revert 都能提前暴露
+    pub async fn simulate(
+        &self,
+        func: &ContractMethod,
+        from: Address,
+    ) -> Result<SimulationResult, BlockchainError> {
+        if func.is_view() {
+            return Err(BlockchainError::ContractCallError(format!(
+                "{} is a view function, nothing to simulate",
+                func.method_name()
+            )));
+        }
+        let contract = self.contract()?;
+        let tx = TransactionRequest::default()
+            .with_from(from)
+            .with_to(*contract.address())
+            .with_input(self.encode_call(func)?);
+        let provider = contract.provider();
+
+        let mut result = SimulationResult {
+            method: func.method_name().to_string(),
+            from,
+            success: false,
+            revert_reason: None,
+            estimated_gas: None,
+            events: vec![],
+        };
+
+        if let Err(e) = provider.call(&tx).block(BlockId::pending()).await {
+            result.revert_reason = Some(decode_revert(&e));
+            return Ok(result);
+        }
+        result.success = true;
+
+        let gas = provider
+            .estimate_gas(&tx)
+            .block(BlockId::pending())
+            .await
+            .map_err(|e| {
+                BlockchainError::ContractCallError(format!(
+                    "Failed to estimate gas for {}: {}",
+                    func.method_name(),
+                    e
+                ))
+            })?;
+        result.estimated_gas = Some(gas);
+
+        // 预测事件依赖节点的 debug_traceCall，节点不支持时只返回空列表
+        match provider
+            .raw_request::<_, Value>(
+                "debug_traceCall".into(),
+                (
+                    &tx,
+                    BlockId::pending(),
+                    json!({ "tracer": "callTracer", "tracerConfig": { "withLog": true } }),
+                ),
+            )
+            .await
+        {
+            Ok(trace) => collect_events(&trace, &mut result.events),
+            Err(e) => println!("debug_traceCall unavailable, skip predicted events: {}", e),
+        }
+
+        Ok(result)
+    }
+
+    // 按 ContractMethod 构造调用数据
+    fn encode_call(&self, func: &ContractMethod) -> Result<Bytes, BlockchainError> {
+        let contract = self.contract()?;
+        let calldata = match func.clone() {
+            ContractMethod::safeTransferFrom {
+                from,
+                to,
+                tokenId,
+                amount,
+            } => match amount {
+                None => contract
+                    .safeTransferFrom_0(from, to, tokenId)
+                    .calldata()
+                    .clone(),
+                Some(data) => contract
+                    .safeTransferFrom_1(from, to, tokenId, data)
+                    .calldata()
+                    .clone(),
+            },
+            ContractMethod::safeTransferFromWithValue {
+                from,
+                to,
+                tokenId,
+                value,
+            } => contract
+                .safeTransferFromWithValue(from, to, tokenId, value)
+                .calldata()
+                .clone(),
+            ContractMethod::transferFrom { from, to, tokenId } => contract
+                .transferFrom(from, to, tokenId)
+                .calldata()
+                .clone(),
+            ContractMethod::approve { to, tokenId } => {
+                contract.approve(to, tokenId).calldata().clone()
+            }
+            ContractMethod::setApprovalForAll { operator, approved } => contract
+                .setApprovalForAll(operator, approved)
+                .calldata()
+                .clone(),
+            ContractMethod::modifyImageInfo {
+                tokenId,
+                _tokenURIs,
+                owner,
+                watermark,
+                captureTime,
+                captureDevice,
+                captureCompany,
+                submissionTime,
+                submissionReceiver,
+            } => contract
+                .modifyImageInfo(
+                    tokenId,
+                    _tokenURIs,
+                    owner,
+                    watermark,
+                    captureTime,
+                    captureDevice,
+                    captureCompany,
+                    submissionTime,
+                    submissionReceiver,
+                )
+                .calldata()
+                .clone(),
+            ContractMethod::modifyCaptureInfo {
+                tokenId,
+                captureTime,
+                captureDevice,
+                captureCompany,
+            } => contract
+                .modifyCaptureInfo(tokenId, captureTime, captureDevice, captureCompany)
+                .calldata()
+                .clone(),
+            ContractMethod::safeMint {
+                to,
+                quantity,
+                _tokenURIs,
+                watermarks,
+                captureTimes,
+                captureDevices,
+                captureCompanies,
+                submissionTimes,
+                submissionReceivers,
+            } => {
+                if watermarks.is_none()
+                    && captureTimes.is_none()
+                    && captureDevices.is_none()
+                    && captureCompanies.is_none()
+                    && submissionTimes.is_none()
+                    && submissionReceivers.is_none()
+                {
+                    contract
+                        .safeMint_1(to, quantity, _tokenURIs)
+                        .calldata()
+                        .clone()
+                } else {
+                    contract
+                        .safeMint_0(
+                            to,
+                            quantity,
+                            _tokenURIs,
+                            watermarks.unwrap_or_default(),
+                            captureTimes.unwrap_or_default(),
+                            captureDevices.unwrap_or_default(),
+                            captureCompanies.unwrap_or_default(),
+                            submissionTimes.unwrap_or_default(),
+                            submissionReceivers.unwrap_or_default(),
+                        )
+                        .calldata()
+                        .clone()
+                }
+            }
+            ContractMethod::safeBatchTransferFrom {
+                by,
+                from,
+                to,
+                tokenIds,
+                data,
+            } => contract
+                .safeBatchTransferFrom(by, from, to, tokenIds, data)
+                .calldata()
+                .clone(),
+            ContractMethod::batchTransferFrom { from, to, tokenIds } => contract
+                .batchTransferFrom(from, to, tokenIds)
+                .calldata()
+                .clone(),
+            ContractMethod::burn { tokenId } => contract.burn(tokenId).calldata().clone(),
+            ContractMethod::batchBurn { tokenIds } => {
+                contract.batchBurn(tokenIds).calldata().clone()
+            }
+            view => {
+                return Err(BlockchainError::ContractCallError(format!(
+                    "{} is a view function",
+                    view.method_name()
+                )))
+            }
+        };
+        Ok(calldata)
+    }
+}
+
+/// 将节点返回的 revert 数据解码为合约自定义错误、Error(string) 或 Panic
+pub fn decode_revert(err: &TransportError) -> String {
+    let Some(payload) = err.as_error_resp() else {
+        return err.to_string();
+    };
+    match payload.as_decoded_error::<ContractError<ImageTokenErrors>>(false) {
+        Some(ContractError::CustomError(e)) => custom_error_name(&e),
+        Some(ContractError::Revert(revert)) => revert.reason,
+        Some(ContractError::Panic(panic)) => panic.to_string(),
+        None => payload.message.to_string(),
+    }
+}
+
+// 合约中的自定义错误都没有参数，Debug 输出形如 `TransferFromIncorrectOwner(TransferFromIncorrectOwner)`
+fn custom_error_name(e: &ImageTokenErrors) -> String {
+    let debug = format!("{:?}", e);
+    debug.split('(').next().unwrap_or(&debug).to_string()
+}
+
+// 递归收集 callTracer 结果中的日志，并按 ImageToken 的事件定义解码
+fn collect_events(frame: &Value, events: &mut Vec<PredictedEvent>) {
+    if let Some(logs) = frame.get("logs").and_then(Value::as_array) {
+        for log in logs {
+            if let Some(event) = decode_log(log) {
+                events.push(event);
+            }
+        }
+    }
+    if let Some(calls) = frame.get("calls").and_then(Value::as_array) {
+        for call in calls {
+            collect_events(call, events);
+        }
+    }
+}
+
+fn decode_log(log: &Value) -> Option<PredictedEvent> {
+    let address: Address = serde_json::from_value(log.get("address")?.clone()).ok()?;
+    let topics: Vec<B256> = serde_json::from_value(log.get("topics")?.clone()).ok()?;
+    let data: Bytes = serde_json::from_value(log.get("data")?.clone()).ok()?;
+    let (name, args) = match ImageTokenEvents::decode_raw_log(&topics, &data, false).ok()? {
+        ImageTokenEvents::Approval(e) => (
+            "Approval",
+            json!({ "owner": e.owner, "approved": e.approved, "tokenId": e.tokenId }),
+        ),
+        ImageTokenEvents::ApprovalForAll(e) => (
+            "ApprovalForAll",
+            json!({ "owner": e.owner, "operator": e.operator, "approved": e.approved }),
+        ),
+        ImageTokenEvents::ConsecutiveTransfer(e) => (
+            "ConsecutiveTransfer",
+            json!({
+                "fromTokenId": e.fromTokenId,
+                "toTokenId": e.toTokenId,
+                "from": e.from,
+                "to": e.to,
+            }),
+        ),
+        ImageTokenEvents::MetadataUpdate(e) => {
+            ("MetadataUpdate", json!({ "tokenId": e._tokenId }))
+        }
+        ImageTokenEvents::Transfer(e) => (
+            "Transfer",
+            json!({ "from": e.from, "to": e.to, "tokenId": e.tokenId }),
+        ),
+    };
+    Some(PredictedEvent {
+        name: name.to_string(),
+        address,
+        args,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloy::primitives::{Address, U256};
+    use alloy::sol_types::SolEvent;
+
+    #[test]
+    fn test_collect_events_from_call_trace() {
+        let from = Address::ZERO;
+        let to = Address::repeat_byte(0x22);
+        let transfer = crate::Transaction::sendTx::ImageToken::Transfer {
+            from,
+            to,
+            tokenId: U256::from(7),
+        };
+        let log_data = transfer.encode_log_data();
+        let trace = json!({
+            "calls": [{
+                "logs": [{
+                    "address": Address::repeat_byte(0x11),
+                    "topics": log_data.topics(),
+                    "data": log_data.data,
+                }]
+            }]
+        });
+
+        let mut events = vec![];
+        collect_events(&trace, &mut events);
+        assert_eq!(events.len(), 1);
+        assert_eq!(events[0].name, "Transfer");
+        assert_eq!(events[0].args["to"], json!(to));
+        assert_eq!(events[0].args["tokenId"], json!(U256::from(7)));
+    }
+}
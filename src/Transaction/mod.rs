@@ -8,3 +8,11 @@ pub mod sendTx;
 pub mod ImageData;
 
 pub mod ContractMethod;
+
+pub mod Simulation;
+
+pub mod Journal;
+
+pub mod Market;
+
+pub mod Metadata;
@@ -11,12 +11,16 @@ use crate::Error::BlockchainError;
 */
 use crate::Error::TxError;
 use crate::Transaction::ContractMethod::{ContractMethod, ContractMethodResult};
+use crate::Transaction::Journal::KIND_ORIGINAL;
+use alloy::contract::SolCallBuilder;
+use alloy::eips::eip2718::Encodable2718;
 use alloy::network::NetworkWallet;
+use alloy::primitives::aliases::TxHash;
 use alloy::providers::fillers::{FillProvider, JoinFill, WalletFiller};
-use alloy::providers::{Identity, PendingTransactionBuilder, ReqwestProvider, WalletProvider};
+use alloy::providers::{Identity, ReqwestProvider, WalletProvider};
 use alloy::{
     network::{Ethereum, EthereumWallet, TransactionBuilder},
-    primitives::{address, hex, Address, U256},
+    primitives::{address, hex, Address, Bytes, FixedBytes, U256},
     providers::{Provider, ProviderBuilder, RootProvider},
     rpc::types::TransactionRequest,
     signers::local::PrivateKeySigner,
@@ -27,7 +31,9 @@ use alloy::{
 use eyre::Result;
 use serde::{Deserialize, Serialize};
 use serde_json::Value::Null;
+use std::cell::Cell;
 use std::fmt::Debug;
+use std::future::Future;
 use std::hash::Hash;
 use std::str::FromStr;
 use std::{convert::TryFrom, process, sync::Arc};
@@ -40,12 +46,31 @@ sol!(
     "./ImageToken_sol_MyToken.json"
 );
 use crate::Transaction::sendTx::ImageToken::{totalSupplyReturn, ImageTokenInstance};
+use crate::Transaction::sendTx::IERC721A::{ImageInfo, SaleInfo};
+
+tokio::task_local! {
+    // 当前请求中是否已经有交易广播出去，由 track_broadcast 设置作用域
+    static BROADCAST: Cell<bool>;
+}
+
+/// 执行 future，同时返回执行过程中是否有交易广播出去。
+/// 广播之后的失败（如等待回执超时）不代表交易不会上链，调用方据此决定能否安全重试
+pub async fn track_broadcast<F: Future>(future: F) -> (F::Output, bool) {
+    BROADCAST
+        .scope(Cell::new(false), async move {
+            let output = future.await;
+            (output, BROADCAST.with(Cell::get))
+        })
+        .await
+}
+
 #[derive(Debug, Clone)]
 pub struct Handler {
     http_url: String,
     wallet_pk: String,
     contract_address: Address,
     user_address: Address,
+    requested_by: Option<String>,
     contract: Option<ImageTokenInstance<Http<Client>, ReqwestProvider>>,
 }
 impl Handler {
@@ -55,6 +80,7 @@ impl Handler {
             wallet_pk: "".to_string(),
             contract_address: Default::default(),
             user_address: Default::default(),
+            requested_by: None,
             contract: None,
         }
     }
@@ -104,6 +130,7 @@ impl Handler {
             wallet_pk: wallet_pk.to_string(),
             contract_address: Address::from_str(contract_address).unwrap(),
             user_address: Address::from_str(user_address).unwrap(),
+            requested_by: None,
             contract: Some(contract),
         })
     }
@@ -113,560 +140,579 @@ impl Handler {
         &self,
         func: ContractMethod,
     ) -> Result<ContractMethodResult, BlockchainError> {
-        let mut contract_method_result: ContractMethodResult = ContractMethodResult::Defalut;
-
-        let uer_address = self.user_address.clone();
-
-        if let Some(contract) = &self.contract {
-            match func {
-                ContractMethod::safeMint {
-                    to,
-                    quantity,
-                    _tokenURIs,
-                    watermarks,
-                    captureTimes,
-                    captureDevices,
-                    captureCompanies,
-                    submissionTimes,
-                    submissionReceivers,
-                } => {
-                    // 根据传入的参数调用合约方法
-                    let tx_builder = if watermarks.is_none()
-                        && captureTimes.is_none()
-                        && captureDevices.is_none()
-                        && captureCompanies.is_none()
-                        && submissionTimes.is_none()
-                        && submissionReceivers.is_none()
-                    {
-                        let builder = contract
-                            .safeMint_1(to, quantity, _tokenURIs)
-                            .from(uer_address);
-                        let result = builder.send().await.map_err(|e| {
-                            BlockchainError::SendTransactionError(format!(
-                                "Failed to send safeMint transaction: {}",
-                                e
-                            ))
-                        })?;
-                        result
-                    } else {
-                        let builder = contract
-                            .safeMint_0(
-                                to,
-                                quantity,
-                                _tokenURIs,
-                                watermarks.unwrap(),
-                                captureTimes.unwrap(),
-                                captureDevices.unwrap(),
-                                captureCompanies.unwrap(),
-                                submissionTimes.unwrap(),
-                                submissionReceivers.unwrap(),
-                            )
-                            .from(uer_address);
-                        let result = builder.send().await.map_err(|e| {
-                            BlockchainError::SendTransactionError(format!(
-                                "Failed to send safeMint transaction: {}",
-                                e
-                            ))
-                        })?;
-                        result
-                    };
-                    let hash = tx_builder.watch().await.map_err(|e| {
-                        BlockchainError::WatchTransactionError(format!(
-                            "Failed to watch safeMint transaction: {}",
-                            e
-                        ))
-                    })?;
-                    contract_method_result = ContractMethodResult::TxHash(hash);
-                }
-                _ => {
-                    println!("This function only call safeMint");
-                }
+        match func {
+            ContractMethod::safeMint { .. } => func.execute(self).await,
+            _ => {
+                println!("This function only call safeMint");
+                Ok(ContractMethodResult::Defalut)
             }
         }
-        Ok(contract_method_result)
     }
 
+    /// 按 ContractMethod 命令执行合约调用，返回无类型的 ContractMethodResult
+    /// 新代码请直接使用下方的类型化方法
     pub async fn match_func(
         &self,
         func: ContractMethod,
     ) -> Result<ContractMethodResult, BlockchainError> {
-        let mut contract_method_result: ContractMethodResult = ContractMethodResult::Defalut;
+        func.execute(self).await
+    }
+
+    /// 当前交易的发送者地址
+    pub fn user_address(&self) -> Address {
+        self.user_address
+    }
+
+    /// 标记之后发送的交易由哪个用户发起，写入交易日志，加速、取消交易时据此校验归属
+    pub fn on_behalf_of(mut self, username: &str) -> Self {
+        self.requested_by = Some(username.to_string());
+        self
+    }
+
+    pub(crate) fn requested_by(&self) -> Option<&str> {
+        self.requested_by.as_deref()
+    }
+
+    pub(crate) fn contract(
+        &self,
+    ) -> Result<&ImageTokenInstance<Http<Client>, ReqwestProvider>, BlockchainError> {
+        self.contract.as_ref().ok_or_else(|| {
+            BlockchainError::ContractInitializeError(
+                "please intialize contract first".to_string(),
+            )
+        })
+    }
+
+    // 以当前用户身份发送交易，记录到交易日志，并等待交易（或其替换交易）被打包
+    async fn send_and_watch<C: SolCall>(
+        &self,
+        method: &str,
+        call: SolCallBuilder<Http<Client>, &ReqwestProvider, C>,
+    ) -> Result<TxHash, BlockchainError> {
+        let tx_hash = self.broadcast(method, call).await?;
+        self.wait_for_mined(method, tx_hash).await
+    }
+
+    // 只发送交易并记录到交易日志，不等待打包
+    async fn broadcast<C: SolCall>(
+        &self,
+        method: &str,
+        call: SolCallBuilder<Http<Client>, &ReqwestProvider, C>,
+    ) -> Result<TxHash, BlockchainError> {
+        let tx_hash = self
+            .sign_and_send(method, call.into_transaction_request())
+            .await?;
+        self.record_broadcast(method, KIND_ORIGINAL, None, tx_hash)
+            .await;
+        Ok(tx_hash)
+    }
+
+    /// 从当前账户向 to 转账 value（wei）并记录到交易日志，返回交易哈希，不等待打包，
+    /// 市场成交时用于买家付款，调用方需要在广播之后保存哈希再等待
+    pub async fn send_value(&self, to: Address, value: U256) -> Result<TxHash, BlockchainError> {
+        let request = TransactionRequest::default().with_to(to).with_value(value);
+        let tx_hash = self.sign_and_send("transferValue", request).await?;
+        self.record_broadcast("transferValue", KIND_ORIGINAL, None, tx_hash)
+            .await;
+        Ok(tx_hash)
+    }
+
+    /// 用 wallet_pk 在本地签名后以 raw transaction 发送，节点上不需要解锁发送者账户，
+    /// 未设置的 nonce、gas 和手续费按节点当前状态补全
+    pub(crate) async fn sign_and_send(
+        &self,
+        method: &str,
+        request: TransactionRequest,
+    ) -> Result<TxHash, BlockchainError> {
+        let send_error = |e: String| {
+            BlockchainError::SendTransactionError(format!(
+                "Failed to send {} transaction: {}",
+                method, e
+            ))
+        };
+        let provider = self.contract()?.provider();
+        let signer: PrivateKeySigner = self
+            .wallet_pk
+            .parse()
+            .map_err(|_| send_error("parse private_Key failed".to_string()))?;
+        let wallet = EthereumWallet::from(signer);
+
+        let mut request = request.with_from(self.user_address);
+        if request.nonce.is_none() {
+            let nonce = provider
+                .get_transaction_count(self.user_address)
+                .pending()
+                .await
+                .map_err(|e| send_error(e.to_string()))?;
+            request.set_nonce(nonce);
+        }
+        if request.chain_id.is_none() {
+            let chain_id = provider
+                .get_chain_id()
+                .await
+                .map_err(|e| send_error(e.to_string()))?;
+            request.set_chain_id(chain_id);
+        }
+        if request.gas.is_none() {
+            let gas = provider
+                .estimate_gas(&request)
+                .await
+                .map_err(|e| send_error(e.to_string()))?;
+            request.set_gas_limit(gas);
+        }
+        if request.gas_price.is_none() && request.max_fee_per_gas.is_none() {
+            let fees = provider
+                .estimate_eip1559_fees(None)
+                .await
+                .map_err(|e| send_error(e.to_string()))?;
+            request.set_max_fee_per_gas(fees.max_fee_per_gas);
+            request.set_max_priority_fee_per_gas(fees.max_priority_fee_per_gas);
+        }
 
-        let user_address = self.user_address.clone();
-        match self.contract.clone() {
-            None => {
-                println!("please intialize contract first")
+        let envelope = request
+            .build(&wallet)
+            .await
+            .map_err(|e| send_error(e.to_string()))?;
+        let pending = provider
+            .send_raw_transaction(&envelope.encoded_2718())
+            .await
+            .map_err(|e| send_error(e.to_string()))?;
+        let _ = BROADCAST.try_with(|broadcast| broadcast.set(true));
+        Ok(*pending.tx_hash())
+    }
+
+    /// view
+    pub async fn name(&self) -> Result<String, BlockchainError> {
+        let result = self
+            .contract()?
+            .name()
+            .call()
+            .await
+            .map_err(|e| call_error("name", e))?;
+        Ok(result._0)
+    }
+
+    /// view
+    pub async fn symbol(&self) -> Result<String, BlockchainError> {
+        let result = self
+            .contract()?
+            .symbol()
+            .call()
+            .await
+            .map_err(|e| call_error("symbol", e))?;
+        Ok(result._0)
+    }
+
+    /// view
+    pub async fn total_supply(&self) -> Result<U256, BlockchainError> {
+        let result = self
+            .contract()?
+            .totalSupply()
+            .call()
+            .await
+            .map_err(|e| call_error("totalSupply", e))?;
+        Ok(result.result)
+    }
+
+    /// view
+    pub async fn balance_of(&self, owner: Address) -> Result<U256, BlockchainError> {
+        let result = self
+            .contract()?
+            .balanceOf(owner)
+            .call()
+            .await
+            .map_err(|e| call_error("balanceOf", e))?;
+        Ok(result._0)
+    }
+
+    /// view
+    pub async fn owner_of(&self, token_id: U256) -> Result<Address, BlockchainError> {
+        let result = self
+            .contract()?
+            .ownerOf(token_id)
+            .call()
+            .await
+            .map_err(|e| call_error("ownerOf", e))?;
+        Ok(result._0)
+    }
+
+    /// view
+    pub async fn is_approved_for_all(
+        &self,
+        owner: Address,
+        operator: Address,
+    ) -> Result<bool, BlockchainError> {
+        let result = self
+            .contract()?
+            .isApprovedForAll(owner, operator)
+            .call()
+            .await
+            .map_err(|e| call_error("isApprovedForAll", e))?;
+        Ok(result._0)
+    }
+
+    /// view
+    pub async fn supports_interface(
+        &self,
+        interface_id: FixedBytes<4>,
+    ) -> Result<bool, BlockchainError> {
+        let result = self
+            .contract()?
+            .supportsInterface(interface_id)
+            .call()
+            .await
+            .map_err(|e| call_error("supportsInterface", e))?;
+        Ok(result._0)
+    }
+
+    /// view
+    pub async fn token_uri(&self, token_id: U256) -> Result<String, BlockchainError> {
+        let result = self
+            .contract()?
+            .tokenURI(token_id)
+            .call()
+            .await
+            .map_err(|e| call_error("tokenURI", e))?;
+        Ok(result._0)
+    }
+
+    /// view
+    pub async fn image_info(&self, token_id: U256) -> Result<ImageInfo, BlockchainError> {
+        let result = self
+            .contract()?
+            ._imageInfo(token_id)
+            .call()
+            .await
+            .map_err(|e| call_error("_imageInfo", e))?;
+        Ok(result.imageInfo)
+    }
+
+    /// view
+    pub async fn image_sale_history(
+        &self,
+        token_id: U256,
+        index: U256,
+    ) -> Result<SaleInfo, BlockchainError> {
+        let result = self
+            .contract()?
+            ._imageSaleHistory(token_id, index)
+            .call()
+            .await
+            .map_err(|e| call_error("_imageSaleHistory", e))?;
+        Ok(result.saleInfo)
+    }
+
+    /// 从交易回执的 Transfer 事件（from 为零地址）中找出本次铸造的 token id
+    pub async fn minted_token_ids(&self, tx_hash: TxHash) -> Result<Vec<U256>, BlockchainError> {
+        let contract = self.contract()?;
+        let receipt = contract
+            .provider()
+            .get_transaction_receipt(tx_hash)
+            .await
+            .map_err(|e| {
+                BlockchainError::WatchTransactionError(format!(
+                    "Failed to get receipt of {}: {}",
+                    tx_hash, e
+                ))
+            })?
+            .ok_or_else(|| BlockchainError::TransactionNotFound(tx_hash.to_string()))?;
+        if !receipt.status() {
+            return Err(BlockchainError::ContractCallError(format!(
+                "transaction {} reverted",
+                tx_hash
+            )));
+        }
+        Ok(receipt
+            .inner
+            .logs()
+            .iter()
+            .filter(|log| log.address() == *contract.address())
+            .filter_map(|log| log.log_decode::<ImageToken::Transfer>().ok())
+            .filter(|transfer| transfer.inner.data.from == Address::ZERO)
+            .map(|transfer| transfer.inner.data.tokenId)
+            .collect())
+    }
+
+    /// 读取下标为 index 的销售记录，下标越界（合约 revert）时返回 None
+    async fn sale_at(
+        &self,
+        token_id: U256,
+        index: u64,
+    ) -> Result<Option<SaleInfo>, BlockchainError> {
+        match self
+            .contract()?
+            ._imageSaleHistory(token_id, U256::from(index))
+            .call()
+            .await
+        {
+            Ok(result) => Ok(Some(result.saleInfo)),
+            Err(alloy::contract::Error::TransportError(e)) if e.as_error_resp().is_some() => {
+                Ok(None)
+            }
+            Err(e) => Err(call_error("_imageSaleHistory", e)),
+        }
+    }
+
+    /// 从 index = offset 开始逐条读取销售记录，直到下标越界（合约 revert）或已读满 limit 条
+    pub async fn sale_history_range(
+        &self,
+        token_id: U256,
+        offset: u64,
+        limit: u64,
+    ) -> Result<Vec<SaleInfo>, BlockchainError> {
+        let mut sales = vec![];
+        for index in offset..offset.saturating_add(limit) {
+            match self.sale_at(token_id, index).await? {
+                Some(sale) => sales.push(sale),
+                None => break,
             }
-            Some(contract) => match func {
-                ContractMethod::name => {
-                    let result = contract
-                        .name()
-                        .call()
-                        .await
-                        .map_err(|e| {
-                            BlockchainError::SendTransactionError(format!(
-                                "Failed to send name transaction: {}",
-                                e
-                            ))
-                        })?
-                        ._0;
-                    contract_method_result = ContractMethodResult::String(result);
-                }
-                ContractMethod::symbol => {
-                    let result = contract
-                        .symbol()
-                        .call()
-                        .await
-                        .map_err(|e| {
-                            BlockchainError::SendTransactionError(format!(
-                                "Failed to send symbol transaction: {}",
-                                e
-                            ))
-                        })?
-                        ._0;
-                    contract_method_result = ContractMethodResult::String(result);
-                }
-                ContractMethod::totalSupply => {
-                    let result = contract
-                        .totalSupply()
-                        .call()
-                        .await
-                        .map_err(|e| {
-                            BlockchainError::SendTransactionError(format!(
-                                "Failed to send totalSupply transaction: {}",
-                                e
-                            ))
-                        })?
-                        .result;
-                    contract_method_result = ContractMethodResult::U256(result);
-                    println!("contract_method_result is {:?}", contract_method_result);
-                }
-                ContractMethod::balanceOf { owner } => {
-                    let result = contract
-                        .balanceOf(owner)
-                        .call()
-                        .await
-                        .map_err(|e| {
-                            BlockchainError::SendTransactionError(format!(
-                                "Failed to send balanceOf transaction: {}",
-                                e
-                            ))
-                        })?
-                        ._0;
-                    contract_method_result = ContractMethodResult::U256(result);
-                }
-                ContractMethod::ownerOf { tokenId } => {
-                    let result = contract
-                        .ownerOf(tokenId)
-                        .call()
-                        .await
-                        .map_err(|e| {
-                            BlockchainError::SendTransactionError(format!(
-                                "Failed to send ownerOf transaction: {}",
-                                e
-                            ))
-                        })?
-                        ._0;
-                    contract_method_result = ContractMethodResult::Address(result);
-                }
-                ContractMethod::isApprovedForAll { owner, operator } => {
-                    let result = contract
-                        .isApprovedForAll(owner, operator)
-                        .call()
-                        .await
-                        .map_err(|e| {
-                            BlockchainError::SendTransactionError(format!(
-                                "Failed to send isApprovedForAll transaction: {}",
-                                e
-                            ))
-                        })?
-                        ._0;
-                    contract_method_result = ContractMethodResult::Bool(result);
-                }
-                ContractMethod::supportsInterface { interfaceId } => {
-                    let result = contract
-                        .supportsInterface(interfaceId)
-                        .call()
-                        .await
-                        .map_err(|e| {
-                            BlockchainError::SendTransactionError(format!(
-                                "Failed to send supportsInterface transaction: {}",
-                                e
-                            ))
-                        })?
-                        ._0;
-                    contract_method_result = ContractMethodResult::Bool(result);
-                }
-                ContractMethod::tokenURI { tokenId } => {
-                    let result = contract
-                        .tokenURI(tokenId)
-                        .call()
-                        .await
-                        .map_err(|e| {
-                            BlockchainError::SendTransactionError(format!(
-                                "Failed to send tokenURI transaction: {}",
-                                e
-                            ))
-                        })?
-                        ._0;
-                    contract_method_result = ContractMethodResult::String(result);
-                }
-                ContractMethod::_imageInfo { tokenId } => {
-                    let result = contract
-                        ._imageInfo(tokenId)
-                        .call()
-                        .await
-                        .map_err(|e| {
-                            BlockchainError::SendTransactionError(format!(
-                                "Failed to send _imageInfo transaction: {}",
-                                e
-                            ))
-                        })?
-                        .imageInfo;
-                    contract_method_result = ContractMethodResult::ImageInfo(result);
-                }
-                ContractMethod::_imageSaleHistory { tokenId, index } => {
-                    let result = contract
-                        ._imageSaleHistory(tokenId, index)
-                        .call()
-                        .await
-                        .map_err(|e| {
-                            BlockchainError::SendTransactionError(format!(
-                                "Failed to send _imageSaleHistory transaction: {}",
-                                e
-                            ))
-                        })?
-                        .saleInfo;
-                    contract_method_result = ContractMethodResult::SaleInfo(result);
-                }
-                ContractMethod::safeTransferFrom {
-                    from,
-                    to,
-                    tokenId,
-                    amount,
-                } => {
-                    let result: PendingTransactionBuilder<Http<Client>, Ethereum> =
-                        if amount.is_none() {
-                            contract
-                                .safeTransferFrom_0(from, to, tokenId)
-                                .send()
-                                .await
-                                .map_err(|e| {
-                                    BlockchainError::SendTransactionError(format!(
-                                        "Failed to send safeTransferFrom_0 transaction: {}",
-                                        e
-                                    ))
-                                })?
-                        } else {
-                            contract
-                                .safeTransferFrom_1(from, to, tokenId, amount.unwrap())
-                                .send()
-                                .await
-                                .map_err(|e| {
-                                    BlockchainError::SendTransactionError(format!(
-                                        "Failed to send safeTransferFrom_1 transaction: {}",
-                                        e
-                                    ))
-                                })?
-                        };
-                    let hash = result.watch().await.map_err(|e| {
-                        BlockchainError::WatchTransactionError(format!(
-                            "Failed to watch safeTransferFrom transaction: {}",
-                            e
-                        ))
-                    })?;
-                    contract_method_result = ContractMethodResult::TxHash(hash);
-                }
-                ContractMethod::safeTransferFromWithValue {
-                    from,
-                    to,
-                    tokenId,
-                    value,
-                } => {
-                    let result = contract
-                        .safeTransferFromWithValue(from, to, tokenId, value)
-                        .send()
-                        .await
-                        .map_err(|e| {
-                            BlockchainError::SendTransactionError(format!(
-                                "Failed to send safeTransferFromWithValue transaction: {}",
-                                e
-                            ))
-                        })?;
-                    let hash = result.watch().await.map_err(|e| {
-                        BlockchainError::WatchTransactionError(format!(
-                            "Failed to watch safeTransferFromWithValue transaction: {}",
-                            e
-                        ))
-                    })?;
-                    contract_method_result = ContractMethodResult::TxHash(hash);
-                }
-                ContractMethod::transferFrom { from, to, tokenId } => {
-                    let result = contract
-                        .transferFrom(from, to, tokenId)
-                        .send()
-                        .await
-                        .map_err(|e| {
-                            BlockchainError::SendTransactionError(format!(
-                                "Failed to send transferFrom transaction: {}",
-                                e
-                            ))
-                        })?;
-                    let hash = result.watch().await.map_err(|e| {
-                        BlockchainError::WatchTransactionError(format!(
-                            "Failed to watch transferFrom transaction: {}",
-                            e
-                        ))
-                    })?;
-                    contract_method_result = ContractMethodResult::TxHash(hash);
-                }
-                ContractMethod::approve { to, tokenId } => {
-                    let result = contract.approve(to, tokenId).send().await.map_err(|e| {
-                        BlockchainError::SendTransactionError(format!(
-                            "Failed to send approve transaction: {}",
-                            e
-                        ))
-                    })?;
-                    let hash = result.watch().await.map_err(|e| {
-                        BlockchainError::WatchTransactionError(format!(
-                            "Failed to watch approve transaction: {}",
-                            e
-                        ))
-                    })?;
-                    contract_method_result = ContractMethodResult::TxHash(hash);
-                }
-                ContractMethod::getApproved { tokenId } => {
-                    let result = contract
-                        .getApproved(tokenId)
-                        .call()
-                        .await
-                        .map_err(|e| {
-                            BlockchainError::SendTransactionError(format!(
-                                "Failed to send getApproved transaction: {}",
-                                e
-                            ))
-                        })?
-                        ._0;
-                    contract_method_result = ContractMethodResult::Address(result);
-                }
-                ContractMethod::setApprovalForAll { operator, approved } => {
-                    let result = contract
-                        .setApprovalForAll(operator, approved)
-                        .send()
-                        .await
-                        .map_err(|e| {
-                            BlockchainError::SendTransactionError(format!(
-                                "Failed to send setApprovalForAll transaction: {}",
-                                e
-                            ))
-                        })?;
-                    let hash = result.watch().await.map_err(|e| {
-                        BlockchainError::WatchTransactionError(format!(
-                            "Failed to watch setApprovalForAll transaction: {}",
-                            e
-                        ))
-                    })?;
-                    contract_method_result = ContractMethodResult::TxHash(hash);
-                }
-                ContractMethod::modifyImageInfo {
-                    tokenId,
-                    _tokenURIs,
-                    owner,
-                    watermark,
-                    captureTime,
-                    captureDevice,
-                    captureCompany,
-                    submissionTime,
-                    submissionReceiver,
-                } => {
-                    let result = contract
-                        .modifyImageInfo(
-                            tokenId,
-                            _tokenURIs,
-                            owner,
-                            watermark,
-                            captureTime,
-                            captureDevice,
-                            captureCompany,
-                            submissionTime,
-                            submissionReceiver,
-                        )
-                        .send()
-                        .await
-                        .map_err(|e| {
-                            BlockchainError::SendTransactionError(format!(
-                                "Failed to send modifyImageInfo transaction: {}",
-                                e
-                            ))
-                        })?;
-                    let hash = result.watch().await.map_err(|e| {
-                        BlockchainError::WatchTransactionError(format!(
-                            "Failed to watch modifyImageInfo transaction: {}",
-                            e
-                        ))
-                    })?;
-                    contract_method_result = ContractMethodResult::TxHash(hash);
-                }
-                ContractMethod::modifyCaptureInfo {
-                    tokenId,
-                    captureTime,
-                    captureDevice,
-                    captureCompany,
-                } => {
-                    let result = contract
-                        .modifyCaptureInfo(tokenId, captureTime, captureDevice, captureCompany)
-                        .send()
-                        .await
-                        .map_err(|e| {
-                            BlockchainError::SendTransactionError(format!(
-                                "Failed to send modifyCaptureInfo transaction: {}",
-                                e
-                            ))
-                        })?;
-                    let hash = result.watch().await.map_err(|e| {
-                        BlockchainError::WatchTransactionError(format!(
-                            "Failed to watch modifyCaptureInfo transaction: {}",
-                            e
-                        ))
-                    })?;
-                    contract_method_result = ContractMethodResult::TxHash(hash);
-                }
-                ContractMethod::safeMint {
-                    to,
-                    quantity,
-                    _tokenURIs,
-                    watermarks,
-                    captureTimes,
-                    captureDevices,
-                    captureCompanies,
-                    submissionTimes,
-                    submissionReceivers,
-                } => {
-                    let tx_builder = if watermarks.is_none()
-                        && captureTimes.is_none()
-                        && captureDevices.is_none()
-                        && captureCompanies.is_none()
-                        && submissionTimes.is_none()
-                        && submissionReceivers.is_none()
-                    {
-                        let builder = contract
-                            .safeMint_1(to, quantity, _tokenURIs)
-                            .from(user_address.clone());
-                        let result = builder.send().await.map_err(|e| {
-                            BlockchainError::SendTransactionError(format!(
-                                "Failed to send safeMint_1 transaction: {}",
-                                e
-                            ))
-                        })?;
-                        result
-                    } else {
-                        let builder = contract
-                            .safeMint_0(
-                                to,
-                                quantity,
-                                _tokenURIs,
-                                watermarks.unwrap(),
-                                captureTimes.unwrap(),
-                                captureDevices.unwrap(),
-                                captureCompanies.unwrap(),
-                                submissionTimes.unwrap(),
-                                submissionReceivers.unwrap(),
-                            )
-                            .from(user_address.clone());
-                        let result = builder.send().await.map_err(|e| {
-                            BlockchainError::SendTransactionError(format!(
-                                "Failed to send safeMint_0 transaction: {}",
-                                e
-                            ))
-                        })?;
-                        result
-                    };
-                    let hash = tx_builder.watch().await.map_err(|e| {
-                        BlockchainError::WatchTransactionError(format!(
-                            "Failed to watch safeMint transaction: {}",
-                            e
-                        ))
-                    })?;
-                    contract_method_result = ContractMethodResult::TxHash(hash);
-                }
-                ContractMethod::safeBatchTransferFrom {
-                    by,
-                    from,
-                    to,
-                    tokenIds,
-                    data,
-                } => {
-                    let result = contract
-                        .safeBatchTransferFrom(by, from, to, tokenIds, data)
-                        .send()
-                        .await
-                        .map_err(|e| {
-                            BlockchainError::SendTransactionError(format!(
-                                "Failed to send safeBatchTransferFrom transaction: {}",
-                                e
-                            ))
-                        })?;
-                    let hash = result.watch().await.map_err(|e| {
-                        BlockchainError::WatchTransactionError(format!(
-                            "Failed to watch safeBatchTransferFrom transaction: {}",
-                            e
-                        ))
-                    })?;
-                    contract_method_result = ContractMethodResult::TxHash(hash);
-                }
-                ContractMethod::batchTransferFrom { from, to, tokenIds } => {
-                    let result = contract
-                        .batchTransferFrom(from, to, tokenIds)
-                        .send()
-                        .await
-                        .map_err(|e| {
-                            BlockchainError::SendTransactionError(format!(
-                                "Failed to send batchTransferFrom transaction: {}",
-                                e
-                            ))
-                        })?;
-                    let hash = result.watch().await.map_err(|e| {
-                        BlockchainError::WatchTransactionError(format!(
-                            "Failed to watch batchTransferFrom transaction: {}",
-                            e
-                        ))
-                    })?;
-                    contract_method_result = ContractMethodResult::TxHash(hash);
-                }
-                ContractMethod::burn { tokenId } => {
-                    let result = contract.burn(tokenId).send().await.map_err(|e| {
-                        BlockchainError::SendTransactionError(format!(
-                            "Failed to send burn transaction: {}",
-                            e
-                        ))
-                    })?;
-                    let hash = result.watch().await.map_err(|e| {
-                        BlockchainError::WatchTransactionError(format!(
-                            "Failed to watch burn transaction: {}",
-                            e
-                        ))
-                    })?;
-                    contract_method_result = ContractMethodResult::TxHash(hash);
-                }
-                ContractMethod::batchBurn { tokenIds } => {
-                    let result = contract.batchBurn(tokenIds).send().await.map_err(|e| {
-                        BlockchainError::SendTransactionError(format!(
-                            "Failed to send batchBurn transaction: {}",
-                            e
-                        ))
-                    })?;
-                    let hash = result.watch().await.map_err(|e| {
-                        BlockchainError::WatchTransactionError(format!(
-                            "Failed to watch batchBurn transaction: {}",
-                            e
-                        ))
-                    })?;
-                    contract_method_result = ContractMethodResult::TxHash(hash);
-                }
-            },
         }
-        Ok(contract_method_result)
+        Ok(sales)
+    }
+
+    /// 合约中最新的一条销售记录，没有销售时返回 None。
+    /// 合约不提供记录条数，先按 1、2、4… 探测到越界的下标，再二分查找最后一个存在的下标
+    pub async fn latest_sale(&self, token_id: U256) -> Result<Option<SaleInfo>, BlockchainError> {
+        if self.sale_at(token_id, 0).await?.is_none() {
+            return Ok(None);
+        }
+        // 始终满足 low 存在、high 越界
+        let (mut low, mut high) = (0u64, 1u64);
+        while self.sale_at(token_id, high).await?.is_some() {
+            low = high;
+            high = high.saturating_mul(2);
+        }
+        while high - low > 1 {
+            let middle = low + (high - low) / 2;
+            if self.sale_at(token_id, middle).await?.is_some() {
+                low = middle;
+            } else {
+                high = middle;
+            }
+        }
+        self.image_sale_history(token_id, U256::from(low))
+            .await
+            .map(Some)
+    }
+
+    /// view
+    pub async fn get_approved(&self, token_id: U256) -> Result<Address, BlockchainError> {
+        let result = self
+            .contract()?
+            .getApproved(token_id)
+            .call()
+            .await
+            .map_err(|e| call_error("getApproved", e))?;
+        Ok(result._0)
+    }
+
+    pub async fn safe_transfer_from(
+        &self,
+        from: Address,
+        to: Address,
+        token_id: U256,
+    ) -> Result<TxHash, BlockchainError> {
+        let call = self.contract()?.safeTransferFrom_0(from, to, token_id);
+        self.send_and_watch("safeTransferFrom_0", call).await
+    }
+
+    pub async fn safe_transfer_from_with_data(
+        &self,
+        from: Address,
+        to: Address,
+        token_id: U256,
+        data: Bytes,
+    ) -> Result<TxHash, BlockchainError> {
+        let call = self.contract()?.safeTransferFrom_1(from, to, token_id, data);
+        self.send_and_watch("safeTransferFrom_1", call).await
+    }
+
+    pub async fn safe_transfer_from_with_value(
+        &self,
+        from: Address,
+        to: Address,
+        token_id: U256,
+        value: U256,
+    ) -> Result<TxHash, BlockchainError> {
+        let call = self
+            .contract()?
+            .safeTransferFromWithValue(from, to, token_id, value);
+        self.send_and_watch("safeTransferFromWithValue", call).await
+    }
+
+    pub async fn transfer_from(
+        &self,
+        from: Address,
+        to: Address,
+        token_id: U256,
+    ) -> Result<TxHash, BlockchainError> {
+        let call = self.contract()?.transferFrom(from, to, token_id);
+        self.send_and_watch("transferFrom", call).await
     }
+
+    pub async fn approve(&self, to: Address, token_id: U256) -> Result<TxHash, BlockchainError> {
+        let call = self.contract()?.approve(to, token_id);
+        self.send_and_watch("approve", call).await
+    }
+
+    pub async fn set_approval_for_all(
+        &self,
+        operator: Address,
+        approved: bool,
+    ) -> Result<TxHash, BlockchainError> {
+        let call = self.contract()?.setApprovalForAll(operator, approved);
+        self.send_and_watch("setApprovalForAll", call).await
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    pub async fn modify_image_info(
+        &self,
+        token_id: U256,
+        token_uri: String,
+        owner: Address,
+        watermark: String,
+        capture_time: U256,
+        capture_device: String,
+        capture_company: String,
+        submission_time: U256,
+        submission_receiver: String,
+    ) -> Result<TxHash, BlockchainError> {
+        let call = self.contract()?.modifyImageInfo(
+            token_id,
+            token_uri,
+            owner,
+            watermark,
+            capture_time,
+            capture_device,
+            capture_company,
+            submission_time,
+            submission_receiver,
+        );
+        self.send_and_watch("modifyImageInfo", call).await
+    }
+
+    pub async fn modify_capture_info(
+        &self,
+        token_id: U256,
+        capture_time: U256,
+        capture_device: String,
+        capture_company: String,
+    ) -> Result<TxHash, BlockchainError> {
+        let call = self.contract()?.modifyCaptureInfo(
+            token_id,
+            capture_time,
+            capture_device,
+            capture_company,
+        );
+        self.send_and_watch("modifyCaptureInfo", call).await
+    }
+
+    /// 只铸造 token 并设置 tokenURI，不写入图片信息
+    pub async fn safe_mint(
+        &self,
+        to: Address,
+        quantity: U256,
+        token_uris: Vec<String>,
+    ) -> Result<TxHash, BlockchainError> {
+        let call = self.contract()?.safeMint_1(to, quantity, token_uris);
+        self.send_and_watch("safeMint_1", call).await
+    }
+
+    /// 铸造 token 并同时写入每张图片的水印及拍摄、提交信息
+    #[allow(clippy::too_many_arguments)]
+    pub async fn safe_mint_with_info(
+        &self,
+        to: Address,
+        quantity: U256,
+        token_uris: Vec<String>,
+        watermarks: Vec<String>,
+        capture_times: Vec<U256>,
+        capture_devices: Vec<String>,
+        capture_companies: Vec<String>,
+        submission_times: Vec<U256>,
+        submission_receivers: Vec<String>,
+    ) -> Result<TxHash, BlockchainError> {
+        let tx_hash = self
+            .broadcast_safe_mint_with_info(
+                to,
+                quantity,
+                token_uris,
+                watermarks,
+                capture_times,
+                capture_devices,
+                capture_companies,
+                submission_times,
+                submission_receivers,
+            )
+            .await?;
+        self.wait_for_mined("safeMint_0", tx_hash).await
+    }
+
+    /// 只广播 safeMint 交易，返回交易哈希，调用方保存哈希之后再用 wait_for_mined 等待打包
+    #[allow(clippy::too_many_arguments)]
+    pub async fn broadcast_safe_mint_with_info(
+        &self,
+        to: Address,
+        quantity: U256,
+        token_uris: Vec<String>,
+        watermarks: Vec<String>,
+        capture_times: Vec<U256>,
+        capture_devices: Vec<String>,
+        capture_companies: Vec<String>,
+        submission_times: Vec<U256>,
+        submission_receivers: Vec<String>,
+    ) -> Result<TxHash, BlockchainError> {
+        let call = self.contract()?.safeMint_0(
+            to,
+            quantity,
+            token_uris,
+            watermarks,
+            capture_times,
+            capture_devices,
+            capture_companies,
+            submission_times,
+            submission_receivers,
+        );
+        self.broadcast("safeMint_0", call).await
+    }
+
+    pub async fn safe_batch_transfer_from(
+        &self,
+        by: Address,
+        from: Address,
+        to: Address,
+        token_ids: Vec<U256>,
+        data: Bytes,
+    ) -> Result<TxHash, BlockchainError> {
+        let call = self
+            .contract()?
+            .safeBatchTransferFrom(by, from, to, token_ids, data);
+        self.send_and_watch("safeBatchTransferFrom", call).await
+    }
+
+    pub async fn batch_transfer_from(
+        &self,
+        from: Address,
+        to: Address,
+        token_ids: Vec<U256>,
+    ) -> Result<TxHash, BlockchainError> {
+        let call = self.contract()?.batchTransferFrom(from, to, token_ids);
+        self.send_and_watch("batchTransferFrom", call).await
+    }
+
+    pub async fn burn(&self, token_id: U256) -> Result<TxHash, BlockchainError> {
+        let call = self.contract()?.burn(token_id);
+        self.send_and_watch("burn", call).await
+    }
+
+    pub async fn batch_burn(&self, token_ids: Vec<U256>) -> Result<TxHash, BlockchainError> {
+        let call = self.contract()?.batchBurn(token_ids);
+        self.send_and_watch("batchBurn", call).await
+    }
+}
+
+fn call_error(method: &str, e: alloy::contract::Error) -> BlockchainError {
+    BlockchainError::ContractCallError(format!("Failed to call {}: {}", method, e))
 }
 #[cfg(test)]
 mod tests {
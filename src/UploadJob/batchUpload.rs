@@ -0,0 +1,327 @@
+use crate::Error::ImageError;
+use crate::IPFSImageStorage::storeImage::{add_bytes, pin_cid, unpin_cid, IPFS_API_URL};
+use crate::Router::Image_routers::MAX_IMAGE_FILE_SIZE;
+use crate::UploadJob::uploadJob::{
+    broadcast_mint, confirm_mint, user_address, watermark_reference, ImageUploadMetadata,
+};
+use crate::WatermarkService::engine::{engine_for_user, EngineConfig};
+use crate::WatermarkService::watermarkservice::{execute_watermark_bytes, record_image};
+use rocket::futures::stream::{self, StreamExt};
+use serde::{Deserialize, Serialize};
+use std::fs::File;
+use std::io::{self, Read};
+use std::path::{Path, PathBuf};
+
+// 批量登记：多张图片先并发完成加水印、存储和固定，再按批次合并到尽量少的 safeMint 交易中铸造，
+// 每个文件单独返回结果，单个文件失败不影响其他文件
+
+// 一次请求最多处理的图片数量
+pub const MAX_BATCH_FILES: usize = 500;
+// 同时进行加水印和 ipfs 上传的文件数量
+pub const BATCH_CONCURRENCY: usize = 4;
+// 每笔 safeMint 交易最多铸造的 token 数量，避免超出区块 gas 上限
+pub const MINT_BATCH_SIZE: usize = 50;
+
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct BatchFileResult {
+    pub file_name: String,
+    pub cid: Option<String>,
+    pub token_id: Option<String>,
+    pub tx_hash: Option<String>,
+    pub error: Option<String>,
+}
+
+/// 待处理的一个文件，path 为 Err 时表示在保存或解压阶段已经失败
+pub struct BatchInput {
+    pub file_name: String,
+    pub path: Result<PathBuf, String>,
+}
+
+/// 将 ZIP 压缩包中的文件解压到 dir，跳过目录、隐藏文件和 macOS 的资源文件，
+/// 单个文件超过大小上限时记为失败，文件总数超过 limit 或解压后的总字节数超过 max_total_bytes 时返回错误
+pub fn extract_archive(
+    archive: &Path,
+    dir: &Path,
+    limit: usize,
+    max_total_bytes: u64,
+) -> Result<Vec<BatchInput>, ImageError> {
+    let file = File::open(archive).map_err(|e| ImageError::IOError(e.to_string()))?;
+    let mut zip = zip::ZipArchive::new(file)
+        .map_err(|e| ImageError::IOError(format!("invalid zip archive: {}", e)))?;
+
+    let mut inputs = Vec::new();
+    // 已经解压的字节数，包括超过单个文件上限而被丢弃的部分
+    let mut total_bytes: u64 = 0;
+    for index in 0..zip.len() {
+        let mut entry = zip
+            .by_index(index)
+            .map_err(|e| ImageError::IOError(format!("invalid zip archive: {}", e)))?;
+        if entry.is_dir() {
+            continue;
+        }
+        // enclosed_name 会拒绝绝对路径和包含 .. 的路径
+        let Some(name) = entry.enclosed_name().map(|path| path.to_path_buf()) else {
+            continue;
+        };
+        let hidden = name.components().any(|component| {
+            let component = component.as_os_str().to_string_lossy();
+            component.starts_with('.') || component == "__MACOSX"
+        });
+        if hidden {
+            continue;
+        }
+        if inputs.len() >= limit {
+            return Err(ImageError::IOError(format!(
+                "archive contains more than {} files",
+                limit
+            )));
+        }
+
+        let file_name = name.to_string_lossy().to_string();
+        let path = dir.join(format!("{}.entry", index));
+        // 每个文件最多读取到单个文件上限或剩余总量再多一个字节，用于判断是否超限
+        let remaining = max_total_bytes - total_bytes;
+        let result = (|| {
+            let mut output = File::create(&path).map_err(|e| e.to_string())?;
+            // 以解压后的实际大小为准，不信任压缩包中记录的大小
+            let read_limit = MAX_IMAGE_FILE_SIZE.min(remaining) + 1;
+            let written = io::copy(&mut (&mut entry).take(read_limit), &mut output)
+                .map_err(|e| e.to_string())?;
+            Ok::<_, String>(written)
+        })();
+        let written = *result.as_ref().unwrap_or(&0);
+        if written > remaining {
+            let _ = std::fs::remove_file(&path);
+            return Err(ImageError::ImageRejected(format!(
+                "archive expands to more than {} bytes",
+                max_total_bytes
+            )));
+        }
+        total_bytes += written;
+        let result = result.and_then(|written| {
+            if written > MAX_IMAGE_FILE_SIZE {
+                return Err(format!("file exceeds {} bytes", MAX_IMAGE_FILE_SIZE));
+            }
+            if written == 0 {
+                return Err("file is empty".to_string());
+            }
+            Ok(path.clone())
+        });
+        if result.is_err() {
+            let _ = std::fs::remove_file(&path);
+        }
+        inputs.push(BatchInput {
+            file_name,
+            path: result,
+        });
+    }
+    Ok(inputs)
+}
+
+/// 批量登记图片：并发完成加水印、存储和固定后，每 MINT_BATCH_SIZE 个文件合并为一笔 safeMint 交易，
+/// 返回的结果与 inputs 一一对应
+pub async fn register_batch(
+    username: &str,
+    inputs: Vec<BatchInput>,
+    metadata: &ImageUploadMetadata,
+) -> Vec<BatchFileResult> {
+    let engine = match engine_for_user(username).await {
+        Ok(engine) => engine,
+        Err(e) => {
+            return inputs
+                .into_iter()
+                .map(|input| BatchFileResult {
+                    file_name: input.file_name,
+                    error: Some(e.to_string()),
+                    ..Default::default()
+                })
+                .collect();
+        }
+    };
+    let engine = &engine;
+    let stored: Vec<(BatchFileResult, Option<String>)> = stream::iter(inputs)
+        .map(|input| async move {
+            let mut result = BatchFileResult {
+                file_name: input.file_name,
+                ..Default::default()
+            };
+            let watermark = match input.path {
+                Ok(path) => match store_image(&path, engine).await {
+                    Ok((cid, watermark)) => {
+                        result.cid = Some(cid);
+                        Some(watermark)
+                    }
+                    Err(e) => {
+                        result.error = Some(e.to_string());
+                        None
+                    }
+                },
+                Err(e) => {
+                    result.error = Some(e);
+                    None
+                }
+            };
+            (result, watermark)
+        })
+        .buffered(BATCH_CONCURRENCY)
+        .collect()
+        .await;
+
+    let mut results: Vec<BatchFileResult> = Vec::with_capacity(stored.len());
+    let mut pending = Vec::new();
+    for (result, watermark) in stored {
+        if let (Some(cid), Some(watermark)) = (result.cid.clone(), watermark) {
+            pending.push((results.len(), (cid, watermark)));
+        }
+        results.push(result);
+    }
+    if pending.is_empty() {
+        return results;
+    }
+
+    let to = match user_address(username).await {
+        Ok(to) => Some(to),
+        Err(e) => {
+            for (index, (cid, _)) in &pending {
+                results[*index].error = Some(e.to_string());
+                unpin(cid).await;
+            }
+            None
+        }
+    };
+    let Some(to) = to else {
+        return results;
+    };
+
+    for chunk in pending.chunks(MINT_BATCH_SIZE) {
+        let images: Vec<(String, String)> = chunk
+            .iter()
+            .map(|(_, (cid, watermark))| (cid.clone(), watermark_reference(watermark)))
+            .collect();
+        let tx_hash = match broadcast_mint(username, to, &images, metadata).await {
+            Ok(tx_hash) => tx_hash,
+            Err(e) => {
+                // 交易没有广播出去时取消固定，不在 ipfs 中留下没有对应 token 的图片
+                for (index, (cid, _)) in chunk {
+                    results[*index].error = Some(e.to_string());
+                    unpin(cid).await;
+                }
+                continue;
+            }
+        };
+        // 交易已经广播，之后的失败（包括等待回执超时）都保留固定并返回交易哈希，交易仍可能被打包
+        let minted = match confirm_mint(username, tx_hash).await {
+            Ok(token_ids) if token_ids.len() == images.len() => Ok(token_ids),
+            Ok(token_ids) => Err(ImageError::MintError(format!(
+                "expected {} tokens in {} but found {}",
+                images.len(),
+                tx_hash,
+                token_ids.len()
+            ))),
+            Err(e) => Err(e),
+        };
+        let token_ids = match minted {
+            Ok(token_ids) => token_ids,
+            Err(e) => {
+                for (index, _) in chunk {
+                    results[*index].tx_hash = Some(tx_hash.to_string());
+                    results[*index].error = Some(e.to_string());
+                }
+                continue;
+            }
+        };
+
+        // safeMint 按 tokenURI 的顺序连续铸造，token id 与文件按下标对应
+        for ((index, (cid, watermark)), token_id) in chunk.iter().zip(token_ids) {
+            let result = &mut results[*index];
+            result.token_id = Some(token_id.to_string());
+            result.tx_hash = Some(tx_hash.to_string());
+            let recorded = record_image(
+                username,
+                cid,
+                Some(token_id.to_string()),
+                watermark,
+                engine,
+                metadata,
+            )
+            .await;
+            if let Err(e) = recorded {
+                result.error = Some(format!(
+                    "token {} minted but failed to record image: {}",
+                    token_id, e
+                ));
+            }
+        }
+    }
+    results
+}
+
+// 加水印、存储并固定单个文件，返回 cid 和水印图片的 base64
+async fn store_image(path: &Path, engine: &EngineConfig) -> Result<(String, String), ImageError> {
+    let img_bytes = tokio::fs::read(path)
+        .await
+        .map_err(|e| ImageError::IOError(e.to_string()))?;
+    let engine = engine.clone();
+    let (watermarked, watermark_base64) =
+        tokio::task::spawn_blocking(move || execute_watermark_bytes(&img_bytes, &engine))
+            .await
+            .map_err(|e| ImageError::WatermarkProcessError(e.to_string()))??;
+
+    let cid = add_bytes(watermarked, "watermarked_image.jpg", IPFS_API_URL)
+        .await
+        .map_err(|e| ImageError::IpfsError(e.to_string()))?;
+    pin_cid(&cid, IPFS_API_URL)
+        .await
+        .map_err(|e| ImageError::IpfsError(e.to_string()))?;
+    Ok((cid, watermark_base64))
+}
+
+async fn unpin(cid: &str) {
+    if let Err(e) = unpin_cid(cid, IPFS_API_URL).await {
+        println!("Failed to unpin {} after mint failure: {}", cid, e);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+    use zip::write::FileOptions;
+
+    #[test]
+    fn test_extract_archive() {
+        let dir = std::env::temp_dir().join(format!("batch_upload_test_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let archive = dir.join("images.zip");
+
+        let mut writer = zip::ZipWriter::new(File::create(&archive).unwrap());
+        let options = FileOptions::default();
+        writer.add_directory("photos/", options).unwrap();
+        writer.start_file("photos/a.jpg", options).unwrap();
+        writer.write_all(b"first").unwrap();
+        writer.start_file("b.png", options).unwrap();
+        writer.write_all(b"second").unwrap();
+        writer.start_file("empty.jpg", options).unwrap();
+        writer.start_file(".DS_Store", options).unwrap();
+        writer.write_all(b"hidden").unwrap();
+        writer.start_file("__MACOSX/._a.jpg", options).unwrap();
+        writer.write_all(b"resource").unwrap();
+        writer.finish().unwrap();
+
+        let inputs = extract_archive(&archive, &dir, 10, 11).unwrap();
+        let names: Vec<&str> = inputs
+            .iter()
+            .map(|input| input.file_name.as_str())
+            .collect();
+        assert_eq!(names, vec!["photos/a.jpg", "b.png", "empty.jpg"]);
+        let first = inputs[0].path.as_ref().unwrap();
+        assert_eq!(std::fs::read(first).unwrap(), b"first");
+        assert!(inputs[2].path.is_err());
+
+        // 超过文件数量上限
+        assert!(extract_archive(&archive, &dir, 1, 11).is_err());
+        // 解压后的总字节数超过上限
+        assert!(extract_archive(&archive, &dir, 10, 10).is_err());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
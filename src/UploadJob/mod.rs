@@ -0,0 +1,8 @@
+/** 这个模块的任务：
+   1.保存上传的图片并创建异步任务，立即返回任务 ID
+   2.后台 worker 依次完成加水印、存储到 ipfs、固定、铸造
+   3.将任务进度写入数据库，并推送给轮询和 SSE 接口
+   4.批量登记多张图片，合并为尽量少的 safeMint 交易铸造
+*/
+pub mod batchUpload;
+pub mod uploadJob;
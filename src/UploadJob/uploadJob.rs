@@ -0,0 +1,506 @@
+use crate::DataBase::{get_db, Images, UploadJobs, Users};
+use crate::Error::{BlockchainError, ImageError};
+use crate::IPFSImageStorage::storeImage::{
+    add_bytes, pin_cid, unpin_cid, IPFS_API_URL, IPFS_GATEWAY_URL,
+};
+use crate::Router::onchain_router::connect_handler;
+use crate::Router::validation;
+use crate::WatermarkService::engine::{engine_for_user, EngineConfig};
+use crate::WatermarkService::watermarkservice::{execute_watermark_bytes, record_image};
+use alloy::primitives::aliases::TxHash;
+use alloy::primitives::{Address, U256};
+use base64::engine::general_purpose;
+use base64::Engine;
+use rbatis::rbdc::datetime::DateTime;
+use rocket::fairing::AdHoc;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::path::PathBuf;
+use std::sync::{Arc, OnceLock};
+use std::time::{SystemTime, UNIX_EPOCH};
+use tokio::sync::{broadcast, mpsc, Semaphore};
+use validator::Validate;
+
+// 异步上传任务：上传接口只保存文件并写入一条 received 状态的任务，
+// 后台 worker 依次推进 watermarking -> storing -> pinning -> (minting) -> done，任一阶段出错则置为 failed，
+// 每次状态变化都会写回数据库并广播给订阅进度的 SSE 连接。
+// register_image 创建的任务会在固定后铸造 token，交易广播之前失败时取消固定，成功后把 token id 记录到 images 表。
+// 服务重启后未完成的任务会重新排队：已经存储的水印图片直接沿用，已经广播的铸造交易按保存的哈希核对结果
+
+pub const STATE_RECEIVED: &str = "received";
+pub const STATE_WATERMARKING: &str = "watermarking";
+pub const STATE_STORING: &str = "storing";
+pub const STATE_PINNING: &str = "pinning";
+pub const STATE_MINTING: &str = "minting";
+pub const STATE_DONE: &str = "done";
+pub const STATE_FAILED: &str = "failed";
+
+// 同时处理的任务数，水印脚本和 ipfs 上传都比较耗资源
+const MAX_CONCURRENT_JOBS: usize = 2;
+const PROGRESS_CAPACITY: usize = 256;
+
+static QUEUE: OnceLock<mpsc::UnboundedSender<String>> = OnceLock::new();
+static PROGRESS: OnceLock<broadcast::Sender<JobProgress>> = OnceLock::new();
+
+// 随文件一起提交的可选图片信息
+#[derive(Clone, Debug, Default, Serialize, Deserialize, Validate)]
+pub struct ImageUploadMetadata {
+    #[validate(custom(function = "validation::unix_time"))]
+    pub capture_time: Option<u64>,
+    #[validate(custom(function = "validation::text"))]
+    pub capture_device: Option<String>,
+    #[validate(custom(function = "validation::text"))]
+    pub capture_company: Option<String>,
+    #[validate(custom(function = "validation::unix_time"))]
+    pub submission_time: Option<u64>,
+    #[validate(custom(function = "validation::text"))]
+    pub submission_receiver: Option<String>,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct JobProgress {
+    pub job_id: String,
+    pub state: String,
+    pub cid: Option<String>,
+    pub token_id: Option<String>,
+    pub tx_hash: Option<String>,
+    pub error: Option<String>,
+}
+
+impl From<&UploadJobs> for JobProgress {
+    fn from(job: &UploadJobs) -> Self {
+        JobProgress {
+            job_id: job.job_id.clone().unwrap_or_default(),
+            state: job.state.clone().unwrap_or_default(),
+            cid: job.cid.clone(),
+            token_id: job.token_id.clone(),
+            tx_hash: job.tx_hash.clone(),
+            error: job.error.clone(),
+        }
+    }
+}
+
+/// 任务是否已经结束（成功或失败）
+pub fn is_finished(state: &str) -> bool {
+    state == STATE_DONE || state == STATE_FAILED
+}
+
+/// 上传文件在任务完成前保存的目录
+pub fn job_dir() -> PathBuf {
+    std::env::temp_dir().join("blockchain-image-jobs")
+}
+
+pub fn new_job_id() -> String {
+    format!("{:032x}", rand::random::<u128>())
+}
+
+/// 订阅所有任务的进度变化
+pub fn subscribe_progress() -> broadcast::Receiver<JobProgress> {
+    progress().subscribe()
+}
+
+fn progress() -> &'static broadcast::Sender<JobProgress> {
+    PROGRESS.get_or_init(|| broadcast::channel(PROGRESS_CAPACITY).0)
+}
+
+/// 启动后台 worker，并重新排队上次服务停止时未完成的任务
+pub fn upload_job_worker() -> AdHoc {
+    AdHoc::on_liftoff("Upload Job Worker", |_| {
+        Box::pin(async {
+            let (sender, mut receiver) = mpsc::unbounded_channel::<String>();
+            if QUEUE.set(sender).is_err() {
+                return;
+            }
+            tokio::spawn(async move {
+                let semaphore = Arc::new(Semaphore::new(MAX_CONCURRENT_JOBS));
+                while let Some(job_id) = receiver.recv().await {
+                    let Ok(permit) = semaphore.clone().acquire_owned().await else {
+                        break;
+                    };
+                    tokio::spawn(async move {
+                        process_job(&job_id).await;
+                        drop(permit);
+                    });
+                }
+            });
+            tokio::spawn(async {
+                match UploadJobs::select_unfinished(get_db().await).await {
+                    Ok(jobs) => {
+                        for job_id in jobs.into_iter().filter_map(|job| job.job_id) {
+                            enqueue(job_id);
+                        }
+                    }
+                    Err(e) => println!("Failed to load unfinished upload jobs: {}", e),
+                }
+            });
+        })
+    })
+}
+
+fn enqueue(job_id: String) -> bool {
+    match QUEUE.get() {
+        Some(queue) => queue.send(job_id).is_ok(),
+        None => false,
+    }
+}
+
+/// 保存任务并放入队列，mint 为 true 时存储完成后继续铸造 token
+pub async fn submit_job(
+    username: &str,
+    job_id: &str,
+    file_path: PathBuf,
+    metadata: &ImageUploadMetadata,
+    mint: bool,
+) -> Result<UploadJobs, ImageError> {
+    if QUEUE.get().is_none() {
+        return Err(ImageError::IOError(
+            "upload job worker is not running".to_string(),
+        ));
+    }
+    let now = DateTime::now();
+    let job = UploadJobs {
+        id: None,
+        job_id: Some(job_id.to_string()),
+        username: Some(username.to_string()),
+        state: Some(STATE_RECEIVED.to_string()),
+        file_path: Some(file_path.to_string_lossy().to_string()),
+        metadata: serde_json::to_string(metadata).ok(),
+        mint: Some(mint),
+        cid: None,
+        token_id: None,
+        tx_hash: None,
+        error: None,
+        created_at: Some(now.clone()),
+        updated_at: Some(now),
+        watermark_base64: None,
+        engine: None,
+    };
+    UploadJobs::insert(get_db().await, &job)
+        .await
+        .map_err(ImageError::DatabaseError)?;
+    let _ = progress().send(JobProgress::from(&job));
+    if !enqueue(job_id.to_string()) {
+        return Err(ImageError::IOError(
+            "upload job worker is not running".to_string(),
+        ));
+    }
+    Ok(job)
+}
+
+/// 查询属于 username 的任务，不属于该用户时同样视为不存在
+pub async fn load_job(job_id: &str, username: &str) -> Result<UploadJobs, ImageError> {
+    match UploadJobs::select_by_job_id(get_db().await, job_id).await {
+        Ok(Some(job)) if job.username.as_deref() == Some(username) => Ok(job),
+        Ok(_) => Err(ImageError::JobNotFound(job_id.to_string())),
+        Err(e) => Err(ImageError::DatabaseError(e)),
+    }
+}
+
+async fn process_job(job_id: &str) {
+    let mut job = match UploadJobs::select_by_job_id(get_db().await, job_id).await {
+        Ok(Some(job)) => job,
+        Ok(None) => return,
+        Err(e) => {
+            println!("Failed to load upload job {}: {}", job_id, e);
+            return;
+        }
+    };
+    if is_finished(job.state.as_deref().unwrap_or_default()) {
+        return;
+    }
+
+    if let Err(e) = run_stages(&mut job).await {
+        println!("Upload job {} failed: {}", job_id, e);
+        job.error = Some(e.to_string());
+        update_state(&mut job, STATE_FAILED).await;
+    }
+    if let Some(path) = &job.file_path {
+        let _ = tokio::fs::remove_file(path).await;
+    }
+}
+
+async fn run_stages(job: &mut UploadJobs) -> Result<(), ImageError> {
+    let username = job.username.clone().unwrap_or_default();
+    // 重启后继续的任务如果已经存储过水印图片，直接沿用，重新加水印会得到另一个随机水印
+    let (cid, watermark_base64, engine) = match stored_image(job) {
+        Some(stored) => stored,
+        None => watermark_and_store(job, &username).await?,
+    };
+
+    update_state(job, STATE_PINNING).await;
+    pin_cid(&cid, IPFS_API_URL)
+        .await
+        .map_err(|e| ImageError::IpfsError(e.to_string()))?;
+
+    if !job.mint.unwrap_or(false) {
+        record_once(
+            &username,
+            &cid,
+            None,
+            &watermark_base64,
+            &engine,
+            &job_metadata(job),
+        )
+        .await?;
+        update_state(job, STATE_DONE).await;
+        return Ok(());
+    }
+
+    update_state(job, STATE_MINTING).await;
+    let tx_hash = match job.tx_hash.as_deref().and_then(|hash| hash.parse().ok()) {
+        // 上次已经广播过铸造交易，只按交易哈希核对结果，不再重新铸造
+        Some(tx_hash) => tx_hash,
+        None => {
+            match broadcast_job_mint(job, &cid, &watermark_reference(&watermark_base64)).await {
+                Ok(tx_hash) => {
+                    // 先保存交易哈希再等待打包
+                    job.tx_hash = Some(tx_hash.to_string());
+                    update_state(job, STATE_MINTING).await;
+                    tx_hash
+                }
+                Err(e) => {
+                    // 交易没有广播出去时取消固定，不在 ipfs 中留下没有对应 token 的图片
+                    if let Err(unpin_error) = unpin_cid(&cid, IPFS_API_URL).await {
+                        println!(
+                            "Failed to unpin {} after mint failure: {}",
+                            cid, unpin_error
+                        );
+                    }
+                    return Err(e);
+                }
+            }
+        }
+    };
+    // 交易广播之后的任何失败（包括等待回执超时）都不取消固定，交易仍可能被打包
+    let token_id = confirm_mint(&username, tx_hash)
+        .await?
+        .first()
+        .copied()
+        .ok_or_else(|| ImageError::MintError(format!("no token minted in {}", tx_hash)))?;
+    job.token_id = Some(token_id.to_string());
+    // token 已经上链无法回滚，记录失败时在错误信息中保留 token id 便于人工补录
+    record_once(
+        &username,
+        &cid,
+        Some(token_id.to_string()),
+        &watermark_base64,
+        &engine,
+        &job_metadata(job),
+    )
+    .await
+    .map_err(|e| {
+        ImageError::MintError(format!(
+            "token {} minted but failed to record image: {}",
+            token_id, e
+        ))
+    })?;
+
+    update_state(job, STATE_DONE).await;
+    Ok(())
+}
+
+// 上次运行已经保存的 (cid, 水印, 引擎)，水印和引擎在加水印后写入任务，cid 在存储后写入
+fn stored_image(job: &UploadJobs) -> Option<(String, String, EngineConfig)> {
+    let engine = serde_json::from_str(job.engine.as_deref()?).ok()?;
+    Some((job.cid.clone()?, job.watermark_base64.clone()?, engine))
+}
+
+// 加水印并存储到 ipfs，每一步的结果都写回任务，返回 (cid, 水印, 引擎)
+async fn watermark_and_store(
+    job: &mut UploadJobs,
+    username: &str,
+) -> Result<(String, String, EngineConfig), ImageError> {
+    let path = job.file_path.clone().unwrap_or_default();
+    let img_bytes = tokio::fs::read(&path)
+        .await
+        .map_err(|e| ImageError::IOError(format!("Failed to read {}: {}", path, e)))?;
+
+    update_state(job, STATE_WATERMARKING).await;
+    // 加水印是阻塞调用，放到阻塞线程池中执行
+    let engine = engine_for_user(username).await?;
+    let job_engine = engine.clone();
+    let (watermarked, watermark_base64) =
+        tokio::task::spawn_blocking(move || execute_watermark_bytes(&img_bytes, &job_engine))
+            .await
+            .map_err(|e| ImageError::WatermarkProcessError(e.to_string()))??;
+    job.watermark_base64 = Some(watermark_base64.clone());
+    job.engine = serde_json::to_string(&engine).ok();
+
+    update_state(job, STATE_STORING).await;
+    let cid = add_bytes(watermarked, "watermarked_image.jpg", IPFS_API_URL)
+        .await
+        .map_err(|e| ImageError::IpfsError(e.to_string()))?;
+    job.cid = Some(cid.clone());
+    Ok((cid, watermark_base64, engine))
+}
+
+// 重启前可能已经登记过这张图片，已登记时不再重复写入
+async fn record_once(
+    username: &str,
+    cid: &str,
+    token_id: Option<String>,
+    watermark_base64: &str,
+    engine: &EngineConfig,
+    metadata: &ImageUploadMetadata,
+) -> Result<(), ImageError> {
+    let registered = Images::select_by_cid(get_db().await, cid)
+        .await
+        .map_err(ImageError::DatabaseError)?;
+    if registered.is_some() {
+        return Ok(());
+    }
+    record_image(username, cid, token_id, watermark_base64, engine, metadata).await
+}
+
+// 创建任务时随文件提交的图片信息
+fn job_metadata(job: &UploadJobs) -> ImageUploadMetadata {
+    job.metadata
+        .as_deref()
+        .and_then(|metadata| serde_json::from_str(metadata).ok())
+        .unwrap_or_default()
+}
+
+// 链上只保存水印图片的 sha256，水印本身不公开
+pub(crate) fn watermark_reference(watermark_base64: &str) -> String {
+    let watermark = general_purpose::STANDARD
+        .decode(watermark_base64)
+        .unwrap_or_else(|_| watermark_base64.as_bytes().to_vec());
+    format!("sha256:{:x}", Sha256::digest(&watermark))
+}
+
+// 以上传者的地址广播铸造一个 token 的交易，拍摄信息取自上传时提交的字段
+async fn broadcast_job_mint(
+    job: &UploadJobs,
+    cid: &str,
+    watermark: &str,
+) -> Result<TxHash, ImageError> {
+    let username = job.username.as_deref().unwrap_or_default();
+    let to = user_address(username).await?;
+    broadcast_mint(
+        username,
+        to,
+        &[(cid.to_string(), watermark.to_string())],
+        &job_metadata(job),
+    )
+    .await
+}
+
+/// 广播一笔 safeMint 交易，为每个 (cid, 水印摘要) 铸造一个 token，tokenURI 为图片的网关地址，
+/// 所有 token 共用同一份拍摄信息，交易以 username 的名义记入交易日志。
+/// 只返回交易哈希，调用方应先保存哈希，再用 confirm_mint 等待结果
+pub(crate) async fn broadcast_mint(
+    username: &str,
+    to: Address,
+    images: &[(String, String)],
+    metadata: &ImageUploadMetadata,
+) -> Result<TxHash, ImageError> {
+    let count = images.len();
+    let submission_time = metadata.submission_time.unwrap_or_else(now_secs);
+
+    let handler = connect_handler()
+        .await
+        .map_err(mint_error)?
+        .on_behalf_of(username);
+    handler
+        .broadcast_safe_mint_with_info(
+            to,
+            U256::from(count),
+            images
+                .iter()
+                .map(|(cid, _)| format!("{}{}", IPFS_GATEWAY_URL, cid))
+                .collect(),
+            images
+                .iter()
+                .map(|(_, watermark)| watermark.clone())
+                .collect(),
+            vec![U256::from(metadata.capture_time.unwrap_or_default()); count],
+            vec![metadata.capture_device.clone().unwrap_or_default(); count],
+            vec![metadata.capture_company.clone().unwrap_or_default(); count],
+            vec![U256::from(submission_time); count],
+            vec![metadata.submission_receiver.clone().unwrap_or_default(); count],
+        )
+        .await
+        .map_err(mint_error)
+}
+
+/// 等待铸造交易（或替换它的加速交易）被打包，从回执中取出按铸造顺序排列的 token id
+pub(crate) async fn confirm_mint(username: &str, tx_hash: TxHash) -> Result<Vec<U256>, ImageError> {
+    let handler = connect_handler()
+        .await
+        .map_err(mint_error)?
+        .on_behalf_of(username);
+    let mined = handler
+        .wait_for_mined("safeMint_0", tx_hash)
+        .await
+        .map_err(mint_error)?;
+    handler.minted_token_ids(mined).await.map_err(mint_error)
+}
+
+fn mint_error(e: BlockchainError) -> ImageError {
+    ImageError::MintError(e.to_string())
+}
+
+// 查询用户的以太坊地址，铸造出的 token 归该地址所有
+pub(crate) async fn user_address(username: &str) -> Result<Address, ImageError> {
+    match Users::select_by_username(get_db().await, username).await {
+        Ok(Some(Users {
+            address: Some(address),
+            ..
+        })) => address
+            .parse()
+            .map_err(|_| ImageError::MintError(format!("invalid address of user {}", username))),
+        Ok(_) => Err(ImageError::MintError(format!(
+            "Failed to find address of user {}",
+            username
+        ))),
+        Err(e) => Err(ImageError::DatabaseError(e)),
+    }
+}
+
+pub(crate) fn now_secs() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default()
+}
+
+// 更新任务状态，写库失败只打印日志，进度照常广播
+async fn update_state(job: &mut UploadJobs, state: &str) {
+    job.state = Some(state.to_string());
+    job.updated_at = Some(DateTime::now());
+    if let Err(e) = UploadJobs::update_by_column(get_db().await, job, "id").await {
+        println!("Failed to update upload job: {}", e);
+    }
+    let _ = progress().send(JobProgress::from(&*job));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_submit_job_requires_worker() {
+        // 未挂载 worker 时直接拒绝，不写入数据库
+        let result = submit_job(
+            "test_user",
+            &new_job_id(),
+            job_dir().join("missing"),
+            &ImageUploadMetadata::default(),
+            false,
+        )
+        .await;
+        assert!(matches!(result, Err(ImageError::IOError(_))));
+        assert!(is_finished(STATE_FAILED));
+        assert!(!is_finished(STATE_MINTING));
+    }
+
+    #[test]
+    fn test_watermark_reference() {
+        let watermark = general_purpose::STANDARD.encode(b"watermark");
+        assert_eq!(
+            watermark_reference(&watermark),
+            format!("sha256:{:x}", Sha256::digest(b"watermark"))
+        );
+        assert_eq!(watermark_reference(&watermark).len(), "sha256:".len() + 64);
+    }
+}
@@ -0,0 +1,408 @@
+use crate::Error::ImageError;
+use crate::WatermarkService::engine::{EngineConfig, WatermarkEngine};
+use base64::engine::general_purpose;
+use base64::Engine;
+use image::codecs::jpeg::JpegEncoder;
+use image::imageops::{self, FilterType};
+use image::{DynamicImage, GrayImage, RgbImage};
+use imageproc::geometric_transformations::{rotate_about_center, Interpolation};
+use imageproc::noise::gaussian_noise;
+use serde::{Deserialize, Serialize};
+use std::path::{Path, PathBuf};
+
+// 水印鲁棒性测试：对语料目录中的每张图片用指定引擎加水印，再依次施加各种攻击，
+// 用同一引擎提取水印并与嵌入的水印比较，报告误码率，以及攻击后图片相对带水印图片的 PSNR 和 SSIM。
+// 缩放类攻击（resize、screenshot）在提取前恢复到原尺寸，模拟验证方先将可疑图片缩放到登记尺寸；
+// 裁剪和旋转不做恢复。由 tests/watermarkBenchmark.rs 和 watermarkBenchmark 命令调用
+
+// 完全相同的图片的 PSNR 记为该值
+const MAX_PSNR: f64 = 100.0;
+// SSIM 的窗口大小和步长
+const SSIM_WINDOW: u32 = 8;
+const SSIM_STEP: u32 = 4;
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "attack", rename_all = "snake_case")]
+pub enum Attack {
+    // 不做处理，作为基准
+    Original,
+    // JPEG 重新压缩
+    Jpeg { quality: u8 },
+    // 缩放到 scale 倍后恢复原尺寸
+    Resize { scale: f32 },
+    // 保留左上角 fraction 比例的宽和高
+    Crop { fraction: f32 },
+    // 绕中心旋转，空出的部分填黑色
+    Rotate { degrees: f32 },
+    // 高斯噪声，sigma 为像素值的标准差
+    Noise { sigma: f64 },
+    // 截屏：按显示比例缩放、亮度和对比度变化后保存为 JPEG，再恢复原尺寸
+    Screenshot { scale: f32, quality: u8 },
+}
+
+impl Attack {
+    /// 报告中使用的名称，如 jpeg(quality=75)
+    pub fn label(&self) -> String {
+        match self {
+            Attack::Original => "original".to_string(),
+            Attack::Jpeg { quality } => format!("jpeg(quality={})", quality),
+            Attack::Resize { scale } => format!("resize(scale={})", scale),
+            Attack::Crop { fraction } => format!("crop(fraction={})", fraction),
+            Attack::Rotate { degrees } => format!("rotate(degrees={})", degrees),
+            Attack::Noise { sigma } => format!("noise(sigma={})", sigma),
+            Attack::Screenshot { scale, quality } => {
+                format!("screenshot(scale={},quality={})", scale, quality)
+            }
+        }
+    }
+
+    pub fn apply(&self, img: &RgbImage) -> Result<RgbImage, ImageError> {
+        let (width, height) = img.dimensions();
+        Ok(match self {
+            Attack::Original => img.clone(),
+            Attack::Jpeg { quality } => recompress(img, *quality)?,
+            Attack::Resize { scale } => {
+                let resized = resize(img, *scale, FilterType::Triangle);
+                imageops::resize(&resized, width, height, FilterType::Triangle)
+            }
+            Attack::Crop { fraction } => {
+                let fraction = fraction.clamp(0.0, 1.0);
+                let crop_width = ((width as f32 * fraction) as u32).max(1);
+                let crop_height = ((height as f32 * fraction) as u32).max(1);
+                imageops::crop_imm(img, 0, 0, crop_width, crop_height).to_image()
+            }
+            Attack::Rotate { degrees } => rotate_about_center(
+                img,
+                degrees.to_radians(),
+                Interpolation::Bilinear,
+                image::Rgb([0, 0, 0]),
+            ),
+            Attack::Noise { sigma } => gaussian_noise(img, 0.0, *sigma, 1),
+            Attack::Screenshot { scale, quality } => {
+                let mut shot = resize(img, *scale, FilterType::CatmullRom);
+                for pixel in shot.pixels_mut() {
+                    for channel in pixel.0.iter_mut() {
+                        *channel = (*channel as f32 * 0.92 + 8.0).round().clamp(0.0, 255.0) as u8;
+                    }
+                }
+                let shot = recompress(&shot, *quality)?;
+                imageops::resize(&shot, width, height, FilterType::Triangle)
+            }
+        })
+    }
+}
+
+fn resize(img: &RgbImage, scale: f32, filter: FilterType) -> RgbImage {
+    let width = ((img.width() as f32 * scale).round() as u32).max(1);
+    let height = ((img.height() as f32 * scale).round() as u32).max(1);
+    imageops::resize(img, width, height, filter)
+}
+
+fn recompress(img: &RgbImage, quality: u8) -> Result<RgbImage, ImageError> {
+    let mut jpeg = Vec::new();
+    JpegEncoder::new_with_quality(&mut jpeg, quality.clamp(1, 100))
+        .encode_image(img)
+        .map_err(|_| ImageError::EncodeBytesError)?;
+    Ok(image::load_from_memory(&jpeg)
+        .map_err(|_| ImageError::DecodeBytesError)?
+        .to_rgb8())
+}
+
+pub fn default_attacks() -> Vec<Attack> {
+    vec![
+        Attack::Original,
+        Attack::Jpeg { quality: 90 },
+        Attack::Jpeg { quality: 75 },
+        Attack::Jpeg { quality: 50 },
+        Attack::Resize { scale: 0.5 },
+        Attack::Crop { fraction: 0.8 },
+        Attack::Rotate { degrees: 2.0 },
+        Attack::Noise { sigma: 5.0 },
+        Attack::Screenshot {
+            scale: 0.75,
+            quality: 85,
+        },
+    ]
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(default)]
+pub struct BenchmarkConfig {
+    // 语料目录，其中能解码的图片都会参与测试
+    pub corpus: String,
+    pub engine: EngineConfig,
+    pub attacks: Vec<Attack>,
+}
+
+impl Default for BenchmarkConfig {
+    fn default() -> Self {
+        BenchmarkConfig {
+            corpus: "UserInfo/Image-01".to_string(),
+            engine: EngineConfig::default(),
+            attacks: default_attacks(),
+        }
+    }
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct AttackResult {
+    #[serde(flatten)]
+    pub attack: Attack,
+    pub label: String,
+    // 提取失败时为 None，原因见 error
+    pub bit_error_rate: Option<f32>,
+    pub correlation: Option<f32>,
+    pub psnr: f64,
+    pub ssim: f64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct ImageResult {
+    pub file: String,
+    pub width: u32,
+    pub height: u32,
+    // 带水印图片相对原图的 PSNR 和 SSIM
+    pub embed_psnr: f64,
+    pub embed_ssim: f64,
+    pub attacks: Vec<AttackResult>,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct AttackSummary {
+    pub label: String,
+    // 提取成功的图片数
+    pub images: usize,
+    pub mean_bit_error_rate: Option<f32>,
+    pub mean_psnr: f64,
+    pub mean_ssim: f64,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct BenchmarkReport {
+    pub engine: EngineConfig,
+    pub images: Vec<ImageResult>,
+    pub summary: Vec<AttackSummary>,
+}
+
+/// 按配置运行测试，图片较大时耗时较长，在异步环境中需要放到 spawn_blocking 中
+pub fn run_benchmark(config: &BenchmarkConfig) -> Result<BenchmarkReport, ImageError> {
+    let engine = config.engine.engine();
+    let mut images = Vec::new();
+    for path in corpus_files(Path::new(&config.corpus))? {
+        // 语料目录中可能有其他文件，无法解码的直接跳过
+        let Ok(original) = image::open(&path) else {
+            continue;
+        };
+        images.push(benchmark_image(
+            engine.as_ref(),
+            &path,
+            &original,
+            &config.attacks,
+        )?);
+    }
+    let summary = config
+        .attacks
+        .iter()
+        .map(|attack| summarize(attack, &images))
+        .collect();
+    Ok(BenchmarkReport {
+        engine: config.engine.clone(),
+        images,
+        summary,
+    })
+}
+
+fn corpus_files(corpus: &Path) -> Result<Vec<PathBuf>, ImageError> {
+    let mut files: Vec<PathBuf> = std::fs::read_dir(corpus)
+        .map_err(|e| ImageError::IOError(format!("{}: {}", corpus.display(), e)))?
+        .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+        .filter(|path| path.is_file())
+        .collect();
+    files.sort();
+    Ok(files)
+}
+
+fn benchmark_image(
+    engine: &dyn WatermarkEngine,
+    path: &Path,
+    original: &DynamicImage,
+    attacks: &[Attack],
+) -> Result<ImageResult, ImageError> {
+    let (watermarked_base64, watermark_base64) = engine.embed(original)?;
+    let watermarked = general_purpose::STANDARD
+        .decode(watermarked_base64)
+        .map_err(|_| ImageError::DecodeBytesError)
+        .and_then(|bytes| {
+            image::load_from_memory(&bytes).map_err(|_| ImageError::DecodeBytesError)
+        })?
+        .to_rgb8();
+    let original = original.to_rgb8();
+
+    let mut results = Vec::with_capacity(attacks.len());
+    for attack in attacks {
+        let attacked = attack.apply(&watermarked)?;
+        let extracted = engine
+            .extract(&DynamicImage::ImageRgb8(attacked.clone()))
+            .and_then(|soft| engine.compare(&soft, &watermark_base64));
+        let (bit_error_rate, correlation, error) = match extracted {
+            Ok((rate, correlation)) => (Some(rate), Some(correlation), None),
+            Err(e) => (None, None, Some(e.to_string())),
+        };
+        results.push(AttackResult {
+            attack: attack.clone(),
+            label: attack.label(),
+            bit_error_rate,
+            correlation,
+            psnr: psnr(&watermarked, &attacked),
+            ssim: ssim(&watermarked, &attacked),
+            error,
+        });
+    }
+    Ok(ImageResult {
+        file: path
+            .file_name()
+            .map(|name| name.to_string_lossy().to_string())
+            .unwrap_or_default(),
+        width: original.width(),
+        height: original.height(),
+        embed_psnr: psnr(&original, &watermarked),
+        embed_ssim: ssim(&original, &watermarked),
+        attacks: results,
+    })
+}
+
+fn summarize(attack: &Attack, images: &[ImageResult]) -> AttackSummary {
+    let results: Vec<&AttackResult> = images
+        .iter()
+        .filter_map(|image| image.attacks.iter().find(|result| &result.attack == attack))
+        .collect();
+    let rates: Vec<f32> = results
+        .iter()
+        .filter_map(|result| result.bit_error_rate)
+        .collect();
+    let mean = |values: Vec<f64>| {
+        if values.is_empty() {
+            0.0
+        } else {
+            values.iter().sum::<f64>() / values.len() as f64
+        }
+    };
+    AttackSummary {
+        label: attack.label(),
+        images: rates.len(),
+        mean_bit_error_rate: (!rates.is_empty())
+            .then(|| rates.iter().sum::<f32>() / rates.len() as f32),
+        mean_psnr: mean(results.iter().map(|result| result.psnr).collect()),
+        mean_ssim: mean(results.iter().map(|result| result.ssim).collect()),
+    }
+}
+
+/// RGB 三个通道的 PSNR（dB），尺寸不同时只比较左上角重叠的部分
+pub fn psnr(reference: &RgbImage, distorted: &RgbImage) -> f64 {
+    let width = reference.width().min(distorted.width());
+    let height = reference.height().min(distorted.height());
+    let mut squared = 0.0f64;
+    for y in 0..height {
+        for x in 0..width {
+            let (a, b) = (reference.get_pixel(x, y), distorted.get_pixel(x, y));
+            for channel in 0..3 {
+                squared += (a.0[channel] as f64 - b.0[channel] as f64).powi(2);
+            }
+        }
+    }
+    let count = (width as f64) * (height as f64) * 3.0;
+    if count == 0.0 || squared == 0.0 {
+        return MAX_PSNR;
+    }
+    (10.0 * (255.0f64.powi(2) / (squared / count)).log10()).min(MAX_PSNR)
+}
+
+/// 亮度通道上 8x8 滑动窗口的平均 SSIM，尺寸不同时只比较左上角重叠的部分
+pub fn ssim(reference: &RgbImage, distorted: &RgbImage) -> f64 {
+    const C1: f64 = (0.01 * 255.0) * (0.01 * 255.0);
+    const C2: f64 = (0.03 * 255.0) * (0.03 * 255.0);
+    let (a, b) = (luma(reference), luma(distorted));
+    let width = a.width().min(b.width());
+    let height = a.height().min(b.height());
+    if width < SSIM_WINDOW || height < SSIM_WINDOW {
+        return if psnr(reference, distorted) >= MAX_PSNR {
+            1.0
+        } else {
+            0.0
+        };
+    }
+
+    let samples = (SSIM_WINDOW * SSIM_WINDOW) as f64;
+    let mut total = 0.0;
+    let mut windows = 0usize;
+    for y in (0..=height - SSIM_WINDOW).step_by(SSIM_STEP as usize) {
+        for x in (0..=width - SSIM_WINDOW).step_by(SSIM_STEP as usize) {
+            let (mut sum_a, mut sum_b, mut sum_aa, mut sum_bb, mut sum_ab) =
+                (0.0, 0.0, 0.0, 0.0, 0.0);
+            for dy in 0..SSIM_WINDOW {
+                for dx in 0..SSIM_WINDOW {
+                    let pa = a.get_pixel(x + dx, y + dy).0[0] as f64;
+                    let pb = b.get_pixel(x + dx, y + dy).0[0] as f64;
+                    sum_a += pa;
+                    sum_b += pb;
+                    sum_aa += pa * pa;
+                    sum_bb += pb * pb;
+                    sum_ab += pa * pb;
+                }
+            }
+            let (mean_a, mean_b) = (sum_a / samples, sum_b / samples);
+            let var_a = sum_aa / samples - mean_a * mean_a;
+            let var_b = sum_bb / samples - mean_b * mean_b;
+            let covariance = sum_ab / samples - mean_a * mean_b;
+            total += ((2.0 * mean_a * mean_b + C1) * (2.0 * covariance + C2))
+                / ((mean_a * mean_a + mean_b * mean_b + C1) * (var_a + var_b + C2));
+            windows += 1;
+        }
+    }
+    total / windows as f64
+}
+
+fn luma(img: &RgbImage) -> GrayImage {
+    DynamicImage::ImageRgb8(img.clone()).to_luma8()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_attacks_and_metrics() {
+        let img = RgbImage::from_fn(96, 64, |x, y| {
+            image::Rgb([(x * 2) as u8, (y * 3) as u8, ((x + y) % 256) as u8])
+        });
+        assert_eq!(psnr(&img, &img), MAX_PSNR);
+        assert!((ssim(&img, &img) - 1.0).abs() < 1e-9);
+
+        // 缩放类攻击保持尺寸，裁剪缩小尺寸
+        for attack in default_attacks() {
+            let attacked = attack.apply(&img).unwrap();
+            match attack {
+                Attack::Crop { .. } => assert_eq!(attacked.dimensions(), (76, 51)),
+                _ => assert_eq!(attacked.dimensions(), img.dimensions()),
+            }
+        }
+
+        // 失真越大 PSNR 和 SSIM 越低
+        let light = Attack::Noise { sigma: 2.0 }.apply(&img).unwrap();
+        let heavy = Attack::Noise { sigma: 30.0 }.apply(&img).unwrap();
+        assert!(psnr(&img, &light) > psnr(&img, &heavy));
+        assert!(ssim(&img, &light) > ssim(&img, &heavy));
+        assert!(ssim(&img, &heavy) < 0.9);
+
+        // 配置中的攻击按 attack 字段区分
+        let attacks: Vec<Attack> = serde_json::from_value(serde_json::json!([
+            { "attack": "jpeg", "quality": 60 },
+            { "attack": "rotate", "degrees": 1.5 }
+        ]))
+        .unwrap();
+        assert_eq!(attacks[0].label(), "jpeg(quality=60)");
+        assert_eq!(attacks[1], Attack::Rotate { degrees: 1.5 });
+    }
+}
@@ -0,0 +1,417 @@
+use crate::DataBase::{get_db, Users};
+use crate::Error::ImageError;
+use crate::WatermarkService::nativeWatermark::{self, png_to_bits};
+use crate::WatermarkService::pythonPool::{
+    python_pool, PoolConfig, DEFAULT_TIMEOUT_SECS, DEFAULT_WORKERS,
+};
+use crate::WatermarkService::structuredPayload::{WatermarkPayload, CODEWORD_BITS};
+use crate::WatermarkService::verification::compare;
+use base64::engine::general_purpose;
+use base64::Engine;
+use image::codecs::jpeg::JpegEncoder;
+use image::DynamicImage;
+use rocket::fairing::AdHoc;
+use rocket::figment::Figment;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::sync::OnceLock;
+use std::time::Duration;
+
+// 可替换的水印引擎：每个组织（users.company_name）可以在 Rocket.toml 的 [default.watermark] 中选择引擎和参数，
+// 没有配置时使用 Python 脚本，例如
+//   [default.watermark.default]
+//   engine = "python"
+//   serve = false    # 脚本不支持 --serve 时每个请求启动一次进程
+//   [default.watermark.organizations.acme]
+//   engine = "native"
+//   strength = 36.0
+//   [default.watermark.organizations.studio]
+//   engine = "payload"
+// payload 引擎嵌入带 MAC 的结构化载荷，密钥在 [default.watermark] 的 payload_key 中配置，不随记录保存。
+// 加水印时使用的引擎和参数会序列化后记录在 watermarks.parameters 中，验证时按记录的引擎提取
+
+pub const PYTHON_SCRIPT: &str = "/root/BlockchainImage/python-watermark/main_class.py";
+
+static CONFIG: OnceLock<WatermarkConfig> = OnceLock::new();
+
+pub trait WatermarkEngine: Send + Sync {
+    /// 加水印，返回 (带水印图片的 base64, 水印图片的 base64)
+    fn embed(&self, img: &DynamicImage) -> Result<(String, String), ImageError>;
+
+    /// 从图片中提取水印的软判决值，大于 0 表示比特 1
+    fn extract(&self, img: &DynamicImage) -> Result<Vec<f32>, ImageError>;
+
+    /// 将提取结果与登记的水印比较，返回 (误码率, 归一化相关系数)
+    fn compare(&self, soft: &[f32], watermark_base64: &str) -> Result<(f32, f32), ImageError> {
+        let png = general_purpose::STANDARD
+            .decode(watermark_base64)
+            .map_err(|_| ImageError::DecodeBytesError)?;
+        Ok(compare(soft, &png_to_bits(&png, soft.len())?))
+    }
+
+    /// 将提取结果解码为结构化载荷，纠错失败、MAC 不符或引擎不嵌入载荷时返回 None
+    fn decode_payload(&self, _soft: &[f32]) -> Option<WatermarkPayload> {
+        None
+    }
+
+    /// 引擎名称和参数，与图片一起记录
+    fn describe(&self) -> EngineConfig;
+}
+
+/// 引擎及其参数，engine 字段区分类型
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "engine", rename_all = "snake_case")]
+pub enum EngineConfig {
+    // 外部 Python 脚本，由进程池处理；serve 为 true 时进程常驻，否则每个请求启动一次进程
+    Python {
+        #[serde(default = "default_script")]
+        script: String,
+        #[serde(default = "default_workers")]
+        workers: usize,
+        #[serde(default = "default_timeout_secs")]
+        timeout_secs: u64,
+        #[serde(default)]
+        serve: bool,
+    },
+    // 进程内的 DWT-DCT 盲水印
+    Native {
+        #[serde(default = "default_strength")]
+        strength: f32,
+    },
+    // 进程内的 DWT-DCT 盲水印，嵌入 (所有者 ID, 图片 ID, 时间戳) 和 MAC，带纠错码
+    Payload {
+        #[serde(default = "default_strength")]
+        strength: f32,
+        // 由 engine_for_user 填入上传用户的 id，不记录
+        #[serde(skip)]
+        owner_id: u32,
+    },
+    // 不加水印，只重新编码为 JPEG，用于测试
+    Noop,
+}
+
+// 默认仍使用原有的 Python 脚本，进程内引擎需要在配置中显式选择
+impl Default for EngineConfig {
+    fn default() -> Self {
+        EngineConfig::Python {
+            script: default_script(),
+            workers: DEFAULT_WORKERS,
+            timeout_secs: DEFAULT_TIMEOUT_SECS,
+            serve: false,
+        }
+    }
+}
+
+impl EngineConfig {
+    pub fn engine(&self) -> Box<dyn WatermarkEngine> {
+        match self {
+            EngineConfig::Python {
+                script,
+                workers,
+                timeout_secs,
+                serve,
+            } => Box::new(PythonEngine {
+                pool: PoolConfig {
+                    workers: *workers,
+                    serve: *serve,
+                    timeout: Duration::from_secs(*timeout_secs),
+                    ..PoolConfig::new(script)
+                },
+            }),
+            EngineConfig::Native { strength } => Box::new(NativeEngine {
+                strength: *strength,
+            }),
+            EngineConfig::Payload { strength, owner_id } => Box::new(PayloadEngine {
+                strength: *strength,
+                owner_id: *owner_id,
+                key: watermark_config().payload_key(),
+            }),
+            EngineConfig::Noop => Box::new(NoopEngine),
+        }
+    }
+
+    /// 引擎名称，记录在 watermarks.engine 中
+    pub fn name(&self) -> &'static str {
+        match self {
+            EngineConfig::Python { .. } => "python",
+            EngineConfig::Native { .. } => "native",
+            EngineConfig::Payload { .. } => "payload",
+            EngineConfig::Noop => "noop",
+        }
+    }
+
+    /// 记录在 watermarks.parameters 中的形式
+    pub fn to_record(&self) -> String {
+        serde_json::to_string(self).unwrap_or_default()
+    }
+
+    /// 解析 watermarks.parameters，没有记录时视为默认参数的 Python 引擎
+    pub fn from_record(record: Option<&str>) -> Result<Self, ImageError> {
+        match record {
+            Some(record) => serde_json::from_str(record).map_err(|_| ImageError::JsonParseError),
+            None => Ok(EngineConfig::default()),
+        }
+    }
+}
+
+fn default_script() -> String {
+    PYTHON_SCRIPT.to_string()
+}
+
+fn default_workers() -> usize {
+    DEFAULT_WORKERS
+}
+
+fn default_timeout_secs() -> u64 {
+    DEFAULT_TIMEOUT_SECS
+}
+
+fn default_strength() -> f32 {
+    nativeWatermark::DEFAULT_STRENGTH
+}
+
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct WatermarkConfig {
+    pub default: EngineConfig,
+    // 组织名 -> 引擎
+    pub organizations: HashMap<String, EngineConfig>,
+    // payload 引擎计算 MAC 所用的密钥
+    pub payload_key: Option<String>,
+}
+
+impl WatermarkConfig {
+    pub fn engine_for(&self, organization: Option<&str>) -> EngineConfig {
+        organization
+            .and_then(|organization| self.organizations.get(organization))
+            .unwrap_or(&self.default)
+            .clone()
+    }
+
+    pub fn payload_key(&self) -> Vec<u8> {
+        self.payload_key.clone().unwrap_or_default().into_bytes()
+    }
+}
+
+pub fn watermark_config() -> &'static WatermarkConfig {
+    CONFIG.get_or_init(WatermarkConfig::default)
+}
+
+/// 从 Rocket 配置中读取 watermark 并设为当前配置，只有第一次调用生效
+pub fn load_watermark_config(figment: &Figment) -> &'static WatermarkConfig {
+    let config = figment
+        .extract_inner::<WatermarkConfig>("watermark")
+        .unwrap_or_default();
+    let _ = CONFIG.set(config);
+    watermark_config()
+}
+
+/// 启动时从 Rocket 配置中读取 watermark
+pub fn watermark_engine_config() -> AdHoc {
+    AdHoc::on_ignite("Watermark Engines", |rocket| async {
+        load_watermark_config(rocket.figment());
+        rocket
+    })
+}
+
+/// 按用户所属组织选择引擎
+pub async fn engine_for_user(username: &str) -> Result<EngineConfig, ImageError> {
+    let user = Users::select_by_username(get_db().await, username)
+        .await
+        .map_err(ImageError::DatabaseError)?;
+    let organization = user.as_ref().and_then(|user| user.company_name.as_deref());
+    let mut engine = watermark_config().engine_for(organization);
+    if let EngineConfig::Payload { owner_id, .. } = &mut engine {
+        *owner_id = user.and_then(|user| user.id).unwrap_or_default() as u32;
+    }
+    Ok(engine)
+}
+
+pub struct PythonEngine {
+    pub pool: PoolConfig,
+}
+
+impl WatermarkEngine for PythonEngine {
+    fn embed(&self, img: &DynamicImage) -> Result<(String, String), ImageError> {
+        python_pool(&self.pool).watermark(img)
+    }
+
+    fn extract(&self, _img: &DynamicImage) -> Result<Vec<f32>, ImageError> {
+        Err(ImageError::WatermarkProcessError(
+            "the python watermark script does not support extraction".to_string(),
+        ))
+    }
+
+    fn describe(&self) -> EngineConfig {
+        EngineConfig::Python {
+            script: self.pool.script.clone(),
+            workers: self.pool.workers,
+            timeout_secs: self.pool.timeout.as_secs(),
+            serve: self.pool.serve,
+        }
+    }
+}
+
+pub struct NativeEngine {
+    pub strength: f32,
+}
+
+impl WatermarkEngine for NativeEngine {
+    fn embed(&self, img: &DynamicImage) -> Result<(String, String), ImageError> {
+        nativeWatermark::watermark_image(img, self.strength)
+    }
+
+    fn extract(&self, img: &DynamicImage) -> Result<Vec<f32>, ImageError> {
+        nativeWatermark::extract(img, nativeWatermark::WATERMARK_BITS, self.strength)
+    }
+
+    fn describe(&self) -> EngineConfig {
+        EngineConfig::Native {
+            strength: self.strength,
+        }
+    }
+}
+
+pub struct PayloadEngine {
+    pub strength: f32,
+    pub owner_id: u32,
+    pub key: Vec<u8>,
+}
+
+impl WatermarkEngine for PayloadEngine {
+    fn embed(&self, img: &DynamicImage) -> Result<(String, String), ImageError> {
+        let bits = WatermarkPayload::new(self.owner_id, img).encode(&self.key)?;
+        nativeWatermark::watermark_bits(img, &bits, self.strength)
+    }
+
+    fn extract(&self, img: &DynamicImage) -> Result<Vec<f32>, ImageError> {
+        nativeWatermark::extract(img, CODEWORD_BITS, self.strength)
+    }
+
+    fn decode_payload(&self, soft: &[f32]) -> Option<WatermarkPayload> {
+        WatermarkPayload::decode(soft, &self.key).ok()
+    }
+
+    fn describe(&self) -> EngineConfig {
+        EngineConfig::Payload {
+            strength: self.strength,
+            owner_id: self.owner_id,
+        }
+    }
+}
+
+pub struct NoopEngine;
+
+impl WatermarkEngine for NoopEngine {
+    fn embed(&self, img: &DynamicImage) -> Result<(String, String), ImageError> {
+        let mut jpeg = Vec::new();
+        JpegEncoder::new_with_quality(&mut jpeg, nativeWatermark::OUTPUT_QUALITY)
+            .encode_image(&img.to_rgb8())
+            .map_err(|_| ImageError::EncodeBytesError)?;
+        Ok((general_purpose::STANDARD.encode(&jpeg), String::new()))
+    }
+
+    fn extract(&self, _img: &DynamicImage) -> Result<Vec<f32>, ImageError> {
+        Ok(Vec::new())
+    }
+
+    fn compare(&self, _soft: &[f32], _watermark_base64: &str) -> Result<(f32, f32), ImageError> {
+        Ok((1.0, 0.0))
+    }
+
+    fn describe(&self) -> EngineConfig {
+        EngineConfig::Noop
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::RgbImage;
+
+    #[test]
+    fn test_engine_config() {
+        let config: WatermarkConfig = serde_json::from_value(serde_json::json!({
+            "default": { "engine": "native", "strength": 40.0 },
+            "organizations": {
+                "acme": { "engine": "python" },
+                "qa": { "engine": "noop" }
+            }
+        }))
+        .unwrap();
+        assert_eq!(
+            config.engine_for(Some("acme")),
+            EngineConfig::Python {
+                script: PYTHON_SCRIPT.to_string(),
+                workers: DEFAULT_WORKERS,
+                timeout_secs: DEFAULT_TIMEOUT_SECS,
+                serve: false,
+            }
+        );
+        assert_eq!(config.engine_for(Some("qa")), EngineConfig::Noop);
+        assert_eq!(config.engine_for(Some("acme")).name(), "python");
+        assert_eq!(
+            config.engine_for(None),
+            EngineConfig::Native { strength: 40.0 }
+        );
+        assert_eq!(config.engine_for(Some("other")), config.default);
+
+        // 记录往返，未记录时为默认引擎
+        let record = config.default.to_record();
+        assert_eq!(
+            EngineConfig::from_record(Some(&record)).unwrap(),
+            config.default
+        );
+        assert_eq!(
+            EngineConfig::from_record(None).unwrap(),
+            EngineConfig::default()
+        );
+        // 没有配置 watermark 时使用 Python 脚本
+        assert_eq!(
+            WatermarkConfig::default().engine_for(None),
+            config.engine_for(Some("acme"))
+        );
+
+        // 同一引擎嵌入的水印可以被提取并比较
+        let img = DynamicImage::ImageRgb8(RgbImage::from_fn(256, 256, |x, y| {
+            image::Rgb([(x % 200) as u8 + 20, (y % 180) as u8 + 30, 128])
+        }));
+        let engine = config.default.engine();
+        let (watermarked, watermark) = engine.embed(&img).unwrap();
+        let watermarked =
+            image::load_from_memory(&general_purpose::STANDARD.decode(watermarked).unwrap())
+                .unwrap();
+        let soft = engine.extract(&watermarked).unwrap();
+        assert_eq!(engine.compare(&soft, &watermark).unwrap().0, 0.0);
+
+        // payload 引擎的所有者不记录，提取后可以直接解码
+        let payload = EngineConfig::Payload {
+            strength: nativeWatermark::DEFAULT_STRENGTH,
+            owner_id: 9,
+        };
+        assert_eq!(
+            EngineConfig::from_record(Some(&payload.to_record())).unwrap(),
+            EngineConfig::Payload {
+                strength: nativeWatermark::DEFAULT_STRENGTH,
+                owner_id: 0,
+            }
+        );
+        let engine = PayloadEngine {
+            strength: nativeWatermark::DEFAULT_STRENGTH,
+            owner_id: 9,
+            key: b"engine-test-key".to_vec(),
+        };
+        let (watermarked, watermark) = engine.embed(&img).unwrap();
+        let watermarked =
+            image::load_from_memory(&general_purpose::STANDARD.decode(watermarked).unwrap())
+                .unwrap();
+        let soft = engine.extract(&watermarked).unwrap();
+        assert_eq!(engine.compare(&soft, &watermark).unwrap().0, 0.0);
+        assert_eq!(engine.decode_payload(&soft).unwrap().owner_id, 9);
+
+        let noop = EngineConfig::Noop.engine();
+        let (_, watermark) = noop.embed(&img).unwrap();
+        assert!(watermark.is_empty());
+        assert_eq!(noop.compare(&[], &watermark).unwrap(), (1.0, 0.0));
+    }
+}
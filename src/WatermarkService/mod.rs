@@ -1 +1,9 @@
 pub mod watermarkservice;
+pub mod benchmark;
+pub mod engine;
+pub mod nativeWatermark;
+pub mod pythonPool;
+pub mod structuredPayload;
+pub mod uploadPolicy;
+pub mod verification;
+pub mod visibleWatermark;
@@ -0,0 +1,313 @@
+use crate::Error::ImageError;
+use base64::engine::general_purpose;
+use base64::Engine;
+use image::codecs::jpeg::JpegEncoder;
+use image::{DynamicImage, GrayImage, ImageFormat, Luma, RgbImage};
+use std::f32::consts::PI;
+use std::io::Cursor;
+
+// 进程内的盲水印（DWT-DCT）：
+//   1.取图片亮度 Y，做一级 Haar 小波变换得到低频子带 LL
+//   2.将 LL 分成 8x8 的块，对每块做 DCT，在几个中低频系数上用量化索引调制（QIM）嵌入一个比特
+//   3.水印比特按块循环重复嵌入，提取时对所有块的软判决求和，按符号恢复每个比特
+// 只修改 LL 时，逆 Haar 变换等价于把 LL 的变化量的一半加到对应 2x2 像素上，
+// 再将亮度变化量同时加到 R、G、B 上，不需要保存其他子带。提取不需要原图
+
+// 水印比特数，水印图片为 WATERMARK_SIDE x WATERMARK_SIDE 的黑白图
+pub const WATERMARK_SIDE: u32 = 8;
+pub const WATERMARK_BITS: usize = (WATERMARK_SIDE * WATERMARK_SIDE) as usize;
+// QIM 量化步长，越大越能抵抗 JPEG 压缩，失真也越大
+pub const DEFAULT_STRENGTH: f32 = 36.0;
+// 输出 JPEG 的质量
+pub const OUTPUT_QUALITY: u8 = 95;
+
+const BLOCK: usize = 8;
+// 每块中嵌入同一比特的 DCT 系数位置 (行, 列)
+const COEFFICIENTS: [(usize, usize); 3] = [(0, 1), (1, 0), (1, 1)];
+
+/// 生成随机水印，对图片嵌入后返回 (带水印图片的 base64, 水印图片的 base64)，与 Python 脚本的返回值相同
+pub fn watermark_image(img: &DynamicImage, strength: f32) -> Result<(String, String), ImageError> {
+    let bits: Vec<bool> = (0..WATERMARK_BITS).map(|_| rand::random()).collect();
+    watermark_bits(img, &bits, strength)
+}
+
+/// 嵌入指定的比特，返回值与 watermark_image 相同
+pub fn watermark_bits(
+    img: &DynamicImage,
+    bits: &[bool],
+    strength: f32,
+) -> Result<(String, String), ImageError> {
+    let watermarked = embed(img, bits, strength)?;
+
+    let mut jpeg = Vec::new();
+    JpegEncoder::new_with_quality(&mut jpeg, OUTPUT_QUALITY)
+        .encode_image(&watermarked)
+        .map_err(|_| ImageError::EncodeBytesError)?;
+    Ok((
+        general_purpose::STANDARD.encode(&jpeg),
+        general_purpose::STANDARD.encode(bits_to_png(bits)?),
+    ))
+}
+
+/// 将 bits 嵌入图片，返回带水印的 RGB 图片
+pub fn embed(img: &DynamicImage, bits: &[bool], strength: f32) -> Result<RgbImage, ImageError> {
+    let mut rgb = img.to_rgb8();
+    let (luma, width, height) = luminance(&rgb);
+    let blocks = block_grid(width, height, bits.len())?;
+
+    let ll = haar_ll(&luma, width, blocks);
+    let mut marked = ll.clone();
+    let ll_width = blocks.0 * BLOCK;
+    for (index, (bx, by)) in block_positions(blocks).enumerate() {
+        let bit = bits[index % bits.len()];
+        let mut block = read_block(&marked, ll_width, bx, by);
+        let mut coefficients = dct(&block);
+        for (u, v) in COEFFICIENTS {
+            coefficients[u][v] = quantize(coefficients[u][v], bit, strength);
+        }
+        block = idct(&coefficients);
+        write_block(&mut marked, ll_width, bx, by, &block);
+    }
+
+    // 逆 Haar：LL 的变化量的一半加到 2x2 的每个像素上
+    for y in 0..blocks.1 * BLOCK {
+        for x in 0..ll_width {
+            let delta = (marked[y * ll_width + x] - ll[y * ll_width + x]) / 2.0;
+            for (px, py) in [(0, 0), (1, 0), (0, 1), (1, 1)] {
+                let pixel = rgb.get_pixel_mut((2 * x + px) as u32, (2 * y + py) as u32);
+                for channel in pixel.0.iter_mut() {
+                    *channel = (*channel as f32 + delta).round().clamp(0.0, 255.0) as u8;
+                }
+            }
+        }
+    }
+    Ok(rgb)
+}
+
+/// 从图片中提取 len 个比特的软判决值，范围 [-1, 1]，大于 0 表示比特 1，绝对值越大越可信
+pub fn extract(img: &DynamicImage, len: usize, strength: f32) -> Result<Vec<f32>, ImageError> {
+    let rgb = img.to_rgb8();
+    let (luma, width, height) = luminance(&rgb);
+    let blocks = block_grid(width, height, len)?;
+
+    let ll = haar_ll(&luma, width, blocks);
+    let ll_width = blocks.0 * BLOCK;
+    let mut votes = vec![0.0f32; len];
+    let mut counts = vec![0usize; len];
+    for (index, (bx, by)) in block_positions(blocks).enumerate() {
+        let coefficients = dct(&read_block(&ll, ll_width, bx, by));
+        for (u, v) in COEFFICIENTS {
+            votes[index % len] += soft_bit(coefficients[u][v], strength);
+            counts[index % len] += 1;
+        }
+    }
+    Ok(votes
+        .iter()
+        .zip(counts)
+        .map(|(vote, count)| vote / count as f32)
+        .collect())
+}
+
+/// 软判决值转为比特
+pub fn hard_bits(soft: &[f32]) -> Vec<bool> {
+    soft.iter().map(|value| *value > 0.0).collect()
+}
+
+/// 水印比特保存为黑白 PNG（白色为 1），按行排列
+pub fn bits_to_png(bits: &[bool]) -> Result<Vec<u8>, ImageError> {
+    let side = (bits.len() as f64).sqrt().ceil() as u32;
+    let image = GrayImage::from_fn(side, side, |x, y| {
+        let bit = bits.get((y * side + x) as usize).copied().unwrap_or(false);
+        Luma([if bit { 255 } else { 0 }])
+    });
+    let mut png = Vec::new();
+    DynamicImage::ImageLuma8(image)
+        .write_to(&mut Cursor::new(&mut png), ImageFormat::Png)
+        .map_err(|_| ImageError::EncodeBytesError)?;
+    Ok(png)
+}
+
+/// 从水印 PNG 中读出比特
+pub fn png_to_bits(png: &[u8], len: usize) -> Result<Vec<bool>, ImageError> {
+    let image = image::load_from_memory_with_format(png, ImageFormat::Png)
+        .map_err(|_| ImageError::DecodeBytesError)?
+        .to_luma8();
+    Ok(image
+        .pixels()
+        .take(len)
+        .map(|pixel| pixel.0[0] >= 128)
+        .collect())
+}
+
+fn luminance(rgb: &RgbImage) -> (Vec<f32>, usize, usize) {
+    let luma = rgb
+        .pixels()
+        .map(|p| 0.299 * p.0[0] as f32 + 0.587 * p.0[1] as f32 + 0.114 * p.0[2] as f32)
+        .collect();
+    (luma, rgb.width() as usize, rgb.height() as usize)
+}
+
+// LL 子带中 8x8 块的列数和行数，每块对应原图 16x16 像素，右侧和下方不足一块的部分不处理
+fn block_grid(width: usize, height: usize, bits: usize) -> Result<(usize, usize), ImageError> {
+    let blocks = (width / (2 * BLOCK), height / (2 * BLOCK));
+    if bits == 0 || blocks.0 * blocks.1 < bits {
+        return Err(ImageError::ImageRejected(format!(
+            "image of {}x{} is too small to carry a {}-bit watermark",
+            width, height, bits
+        )));
+    }
+    Ok(blocks)
+}
+
+fn block_positions(blocks: (usize, usize)) -> impl Iterator<Item = (usize, usize)> {
+    (0..blocks.1).flat_map(move |by| (0..blocks.0).map(move |bx| (bx, by)))
+}
+
+// 一级 Haar 变换的 LL 子带：(a + b + c + d) / 2
+fn haar_ll(luma: &[f32], width: usize, blocks: (usize, usize)) -> Vec<f32> {
+    let (ll_width, ll_height) = (blocks.0 * BLOCK, blocks.1 * BLOCK);
+    let mut ll = vec![0.0; ll_width * ll_height];
+    for y in 0..ll_height {
+        for x in 0..ll_width {
+            let top = 2 * y * width + 2 * x;
+            let bottom = top + width;
+            ll[y * ll_width + x] =
+                (luma[top] + luma[top + 1] + luma[bottom] + luma[bottom + 1]) / 2.0;
+        }
+    }
+    ll
+}
+
+fn read_block(ll: &[f32], ll_width: usize, bx: usize, by: usize) -> [[f32; BLOCK]; BLOCK] {
+    let mut block = [[0.0; BLOCK]; BLOCK];
+    for (row, values) in block.iter_mut().enumerate() {
+        let start = (by * BLOCK + row) * ll_width + bx * BLOCK;
+        values.copy_from_slice(&ll[start..start + BLOCK]);
+    }
+    block
+}
+
+fn write_block(
+    ll: &mut [f32],
+    ll_width: usize,
+    bx: usize,
+    by: usize,
+    block: &[[f32; BLOCK]; BLOCK],
+) {
+    for (row, values) in block.iter().enumerate() {
+        let start = (by * BLOCK + row) * ll_width + bx * BLOCK;
+        ll[start..start + BLOCK].copy_from_slice(values);
+    }
+}
+
+// 正交 DCT-II 的基：basis[u][x] = c(u) * cos((2x + 1)uπ / 16)
+fn dct_basis() -> [[f32; BLOCK]; BLOCK] {
+    let mut basis = [[0.0; BLOCK]; BLOCK];
+    for (u, row) in basis.iter_mut().enumerate() {
+        let scale = if u == 0 {
+            (1.0 / BLOCK as f32).sqrt()
+        } else {
+            (2.0 / BLOCK as f32).sqrt()
+        };
+        for (x, value) in row.iter_mut().enumerate() {
+            *value = scale * ((2 * x + 1) as f32 * u as f32 * PI / (2 * BLOCK) as f32).cos();
+        }
+    }
+    basis
+}
+
+fn dct(block: &[[f32; BLOCK]; BLOCK]) -> [[f32; BLOCK]; BLOCK] {
+    let basis = dct_basis();
+    let mut out = [[0.0; BLOCK]; BLOCK];
+    for u in 0..BLOCK {
+        for v in 0..BLOCK {
+            let mut sum = 0.0;
+            for y in 0..BLOCK {
+                for x in 0..BLOCK {
+                    sum += basis[u][y] * basis[v][x] * block[y][x];
+                }
+            }
+            out[u][v] = sum;
+        }
+    }
+    out
+}
+
+fn idct(coefficients: &[[f32; BLOCK]; BLOCK]) -> [[f32; BLOCK]; BLOCK] {
+    let basis = dct_basis();
+    let mut out = [[0.0; BLOCK]; BLOCK];
+    for y in 0..BLOCK {
+        for x in 0..BLOCK {
+            let mut sum = 0.0;
+            for u in 0..BLOCK {
+                for v in 0..BLOCK {
+                    sum += basis[u][y] * basis[v][x] * coefficients[u][v];
+                }
+            }
+            out[y][x] = sum;
+        }
+    }
+    out
+}
+
+// QIM：比特 0 量化到 k * step，比特 1 量化到 (k + 1/2) * step
+fn quantize(value: f32, bit: bool, step: f32) -> f32 {
+    let offset = if bit { step / 2.0 } else { 0.0 };
+    ((value - offset) / step).round() * step + offset
+}
+
+// 到比特 0 格点的距离减去到比特 1 格点的距离，归一化到 [-1, 1]
+fn soft_bit(value: f32, step: f32) -> f32 {
+    let distance = |bit| (value - quantize(value, bit, step)).abs();
+    (distance(false) - distance(true)) / (step / 2.0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // 带纹理的测试图片，避免纯色图片中亮度被截断
+    fn test_image(width: u32, height: u32) -> DynamicImage {
+        DynamicImage::ImageRgb8(RgbImage::from_fn(width, height, |x, y| {
+            let noise = ((x * 7919 + y * 104_729) % 61) as f32;
+            let r = 60.0 + 100.0 * (x as f32 / width as f32) + noise;
+            let g = 50.0 + 120.0 * (y as f32 / height as f32) + noise / 2.0;
+            let b = 128.0 + 40.0 * ((x + y) as f32 / 40.0).sin();
+            image::Rgb([r as u8, g as u8, b as u8])
+        }))
+    }
+
+    #[test]
+    fn test_embed_and_extract_after_jpeg() {
+        let img = test_image(320, 240);
+        let bits: Vec<bool> = (0..WATERMARK_BITS).map(|i| (i * 37 + 11) % 5 < 2).collect();
+        let watermarked = embed(&img, &bits, DEFAULT_STRENGTH).unwrap();
+
+        // 失真：PSNR 应高于 38dB
+        let mse: f64 = img
+            .to_rgb8()
+            .as_raw()
+            .iter()
+            .zip(watermarked.as_raw())
+            .map(|(a, b)| (*a as f64 - *b as f64).powi(2))
+            .sum::<f64>()
+            / watermarked.as_raw().len() as f64;
+        assert!(10.0 * (255.0f64.powi(2) / mse).log10() > 38.0);
+
+        // 经过 JPEG 质量 75 压缩后仍能完整提取
+        let mut jpeg = Vec::new();
+        JpegEncoder::new_with_quality(&mut jpeg, 75)
+            .encode_image(&watermarked)
+            .unwrap();
+        let decoded = image::load_from_memory(&jpeg).unwrap();
+        let extracted = hard_bits(&extract(&decoded, WATERMARK_BITS, DEFAULT_STRENGTH).unwrap());
+        assert_eq!(extracted, bits);
+
+        // 水印图片往返
+        let png = bits_to_png(&bits).unwrap();
+        assert_eq!(png_to_bits(&png, WATERMARK_BITS).unwrap(), bits);
+
+        // 图片太小
+        assert!(embed(&test_image(64, 64), &bits, DEFAULT_STRENGTH).is_err());
+    }
+}
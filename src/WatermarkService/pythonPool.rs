@@ -0,0 +1,413 @@
+use crate::Error::ImageError;
+use base64::engine::general_purpose;
+use base64::Engine;
+use image::{DynamicImage, ImageFormat};
+use serde::Deserialize;
+use serde_json::{json, Value};
+use std::collections::HashMap;
+use std::io::{BufRead, BufReader, Cursor, Read, Write};
+use std::process::{Child, ChildStdin, ChildStdout, Command, Stdio};
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, SyncSender};
+use std::sync::{Arc, Mutex, OnceLock};
+use std::thread;
+use std::time::{Duration, Instant};
+
+// Python 水印进程池，支持两种模式：
+// 常驻模式（serve）：每个 worker 线程持有一个以 `--serve` 启动的 Python 进程，
+// 进程只在启动时加载一次 numpy 和模型，之后按行读写 JSON：
+//   请求 {"id": "...", "base64_image": "..."}
+//   响应 {"id": "...", "watermarked_image": "...", "watermark_image": "...", "error": null}
+// 响应按 id 与请求对应，无法解析或 id 不符的行视为脚本的日志输出并忽略。
+// 超时或进程退出时杀掉进程并返回错误，下一个任务会重新启动进程。
+// 单次模式：与现有的 main_class.py 相同，每个请求启动一个进程，从 stdin 读取全部输入后输出一个 JSON 对象，
+// 超时时杀掉进程。脚本支持 `--serve` 之前使用单次模式。
+// 同时处理的任务数等于 worker 数量，排队的任务数有上限，所有等待都在 worker 线程和调用方的阻塞线程中完成，
+// 调用方需要在 spawn_blocking 中调用
+
+pub const DEFAULT_WORKERS: usize = 2;
+pub const DEFAULT_TIMEOUT_SECS: u64 = 60;
+// 每个 worker 最多排队的任务数，队列满时调用方阻塞等待
+const QUEUE_PER_WORKER: usize = 4;
+
+static POOLS: OnceLock<Mutex<HashMap<PoolConfig, Arc<PythonPool>>>> = OnceLock::new();
+
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct PoolConfig {
+    pub interpreter: String,
+    pub script: String,
+    pub workers: usize,
+    pub timeout: Duration,
+    // 是否以 --serve 启动常驻进程，否则每个请求启动一次进程
+    pub serve: bool,
+}
+
+impl PoolConfig {
+    pub fn new(script: &str) -> Self {
+        PoolConfig {
+            interpreter: "python3".to_string(),
+            script: script.to_string(),
+            workers: DEFAULT_WORKERS,
+            timeout: Duration::from_secs(DEFAULT_TIMEOUT_SECS),
+            serve: false,
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub struct PythonOutput {
+    pub id: Option<String>,
+    pub watermarked_image: Option<String>,
+    pub watermark_image: Option<String>,
+    pub error: Option<String>,
+}
+
+struct Job {
+    payload: Value,
+    reply: mpsc::Sender<Result<PythonOutput, ImageError>>,
+}
+
+pub struct PythonPool {
+    sender: SyncSender<Job>,
+}
+
+/// 按配置获取进程池，相同配置共用一个进程池，第一次使用时启动
+pub fn python_pool(config: &PoolConfig) -> Arc<PythonPool> {
+    let pools = POOLS.get_or_init(|| Mutex::new(HashMap::new()));
+    let mut pools = pools.lock().unwrap_or_else(|e| e.into_inner());
+    pools
+        .entry(config.clone())
+        .or_insert_with(|| Arc::new(PythonPool::start(config.clone())))
+        .clone()
+}
+
+impl PythonPool {
+    pub fn start(config: PoolConfig) -> Self {
+        let workers = config.workers.max(1);
+        let (sender, receiver) = mpsc::sync_channel(workers * QUEUE_PER_WORKER);
+        let receiver = Arc::new(Mutex::new(receiver));
+        for index in 0..workers {
+            let receiver = receiver.clone();
+            let config = config.clone();
+            let spawned = thread::Builder::new()
+                .name(format!("python-watermark-{}", index))
+                .spawn(move || worker_loop(&config, &receiver));
+            if let Err(e) = spawned {
+                println!("Failed to start python watermark worker {}: {}", index, e);
+            }
+        }
+        PythonPool { sender }
+    }
+
+    /// 发送一个请求并阻塞等待响应
+    pub fn call(&self, payload: Value) -> Result<PythonOutput, ImageError> {
+        let (reply, response) = mpsc::channel();
+        self.sender
+            .send(Job { payload, reply })
+            .map_err(|_| pool_stopped())?;
+        response.recv().map_err(|_| pool_stopped())?
+    }
+
+    /// 以 JPEG 发送图片，返回 (带水印图片的 base64, 水印图片的 base64)
+    pub fn watermark(&self, img: &DynamicImage) -> Result<(String, String), ImageError> {
+        let mut img_bytes = Vec::new();
+        img.write_to(&mut Cursor::new(&mut img_bytes), ImageFormat::Jpeg)
+            .map_err(|_| ImageError::EncodeBytesError)?;
+        let output = self.call(json!({
+            "base64_image": general_purpose::STANDARD.encode(&img_bytes)
+        }))?;
+
+        match (
+            output.watermarked_image,
+            output.watermark_image,
+            output.error,
+        ) {
+            (Some(watermarked), Some(watermark), _) => Ok((watermarked, watermark)),
+            (_, _, Some(error)) => Err(ImageError::WatermarkProcessError(error)),
+            _ => Err(ImageError::WatermarkProcessError(
+                "Python script did not return expected data".to_string(),
+            )),
+        }
+    }
+}
+
+fn pool_stopped() -> ImageError {
+    ImageError::WatermarkProcessError("python watermark workers have stopped".to_string())
+}
+
+fn worker_loop(config: &PoolConfig, receiver: &Mutex<Receiver<Job>>) {
+    let mut worker: Option<Worker> = None;
+    loop {
+        // 只在取任务时持有锁，处理任务时其他 worker 可以继续取
+        let job = {
+            let receiver = receiver.lock().unwrap_or_else(|e| e.into_inner());
+            match receiver.recv() {
+                Ok(job) => job,
+                Err(_) => return,
+            }
+        };
+
+        if !config.serve {
+            let _ = job.reply.send(run_once(config, job.payload));
+            continue;
+        }
+        if worker.is_none() {
+            match Worker::spawn(config) {
+                Ok(spawned) => worker = Some(spawned),
+                Err(e) => {
+                    let _ = job.reply.send(Err(e));
+                    continue;
+                }
+            }
+        }
+        let Some(current) = worker.as_mut() else {
+            continue;
+        };
+        let result = current.call(job.payload, config.timeout);
+        if result.is_err() {
+            // 超时或进程退出：丢弃进程，下一个任务重新启动
+            worker = None;
+        }
+        let _ = job.reply.send(result);
+    }
+}
+
+struct Worker {
+    child: Child,
+    stdin: ChildStdin,
+    lines: Receiver<String>,
+}
+
+impl Worker {
+    fn spawn(config: &PoolConfig) -> Result<Self, ImageError> {
+        let (child, stdin, stdout) = spawn_script(config, &["--serve"])?;
+        // stdout 按行转发，进程退出时 channel 断开
+        let (sender, lines) = mpsc::channel();
+        thread::spawn(move || {
+            for line in BufReader::new(stdout).lines().map_while(Result::ok) {
+                if sender.send(line).is_err() {
+                    break;
+                }
+            }
+        });
+        Ok(Worker {
+            child,
+            stdin,
+            lines,
+        })
+    }
+
+    fn call(&mut self, mut payload: Value, timeout: Duration) -> Result<PythonOutput, ImageError> {
+        let id = format!("{:032x}", rand::random::<u128>());
+        if let Value::Object(request) = &mut payload {
+            request.insert("id".to_string(), Value::String(id.clone()));
+        }
+        writeln!(self.stdin, "{}", payload)
+            .and_then(|_| self.stdin.flush())
+            .map_err(|_| self.exited())?;
+
+        let deadline = Instant::now() + timeout;
+        loop {
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            let line = match self.lines.recv_timeout(remaining) {
+                Ok(line) => line,
+                Err(RecvTimeoutError::Timeout) => {
+                    return Err(ImageError::WatermarkProcessError(format!(
+                        "python watermark worker timed out after {}s",
+                        timeout.as_secs_f32()
+                    )))
+                }
+                Err(RecvTimeoutError::Disconnected) => return Err(self.exited()),
+            };
+            if let Ok(output) = serde_json::from_str::<PythonOutput>(&line) {
+                if output.id.as_deref() == Some(id.as_str()) {
+                    return Ok(output);
+                }
+            }
+        }
+    }
+
+    fn exited(&mut self) -> ImageError {
+        exited(&mut self.child)
+    }
+}
+
+impl Drop for Worker {
+    fn drop(&mut self) {
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+    }
+}
+
+// 启动脚本并转发 stderr，返回进程和它的 stdin、stdout
+fn spawn_script(
+    config: &PoolConfig,
+    args: &[&str],
+) -> Result<(Child, ChildStdin, ChildStdout), ImageError> {
+    let mut child = Command::new(&config.interpreter)
+        .arg(&config.script)
+        .args(args)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .map_err(|_| ImageError::FailedStartAddWatermark)?;
+    let (Some(stdin), Some(stdout), Some(stderr)) =
+        (child.stdin.take(), child.stdout.take(), child.stderr.take())
+    else {
+        let _ = child.kill();
+        let _ = child.wait();
+        return Err(ImageError::FailedStartAddWatermark);
+    };
+    // 持续读取 stderr，避免管道写满阻塞 Python 进程
+    thread::spawn(move || {
+        for line in BufReader::new(stderr).lines().map_while(Result::ok) {
+            println!("python watermark worker: {}", line);
+        }
+    });
+    Ok((child, stdin, stdout))
+}
+
+// 单次模式：启动进程，写入请求后关闭 stdin，在超时之前读取全部输出并解析为一个 JSON 对象
+fn run_once(config: &PoolConfig, payload: Value) -> Result<PythonOutput, ImageError> {
+    let (mut child, mut stdin, mut stdout) = spawn_script(config, &[])?;
+    // 读写都放到单独的线程中，进程卡住时不影响超时
+    thread::spawn(move || {
+        let _ = stdin.write_all(payload.to_string().as_bytes());
+    });
+    let (sender, output) = mpsc::channel();
+    thread::spawn(move || {
+        let mut text = String::new();
+        let _ = sender.send(stdout.read_to_string(&mut text).map(|_| text));
+    });
+
+    let text = match output.recv_timeout(config.timeout) {
+        Ok(Ok(text)) => text,
+        Err(RecvTimeoutError::Timeout) => {
+            let _ = child.kill();
+            let _ = child.wait();
+            return Err(ImageError::WatermarkProcessError(format!(
+                "python watermark script timed out after {}s",
+                config.timeout.as_secs_f32()
+            )));
+        }
+        Ok(Err(_)) | Err(RecvTimeoutError::Disconnected) => {
+            let _ = child.kill();
+            let error = exited(&mut child);
+            let _ = child.wait();
+            return Err(error);
+        }
+    };
+    let _ = child.wait();
+    // 脚本可能在结果之前打印日志，整体无法解析时取最后一个能解析的行
+    serde_json::from_str::<PythonOutput>(text.trim())
+        .ok()
+        .or_else(|| {
+            text.lines()
+                .rev()
+                .find_map(|line| serde_json::from_str::<PythonOutput>(line).ok())
+        })
+        .ok_or_else(|| {
+            ImageError::WatermarkProcessError(
+                "Python script did not return expected data".to_string(),
+            )
+        })
+}
+
+fn exited(child: &mut Child) -> ImageError {
+    let status = child
+        .try_wait()
+        .ok()
+        .flatten()
+        .map(|status| status.to_string())
+        .unwrap_or_else(|| "closed its pipes".to_string());
+    ImageError::WatermarkProcessError(format!("python watermark worker exited: {}", status))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // 用 sh 模拟 Python worker：按 id 回显，遇到 crash 退出，遇到 slow 不返回
+    const FAKE_WORKER: &str = r#"
+echo "loading model" >&2
+while IFS= read -r line; do
+  id=$(printf '%s' "$line" | sed 's/.*"id":"\([0-9a-f]*\)".*/\1/')
+  case "$line" in
+    *crash*) exit 3 ;;
+    *slow*) sleep 5 ;;
+  esac
+  echo "log line"
+  printf '{"id":"%s","watermarked_image":"%s","watermark_image":"d20="}\n' "$id" "$$"
+done
+"#;
+
+    #[test]
+    fn test_python_pool() {
+        let script = std::env::temp_dir().join(format!("fake_worker_{}.sh", std::process::id()));
+        std::fs::write(&script, FAKE_WORKER).unwrap();
+        let pool = PythonPool::start(PoolConfig {
+            interpreter: "sh".to_string(),
+            script: script.to_string_lossy().to_string(),
+            workers: 1,
+            timeout: Duration::from_millis(500),
+            serve: true,
+        });
+
+        // 同一个进程处理多个请求
+        let first = pool.call(json!({ "base64_image": "a" })).unwrap();
+        let second = pool.call(json!({ "base64_image": "b" })).unwrap();
+        assert_eq!(first.watermark_image.as_deref(), Some("d20="));
+        assert_eq!(first.watermarked_image, second.watermarked_image);
+
+        // 进程崩溃后重新启动
+        assert!(pool.call(json!({ "base64_image": "crash" })).is_err());
+        let restarted = pool.call(json!({ "base64_image": "c" })).unwrap();
+        assert_ne!(restarted.watermarked_image, first.watermarked_image);
+
+        // 超时后杀掉进程，之后的请求正常处理
+        let started = Instant::now();
+        assert!(pool.call(json!({ "base64_image": "slow" })).is_err());
+        assert!(started.elapsed() < Duration::from_secs(3));
+        assert!(pool.call(json!({ "base64_image": "d" })).is_ok());
+
+        std::fs::remove_file(&script).unwrap();
+    }
+
+    // 模拟现有的单次脚本：读取全部 stdin 后输出一个 JSON 对象
+    const FAKE_ONE_SHOT: &str = r#"
+input=$(cat)
+case "$input" in
+  *slow*) sleep 5 ;;
+  *fail*) echo "not json"; exit 1 ;;
+esac
+echo "loading model"
+printf '{"watermarked_image":"%s","watermark_image":"d20="}\n' "$$"
+"#;
+
+    #[test]
+    fn test_python_one_shot() {
+        let script = std::env::temp_dir().join(format!("fake_one_shot_{}.sh", std::process::id()));
+        std::fs::write(&script, FAKE_ONE_SHOT).unwrap();
+        let pool = PythonPool::start(PoolConfig {
+            interpreter: "sh".to_string(),
+            script: script.to_string_lossy().to_string(),
+            workers: 1,
+            timeout: Duration::from_millis(500),
+            serve: false,
+        });
+
+        // 每个请求启动一个新进程
+        let first = pool.call(json!({ "base64_image": "a" })).unwrap();
+        let second = pool.call(json!({ "base64_image": "b" })).unwrap();
+        assert_eq!(first.watermark_image.as_deref(), Some("d20="));
+        assert_ne!(first.watermarked_image, second.watermarked_image);
+
+        assert!(pool.call(json!({ "base64_image": "fail" })).is_err());
+        let started = Instant::now();
+        assert!(pool.call(json!({ "base64_image": "slow" })).is_err());
+        assert!(started.elapsed() < Duration::from_secs(3));
+        assert!(pool.call(json!({ "base64_image": "c" })).is_ok());
+
+        std::fs::remove_file(&script).unwrap();
+    }
+}
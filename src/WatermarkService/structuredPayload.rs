@@ -0,0 +1,406 @@
+use crate::Error::ImageError;
+use hmac::{Hmac, Mac};
+use image::DynamicImage;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::sync::OnceLock;
+
+// 结构化水印载荷：将 (所有者 ID, 图片 ID, 时间戳) 编码为 12 字节，附加用密钥计算的 HMAC-SHA256 前 6 字节，
+// 再加 10 字节 Reed-Solomon 校验（GF(256)，最多纠正 5 个字节的错误），共 28 字节 224 比特嵌入图片。
+// 提取后先纠错再校验 MAC，通过校验的水印可以直接解码为记录，并证明是由持有密钥的本系统嵌入的
+
+pub const PAYLOAD_BYTES: usize = 12;
+pub const MAC_BYTES: usize = 6;
+pub const PARITY_BYTES: usize = 10;
+pub const CODEWORD_BYTES: usize = PAYLOAD_BYTES + MAC_BYTES + PARITY_BYTES;
+pub const CODEWORD_BITS: usize = CODEWORD_BYTES * 8;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct WatermarkPayload {
+    // users.id
+    pub owner_id: u32,
+    // 原图像素的 SHA-256 前 4 字节，登记时保存在 watermarks.payload_image_id 中，
+    // 解码出载荷后按它和 owner_id 找到对应的图片记录
+    pub image_id: u32,
+    // 嵌入时的 unix 时间（秒）
+    pub timestamp: u32,
+}
+
+impl WatermarkPayload {
+    /// 为 owner_id 上传的图片生成载荷，时间戳为当前时间
+    pub fn new(owner_id: u32, img: &DynamicImage) -> Self {
+        WatermarkPayload {
+            owner_id,
+            image_id: image_id(img),
+            timestamp: chrono::Utc::now().timestamp() as u32,
+        }
+    }
+
+    pub fn to_bytes(&self) -> [u8; PAYLOAD_BYTES] {
+        let mut bytes = [0u8; PAYLOAD_BYTES];
+        bytes[0..4].copy_from_slice(&self.owner_id.to_be_bytes());
+        bytes[4..8].copy_from_slice(&self.image_id.to_be_bytes());
+        bytes[8..12].copy_from_slice(&self.timestamp.to_be_bytes());
+        bytes
+    }
+
+    pub fn from_bytes(bytes: &[u8; PAYLOAD_BYTES]) -> Self {
+        let field = |offset: usize| {
+            u32::from_be_bytes([
+                bytes[offset],
+                bytes[offset + 1],
+                bytes[offset + 2],
+                bytes[offset + 3],
+            ])
+        };
+        WatermarkPayload {
+            owner_id: field(0),
+            image_id: field(4),
+            timestamp: field(8),
+        }
+    }
+
+    /// 编码为 CODEWORD_BITS 个待嵌入的比特，每字节高位在前
+    pub fn encode(&self, key: &[u8]) -> Result<Vec<bool>, ImageError> {
+        let payload = self.to_bytes();
+        let mut message = payload.to_vec();
+        message.extend_from_slice(&mac(key, &payload)?);
+        let codeword = rs_encode(&message, PARITY_BYTES);
+        Ok(codeword
+            .iter()
+            .flat_map(|byte| (0..8).rev().map(move |shift| (byte >> shift) & 1 == 1))
+            .collect())
+    }
+
+    /// 从提取出的软判决值解码，错误超出纠错能力或 MAC 不符时返回错误
+    pub fn decode(soft: &[f32], key: &[u8]) -> Result<Self, ImageError> {
+        if soft.len() < CODEWORD_BITS {
+            return Err(ImageError::WatermarkProcessError(format!(
+                "structured watermark needs {} bits, got {}",
+                CODEWORD_BITS,
+                soft.len()
+            )));
+        }
+        let received: Vec<u8> = soft[..CODEWORD_BITS]
+            .chunks(8)
+            .map(|bits| {
+                bits.iter()
+                    .fold(0u8, |byte, bit| (byte << 1) | (*bit > 0.0) as u8)
+            })
+            .collect();
+        let message = rs_decode(&received, PARITY_BYTES).ok_or_else(|| {
+            ImageError::WatermarkProcessError(
+                "structured watermark is too damaged to correct".to_string(),
+            )
+        })?;
+
+        let mut payload = [0u8; PAYLOAD_BYTES];
+        payload.copy_from_slice(&message[..PAYLOAD_BYTES]);
+        if mac(key, &payload)? != message[PAYLOAD_BYTES..] {
+            return Err(ImageError::WatermarkProcessError(
+                "structured watermark failed authentication".to_string(),
+            ));
+        }
+        Ok(WatermarkPayload::from_bytes(&payload))
+    }
+}
+
+/// 图片 ID：原图 RGB 像素的 SHA-256 前 4 字节
+pub fn image_id(img: &DynamicImage) -> u32 {
+    let digest = Sha256::digest(img.to_rgb8().as_raw());
+    u32::from_be_bytes([digest[0], digest[1], digest[2], digest[3]])
+}
+
+fn mac(key: &[u8], payload: &[u8]) -> Result<[u8; MAC_BYTES], ImageError> {
+    if key.is_empty() {
+        return Err(ImageError::WatermarkProcessError(
+            "watermark payload key is not configured".to_string(),
+        ));
+    }
+    let mut hmac = Hmac::<Sha256>::new_from_slice(key)
+        .map_err(|e| ImageError::WatermarkProcessError(e.to_string()))?;
+    hmac.update(payload);
+    let mut tag = [0u8; MAC_BYTES];
+    tag.copy_from_slice(&hmac.finalize().into_bytes()[..MAC_BYTES]);
+    Ok(tag)
+}
+
+// Reed-Solomon over GF(256)，本原多项式 0x11d，生成元 2，生成多项式的根为 2^0..2^(nsym-1)。
+// 多项式按最高次项在前的字节数组表示
+
+struct Gf {
+    exp: [u8; 512],
+    log: [u8; 256],
+}
+
+fn gf() -> &'static Gf {
+    static TABLES: OnceLock<Gf> = OnceLock::new();
+    TABLES.get_or_init(|| {
+        let mut tables = Gf {
+            exp: [0; 512],
+            log: [0; 256],
+        };
+        let mut x: u16 = 1;
+        for i in 0..255 {
+            tables.exp[i] = x as u8;
+            tables.log[x as usize] = i as u8;
+            x <<= 1;
+            if x & 0x100 != 0 {
+                x ^= 0x11d;
+            }
+        }
+        for i in 255..512 {
+            tables.exp[i] = tables.exp[i - 255];
+        }
+        tables
+    })
+}
+
+fn gf_mul(a: u8, b: u8) -> u8 {
+    if a == 0 || b == 0 {
+        return 0;
+    }
+    let tables = gf();
+    tables.exp[tables.log[a as usize] as usize + tables.log[b as usize] as usize]
+}
+
+fn gf_div(a: u8, b: u8) -> u8 {
+    if a == 0 {
+        return 0;
+    }
+    let tables = gf();
+    tables.exp[(tables.log[a as usize] as usize + 255 - tables.log[b as usize] as usize) % 255]
+}
+
+fn gf_pow(x: u8, power: i32) -> u8 {
+    let tables = gf();
+    tables.exp[(tables.log[x as usize] as i32 * power).rem_euclid(255) as usize]
+}
+
+fn gf_inverse(x: u8) -> u8 {
+    gf_div(1, x)
+}
+
+fn poly_scale(p: &[u8], x: u8) -> Vec<u8> {
+    p.iter()
+        .map(|coefficient| gf_mul(*coefficient, x))
+        .collect()
+}
+
+// 低次项对齐相加
+fn poly_add(p: &[u8], q: &[u8]) -> Vec<u8> {
+    let len = p.len().max(q.len());
+    let mut r = vec![0u8; len];
+    for (i, coefficient) in p.iter().enumerate() {
+        r[i + len - p.len()] = *coefficient;
+    }
+    for (i, coefficient) in q.iter().enumerate() {
+        r[i + len - q.len()] ^= *coefficient;
+    }
+    r
+}
+
+fn poly_mul(p: &[u8], q: &[u8]) -> Vec<u8> {
+    let mut r = vec![0u8; p.len() + q.len() - 1];
+    for (j, b) in q.iter().enumerate() {
+        for (i, a) in p.iter().enumerate() {
+            r[i + j] ^= gf_mul(*a, *b);
+        }
+    }
+    r
+}
+
+fn poly_eval(p: &[u8], x: u8) -> u8 {
+    p.iter()
+        .skip(1)
+        .fold(p[0], |y, coefficient| gf_mul(y, x) ^ coefficient)
+}
+
+fn generator_poly(nsym: usize) -> Vec<u8> {
+    (0..nsym).fold(vec![1], |g, i| poly_mul(&g, &[1, gf_pow(2, i as i32)]))
+}
+
+/// 系统码编码：返回 message 后接 nsym 个校验字节
+fn rs_encode(message: &[u8], nsym: usize) -> Vec<u8> {
+    let generator = generator_poly(nsym);
+    let mut out = message.to_vec();
+    out.resize(message.len() + nsym, 0);
+    for i in 0..message.len() {
+        let coefficient = out[i];
+        if coefficient != 0 {
+            for (j, g) in generator.iter().enumerate().skip(1) {
+                out[i + j] ^= gf_mul(*g, coefficient);
+            }
+        }
+    }
+    out[..message.len()].copy_from_slice(message);
+    out
+}
+
+// 伴随式，前面补一个 0 以便与错误定位多项式的下标对齐
+fn syndromes(codeword: &[u8], nsym: usize) -> Vec<u8> {
+    std::iter::once(0)
+        .chain((0..nsym).map(|i| poly_eval(codeword, gf_pow(2, i as i32))))
+        .collect()
+}
+
+// Berlekamp-Massey 求错误定位多项式
+fn error_locator(synd: &[u8], nsym: usize) -> Option<Vec<u8>> {
+    let mut err_loc = vec![1u8];
+    let mut old_loc = vec![1u8];
+    let shift = synd.len() - nsym;
+    for i in 0..nsym {
+        let k = i + shift;
+        let mut delta = synd[k];
+        for j in 1..err_loc.len() {
+            delta ^= gf_mul(err_loc[err_loc.len() - 1 - j], synd[k - j]);
+        }
+        old_loc.push(0);
+        if delta != 0 {
+            if old_loc.len() > err_loc.len() {
+                let new_loc = poly_scale(&old_loc, delta);
+                old_loc = poly_scale(&err_loc, gf_inverse(delta));
+                err_loc = new_loc;
+            }
+            err_loc = poly_add(&err_loc, &poly_scale(&old_loc, delta));
+        }
+    }
+    let leading = err_loc.iter().take_while(|c| **c == 0).count();
+    let err_loc = err_loc[leading..].to_vec();
+    if (err_loc.len() - 1) * 2 > nsym {
+        return None;
+    }
+    Some(err_loc)
+}
+
+// Chien 搜索：返回出错字节在码字中的下标
+fn error_positions(err_loc: &[u8], len: usize) -> Option<Vec<usize>> {
+    let reversed: Vec<u8> = err_loc.iter().rev().copied().collect();
+    let positions: Vec<usize> = (0..len)
+        .filter(|i| poly_eval(&reversed, gf_pow(2, *i as i32)) == 0)
+        .map(|i| len - 1 - i)
+        .collect();
+    (positions.len() == err_loc.len() - 1).then_some(positions)
+}
+
+// Forney 算法求错误值并修正
+fn correct_errata(codeword: &mut [u8], synd: &[u8], positions: &[usize]) {
+    let coefficient_positions: Vec<usize> =
+        positions.iter().map(|p| codeword.len() - 1 - p).collect();
+    let errata_locator = coefficient_positions.iter().fold(vec![1u8], |e_loc, i| {
+        poly_mul(&e_loc, &poly_add(&[1], &[gf_pow(2, *i as i32), 0]))
+    });
+
+    // 错误值多项式 Ω(x) = S(x)Λ(x) mod x^(nsym+1)，nsym 为 Λ 的次数
+    let reversed_synd: Vec<u8> = synd.iter().rev().copied().collect();
+    let product = poly_mul(&reversed_synd, &errata_locator);
+    let keep = errata_locator.len().min(product.len());
+    let evaluator = &product[product.len() - keep..];
+
+    let x: Vec<u8> = coefficient_positions
+        .iter()
+        .map(|p| gf_pow(2, -(255 - *p as i32)))
+        .collect();
+    for (i, xi) in x.iter().enumerate() {
+        let xi_inv = gf_inverse(*xi);
+        let locator_prime = x
+            .iter()
+            .enumerate()
+            .filter(|(j, _)| *j != i)
+            .fold(1u8, |acc, (_, xj)| gf_mul(acc, 1 ^ gf_mul(xi_inv, *xj)));
+        let y = gf_mul(*xi, poly_eval(evaluator, xi_inv));
+        codeword[positions[i]] ^= gf_div(y, locator_prime);
+    }
+}
+
+/// 纠错解码，返回去掉校验字节后的消息；错误超出纠错能力时返回 None
+fn rs_decode(codeword: &[u8], nsym: usize) -> Option<Vec<u8>> {
+    let mut codeword = codeword.to_vec();
+    let synd = syndromes(&codeword, nsym);
+    if synd.iter().any(|s| *s != 0) {
+        let err_loc = error_locator(&synd, nsym)?;
+        let positions = error_positions(&err_loc, codeword.len())?;
+        correct_errata(&mut codeword, &synd, &positions);
+        if syndromes(&codeword, nsym).iter().any(|s| *s != 0) {
+            return None;
+        }
+    }
+    codeword.truncate(codeword.len() - nsym);
+    Some(codeword)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::WatermarkService::nativeWatermark::{embed, extract, DEFAULT_STRENGTH};
+    use image::codecs::jpeg::JpegEncoder;
+    use image::RgbImage;
+
+    fn to_soft(bits: &[bool]) -> Vec<f32> {
+        bits.iter()
+            .map(|bit| if *bit { 1.0 } else { -1.0 })
+            .collect()
+    }
+
+    #[test]
+    fn test_structured_payload() {
+        let key = b"payload-test-key";
+        let payload = WatermarkPayload {
+            owner_id: 42,
+            image_id: 0xdead_beef,
+            timestamp: 1_714_521_600,
+        };
+        let bits = payload.encode(key).unwrap();
+        assert_eq!(bits.len(), CODEWORD_BITS);
+        assert_eq!(
+            WatermarkPayload::decode(&to_soft(&bits), key).unwrap(),
+            payload
+        );
+
+        // 5 个字节出错时可以纠正，包括 MAC 和校验字节
+        let mut damaged = bits.clone();
+        for byte in [0, 7, 13, 20, 27] {
+            for bit in 0..3 {
+                damaged[byte * 8 + bit] = !damaged[byte * 8 + bit];
+            }
+        }
+        assert_eq!(
+            WatermarkPayload::decode(&to_soft(&damaged), key).unwrap(),
+            payload
+        );
+
+        // 错误过多、密钥不对、载荷被篡改
+        for byte in [2, 4, 9] {
+            damaged[byte * 8] = !damaged[byte * 8];
+        }
+        assert!(WatermarkPayload::decode(&to_soft(&damaged), key).is_err());
+        assert!(WatermarkPayload::decode(&to_soft(&bits), b"other-key").is_err());
+        let forged = WatermarkPayload {
+            owner_id: 43,
+            ..payload
+        };
+        let mut message = forged.to_bytes().to_vec();
+        message.extend_from_slice(&mac(key, &payload.to_bytes()).unwrap());
+        let forged_bits: Vec<bool> = rs_encode(&message, PARITY_BYTES)
+            .iter()
+            .flat_map(|byte| (0..8).rev().map(move |shift| (byte >> shift) & 1 == 1))
+            .collect();
+        assert!(WatermarkPayload::decode(&to_soft(&forged_bits), key).is_err());
+        assert!(payload.encode(b"").is_err());
+
+        // 嵌入图片并经过 JPEG 压缩后直接解码
+        let img = DynamicImage::ImageRgb8(RgbImage::from_fn(320, 256, |x, y| {
+            image::Rgb([(x % 200) as u8 + 20, (y % 180) as u8 + 30, 128])
+        }));
+        let payload = WatermarkPayload::new(7, &img);
+        assert_eq!(payload.image_id, image_id(&img));
+        let watermarked = embed(&img, &payload.encode(key).unwrap(), DEFAULT_STRENGTH).unwrap();
+        let mut jpeg = Vec::new();
+        JpegEncoder::new_with_quality(&mut jpeg, 75)
+            .encode_image(&watermarked)
+            .unwrap();
+        let decoded = image::load_from_memory(&jpeg).unwrap();
+        let soft = extract(&decoded, CODEWORD_BITS, DEFAULT_STRENGTH).unwrap();
+        assert_eq!(WatermarkPayload::decode(&soft, key).unwrap(), payload);
+    }
+}
@@ -0,0 +1,192 @@
+use crate::Error::ImageError;
+use image::error::ImageError as DecodeError;
+use image::{DynamicImage, ImageFormat, ImageReader, Limits};
+use rocket::fairing::AdHoc;
+use serde::{Deserialize, Serialize};
+use std::io::Cursor;
+use std::sync::OnceLock;
+
+// 上传策略：在解码之前依次检查字节大小、文件头识别出的格式、图片宽高和像素数，
+// 解码时再由 image 的 Limits 限制解码器的内存分配，防止解压炸弹耗尽内存。
+// 可以在 Rocket.toml 的 [default.upload_policy] 中覆盖默认值
+
+static POLICY: OnceLock<UploadPolicy> = OnceLock::new();
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(default)]
+pub struct UploadPolicy {
+    // 单个文件的最大字节数
+    pub max_bytes: u64,
+    // 允许的格式，按文件头识别，如 "jpeg"、"png"
+    pub allowed_formats: Vec<String>,
+    pub max_width: u32,
+    pub max_height: u32,
+    // 宽 x 高的上限
+    pub max_pixels: u64,
+    // 解码器最多分配的内存字节数
+    pub max_alloc: u64,
+    // 批量登记时一个 ZIP 压缩包解压后的总字节数上限
+    pub max_archive_bytes: u64,
+}
+
+impl Default for UploadPolicy {
+    fn default() -> Self {
+        UploadPolicy {
+            max_bytes: 20 * 1024 * 1024,
+            allowed_formats: ["jpeg", "png", "webp", "bmp", "tiff"]
+                .iter()
+                .map(|format| format.to_string())
+                .collect(),
+            max_width: 12_000,
+            max_height: 12_000,
+            max_pixels: 50_000_000,
+            max_alloc: 512 * 1024 * 1024,
+            max_archive_bytes: 2 * 1024 * 1024 * 1024,
+        }
+    }
+}
+
+impl UploadPolicy {
+    /// 只检查文件大小和格式，不读取图片内容
+    pub fn check_bytes(&self, bytes: &[u8]) -> Result<ImageFormat, ImageError> {
+        if bytes.len() as u64 > self.max_bytes {
+            return Err(ImageError::ImageRejected(format!(
+                "image is {} bytes, the limit is {} bytes",
+                bytes.len(),
+                self.max_bytes
+            )));
+        }
+        let format = image::guess_format(bytes)
+            .map_err(|_| ImageError::ImageRejected("unrecognized image format".to_string()))?;
+        let allowed = self
+            .allowed_formats
+            .iter()
+            .any(|name| ImageFormat::from_extension(name) == Some(format));
+        if !allowed {
+            return Err(ImageError::ImageRejected(format!(
+                "image format {:?} is not allowed",
+                format
+            )));
+        }
+        Ok(format)
+    }
+
+    /// 按策略检查并解码图片，损坏或被截断的文件返回 CorruptImage
+    pub fn decode(&self, bytes: &[u8]) -> Result<DynamicImage, ImageError> {
+        let format = self.check_bytes(bytes)?;
+
+        // 先只读取文件头中的尺寸，超出限制时不进行解码
+        let (width, height) = ImageReader::with_format(Cursor::new(bytes), format)
+            .into_dimensions()
+            .map_err(|e| ImageError::CorruptImage(e.to_string()))?;
+        if width > self.max_width || height > self.max_height {
+            return Err(ImageError::ImageRejected(format!(
+                "image is {}x{}, the limit is {}x{}",
+                width, height, self.max_width, self.max_height
+            )));
+        }
+        if u64::from(width) * u64::from(height) > self.max_pixels {
+            return Err(ImageError::ImageRejected(format!(
+                "image has {} pixels, the limit is {}",
+                u64::from(width) * u64::from(height),
+                self.max_pixels
+            )));
+        }
+
+        let mut limits = Limits::default();
+        limits.max_image_width = Some(self.max_width);
+        limits.max_image_height = Some(self.max_height);
+        limits.max_alloc = Some(self.max_alloc);
+        let mut reader = ImageReader::with_format(Cursor::new(bytes), format);
+        reader.limits(limits);
+        reader.decode().map_err(|e| match e {
+            DecodeError::Limits(e) => ImageError::ImageRejected(e.to_string()),
+            e => ImageError::CorruptImage(e.to_string()),
+        })
+    }
+}
+
+/// 当前生效的上传策略，未配置时使用默认值
+pub fn upload_policy() -> &'static UploadPolicy {
+    POLICY.get_or_init(UploadPolicy::default)
+}
+
+/// 启动时从 Rocket 配置中读取 upload_policy
+pub fn upload_policy_config() -> AdHoc {
+    AdHoc::on_ignite("Upload Policy", |rocket| async {
+        let policy = rocket
+            .figment()
+            .extract_inner::<UploadPolicy>("upload_policy")
+            .unwrap_or_default();
+        let _ = POLICY.set(policy);
+        rocket
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::RgbImage;
+
+    fn encode(width: u32, height: u32, format: ImageFormat) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        DynamicImage::ImageRgb8(RgbImage::new(width, height))
+            .write_to(&mut Cursor::new(&mut bytes), format)
+            .unwrap();
+        bytes
+    }
+
+    #[test]
+    fn test_upload_policy() {
+        let policy = UploadPolicy {
+            max_bytes: 64 * 1024,
+            max_width: 200,
+            max_height: 200,
+            max_pixels: 20_000,
+            ..Default::default()
+        };
+
+        let png = encode(100, 100, ImageFormat::Png);
+        assert_eq!(policy.decode(&png).unwrap().width(), 100);
+
+        // 格式不在白名单中、无法识别
+        let gif = encode(10, 10, ImageFormat::Gif);
+        assert!(matches!(
+            policy.decode(&gif),
+            Err(ImageError::ImageRejected(_))
+        ));
+        assert!(matches!(
+            policy.decode(b"not an image at all"),
+            Err(ImageError::ImageRejected(_))
+        ));
+
+        // 超出宽度和像素数限制
+        let wide = encode(300, 10, ImageFormat::Png);
+        assert!(matches!(
+            policy.decode(&wide),
+            Err(ImageError::ImageRejected(_))
+        ));
+        let large = encode(150, 150, ImageFormat::Png);
+        assert!(matches!(
+            policy.decode(&large),
+            Err(ImageError::ImageRejected(_))
+        ));
+
+        // 超出字节数限制
+        let small = UploadPolicy {
+            max_bytes: 16,
+            ..policy.clone()
+        };
+        assert!(matches!(
+            small.decode(&png),
+            Err(ImageError::ImageRejected(_))
+        ));
+
+        // 被截断的文件
+        let truncated = &png[..png.len() / 2];
+        assert!(matches!(
+            policy.decode(truncated),
+            Err(ImageError::CorruptImage(_))
+        ));
+    }
+}
@@ -0,0 +1,342 @@
+use crate::DataBase::{get_db, Images, Users, Watermarks};
+use crate::Error::ImageError;
+use crate::WatermarkService::engine::{EngineConfig, WatermarkEngine};
+use crate::WatermarkService::nativeWatermark::png_to_bits;
+use crate::WatermarkService::structuredPayload::WatermarkPayload;
+use base64::engine::general_purpose;
+use base64::Engine;
+use image::DynamicImage;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::sync::Arc;
+
+// 归属验证：按水印记录的引擎从可疑图片中提取水印，与 watermarks 表中保存的每个水印比较，
+// 误码率最低且不超过阈值的图片即为匹配结果。与一条 64 比特的随机水印比较时，误码率不超过 0.15（9 个比特）
+// 的概率约为 2e-9，与 N 条记录比较取最好的一条时偶然匹配的概率约为 N 倍，置信度按实际比较的记录数计算。
+// payload 引擎嵌入的结构化载荷可以直接解码，MAC 校验通过时按载荷找到对应的记录
+
+// 默认的误码率阈值
+pub const DEFAULT_MAX_BIT_ERROR_RATE: f32 = 0.15;
+// 允许请求指定的最大阈值，误码率接近 0.5 时随机图片也会匹配
+pub const MAX_BIT_ERROR_RATE_LIMIT: f32 = 0.25;
+// 每次从 watermarks 表读取的记录数
+const SCAN_PAGE_SIZE: u64 = 500;
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct WatermarkMatch {
+    pub username: Option<String>,
+    pub image_cid: Option<String>,
+    pub token_id: Option<String>,
+    pub bit_error_rate: f32,
+    pub correlation: f32,
+    pub confidence: f32,
+    // 结构化载荷解码并通过 MAC 校验时返回
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub payload: Option<WatermarkPayload>,
+}
+
+/// 一次查找的结果，compared 为参与比较的记录数，skipped 为引擎不支持提取或记录无法解析而跳过的记录数
+#[derive(Clone, Debug, Default)]
+pub struct OwnerSearch {
+    pub owner: Option<WatermarkMatch>,
+    pub compared: usize,
+    pub skipped: usize,
+}
+
+/// 与 candidates 条独立的随机水印比较时，至少有一条在 bits 个比特中错误不超过 errors 个的概率
+pub fn false_match_probability(bits: usize, errors: usize, candidates: usize) -> f64 {
+    if bits == 0 || errors >= bits {
+        return 1.0;
+    }
+    // 在对数空间中累加二项分布 B(bits, 0.5) 的前 errors + 1 项，避免 2^-bits 下溢
+    let mut ln_term = -(bits as f64) * std::f64::consts::LN_2;
+    let mut ln_sum = ln_term;
+    for i in 1..=errors {
+        ln_term += ((bits - i + 1) as f64 / i as f64).ln();
+        let (high, low) = if ln_term > ln_sum {
+            (ln_term, ln_sum)
+        } else {
+            (ln_sum, ln_term)
+        };
+        ln_sum = high + (low - high).exp().ln_1p();
+    }
+    let single = ln_sum.exp().min(1.0);
+    // 1 - (1 - p)^N
+    -(candidates.max(1) as f64 * (-single).ln_1p()).exp_m1()
+}
+
+/// 返回 (误码率, 归一化相关系数)，bits 中的 1/0 按 +1/-1 参与相关计算
+pub fn compare(soft: &[f32], bits: &[bool]) -> (f32, f32) {
+    let len = soft.len().min(bits.len());
+    if len == 0 {
+        return (1.0, 0.0);
+    }
+    let errors = soft
+        .iter()
+        .zip(bits)
+        .filter(|(value, bit)| (**value > 0.0) != **bit)
+        .count();
+    let dot: f32 = soft
+        .iter()
+        .zip(bits)
+        .map(|(value, bit)| if *bit { *value } else { -*value })
+        .sum();
+    let energy = soft[..len]
+        .iter()
+        .map(|value| value * value)
+        .sum::<f32>()
+        .sqrt();
+    let correlation = if energy > 0.0 {
+        dot / (energy * (len as f32).sqrt())
+    } else {
+        0.0
+    };
+    (errors as f32 / len as f32, correlation)
+}
+
+// 每种引擎的提取结果：(软判决值, 解码出的载荷)，不支持提取时为 None
+type Extracted = Option<(Vec<f32>, Option<WatermarkPayload>)>;
+
+/// 在所有登记过水印的图片中查找与 img 最接近的一张，误码率超过 max_bit_error_rate 时 owner 为 None。
+/// 记录按页读取，每张图片按其记录的引擎和参数提取，相同的引擎只提取一次。
+/// 结构化载荷通过纠错和 MAC 校验时直接按载荷确定记录，不受误码率阈值限制
+pub async fn find_owner(
+    img: DynamicImage,
+    max_bit_error_rate: f32,
+) -> Result<OwnerSearch, ImageError> {
+    let rb = get_db().await;
+    let img = Arc::new(img);
+    let mut extracted: HashMap<String, Extracted> = HashMap::new();
+    // (记录, 误码率, 相关系数, 比特数)
+    let mut best: Option<(Watermarks, f32, f32, usize)> = None;
+    let mut authentic: Option<(Watermarks, f32, f32, WatermarkPayload)> = None;
+    let mut decoded: Option<WatermarkPayload> = None;
+    let mut compared = 0;
+    let mut skipped = 0;
+    let mut after = 0;
+    'scan: loop {
+        let page = Watermarks::select_after(rb, after, SCAN_PAGE_SIZE)
+            .await
+            .map_err(ImageError::DatabaseError)?;
+        let Some(last) = page.last().and_then(|watermark| watermark.id) else {
+            break;
+        };
+        let finished = (page.len() as u64) < SCAN_PAGE_SIZE;
+        after = last;
+        for watermark in page {
+            // 无法解析的记录、不支持提取的引擎（如 python）跳过并计数，不影响其他图片的比较
+            let Ok(config) = EngineConfig::from_record(watermark.parameters.as_deref()) else {
+                skipped += 1;
+                continue;
+            };
+            let engine = config.engine();
+            let record = config.to_record();
+            if !extracted.contains_key(&record) {
+                let img = img.clone();
+                let config = config.clone();
+                let result = tokio::task::spawn_blocking(move || {
+                    let engine = config.engine();
+                    let soft = engine.extract(&img).ok().filter(|soft| !soft.is_empty())?;
+                    let payload = engine.decode_payload(&soft);
+                    Some((soft, payload))
+                })
+                .await
+                .map_err(|e| ImageError::WatermarkProcessError(e.to_string()))?;
+                if let Some((soft, Some(payload))) = &result {
+                    if decoded.is_none() {
+                        decoded = Some(*payload);
+                        // 载荷中带有图片 ID，直接按 ID 找到记录，不再比较其余的记录
+                        if let Some((found, rate, correlation)) =
+                            lookup_payload(engine.as_ref(), soft, payload).await?
+                        {
+                            compared += 1;
+                            authentic = Some((found, rate, correlation, *payload));
+                            break 'scan;
+                        }
+                    }
+                }
+                extracted.insert(record.clone(), result);
+            }
+            let Some(Some((soft, payload))) = extracted.get(&record) else {
+                skipped += 1;
+                continue;
+            };
+            let Some(stored) = watermark.watermark_base64.as_deref() else {
+                skipped += 1;
+                continue;
+            };
+            let Ok((bit_error_rate, correlation)) = engine.compare(soft, stored) else {
+                skipped += 1;
+                continue;
+            };
+            compared += 1;
+            if let Some(payload) = payload {
+                if stored_payload(engine.as_ref(), stored, soft.len()) == Some(*payload) {
+                    authentic = Some((watermark, bit_error_rate, correlation, *payload));
+                    break 'scan;
+                }
+            }
+            if best
+                .as_ref()
+                .is_none_or(|(_, best_rate, _, _)| bit_error_rate < *best_rate)
+            {
+                best = Some((watermark, bit_error_rate, correlation, soft.len()));
+            }
+        }
+        if finished {
+            break;
+        }
+    }
+
+    let owner = match_owner(authentic, best, decoded, max_bit_error_rate, compared).await?;
+    Ok(OwnerSearch {
+        owner,
+        compared,
+        skipped,
+    })
+}
+
+// 按载荷中的图片 ID 查找记录，只接受属于载荷中所有者、且登记的载荷与解码结果相同的记录
+async fn lookup_payload(
+    engine: &dyn WatermarkEngine,
+    soft: &[f32],
+    payload: &WatermarkPayload,
+) -> Result<Option<(Watermarks, f32, f32)>, ImageError> {
+    let rb = get_db().await;
+    let candidates = Watermarks::select_by_payload_image_id(rb, payload.image_id as i64)
+        .await
+        .map_err(ImageError::DatabaseError)?;
+    for watermark in candidates {
+        let Some(stored) = watermark.watermark_base64.as_deref() else {
+            continue;
+        };
+        if stored_payload(engine, stored, soft.len()) != Some(*payload) {
+            continue;
+        }
+        let owner = match watermark.image_id {
+            Some(image_id) => Images::select_by_id(rb, image_id)
+                .await
+                .map_err(ImageError::DatabaseError)?
+                .and_then(|image| image.user_id),
+            None => None,
+        };
+        if owner != Some(payload.owner_id as i32) {
+            continue;
+        }
+        let (rate, correlation) = engine.compare(soft, stored)?;
+        return Ok(Some((watermark, rate, correlation)));
+    }
+    Ok(None)
+}
+
+// 按载荷匹配的记录、误码率最低的记录或只解码出的载荷确定所有者
+async fn match_owner(
+    authentic: Option<(Watermarks, f32, f32, WatermarkPayload)>,
+    best: Option<(Watermarks, f32, f32, usize)>,
+    decoded: Option<WatermarkPayload>,
+    max_bit_error_rate: f32,
+    compared: usize,
+) -> Result<Option<WatermarkMatch>, ImageError> {
+    let rb = get_db().await;
+    // MAC 校验通过即可确认，按误码率匹配时在下面计算
+    let mut confidence = 1.0;
+    let (watermark, bit_error_rate, correlation, payload) = match (authentic, best) {
+        (Some((watermark, rate, correlation, payload)), _) => {
+            (Some(watermark), rate, correlation, Some(payload))
+        }
+        (None, Some((watermark, rate, correlation, bits))) if rate <= max_bit_error_rate => {
+            // 置信度为不是偶然匹配的概率，比较的记录越多，同样的误码率越可能是偶然匹配
+            let errors = (rate * bits as f32).round() as usize;
+            confidence = 1.0 - false_match_probability(bits, errors, compared) as f32;
+            (Some(watermark), rate, correlation, None)
+        }
+        // 载荷通过校验但没有对应的水印记录，只能确定所有者
+        _ => match decoded {
+            Some(payload) => (None, 0.0, 1.0, Some(payload)),
+            None => return Ok(None),
+        },
+    };
+    let image = match watermark.as_ref().and_then(|watermark| watermark.image_id) {
+        Some(image_id) => Images::select_by_id(rb, image_id)
+            .await
+            .map_err(ImageError::DatabaseError)?,
+        None => None,
+    };
+    let user_id = image
+        .as_ref()
+        .and_then(|image| image.user_id)
+        .or(payload.map(|payload| payload.owner_id as i32));
+    let username = match user_id {
+        Some(user_id) => Users::select_by_id(rb, user_id)
+            .await
+            .map_err(ImageError::DatabaseError)?
+            .and_then(|user| user.username),
+        None => None,
+    };
+    Ok(Some(WatermarkMatch {
+        username,
+        image_cid: image.as_ref().and_then(|image| image.cid.clone()),
+        // 水印记录中的 token id 在铸造时写入，旧记录回退到图片上的 token id
+        token_id: watermark
+            .and_then(|watermark| watermark.token_id)
+            .or_else(|| image.and_then(|image| image.token_id)),
+        bit_error_rate,
+        correlation,
+        confidence,
+        payload,
+    }))
+}
+
+/// 登记的水印图片中保存的是载荷的码字，按同一引擎解码得到嵌入的载荷
+pub fn stored_payload(
+    engine: &dyn WatermarkEngine,
+    watermark_base64: &str,
+    len: usize,
+) -> Option<WatermarkPayload> {
+    let png = general_purpose::STANDARD.decode(watermark_base64).ok()?;
+    let bits = png_to_bits(&png, len).ok()?;
+    let soft: Vec<f32> = bits
+        .iter()
+        .map(|bit| if *bit { 1.0 } else { -1.0 })
+        .collect();
+    engine.decode_payload(&soft)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_compare() {
+        let bits = [true, false, true, true];
+        let (rate, correlation) = compare(&[0.9, -0.8, 0.7, 1.0], &bits);
+        assert_eq!(rate, 0.0);
+        assert!(correlation > 0.95);
+
+        // 一个比特错误
+        let (rate, correlation) = compare(&[0.9, 0.2, 0.7, 1.0], &bits);
+        assert_eq!(rate, 0.25);
+        assert!(correlation > 0.5 && correlation < 0.95);
+
+        // 完全相反
+        let (rate, correlation) = compare(&[-0.9, 0.8, -0.7, -1.0], &bits);
+        assert_eq!(rate, 1.0);
+        assert!(correlation < -0.95);
+
+        assert_eq!(compare(&[], &bits), (1.0, 0.0));
+    }
+
+    #[test]
+    fn test_false_match_probability() {
+        // 64 比特中错误不超过 9 个
+        let single = false_match_probability(64, 9, 1);
+        assert!(single > 1e-9 && single < 3e-9);
+        // 与一百万条记录比较时偶然匹配的概率约为一百万倍
+        let many = false_match_probability(64, 9, 1_000_000);
+        assert!((many / single - 1e6).abs() / 1e6 < 0.01);
+        // 误码率 0.5 时几乎必然偶然匹配
+        assert!(false_match_probability(64, 32, 1) > 0.5);
+        assert!(false_match_probability(64, 32, 100) > 0.99);
+        assert_eq!(false_match_probability(64, 64, 1), 1.0);
+    }
+}
@@ -0,0 +1,428 @@
+use crate::Error::ImageError;
+use image::imageops::{self, FilterType};
+use image::{DynamicImage, Rgba, RgbaImage};
+use rocket::fairing::AdHoc;
+use rusttype::{point, Font, Scale};
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, VecDeque};
+use std::sync::{Arc, Mutex, OnceLock};
+
+// 可见水印：只用于对外公开的预览图，ipfs 中的原图只带不可见水印。
+// 预览图先缩小到 max_side 以内，再叠加文字（可包含 {owner}、{token_id}、{date}）或 logo，
+// 叠加层的宽度为预览图宽度的 scale 倍，可以放在指定位置或平铺整张图片。
+// 在 Rocket.toml 的 [default.preview] 中配置，text 和 logo_path 都未配置时只缩小不叠加。
+// 字体和 logo 在启动时加载一次，渲染好的预览图按 cid 和叠加内容缓存在内存中
+
+static CONFIG: OnceLock<PreviewConfig> = OnceLock::new();
+static ASSETS: OnceLock<OverlayAssets> = OnceLock::new();
+static CACHE: OnceLock<Mutex<PreviewCache>> = OnceLock::new();
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Position {
+    TopLeft,
+    TopRight,
+    BottomLeft,
+    #[default]
+    BottomRight,
+    Center,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(default)]
+pub struct PreviewConfig {
+    // 预览图最长边的像素数
+    pub max_side: u32,
+    // 预览图 JPEG 质量
+    pub quality: u8,
+    // 文字模板，如 "© {owner} #{token_id} {date}"
+    pub text: Option<String>,
+    // 渲染文字所用的 TTF 字体
+    pub font_path: Option<String>,
+    // logo 图片（PNG，可带透明通道），配置后优先于文字
+    pub logo_path: Option<String>,
+    pub position: Position,
+    // 叠加层不透明度 0..=1
+    pub opacity: f32,
+    // 叠加层宽度相对预览图宽度的比例
+    pub scale: f32,
+    // 是否平铺
+    pub tile: bool,
+    // 内存中最多缓存的预览图数量
+    pub cache_entries: usize,
+}
+
+impl Default for PreviewConfig {
+    fn default() -> Self {
+        PreviewConfig {
+            max_side: 1024,
+            quality: 85,
+            text: None,
+            font_path: None,
+            logo_path: None,
+            position: Position::default(),
+            opacity: 0.5,
+            scale: 0.3,
+            tile: false,
+            cache_entries: 256,
+        }
+    }
+}
+
+/// 填入文字模板的图片信息
+#[derive(Clone, Debug, Default)]
+pub struct OverlayContext {
+    pub owner: String,
+    pub token_id: Option<String>,
+    pub date: String,
+}
+
+impl OverlayContext {
+    pub fn render(&self, template: &str) -> String {
+        template
+            .replace("{owner}", &self.owner)
+            .replace("{token_id}", self.token_id.as_deref().unwrap_or("-"))
+            .replace("{date}", &self.date)
+    }
+
+    /// 预览图的缓存键，叠加的内容变化（如铸造后有了 token id）时键也随之变化
+    pub fn cache_key(&self, cid: &str) -> String {
+        format!(
+            "{}\n{}\n{}\n{}",
+            cid,
+            self.owner,
+            self.token_id.as_deref().unwrap_or("-"),
+            self.date
+        )
+    }
+}
+
+/// 启动时加载的字体和 logo
+#[derive(Default)]
+pub struct OverlayAssets {
+    pub font: Option<Font<'static>>,
+    pub logo: Option<RgbaImage>,
+}
+
+impl OverlayAssets {
+    /// 读取配置中的字体和 logo，未配置的项为 None
+    pub fn load(config: &PreviewConfig) -> Result<Self, ImageError> {
+        let logo = match &config.logo_path {
+            Some(logo_path) => Some(
+                image::open(logo_path)
+                    .map_err(|e| {
+                        ImageError::IOError(format!("Failed to load logo {}: {}", logo_path, e))
+                    })?
+                    .to_rgba8(),
+            ),
+            None => None,
+        };
+        let font = match &config.font_path {
+            Some(font_path) => {
+                let font_data = std::fs::read(font_path).map_err(|e| {
+                    ImageError::IOError(format!("Failed to load font {}: {}", font_path, e))
+                })?;
+                Some(Font::try_from_vec(font_data).ok_or_else(|| {
+                    ImageError::IOError(format!("{} is not a valid font", font_path))
+                })?)
+            }
+            None => None,
+        };
+        Ok(OverlayAssets { font, logo })
+    }
+}
+
+pub fn preview_config() -> &'static PreviewConfig {
+    CONFIG.get_or_init(PreviewConfig::default)
+}
+
+pub fn overlay_assets() -> &'static OverlayAssets {
+    ASSETS.get_or_init(OverlayAssets::default)
+}
+
+/// 启动时从 Rocket 配置中读取 preview 并加载字体和 logo，加载失败时拒绝启动
+pub fn preview_overlay_config() -> AdHoc {
+    AdHoc::try_on_ignite("Preview Overlay", |rocket| async {
+        let config = rocket
+            .figment()
+            .extract_inner::<PreviewConfig>("preview")
+            .unwrap_or_default();
+        let assets = match OverlayAssets::load(&config) {
+            Ok(assets) => assets,
+            Err(e) => {
+                println!("Failed to load preview overlay: {}", e);
+                return Err(rocket);
+            }
+        };
+        let _ = CONFIG.set(config);
+        let _ = ASSETS.set(assets);
+        Ok(rocket)
+    })
+}
+
+// 按插入顺序淘汰的预览图缓存
+#[derive(Default)]
+struct PreviewCache {
+    entries: HashMap<String, Arc<Vec<u8>>>,
+    order: VecDeque<String>,
+}
+
+fn preview_cache() -> &'static Mutex<PreviewCache> {
+    CACHE.get_or_init(|| Mutex::new(PreviewCache::default()))
+}
+
+/// 取出缓存的预览图 JPEG
+pub fn cached_preview(key: &str) -> Option<Arc<Vec<u8>>> {
+    let cache = preview_cache().lock().unwrap_or_else(|e| e.into_inner());
+    cache.entries.get(key).cloned()
+}
+
+/// 缓存渲染好的预览图，超过 cache_entries 时淘汰最早缓存的
+pub fn cache_preview(key: String, jpeg: Arc<Vec<u8>>, capacity: usize) {
+    if capacity == 0 {
+        return;
+    }
+    let mut cache = preview_cache().lock().unwrap_or_else(|e| e.into_inner());
+    if cache.entries.insert(key.clone(), jpeg).is_none() {
+        cache.order.push_back(key);
+    }
+    while cache.order.len() > capacity {
+        if let Some(oldest) = cache.order.pop_front() {
+            cache.entries.remove(&oldest);
+        }
+    }
+}
+
+/// 由原图生成带可见水印的预览图
+pub fn render_preview(
+    master: &DynamicImage,
+    config: &PreviewConfig,
+    assets: &OverlayAssets,
+    context: &OverlayContext,
+) -> Result<DynamicImage, ImageError> {
+    let preview = if master.width().max(master.height()) > config.max_side {
+        master.thumbnail(config.max_side, config.max_side)
+    } else {
+        master.clone()
+    };
+    let mut canvas = preview.to_rgba8();
+    let target_width = ((canvas.width() as f32 * config.scale) as u32).max(1);
+    if let Some(overlay) = build_overlay(target_width, config, assets, context)? {
+        apply_overlay(&mut canvas, &with_opacity(overlay, config.opacity), config);
+    }
+    Ok(DynamicImage::ImageRgba8(canvas))
+}
+
+fn build_overlay(
+    target_width: u32,
+    config: &PreviewConfig,
+    assets: &OverlayAssets,
+    context: &OverlayContext,
+) -> Result<Option<RgbaImage>, ImageError> {
+    if let Some(logo) = &assets.logo {
+        let height =
+            (logo.height() as u64 * target_width as u64 / logo.width().max(1) as u64).max(1) as u32;
+        return Ok(Some(imageops::resize(
+            logo,
+            target_width,
+            height,
+            FilterType::Triangle,
+        )));
+    }
+
+    let Some(template) = &config.text else {
+        return Ok(None);
+    };
+    let font = assets.font.as_ref().ok_or_else(|| {
+        ImageError::IOError("font_path is required to render preview text".to_string())
+    })?;
+    Ok(Some(render_text(
+        font,
+        &context.render(template),
+        target_width,
+    )))
+}
+
+// 按目标宽度选择字号，将白色文字渲染到透明背景上
+fn render_text(font: &Font, text: &str, target_width: u32) -> RgbaImage {
+    let text_width = |scale: Scale| {
+        font.layout(text, scale, point(0.0, 0.0))
+            .last()
+            .map(|glyph| glyph.position().x + glyph.unpositioned().h_metrics().advance_width)
+            .unwrap_or(0.0)
+    };
+    let base = text_width(Scale::uniform(100.0));
+    let size = if base > 0.0 {
+        100.0 * target_width as f32 / base
+    } else {
+        100.0
+    };
+    let scale = Scale::uniform(size);
+    let metrics = font.v_metrics(scale);
+
+    let width = (text_width(scale).ceil() as u32).max(1);
+    let height = ((metrics.ascent - metrics.descent).ceil() as u32).max(1);
+    let mut image = RgbaImage::new(width, height);
+    for glyph in font.layout(text, scale, point(0.0, metrics.ascent)) {
+        let Some(bounds) = glyph.pixel_bounding_box() else {
+            continue;
+        };
+        glyph.draw(|x, y, coverage| {
+            let (px, py) = (x as i32 + bounds.min.x, y as i32 + bounds.min.y);
+            if px >= 0 && py >= 0 && (px as u32) < width && (py as u32) < height {
+                image.put_pixel(
+                    px as u32,
+                    py as u32,
+                    Rgba([255, 255, 255, (coverage * 255.0) as u8]),
+                );
+            }
+        });
+    }
+    image
+}
+
+fn with_opacity(mut overlay: RgbaImage, opacity: f32) -> RgbaImage {
+    let opacity = opacity.clamp(0.0, 1.0);
+    for pixel in overlay.pixels_mut() {
+        pixel.0[3] = (pixel.0[3] as f32 * opacity).round() as u8;
+    }
+    overlay
+}
+
+fn apply_overlay(canvas: &mut RgbaImage, overlay: &RgbaImage, config: &PreviewConfig) {
+    let (width, height) = (canvas.width() as i64, canvas.height() as i64);
+    let (overlay_width, overlay_height) = (overlay.width() as i64, overlay.height() as i64);
+    let margin = (width / 50).max(1);
+
+    if config.tile {
+        // 平铺时相邻两块之间留出半个叠加层的间距，奇数行错开
+        let step_x = overlay_width + overlay_width / 2;
+        let step_y = overlay_height * 3;
+        for (row, y) in (0..height).step_by(step_y.max(1) as usize).enumerate() {
+            let offset = if row % 2 == 1 { -step_x / 2 } else { 0 };
+            let mut x = offset;
+            while x < width {
+                imageops::overlay(canvas, overlay, x, y);
+                x += step_x.max(1);
+            }
+        }
+        return;
+    }
+
+    let x = match config.position {
+        Position::TopLeft | Position::BottomLeft => margin,
+        Position::TopRight | Position::BottomRight => width - overlay_width - margin,
+        Position::Center => (width - overlay_width) / 2,
+    };
+    let y = match config.position {
+        Position::TopLeft | Position::TopRight => margin,
+        Position::BottomLeft | Position::BottomRight => height - overlay_height - margin,
+        Position::Center => (height - overlay_height) / 2,
+    };
+    imageops::overlay(canvas, overlay, x, y);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_render_preview() {
+        let context = OverlayContext {
+            owner: "alice".to_string(),
+            token_id: Some("7".to_string()),
+            date: "2024-05-01".to_string(),
+        };
+        assert_eq!(
+            context.render("© {owner} #{token_id} {date}"),
+            "© alice #7 2024-05-01"
+        );
+
+        let logo_path =
+            std::env::temp_dir().join(format!("preview_logo_{}.png", std::process::id()));
+        RgbaImage::from_pixel(40, 20, Rgba([255, 0, 0, 255]))
+            .save(&logo_path)
+            .unwrap();
+        let master =
+            DynamicImage::ImageRgba8(RgbaImage::from_pixel(2000, 1000, Rgba([0, 0, 0, 255])));
+        let config = PreviewConfig {
+            max_side: 500,
+            logo_path: Some(logo_path.to_string_lossy().to_string()),
+            opacity: 0.5,
+            scale: 0.2,
+            ..Default::default()
+        };
+
+        let assets = OverlayAssets::load(&config).unwrap();
+
+        // 缩小到 500x250，logo 宽 100 高 50，半透明地放在右下角
+        let preview = render_preview(&master, &config, &assets, &context)
+            .unwrap()
+            .to_rgba8();
+        assert_eq!(preview.dimensions(), (500, 250));
+        let logo_pixel = preview.get_pixel(500 - 10 - 50, 250 - 10 - 25);
+        assert!(logo_pixel.0[0] > 100 && logo_pixel.0[0] < 160);
+        assert_eq!(preview.get_pixel(5, 5).0[0], 0);
+
+        // 平铺时左上角也有 logo
+        let tiled = PreviewConfig {
+            tile: true,
+            ..config.clone()
+        };
+        let preview = render_preview(&master, &tiled, &assets, &context)
+            .unwrap()
+            .to_rgba8();
+        assert!(preview.get_pixel(5, 5).0[0] > 100);
+
+        // 没有配置文字和 logo 时只缩小
+        let no_assets = OverlayAssets::default();
+        let plain =
+            render_preview(&master, &PreviewConfig::default(), &no_assets, &context).unwrap();
+        assert_eq!((plain.width(), plain.height()), (1024, 512));
+        assert!(render_preview(
+            &master,
+            &PreviewConfig {
+                text: Some("{owner}".to_string()),
+                ..Default::default()
+            },
+            &no_assets,
+            &context
+        )
+        .is_err());
+        // 配置的文件不存在时启动即失败
+        assert!(OverlayAssets::load(&PreviewConfig {
+            font_path: Some("/nonexistent/font.ttf".to_string()),
+            ..Default::default()
+        })
+        .is_err());
+
+        std::fs::remove_file(&logo_path).unwrap();
+    }
+
+    #[test]
+    fn test_preview_cache() {
+        let context = OverlayContext {
+            owner: "alice".to_string(),
+            token_id: None,
+            date: "2024-05-01".to_string(),
+        };
+        let unminted = context.cache_key("QmCacheTest");
+        cache_preview(unminted.clone(), Arc::new(vec![1]), 2);
+        assert_eq!(cached_preview(&unminted).as_deref(), Some(&vec![1]));
+
+        // 铸造后叠加内容变化，不再命中旧的预览
+        let minted = OverlayContext {
+            token_id: Some("7".to_string()),
+            ..context
+        }
+        .cache_key("QmCacheTest");
+        assert_ne!(minted, unminted);
+        assert!(cached_preview(&minted).is_none());
+
+        // 超过容量时淘汰最早的
+        cache_preview(minted.clone(), Arc::new(vec![2]), 2);
+        cache_preview("QmCacheTest-other".to_string(), Arc::new(vec![3]), 2);
+        assert!(cached_preview(&unminted).is_none());
+        assert!(cached_preview(&minted).is_some());
+    }
+}
@@ -1,237 +1,81 @@
-use crate::DataBase::{get_db, Images, Users};
+use crate::DataBase::{get_db, Images, Users, Watermarks};
 use crate::Error::ImageError;
 use crate::IPFSImageStorage::storeImage::*;
+use crate::UploadJob::uploadJob::{watermark_reference, ImageUploadMetadata};
+use crate::WatermarkService::engine::{EngineConfig, PYTHON_SCRIPT};
+use crate::WatermarkService::pythonPool::{python_pool, PoolConfig};
+use crate::WatermarkService::structuredPayload::CODEWORD_BITS;
+use crate::WatermarkService::uploadPolicy::upload_policy;
+use crate::WatermarkService::verification::stored_payload;
 use base64::engine::general_purpose;
 use base64::Engine;
-use image::{codecs::jpeg::JpegEncoder, DynamicImage, ImageFormat};
+use image::DynamicImage;
+use rbatis::rbdc::datetime::DateTime;
 use rbatis::Error;
-use serde::Deserialize;
-use serde_json::json;
-use std::io::{Cursor, Read, Write};
-use std::process::{Command, Stdio};
-use std::thread;
 
-#[derive(Deserialize)]
-struct PythonOutput {
-    watermarked_image: Option<String>,
-    watermark_image: Option<String>,
-    error: Option<String>,
-}
-
-// 调用外部 Python 脚本
-pub fn execute_watermark_base64(base64_image: String) -> Result<(String, String), ImageError> {
-    let python_script = "/root/BlockchainImage/python-watermark/main_class.py";
+// 使用 engine 对 base64 编码的图片加水印
+pub fn execute_watermark_base64(
+    base64_image: String,
+    engine: &EngineConfig,
+) -> Result<(Vec<u8>, String), ImageError> {
+    // 解码前按 base64 长度估算字节数，避免为超大的请求分配内存
+    let max_bytes = upload_policy().max_bytes;
+    if base64_image.len() as u64 / 4 * 3 > max_bytes + 3 {
+        return Err(ImageError::ImageRejected(format!(
+            "image exceeds {} bytes",
+            max_bytes
+        )));
+    }
 
     // 解码 Base64 图片为字节流
     let img_bytes = general_purpose::STANDARD
         .decode(&base64_image)
         .map_err(|_| ImageError::DecodeBytesError)?;
 
-    // 将字节流转换为 DynamicImage
-    let img = image::load_from_memory(&img_bytes).map_err(|_| ImageError::DecodeBytesError)?;
-
-    // 将图片保存为 .jpg 格式并处理
-    let mut img_bytes = Vec::new();
-    let mut cursor = Cursor::new(&mut img_bytes);
-    img.write_to(&mut cursor, ImageFormat::Jpeg)
-        .map_err(|_| ImageError::EncodeBytesError)?;
-
-    // 获取处理后的字节流并进行 Base64 编码
-    let encoded_image = general_purpose::STANDARD.encode(&img_bytes);
-
-    // 创建 JSON 对象
-    let json_data = json!({
-        "base64_image": encoded_image
-    });
-
-    // 启动 Python 脚本
-    let mut child = Command::new("python3")
-        .arg(python_script)
-        .stdin(Stdio::piped())
-        .stdout(Stdio::piped())
-        .stderr(Stdio::piped())
-        .spawn()
-        .map_err(|_| ImageError::FailedStartAddWatermark)?;
-
-    // 写入 JSON 数据到 Python 脚本的 stdin
-    if let Some(mut stdin) = child.stdin.take() {
-        stdin
-            .write_all(json_data.to_string().as_bytes())
-            .map_err(|_| ImageError::FailedStartAddWatermark)?;
-        drop(stdin); // 关闭 stdin
-    }
-
-    // 读取 stdout 和 stderr
-    let stdout = child.stdout.take().expect("Failed to capture stdout");
-    let stderr = child.stderr.take().expect("Failed to capture stderr");
-
-    let stdout_thread = thread::spawn(move || {
-        let mut output = String::new();
-        let mut reader = std::io::BufReader::new(stdout);
-        reader
-            .read_to_string(&mut output)
-            .map(|_| output)
-            .map_err(|e| format!("Failed to read stdout: {}", e))
-    });
-
-    let stderr_thread = thread::spawn(move || {
-        let mut output = String::new();
-        let mut reader = std::io::BufReader::new(stderr);
-        reader
-            .read_to_string(&mut output)
-            .map(|_| output)
-            .map_err(|e| format!("Failed to read stderr: {}", e))
-    });
-
-    let stdout_result = stdout_thread.join().unwrap();
-    let stderr_result = stderr_thread.join().unwrap();
-
-    let output = stdout_result.map_err(|e| ImageError::WatermarkProcessError(e))?;
-    let stderr = stderr_result.map_err(|e| ImageError::WatermarkProcessError(e))?;
-
-    // 等待子进程完成
-    let status = child.wait().expect("Failed to wait on child process");
-
-    if status.success() {
-        // 解析 Python 脚本的输出
-        let parsed_output: PythonOutput = serde_json::from_str(&output).map_err(|e| {
-            ImageError::WatermarkProcessError(format!("Failed to parse JSON output: {}", e))
-        })?;
-
-        if let (Some(wm_image), Some(wm)) = (
-            parsed_output.watermarked_image,
-            parsed_output.watermark_image,
-        ) {
-            return Ok((wm_image, wm));
-        }
+    execute_watermark_bytes(&img_bytes, engine)
+}
 
-        if let Some(error_msg) = parsed_output.error {
-            Err(ImageError::WatermarkProcessError(error_msg))
-        } else {
-            Err(ImageError::WatermarkProcessError(
-                "Python script did not return expected data".to_string(),
-            ))
-        }
-    } else {
-        Err(ImageError::JsonParseError)
-    }
+// 直接接收上传文件的原始字节，使用 engine 加水印，返回 (带水印图片的字节, 水印图片的 base64)
+pub fn execute_watermark_bytes(
+    img_bytes: &[u8],
+    engine: &EngineConfig,
+) -> Result<(Vec<u8>, String), ImageError> {
+    // 按上传策略检查后再将字节流转换为 DynamicImage
+    let img = upload_policy().decode(img_bytes)?;
+
+    let (watermarked_base64, watermark_base64) = engine.engine().embed(&img)?;
+    let watermarked = general_purpose::STANDARD
+        .decode(&watermarked_base64)
+        .map_err(|_| ImageError::DecodeBytesError)?;
+    Ok((watermarked, watermark_base64))
 }
+
 // 调用外部 Python 脚本
 pub fn execute_watermark_jpg(img: DynamicImage) -> Result<(String, String), String> {
-    let python_script = "/root/BlockchainImage/python-watermark/main_class.py";
-
-    // 将图片保存为 .jpg 格式
-    let mut img_bytes = Vec::new();
-    let mut cursor = Cursor::new(&mut img_bytes);
-
-    img.write_to(&mut cursor, ImageFormat::Jpeg)
-        .map_err(|_| "Failed to write image to bytes")?;
-
-    // 获取处理后的字节流并进行 Base64 编码
-    let encoded_image = general_purpose::STANDARD.encode(&img_bytes);
-
-    // 创建 JSON 对象
-    let json_data = json!({
-        "base64_image": encoded_image
-    });
-
-    // 调用外部 Python 脚本处理水印
-    let mut child = Command::new("python3")
-        .arg(python_script)
-        .stdin(Stdio::piped())
-        .stdout(Stdio::piped())
-        .stderr(Stdio::piped())
-        .spawn()
-        .expect("Failed to spawn process");
-
-    // 写入 JSON 数据到 Python 脚本的 stdin
-    if let Some(mut stdin) = child.stdin.take() {
-        stdin
-            .write_all(json_data.to_string().as_bytes())
-            .map_err(|_| "Failed to write to stdin".to_string())?;
-        // 写入完毕后立即关闭stdin，告知python输入结束
-        drop(stdin);
-    }
-
-    let stdout = child.stdout.take().expect("Failed to capture stdout");
-    let stderr = child.stderr.take().expect("Failed to capture stderr");
-
-    // 使用线程并行读取 stdout 和 stderr
-    let stdout_thread = thread::spawn(move || {
-        let mut output = String::new();
-        let mut reader = std::io::BufReader::new(stdout);
-        reader
-            .read_to_string(&mut output)
-            .map(|_| output)
-            .map_err(|e| format!("Failed to read stdout: {}", e))
-    });
-
-    let stderr_thread = thread::spawn(move || {
-        let mut output = String::new();
-        let mut reader = std::io::BufReader::new(stderr);
-        reader
-            .read_to_string(&mut output)
-            .map(|_| output)
-            .map_err(|e| format!("Failed to read stderr: {}", e))
-    });
-
-    // 等待线程完成并收集结果
-    let stdout_result = stdout_thread.join().unwrap();
-    let stderr_result = stderr_thread.join().unwrap();
-
-    let output = stdout_result?;
-    let stderr = stderr_result?;
-
-    // 等待子进程完成
-    let status = child
-        .wait()
-        .map_err(|_| "Failed to wait on child process".to_string())?;
-
-    if status.success() {
-        // 解析 Python 脚本的输出
-        let parsed_output: PythonOutput = serde_json::from_str(&output)
-            .map_err(|e| format!("Failed to parse JSON output: {}", e))?;
-
-        if let (Some(wm_image), Some(wm)) = (
-            parsed_output.watermarked_image,
-            parsed_output.watermark_image,
-        ) {
-            return Ok((wm_image, wm));
-        }
-
-        if let Some(error_msg) = parsed_output.error {
-            Err(error_msg)
-        } else {
-            Err("Python script did not return expected data".to_string())
-        }
-    } else {
-        if !stderr.is_empty() {
-            Err(format!("Python script error: {}", stderr))
-        } else {
-            Err("Python script failed".to_string())
-        }
-    }
+    python_pool(&PoolConfig::new(PYTHON_SCRIPT))
+        .watermark(&img)
+        .map_err(|e| e.to_string())
 }
 
 /// todo: 换到ipfs中
 
-// 将带水印的图片存放到ipfs，将水印本身存放到数据库
+// 将带水印的图片存放到ipfs，将水印本身存放到 watermarks 表，随图片提交的图片信息记录在 images 表
 pub async fn storage_image(
-    watermarked_base64: String,
+    watermarked: Vec<u8>,
     watermark_base64: String,
     username: String,
+    engine: &EngineConfig,
+    metadata: Option<&ImageUploadMetadata>,
 ) -> Result<String, ImageError> {
     // 获取数据库连接池
     let rb = get_db().await;
     let mut user_id;
     let mut image_cid = String::new();
 
-    // 将数字水印保存到数据库
+    // 查找上传用户，水印在图片保存后按图片记录
     match Users::select_by_username(rb, &username).await {
-        Ok(Some(mut user)) => {
+        Ok(Some(user)) => {
             user_id = user.id;
-            user.watermark_base64 = Some(watermark_base64.clone());
-            println!("successed to save watermark {}", watermark_base64.clone());
         }
         Err(e) => {
             println!("Failed to find user with username {}", username);
@@ -245,9 +89,8 @@ pub async fn storage_image(
         }
     }
 
-    // 将水印图片保存到ipfs
-    let ipfs_api_url = "http://192.168.0.7:5001";
-    match upload_and_pin_base64(&watermarked_base64, ipfs_api_url).await {
+    // 将水印图片保存到ipfs并固定
+    match add_bytes(watermarked, "watermarked_image.jpg", IPFS_API_URL).await {
         Ok(cid) => {
             image_cid = cid.clone();
             println!("successed to save picture {}", cid);
@@ -259,20 +102,174 @@ pub async fn storage_image(
             ));
         }
     };
+    pin_cid(&image_cid, IPFS_API_URL)
+        .await
+        .map_err(|e| ImageError::IpfsError(e.to_string()))?;
 
-    // 保存图片到数据库中
+    // 保存图片到数据库中，铸造后由 backfill_token_ids 补写 token id
     let image_table = Images {
         id: None,
         cid: Some(image_cid.clone()),
         user_id,
+        token_id: None,
+        created_at: Some(DateTime::now()),
+        metadata: metadata.and_then(|metadata| serde_json::to_string(metadata).ok()),
     };
-    let data = Images::insert(rb, &image_table)
-        .await
-        .map_err(|_| ImageError::DatabaseError(Error::E("Insert data error".to_string())))?;
-    println!("insert = {}", json!(data));
+    insert_image(&image_table, &watermark_base64, engine).await?;
     Ok(image_cid)
 }
 
+// 将 ipfs 中的图片 cid（以及铸造出的 token id）与上传用户、图片信息关联写入 images 表，嵌入的水印写入 watermarks 表
+pub async fn record_image(
+    username: &str,
+    cid: &str,
+    token_id: Option<String>,
+    watermark_base64: &str,
+    engine: &EngineConfig,
+    metadata: &ImageUploadMetadata,
+) -> Result<(), ImageError> {
+    let rb = get_db().await;
+    let user = Users::select_by_username(rb, username)
+        .await
+        .map_err(ImageError::DatabaseError)?
+        .ok_or_else(|| {
+            ImageError::DatabaseError(Error::E(format!(
+                "Failed to find user with username {}",
+                username
+            )))
+        })?;
+    let image_table = Images {
+        id: None,
+        cid: Some(cid.to_string()),
+        user_id: user.id,
+        token_id,
+        created_at: Some(DateTime::now()),
+        metadata: serde_json::to_string(metadata).ok(),
+    };
+    insert_image(&image_table, watermark_base64, engine).await
+}
+
+// 图片和它的水印记录在同一个事务中写入，不会只留下其中一条
+async fn insert_image(
+    image: &Images,
+    watermark_base64: &str,
+    engine: &EngineConfig,
+) -> Result<(), ImageError> {
+    let mut tx = get_db()
+        .await
+        .acquire_begin()
+        .await
+        .map_err(ImageError::DatabaseError)?;
+    let inserted = async {
+        let data = Images::insert(&tx, image).await?;
+        let image_id = data.last_insert_id.as_i64().map(|id| id as i32);
+        let watermark =
+            watermark_record(image_id, image.token_id.clone(), watermark_base64, engine);
+        Watermarks::insert(&tx, &watermark).await
+    }
+    .await;
+    match inserted {
+        Ok(_) => tx.commit().await.map_err(ImageError::DatabaseError),
+        Err(e) => {
+            let _ = tx.rollback().await;
+            Err(ImageError::DatabaseError(e))
+        }
+    }
+}
+
+// 每张图片一行水印记录：所用引擎和参数、水印本身，以及与链上 tokenInfo 中相同的水印摘要，
+// payload 引擎还记录载荷中的图片 ID
+fn watermark_record(
+    image_id: Option<i32>,
+    token_id: Option<String>,
+    watermark_base64: &str,
+    engine: &EngineConfig,
+) -> Watermarks {
+    Watermarks {
+        id: None,
+        image_id,
+        token_id,
+        engine: Some(engine.name().to_string()),
+        parameters: Some(engine.to_record()),
+        watermark_base64: Some(watermark_base64.to_string()),
+        watermark_hash: Some(watermark_reference(watermark_base64)),
+        created_at: Some(DateTime::now()),
+        payload_image_id: match engine {
+            EngineConfig::Payload { .. } => {
+                stored_payload(engine.engine().as_ref(), watermark_base64, CODEWORD_BITS)
+                    .map(|payload| payload.image_id as i64)
+            }
+            _ => None,
+        },
+    }
+}
+
+/// 铸造之后按链上记录的水印摘要找到登记过的图片，把 token id 补写到 images 和 watermarks 表。
+/// minted 为 (水印摘要, token id)，只补写 user_id 登记且尚未铸造的图片，返回补写的图片数
+pub async fn backfill_token_ids(
+    user_id: Option<i32>,
+    minted: &[(String, String)],
+) -> Result<usize, ImageError> {
+    let rb = get_db().await;
+    let mut updated = 0;
+    for (watermark_hash, token_id) in minted {
+        let Some((mut image, mut watermark)) = unminted_image(user_id, watermark_hash).await?
+        else {
+            continue;
+        };
+        image.token_id = Some(token_id.clone());
+        watermark.token_id = Some(token_id.clone());
+
+        let mut tx = rb
+            .acquire_begin()
+            .await
+            .map_err(ImageError::DatabaseError)?;
+        let saved = async {
+            Images::update_by_column(&tx, &image, "id").await?;
+            Watermarks::update_by_column(&tx, &watermark, "id").await
+        }
+        .await;
+        match saved {
+            Ok(_) => tx.commit().await.map_err(ImageError::DatabaseError)?,
+            Err(e) => {
+                let _ = tx.rollback().await;
+                return Err(ImageError::DatabaseError(e));
+            }
+        }
+        updated += 1;
+    }
+    Ok(updated)
+}
+
+// 同一个水印摘要可能对应多张图片（noop 引擎的空水印、同一水印铸造多次），
+// 按登记顺序取第一张属于 user_id 且尚未铸造的图片
+async fn unminted_image(
+    user_id: Option<i32>,
+    watermark_hash: &str,
+) -> Result<Option<(Images, Watermarks)>, ImageError> {
+    let rb = get_db().await;
+    let watermarks = Watermarks::select_by_hash(rb, watermark_hash)
+        .await
+        .map_err(ImageError::DatabaseError)?;
+    for watermark in watermarks {
+        if watermark.token_id.is_some() {
+            continue;
+        }
+        let Some(image_id) = watermark.image_id else {
+            continue;
+        };
+        let image = Images::select_by_id(rb, image_id)
+            .await
+            .map_err(ImageError::DatabaseError)?;
+        if let Some(image) = image {
+            if image.user_id == user_id && image.token_id.is_none() {
+                return Ok(Some((image, watermark)));
+            }
+        }
+    }
+    Ok(None)
+}
+
 // #[test]
 // pub fn test_execute_watermark() {
 //     let image_path = "/home/kenijima/usr/work/ImageService/UserInfo/Image-01/wukong.jpg";
@@ -0,0 +1,65 @@
+// 水印鲁棒性测试命令，结果以 JSON 输出：
+//   cargo run --release --bin watermarkBenchmark -- [语料目录] [--config 配置.json] [--output 报告.json]
+// 默认使用 Rocket.toml 中 [default.watermark.default] 配置的引擎和 UserInfo/Image-01 语料，
+// 配置文件可以覆盖 corpus、engine 和 attacks，例如
+//   {"engine": {"engine": "native", "strength": 48.0},
+//    "attacks": [{"attack": "jpeg", "quality": 60}, {"attack": "rotate", "degrees": 1.0}]}
+use serde_json::Value;
+use std::process;
+use BlockchainImageService::WatermarkService::benchmark::{run_benchmark, BenchmarkConfig};
+use BlockchainImageService::WatermarkService::engine::load_watermark_config;
+
+fn main() {
+    if let Err(e) = run() {
+        eprintln!("watermark benchmark failed: {}", e);
+        process::exit(1);
+    }
+}
+
+fn run() -> Result<(), String> {
+    let mut corpus = None;
+    let mut config_path = None;
+    let mut output = None;
+    let mut args = std::env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--config" => config_path = Some(args.next().ok_or("--config needs a file")?),
+            "--output" => output = Some(args.next().ok_or("--output needs a file")?),
+            "-h" | "--help" => {
+                println!("usage: watermarkBenchmark [corpus] [--config <file.json>] [--output <report.json>]");
+                return Ok(());
+            }
+            _ => corpus = Some(arg),
+        }
+    }
+
+    let active = load_watermark_config(&rocket::Config::figment());
+    let mut config = BenchmarkConfig {
+        engine: active.default.clone(),
+        ..Default::default()
+    };
+    if let Some(path) = config_path {
+        let text = std::fs::read_to_string(&path).map_err(|e| format!("{}: {}", path, e))?;
+        let overrides: Value = serde_json::from_str(&text).map_err(|e| e.to_string())?;
+        if let Some(value) = overrides.get("corpus") {
+            config.corpus = serde_json::from_value(value.clone()).map_err(|e| e.to_string())?;
+        }
+        if let Some(value) = overrides.get("engine") {
+            config.engine = serde_json::from_value(value.clone()).map_err(|e| e.to_string())?;
+        }
+        if let Some(value) = overrides.get("attacks") {
+            config.attacks = serde_json::from_value(value.clone()).map_err(|e| e.to_string())?;
+        }
+    }
+    if let Some(corpus) = corpus {
+        config.corpus = corpus;
+    }
+
+    let report = run_benchmark(&config).map_err(|e| e.to_string())?;
+    let json = serde_json::to_string_pretty(&report).map_err(|e| e.to_string())?;
+    match output {
+        Some(path) => std::fs::write(&path, json).map_err(|e| format!("{}: {}", path, e))?,
+        None => println!("{}", json),
+    }
+    Ok(())
+}
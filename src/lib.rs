@@ -16,6 +16,8 @@ pub mod Router;
 
 pub mod IdentityAuthentication;
 
+pub mod UploadJob;
+
 use crate::IPFSImageStorage::storeImage::get_cid;
 use crate::Transaction::sendTx::Handler;
 use crate::Transaction::ContractMethod::ContractMethod;
@@ -20,7 +20,16 @@
 use BlockchainImageService::DataBase::*;
 use BlockchainImageService::Router::onchain_router::*;
 use BlockchainImageService::Router::Image_routers::*;
+use BlockchainImageService::Router::Token_routers::*;
+use BlockchainImageService::Router::Market_routers::*;
 use BlockchainImageService::Router::User_routers::*;
+use BlockchainImageService::Router::idempotency::IdempotencyFairing;
+use BlockchainImageService::Router::routers::error_catchers;
+use BlockchainImageService::Error::RequestIdFairing;
+use BlockchainImageService::UploadJob::uploadJob::upload_job_worker;
+use BlockchainImageService::WatermarkService::engine::watermark_engine_config;
+use BlockchainImageService::WatermarkService::uploadPolicy::upload_policy_config;
+use BlockchainImageService::WatermarkService::visibleWatermark::preview_overlay_config;
 use rocket::fairing::{Fairing, Info, Kind};
 use rocket::{Request, Response};
 use rocket::http::Header;
@@ -41,7 +50,7 @@ impl Fairing for CORS {
         response.set_header(Header::new("Access-Control-Allow-Origin", "*"));
         response.set_header(Header::new("Access-Control-Allow-Methods", "POST, GET, OPTIONS, DELETE"));
         // 这里你可以根据需求进行更精细化的控制
-        response.set_header(Header::new("Access-Control-Allow-Headers", "Content-Type, Authorization"));
+        response.set_header(Header::new("Access-Control-Allow-Headers", "Content-Type, Authorization, Idempotency-Key"));
     }
 }
 
@@ -57,8 +66,16 @@ fn index() -> &'static str {
 async fn rocket() -> _ {
     // let rb = get_db().await;
 
-    rocket::build()
+    rocket::custom(rocket::Config::figment().merge(("limits", upload_limits())))
         .attach(CORS)
+        .attach(RequestIdFairing)
+        .attach(IdempotencyFairing)
+        .attach(market_order_index())
+        .attach(upload_job_worker())
+        .attach(upload_policy_config())
+        .attach(watermark_engine_config())
+        .attach(preview_overlay_config())
+        .register("/", error_catchers())
         .mount(
         "/",
         routes![
@@ -66,9 +83,49 @@ async fn rocket() -> _ {
             login,
             register,
             upload_image,
+            upload_image_file,
+            create_upload_job,
+            register_image,
+            register_images,
+            get_upload_job,
+            upload_job_events,
             get_image,
+            download_image,
+            verify_watermark,
+            get_preview,
             upload_imageInfo,
-            get_imageInfo
+            get_imageInfo,
+            speed_up_tx,
+            cancel_tx,
+            get_tx_status,
+            transfer_from,
+            safe_transfer_from,
+            safe_transfer_from_with_value,
+            approve,
+            set_approval_for_all,
+            batch_transfer_from,
+            safe_batch_transfer_from,
+            burn,
+            batch_burn,
+            modify_image_info,
+            modify_capture_info,
+            token_name,
+            token_symbol,
+            total_supply,
+            owner_of,
+            get_approved,
+            token_uri,
+            balance_of,
+            is_approved_for_all,
+            sale_history,
+            token_metadata,
+            sell,
+            market_domain,
+            create_listing,
+            create_offer,
+            list_orders,
+            cancel_order,
+            accept_order
         ],
     )
     // .manage(rb)
@@ -0,0 +1,74 @@
+// 水印鲁棒性测试：完整的基准测试对 UserInfo/Image-01 运行全部攻击，耗时较长，默认忽略，
+// 可以用 BENCHMARK_CORPUS 指定其他语料目录，报告输出到标准输出
+// （cargo test --release --test watermarkBenchmark -- --ignored --nocapture）。
+// 默认运行的测试只对语料中的 wukong.jpg 检查原图和 JPEG 重新压缩两项。
+// 两项测试都使用进程内引擎，不依赖 Python 脚本
+use BlockchainImageService::WatermarkService::benchmark::{
+    run_benchmark, Attack, AttackResult, BenchmarkConfig,
+};
+use BlockchainImageService::WatermarkService::engine::EngineConfig;
+use BlockchainImageService::WatermarkService::nativeWatermark::DEFAULT_STRENGTH;
+
+const KNOWN_IMAGE: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/UserInfo/Image-01/wukong.jpg");
+
+fn native() -> EngineConfig {
+    EngineConfig::Native {
+        strength: DEFAULT_STRENGTH,
+    }
+}
+
+fn rate(attacks: &[AttackResult], label: &str) -> f32 {
+    attacks
+        .iter()
+        .find(|result| result.label == label)
+        .and_then(|result| result.bit_error_rate)
+        .unwrap()
+}
+
+#[test]
+#[ignore]
+fn watermark_benchmark() {
+    let corpus = std::env::var("BENCHMARK_CORPUS")
+        .unwrap_or_else(|_| concat!(env!("CARGO_MANIFEST_DIR"), "/UserInfo/Image-01").to_string());
+    let config = BenchmarkConfig {
+        corpus,
+        engine: native(),
+        ..Default::default()
+    };
+    let report = run_benchmark(&config).unwrap();
+    println!("{}", serde_json::to_string_pretty(&report).unwrap());
+
+    assert!(!report.images.is_empty());
+    for image in &report.images {
+        assert!(
+            image.embed_psnr > 35.0,
+            "{} embed psnr {}",
+            image.file,
+            image.embed_psnr
+        );
+    }
+}
+
+// wukong.jpg（1024 像素宽的照片）用进程内引擎加水印后应能完整提取，
+// JPEG 质量 75 的重新压缩后误码率仍低于 0.05
+#[test]
+fn known_image_survives_jpeg() {
+    let corpus = std::env::temp_dir().join(format!("benchmark_known_{}", std::process::id()));
+    std::fs::create_dir_all(&corpus).unwrap();
+    std::fs::copy(KNOWN_IMAGE, corpus.join("wukong.jpg")).unwrap();
+    let config = BenchmarkConfig {
+        corpus: corpus.to_string_lossy().to_string(),
+        engine: native(),
+        attacks: vec![Attack::Original, Attack::Jpeg { quality: 75 }],
+    };
+    let report = run_benchmark(&config).unwrap();
+    std::fs::remove_dir_all(&corpus).unwrap();
+
+    assert_eq!(report.images.len(), 1);
+    let image = &report.images[0];
+    assert_eq!(image.width, 1024);
+    assert!(image.embed_psnr > 35.0, "embed psnr {}", image.embed_psnr);
+    assert_eq!(rate(&image.attacks, "original"), 0.0);
+    let jpeg = rate(&image.attacks, "jpeg(quality=75)");
+    assert!(jpeg < 0.05, "jpeg(quality=75) bit error rate {}", jpeg);
+}